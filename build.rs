@@ -18,6 +18,20 @@ fn main() -> std::io::Result<()> {
 
       path:<path>
 
+    The prefix can be left out for paths starting with `.`, `/`, `~` or `$`. A leading
+    `~` or `~user` and any `$VAR` or `${{VAR}}` references are expanded.
+
+    The entry file (nilla.nix, or the one named by --file) is searched for upwards from
+    the path, stopping at the root of the git repository. If more than one is found the
+    choice is ambiguous and must be made explicitly with `dir`, which takes the path as
+    the project root and uses the entry file in the given directory:
+
+      path:<path>?dir=<dir>
+
+    Projects inside a git repository are copied with `builtins.fetchGit`, so only
+    tracked files are used. Other directories are copied as they are, leaving out
+    `.git` and anything their `.gitignore` files ignore.
+
   git
 
     Fetch a Nilla project from a Git repository. This follows the format:
@@ -28,6 +42,17 @@ fn main() -> std::io::Result<()> {
 
       git:<url>?rev=<rev>&ref=<ref>&submodules=true&dir=<dir>
 
+  git+https, git+http, git+ssh, git+file
+
+    Fetch a Nilla project from a Git repository using a specific transport. This
+    follows the format:
+
+      git+https://<host>/<path>
+      git+ssh://[<user>@]<host>[:<port>]/<path>
+      git+file:///<path>
+
+    The same query parameters as `git` are accepted.
+
   github
 
     Fetch a Nilla project from a GitHub repository. This follows the format:
@@ -48,6 +73,39 @@ fn main() -> std::io::Result<()> {
 
       gitlab:<owner>/<repo>?rev=<rev>&dir=<dir>
 
+  codeberg
+
+    Fetch a Nilla project from a Codeberg repository. This follows the format:
+
+      codeberg:<owner>/<repo>
+
+    Optionally, additional customization can be applied using query parameters:
+
+      codeberg:<owner>/<repo>?rev=<rev>&dir=<dir>
+
+  forgejo, gitea
+
+    Fetch a Nilla project from a self-hosted Forgejo or Gitea instance. The host
+    must be given:
+
+      forgejo:<owner>/<repo>?host=<host>
+      gitea:<owner>/<repo>?host=<host>&rev=<rev>&dir=<dir>
+
+  sourcehut
+
+    Fetch a Nilla project from a sourcehut repository. This follows the format:
+
+      sourcehut:~<owner>/<repo>
+
+  The `github`, `gitlab`, `codeberg` and `sourcehut` sources also accept `host=` for
+  mirrors and self-hosted instances.
+
+  As with flakes, these sources accept a branch, tag or full commit hash as a third
+  path segment, so the following are equivalent:
+
+      github:<owner>/<repo>/<ref>
+      github:<owner>/<repo>?ref=<ref>
+
   tarball
 
     Fetch a Nilla project from a tarball. This follows the format:
@@ -60,6 +118,73 @@ fn main() -> std::io::Result<()> {
       tarball:http://example.com/project.tar.gz
 
       http://example.com/project.tar.gz
+
+  file
+
+    Unpack a Nilla project from a local archive. This follows the format:
+
+      file:<path>
+      file:///<absolute path>
+
+    The prefix can be left out for paths starting with `.`, `/`, `~` or `$` that end in
+    .zip, .tar, .tar.gz, .tgz, .tar.xz, .txz, .tar.zst or .tzst. The archive can be
+    pinned to its SHA-256 hash (as printed by `sha256sum` or `nix hash file`):
+
+      ./infra.tar.zst?sha256=<hash>
+
+  Any project source may end in a `#<name>` fragment to select the system to use when
+  no name is given on the command line, for example:
+
+      github:<owner>/<repo>/<ref>#<name>
+
+{HEADER_STYLE}Configuration{HEADER_STYLE:#}
+  Settings are read from $XDG_CONFIG_HOME/nilla-nixos/config.toml, or the file named
+  by $NILLA_NIXOS_CONFIG.
+
+  hosts
+
+    Credentials used when fetching from private hosts. For example:
+
+      [hosts.\"github.com\"]
+      ssh-identity = \"~/.ssh/id_infra\"
+
+      [hosts.\"git.example.com\"]
+      token-env = \"INFRA_TOKEN\"   # read the token from this environment variable
+      token-user = \"oauth2\"       # username sent with the token over HTTPS
+      transport = \"https\"         # fetch `github:`-style shorthands over HTTPS
+
+      [hosts.\"downloads.example.com\"]
+      netrc = \"~/.config/nix/netrc\"
+
+  registry
+
+    Named aliases for projects, managed with `nilla-nixos registry`. An alias can be
+    used anywhere a project is expected and is checked before any other source. Query
+    parameters and a `#<name>` fragment given with the alias override those stored in
+    the registry, for example `-p infra?ref=staging`.
+
+      [registry]
+      infra = \"github:org/infra?dir=hosts\"
+
+  nix
+
+    Settings and arguments added to every Nix command. `--nix-option` and arguments
+    after `--` on the command line come after these, so they win.
+
+      [nix]
+      options = {{ substituters = \"https://cache.example.com\", sandbox = \"relaxed\" }}
+      extra-args = [\"--accept-flake-config\"]
+
+  timeouts
+
+    How long, in seconds, each stage may take before it is stopped. Stages left out
+    run for as long as they need. Switching the system profile is never interrupted, by
+    a timeout or by Ctrl-C, which instead stops the run once the switch has finished.
+
+      [timeouts]
+      eval = 300
+      build = 7200
+      activation = 600
 "
     ));
 
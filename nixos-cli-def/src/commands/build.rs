@@ -1,4 +1,6 @@
 use clap::Args;
+
+use super::build_options::BuildOptionArgs;
 #[derive(Debug, Args)]
 #[command(about = "Build a NixOS system")]
 pub struct BuildArgs {
@@ -6,4 +8,6 @@ pub struct BuildArgs {
     pub name: Option<String>,
     #[arg(short, long, help = "System architecture (eg: x86_64-linux)")]
     pub system: Option<String>,
+    #[command(flatten)]
+    pub build: BuildOptionArgs,
 }
@@ -0,0 +1,55 @@
+use std::path::PathBuf;
+
+use clap::{ArgAction, Args};
+
+// Options for how systems get built, shared by the commands that build one.
+#[derive(Debug, Clone, Default, Args)]
+#[command(next_help_heading = "Build options")]
+pub struct BuildOptionArgs {
+    #[arg(short, long, help = "Keep building other derivations when one fails")]
+    pub keep_going: bool,
+    #[arg(
+        short = 'j',
+        long,
+        value_name = "JOBS",
+        help = "How many derivations to build at once (a number or `auto`)"
+    )]
+    pub max_jobs: Option<String>,
+    #[arg(
+        long,
+        value_name = "CORES",
+        help = "How many cores each build may use (0 for all of them)"
+    )]
+    pub cores: Option<u32>,
+    #[arg(
+        short,
+        long,
+        value_name = "PATH",
+        value_hint = clap::ValueHint::AnyPath,
+        help = "Link the built system here"
+    )]
+    pub out_link: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Build the system again even if it is in the store, and check the result is the same"
+    )]
+    pub rebuild: bool,
+    #[arg(
+        long = "option",
+        num_args = 2,
+        value_names = ["NAME", "VALUE"],
+        action = ArgAction::Append,
+        help = "Set a Nix setting for the build"
+    )]
+    pub option: Vec<String>,
+}
+
+impl BuildOptionArgs {
+    // The `--option` pairs, as given.
+    pub fn options(&self) -> Vec<(String, String)> {
+        self.option
+            .chunks_exact(2)
+            .map(|pair| (pair[0].clone(), pair[1].clone()))
+            .collect()
+    }
+}
@@ -0,0 +1,19 @@
+use clap::Args;
+
+use super::make_examples;
+
+#[derive(Debug, Args)]
+#[command(
+    about = "Summarise the configuration of a NixOS system",
+    after_long_help = make_examples(&[
+        ("Summarise this machine's system", "nixos info"),
+        ("Summarise a system of a remote project", "nixos info -p github:org/infra web01"),
+        ("Print the summary as JSON", "nixos info web01 --json"),
+    ])
+)]
+pub struct InfoArgs {
+    #[arg(help = "System name (defaults to the hostname)")]
+    pub name: Option<String>,
+    #[arg(long, help = "Print the summary as JSON")]
+    pub json: bool,
+}
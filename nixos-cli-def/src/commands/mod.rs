@@ -1,9 +1,16 @@
 use clap::builder::styling::Style;
 
 pub mod build;
+pub mod build_options;
 pub mod completions;
+pub mod info;
+pub mod option;
+pub mod options;
+pub mod registry;
+pub mod repl;
 pub mod switch;
 pub mod test;
+pub mod why_depends;
 
 const HEADER_STYLE: Style = Style::new().bold().underline();
 const DIM_STYLE: Style = Style::new().dimmed();
@@ -0,0 +1,21 @@
+use clap::Args;
+
+use super::make_examples;
+
+#[derive(Debug, Args)]
+#[command(
+    about = "Show the value and documentation of a NixOS option",
+    after_long_help = make_examples(&[
+        ("Show the ports open on web01", "nixos option web01 networking.firewall.allowedTCPPorts"),
+        ("Show one virtual host", "nixos option web01 'services.nginx.virtualHosts.\"example.com\"'"),
+        ("Print just the value as JSON", "nixos option web01 services.nginx.enable --json"),
+    ])
+)]
+pub struct OptionArgs {
+    #[arg(help = "System name")]
+    pub name: String,
+    #[arg(help = "Option path, with names that are not identifiers in double quotes")]
+    pub option: String,
+    #[arg(long, help = "Print only the value, as JSON")]
+    pub json: bool,
+}
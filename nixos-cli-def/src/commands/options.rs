@@ -0,0 +1,48 @@
+use clap::{Args, Subcommand};
+
+use super::make_examples;
+
+#[derive(Debug, Args)]
+#[command(
+    about = "Explore the NixOS options of a system",
+    after_long_help = make_examples(&[
+        ("Search the options of this machine", "nixos options search nginx virtual"),
+        ("Search the options of another system", "nixos options search -s web01 firewall ports"),
+        ("Match option names with a regular expression", "nixos options search --regex '^services\\.nginx\\..*\\.root$'"),
+    ])
+)]
+pub struct OptionsArgs {
+    #[command(subcommand)]
+    pub command: OptionsCommands,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum OptionsCommands {
+    #[command(
+        about = "Search option names and descriptions",
+        long_about = "Search option names and descriptions, including the options declared by \
+                      the project's own modules, against the nixpkgs pinned by the project. The \
+                      options of a project are cached once gathered, until the project changes."
+    )]
+    Search {
+        #[arg(
+            required = true,
+            num_args = 1..,
+            help = "Words to look for, all of which have to match"
+        )]
+        query: Vec<String>,
+        #[arg(short, long, help = "System name (defaults to the hostname)")]
+        system: Option<String>,
+        #[arg(long, help = "Treat the query as a regular expression")]
+        regex: bool,
+        #[arg(
+            short = 'n',
+            long,
+            default_value_t = 20,
+            help = "Show at most this many options"
+        )]
+        limit: usize,
+        #[arg(long, help = "Print the matching options as JSON")]
+        json: bool,
+    },
+}
@@ -0,0 +1,40 @@
+use clap::{Args, Subcommand};
+
+use super::make_examples;
+
+#[derive(Debug, Args)]
+#[command(
+    about = "Manage named aliases for projects",
+    after_long_help = make_examples(&[
+        ("Add an alias for a repository", "nixos registry add infra 'github:org/infra?dir=hosts'"),
+        ("Build from it, overriding the default branch", "nixos build -p 'infra?ref=staging'"),
+        ("Print all aliases as JSON", "nixos registry list --json"),
+    ])
+)]
+pub struct RegistryArgs {
+    #[command(subcommand)]
+    pub command: RegistryCommands,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum RegistryCommands {
+    #[command(about = "Add or replace an alias")]
+    Add {
+        #[arg(help = "Alias name")]
+        name: String,
+        #[arg(
+            help = "The project the alias points to (check Valid project sources in the man pages)"
+        )]
+        project: String,
+    },
+    #[command(about = "List all aliases", alias = "ls")]
+    List {
+        #[arg(long, help = "Print the registry as JSON")]
+        json: bool,
+    },
+    #[command(about = "Remove an alias", alias = "rm")]
+    Remove {
+        #[arg(help = "Alias name")]
+        name: String,
+    },
+}
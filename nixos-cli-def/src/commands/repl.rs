@@ -0,0 +1,19 @@
+use clap::Args;
+
+use super::make_examples;
+
+#[derive(Debug, Args)]
+#[command(
+    about = "Start nix repl with a system of the project loaded",
+    long_about = "Start nix repl with the project bound as `project`, and `system`, `config`, \
+                  `options`, `pkgs` and `lib` taken from the selected system",
+    after_long_help = make_examples(&[
+        ("Explore the configuration of this machine", "nixos repl"),
+        ("Explore the configuration of another system", "nixos repl web01"),
+        ("Use a system of a remote project", "nixos repl -p github:org/infra web01"),
+    ])
+)]
+pub struct ReplArgs {
+    #[arg(help = "System name (defaults to the hostname)")]
+    pub name: Option<String>,
+}
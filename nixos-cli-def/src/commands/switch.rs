@@ -1,5 +1,7 @@
 use clap::Args;
 
+use super::build_options::BuildOptionArgs;
+
 #[derive(Debug, Args)]
 #[command(about = "Build, install, and switch into a system")]
 pub struct SwitchArgs {
@@ -7,4 +9,6 @@ pub struct SwitchArgs {
     pub name: Option<String>,
     #[arg(short, long, help = "System architecture (eg: x86_64-linux)")]
     pub system: Option<String>,
+    #[command(flatten)]
+    pub build: BuildOptionArgs,
 }
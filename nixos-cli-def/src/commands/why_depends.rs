@@ -0,0 +1,29 @@
+use clap::Args;
+
+use super::make_examples;
+
+#[derive(Debug, Args)]
+#[command(
+    about = "Show why a system depends on a package or store path",
+    long_about = "Show why a system depends on a package or store path: the chains of references \
+                  from the system's toplevel to every matching store path, and the system \
+                  package or systemd unit each chain goes through",
+    after_long_help = make_examples(&[
+        ("Find out what pulls in OpenSSL 1.1", "nixos why-depends web01 openssl-1.1"),
+        ("Ask about an exact store path", "nixos why-depends web01 /nix/store/8xw2ys5dn1iqqlvn8k5zb8jgcw6gsvwh-zlib-1.3.1"),
+        ("Look at build dependencies without building the system", "nixos why-depends web01 gcc --derivation"),
+    ])
+)]
+pub struct WhyDependsArgs {
+    #[arg(help = "System name")]
+    pub name: String,
+    #[arg(help = "Package name, with or without its version, or a store path")]
+    pub package: String,
+    #[arg(
+        long,
+        help = "Follow the references between derivations, which includes build dependencies and needs no build"
+    )]
+    pub derivation: bool,
+    #[arg(long, help = "Print the chains as JSON")]
+    pub json: bool,
+}
@@ -2,7 +2,9 @@ pub mod commands;
 
 use clap::{ArgAction, Parser, Subcommand};
 use commands::{
-    build::BuildArgs, completions::CompletionsArgs, switch::SwitchArgs, test::TestArgs,
+    build::BuildArgs, completions::CompletionsArgs, info::InfoArgs, option::OptionArgs,
+    options::OptionsArgs, registry::RegistryArgs, repl::ReplArgs, switch::SwitchArgs,
+    test::TestArgs, why_depends::WhyDependsArgs,
 };
 
 #[derive(Parser, Debug)]
@@ -23,6 +25,14 @@ pub struct Cli {
 		global = true
 	)]
     pub project: String,
+    #[arg(
+        long,
+        help = "The entry file of the project, relative to its root",
+        value_hint = clap::ValueHint::FilePath,
+        default_value = "nilla.nix",
+        global = true
+    )]
+    pub file: String,
     #[arg(
         long,
         short,
@@ -47,6 +57,36 @@ pub struct Cli {
 		default_value_t = false,
     )]
     pub show_eval_commands: bool,
+    #[arg(
+        long,
+        action = ArgAction::SetTrue,
+        help = "Evaluate everything again instead of using cached results",
+        global = true
+    )]
+    pub no_eval_cache: bool,
+    #[arg(
+        long = "nix-option",
+        value_name = "NAME=VALUE",
+        value_parser = parse_nix_option,
+        action = ArgAction::Append,
+        help = "Set a Nix setting for every Nix command run (repeatable)",
+        global = true
+    )]
+    pub nix_options: Vec<(String, String)>,
+    #[arg(
+        last = true,
+        value_name = "NIX_ARGS",
+        help = "Extra arguments for every Nix command run, after `--`",
+        global = true
+    )]
+    pub nix_args: Vec<String>,
+}
+
+fn parse_nix_option(option: &str) -> Result<(String, String), String> {
+    match option.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+        _ => Err("expected NAME=VALUE, eg. sandbox=relaxed".to_string()),
+    }
 }
 
 #[derive(Subcommand, Debug)]
@@ -55,6 +95,12 @@ pub enum Commands {
     Switch(SwitchArgs),
     Test(TestArgs),
     Build(BuildArgs),
+    Info(InfoArgs),
+    Option(OptionArgs),
+    Options(OptionsArgs),
+    Registry(RegistryArgs),
+    Repl(ReplArgs),
+    WhyDepends(WhyDependsArgs),
     #[command(alias = "completion")]
     Completions(CompletionsArgs),
     #[command(external_subcommand)]
@@ -1,43 +1,31 @@
-use log::{debug, error, info};
-use tokio::process::Command;
+use log::info;
+use nixos_cli_def::{Cli, commands::build::BuildArgs};
 
-pub async fn build_cmd(cli: &nixos_cli_def::Cli, args: &nixos_cli_def::commands::build::BuildArgs) {
-    debug!("Resolving project {}", cli.project);
-    let Ok(project) = crate::util::project::resolve(&cli.project).await else {
-        return error!("Could not find project {}", cli.project);
-    };
+use crate::util::{
+    context::Context,
+    nix::{NixBackend, RebuildAction, RebuildOpts},
+};
 
-    let mut path = project.get_path();
-
-    debug!("Resolved project {path:?}");
-
-    path.push("nilla.nix");
-
-    match path.try_exists() {
-        Ok(false) | Err(_) => return error!("File not found"),
-        _ => {}
-    }
-
-    let hostname = if let Some(name) = args.name.clone() {
-        if name.contains('.') {
-            return error!("Invalid hostname {}", name);
-        } else {
-            name
-        }
-    } else {
-        gethostname::gethostname().into_string().unwrap()
-    };
-
-    let attribute = &format!("systems.nixos.\"{hostname}\".result");
+pub async fn build_cmd(cli: &Cli, args: &BuildArgs) {
+    super::run_with_context(cli, async |ctx| build(ctx, cli, args).await).await
+}
 
-    info!("Building system {hostname}");
-    Command::new("nixos-rebuild")
-        .arg("build")
-        .arg("--file")
-        .arg(path.display().to_string())
-        .arg("--attr")
-        .arg(attribute)
-        .status()
+pub async fn build<B: NixBackend>(
+    ctx: &Context<B>,
+    cli: &Cli,
+    args: &BuildArgs,
+) -> anyhow::Result<()> {
+    let target = super::system_target(ctx, cli, args.name.as_deref()).await?;
+
+    info!("Building system {}", target.hostname);
+    ctx.nix
+        .rebuild(RebuildOpts {
+            action: RebuildAction::Build,
+            file: target.file,
+            attribute: target.attribute,
+            elevate: false,
+            out_link: args.build.out_link.clone(),
+            tuning: super::build_tuning(&args.build),
+        })
         .await
-        .unwrap();
 }
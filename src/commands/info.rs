@@ -0,0 +1,84 @@
+use std::path::Path;
+
+use log::info;
+use nixos_cli_def::{Cli, commands::info::InfoArgs};
+use prettytable::{Table, format::consts::FORMAT_CLEAN, row};
+use serde_json::json;
+
+use crate::util::{
+    context::Context,
+    nix::NixBackend,
+    system_info::{self, BOOTED_SYSTEM, CURRENT_SYSTEM, SystemInfo},
+};
+
+pub async fn info_cmd(cli: &Cli, args: &InfoArgs) {
+    super::run_with_context(cli, async |ctx| info(ctx, cli, args).await).await
+}
+
+pub async fn info<B: NixBackend>(
+    ctx: &Context<B>,
+    cli: &Cli,
+    args: &InfoArgs,
+) -> anyhow::Result<()> {
+    let target = super::system_target(ctx, cli, args.name.as_deref()).await?;
+
+    info!("Evaluating system {}", target.hostname);
+    let summary = system_info::query_info(&ctx.nix, &target.system_expr()).await?;
+    let running = system_info::links_to(Path::new(CURRENT_SYSTEM), &summary.out_path);
+    let booted = system_info::links_to(Path::new(BOOTED_SYSTEM), &summary.out_path);
+
+    if args.json {
+        let mut value = serde_json::to_value(&summary)?;
+        value["system"] = json!(target.hostname);
+        value["running"] = json!(running);
+        value["booted"] = json!(booted);
+        println!("{}", serde_json::to_string_pretty(&value)?);
+        return Ok(());
+    }
+    describe(&target.hostname, &summary, running, booted).printstd();
+    Ok(())
+}
+
+fn or_unknown(value: &Option<String>) -> &str {
+    value.as_deref().unwrap_or("unknown")
+}
+
+fn yes_no(value: Option<bool>) -> &'static str {
+    match value {
+        Some(true) => "yes",
+        Some(false) => "no",
+        None => "unknown, this machine is not running NixOS",
+    }
+}
+
+pub fn describe(
+    hostname: &str,
+    summary: &SystemInfo,
+    running: Option<bool>,
+    booted: Option<bool>,
+) -> Table {
+    let nixos = match (&summary.version, &summary.code_name) {
+        (Some(version), Some(code_name)) => format!("{version} ({code_name})"),
+        (version, _) => or_unknown(version).to_string(),
+    };
+
+    let mut table = Table::new();
+    table.set_format(*FORMAT_CLEAN);
+    table.add_row(row![b->"System", hostname]);
+    table.add_row(row![b->"Nixpkgs", summary.nixpkgs]);
+    table.add_row(row![b->"Revision", or_unknown(&summary.revision)]);
+    table.add_row(row![b->"NixOS", nixos]);
+    table.add_row(row![b->"State version", or_unknown(&summary.state_version)]);
+    table.add_row(row![b->"Kernel", or_unknown(&summary.kernel)]);
+    table.add_row(row![b->"Bootloader", or_unknown(&summary.bootloader)]);
+    table.add_row(row![b->"Platform", or_unknown(&summary.host_platform)]);
+    table.add_row(row![
+        b->"Modules",
+        format!("{} ({} from NixOS)", summary.base_modules + summary.modules, summary.base_modules)
+    ]);
+    table.add_row(row![b->"Enabled services", summary.enabled_services.len()]);
+    table.add_row(row![b->"Toplevel", summary.drv_path]);
+    table.add_row(row![b->"Running", yes_no(running)]);
+    table.add_row(row![b->"Booted", yes_no(booted)]);
+    table
+}
@@ -1,3 +1,121 @@
 pub mod build;
+pub mod info;
+pub mod option;
+pub mod options;
+pub mod registry;
+pub mod repl;
 pub mod switch;
 pub mod test;
+pub mod why_depends;
+
+use std::path::PathBuf;
+
+use anyhow::{Context as _, Result, anyhow, bail};
+use log::{debug, error, warn};
+
+use nixos_cli_def::{Cli, commands::build_options::BuildOptionArgs};
+
+use crate::util::{
+    config,
+    context::Context,
+    eval_cache::CachedEval,
+    eval_session::EvalSession,
+    nix::{BuildTuning, NixBackend, NixCli, project_expr},
+    nix_expr::{AttrPath, Expr},
+    project,
+};
+
+// Run a command of the binary: load the config, set up the context with the real Nix,
+// stop on Ctrl-C and SIGTERM, and report what went wrong.
+pub async fn run_with_context<F>(cli: &Cli, run: F)
+where
+    F: AsyncFnOnce(&Context<CachedEval<EvalSession<NixCli>>>) -> Result<()>,
+{
+    let config = match config::load() {
+        Ok(config) => config,
+        Err(e) => return error!("{e:#}"),
+    };
+
+    let ctx = Context::from_cli(cli, config);
+    if let Err(e) = ctx.cancel_on_signals() {
+        warn!("Could not handle signals: {e:#}");
+    }
+    if let Err(e) = run(&ctx).await {
+        error!("{e:#}");
+    }
+}
+
+// The system a command acts on: the project's entry file and the attribute holding the
+// system's configuration.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SystemTarget {
+    pub hostname: String,
+    pub file: PathBuf,
+    pub attribute: String,
+    // The project imported by hash, for pure evaluations
+    pub project: Expr,
+    // The store entry holding the project, where the files of its own modules are
+    pub source: PathBuf,
+}
+
+impl SystemTarget {
+    // `systems.nixos.<hostname>.result`, holding `config`, `options`, `pkgs` and so on
+    pub fn system_expr(&self) -> Expr {
+        self.project
+            .clone()
+            .select(["systems", "nixos", &self.hostname, "result"])
+    }
+}
+
+// Resolve the project and pick the system to use. The name comes from the command line,
+// then the project's `#<system>` fragment, then the machine's hostname.
+pub async fn system_target<B: NixBackend>(
+    ctx: &Context<B>,
+    cli: &nixos_cli_def::Cli,
+    name: Option<&str>,
+) -> Result<SystemTarget> {
+    debug!("Resolving project {}", cli.project);
+    let (project, system) = project::resolve(ctx, &cli.project, &cli.file)
+        .await
+        .with_context(|| format!("Could not find project {}", cli.project))?;
+
+    let entry = project.get_entry();
+    let mut path = entry.path.to_path_buf();
+
+    debug!("Resolved project {path:?}");
+
+    path.push(&cli.file);
+
+    if !path.try_exists().unwrap_or(false) {
+        bail!("Could not find {} in the project", cli.file);
+    }
+
+    let hostname = match name.map(str::to_string).or(system) {
+        Some(name) => name,
+        None => gethostname::gethostname()
+            .into_string()
+            .map_err(|_| anyhow!("The hostname of this machine is not valid UTF-8"))?,
+    };
+
+    let attribute = AttrPath::from(["systems", "nixos", &hostname, "result"])
+        .to_cli_arg()
+        .with_context(|| format!("Invalid hostname {hostname}"))?;
+
+    Ok(SystemTarget {
+        hostname,
+        file: path,
+        attribute,
+        project: project_expr(&cli.file, &entry),
+        source: entry.path.root().as_path().to_path_buf(),
+    })
+}
+
+pub fn build_tuning(args: &BuildOptionArgs) -> BuildTuning {
+    BuildTuning {
+        keep_going: args.keep_going,
+        max_jobs: args.max_jobs.clone(),
+        cores: args.cores,
+        rebuild: args.rebuild,
+        options: args.options(),
+    }
+}
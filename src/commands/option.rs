@@ -0,0 +1,85 @@
+use nixos_cli_def::{Cli, commands::option::OptionArgs};
+use serde_json::Value;
+
+use crate::util::{
+    context::Context,
+    nix::NixBackend,
+    nix_expr::AttrPath,
+    options::{self, OptionInfo},
+};
+
+pub async fn option_cmd(cli: &Cli, args: &OptionArgs) {
+    super::run_with_context(cli, async |ctx| option(ctx, cli, args).await).await
+}
+
+pub async fn option<B: NixBackend>(
+    ctx: &Context<B>,
+    cli: &Cli,
+    args: &OptionArgs,
+) -> anyhow::Result<()> {
+    let path = AttrPath::parse(&args.option)?;
+    let target = super::system_target(ctx, cli, Some(&args.name)).await?;
+
+    let info = options::query_option(&ctx.nix, &target.system_expr(), &path).await?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&info.value)?);
+    } else {
+        print!("{}", describe(&info));
+    }
+    Ok(())
+}
+
+// Values as they would be written in JSON, strings being the exception where they are
+// documentation meant to be read as is.
+fn show(value: &Value) -> String {
+    match options::doc_text(value) {
+        Some(text) if !value.is_string() => text,
+        _ => serde_json::to_string_pretty(value).unwrap(),
+    }
+}
+
+fn indent(text: &str, by: &str) -> String {
+    text.lines()
+        .map(|line| format!("{by}{line}"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub fn describe(info: &OptionInfo) -> String {
+    let mut out = format!("{}\n", info.path);
+    if info.option != info.path {
+        out.push_str(&format!("Part of option {}\n", info.option));
+    }
+
+    out.push_str(&format!("\nValue:\n{}\n", indent(&show(&info.value), "  ")));
+    if let Some(r#type) = &info.r#type {
+        out.push_str(&format!("\nType:\n  {type}\n"));
+    }
+    if let Some(default) = &info.default {
+        out.push_str(&format!("\nDefault:\n{}\n", indent(&show(default), "  ")));
+    }
+    if let Some(description) = &info.description {
+        out.push_str(&format!(
+            "\nDescription:\n{}\n",
+            indent(description.trim(), "  ")
+        ));
+    }
+
+    if !info.declarations.is_empty() {
+        out.push_str("\nDeclared in:\n");
+        for file in &info.declarations {
+            out.push_str(&format!("  {file}\n"));
+        }
+    }
+    if !info.definitions.is_empty() {
+        out.push_str("\nDefined in:\n");
+        for definition in &info.definitions {
+            out.push_str(&format!(
+                "  {}\n{}\n",
+                definition.file,
+                indent(&show(&definition.value), "    ")
+            ));
+        }
+    }
+    out
+}
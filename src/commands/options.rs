@@ -0,0 +1,76 @@
+use log::info;
+use nixos_cli_def::{
+    Cli,
+    commands::options::{OptionsArgs, OptionsCommands},
+};
+
+use crate::util::{
+    context::Context,
+    nix::NixBackend,
+    options::{self, Query},
+};
+
+pub async fn options_cmd(cli: &Cli, args: &OptionsArgs) {
+    super::run_with_context(cli, async |ctx| run(ctx, cli, args).await).await
+}
+
+pub async fn run<B: NixBackend>(
+    ctx: &Context<B>,
+    cli: &Cli,
+    args: &OptionsArgs,
+) -> anyhow::Result<()> {
+    match &args.command {
+        OptionsCommands::Search {
+            query,
+            system,
+            regex,
+            limit,
+            json,
+        } => {
+            let query = if *regex {
+                Query::regex(&query.join(" "))?
+            } else {
+                Query::fuzzy(query)
+            };
+            let target = super::system_target(ctx, cli, system.as_deref()).await?;
+
+            info!("Gathering the options of {}", target.hostname);
+            let tree = options::option_tree(&ctx.nix, &target.system_expr()).await?;
+            let found = options::search(&tree, &query);
+            let shown = &found[..found.len().min(*limit)];
+
+            if *json {
+                println!("{}", serde_json::to_string_pretty(shown)?);
+                return Ok(());
+            }
+            if found.is_empty() {
+                info!("No options found");
+                return Ok(());
+            }
+            for option in shown {
+                let from_project = if option.declared_in(&target.source) {
+                    " (project)"
+                } else {
+                    ""
+                };
+                let r#type = option.r#type.as_deref().unwrap_or("unspecified");
+                println!("{}{from_project}: {type}", option.name);
+                if let Some(summary) = option
+                    .description
+                    .as_deref()
+                    .and_then(|description| description.lines().find(|line| !line.is_empty()))
+                {
+                    println!("  {}", summary.trim());
+                }
+            }
+            if found.len() > shown.len() {
+                info!(
+                    "Showing {} of {} options, use --limit for more",
+                    shown.len(),
+                    found.len()
+                );
+            }
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use log::{error, info};
+use nixos_cli_def::commands::registry::{RegistryArgs, RegistryCommands};
+use prettytable::{Table, format::consts::FORMAT_CLEAN, row};
+
+use crate::util::{config, registry};
+
+pub fn registry_cmd(_cli: &nixos_cli_def::Cli, args: &RegistryArgs) {
+    let mut config = match config::load() {
+        Ok(config) => config,
+        Err(e) => return error!("{e:#}"),
+    };
+
+    match &args.command {
+        RegistryCommands::Add { name, project } => {
+            let project = match registry::add(&mut config, name, project) {
+                Ok(project) => project,
+                Err(e) => return error!("{e}"),
+            };
+            if let Err(e) = config::save_registry(&config.registry) {
+                return error!("{e:#}");
+            }
+            info!("Added {name} -> {project}");
+        }
+        RegistryCommands::List { json } => {
+            if *json {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&config.registry).unwrap()
+                );
+                return;
+            }
+
+            let mut table = Table::new();
+            table.set_format(*FORMAT_CLEAN);
+            for (name, project) in &config.registry {
+                table.add_row(row![b->name, project]);
+            }
+            table.printstd();
+        }
+        RegistryCommands::Remove { name } => {
+            let project = match registry::remove(&mut config, name) {
+                Ok(project) => project,
+                Err(e) => return error!("{e}"),
+            };
+            if let Err(e) = config::save_registry(&config.registry) {
+                return error!("{e:#}");
+            }
+            info!("Removed {name} -> {project}");
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use std::io::Write;
+
+use anyhow::{Context as _, bail};
+use log::{debug, info};
+use nixos_cli_def::{Cli, commands::repl::ReplArgs};
+use tokio::process::Command;
+
+use crate::util::{
+    context::Context,
+    nix::{NixBackend, repl_command},
+    nix_expr::Expr,
+};
+
+use super::SystemTarget;
+
+pub async fn repl_cmd(cli: &Cli, args: &ReplArgs) {
+    // Once the repl runs, Ctrl-C is for it to interrupt an evaluation. With the signal
+    // handler every command installs, it no longer ends this process, which would leave
+    // the repl without a parent.
+    super::run_with_context(cli, async |ctx| repl(ctx, cli, args).await).await
+}
+
+// What the repl starts with in scope. The project is imported through `builtins.path`
+// with its hash, as for every other evaluation, so it is the exact resolved project
+// rather than whatever the path holds by now.
+pub fn scope(target: &SystemTarget) -> Expr {
+    let system = Expr::var("system");
+    Expr::let_in(
+        [
+            ("project", target.project.clone()),
+            (
+                "system",
+                Expr::var("project").select(["systems", "nixos", &target.hostname, "result"]),
+            ),
+        ],
+        Expr::attrs([
+            ("project", Expr::var("project")),
+            ("system", system.clone()),
+            ("config", system.clone().select(["config"])),
+            ("options", system.clone().select(["options"])),
+            ("pkgs", system.clone().select(["pkgs"])),
+            ("lib", system.select(["pkgs", "lib"])),
+        ]),
+    )
+}
+
+pub async fn repl<B: NixBackend>(
+    ctx: &Context<B>,
+    cli: &Cli,
+    args: &ReplArgs,
+) -> anyhow::Result<()> {
+    let target = super::system_target(ctx, cli, args.name.as_deref()).await?;
+
+    let mut file = tempfile::Builder::new()
+        .prefix("nilla-repl-")
+        .suffix(".nix")
+        .tempfile()
+        .context("Could not create the file for nix repl to load")?;
+    writeln!(file, "{}", scope(&target))?;
+
+    let (program, args) = repl_command(
+        ctx.nix_version.get(&ctx.settings).await?,
+        &ctx.settings,
+        file.path(),
+    );
+    debug!("Running nix repl:\n{program} {}", args.join(" "));
+    info!(
+        "Loading system {}, with project, system, config, options, pkgs and lib in scope",
+        target.hostname
+    );
+
+    // Not supervised: the repl is interactive, so it keeps the terminal and runs for as
+    // long as the user wants it to
+    let status = Command::new(program)
+        .args(&args)
+        .status()
+        .await
+        .context("Could not start nix repl")?;
+    if !status.success() {
+        bail!("nix repl failed ({status})");
+    }
+    Ok(())
+}
@@ -1,55 +1,31 @@
-use log::{debug, error, info};
-use tokio::process::Command;
+use log::info;
+use nixos_cli_def::{Cli, commands::switch::SwitchArgs};
 
-pub async fn switch_cmd(
-    cli: &nixos_cli_def::Cli,
-    args: &nixos_cli_def::commands::switch::SwitchArgs,
-) {
-    debug!("Resolving project {}", cli.project);
-    let Ok(project) = crate::util::project::resolve(&cli.project).await else {
-        return error!("Could not find project {}", cli.project);
-    };
+use crate::util::{
+    context::Context,
+    nix::{NixBackend, RebuildAction, RebuildOpts},
+};
 
-    let mut path = project.get_path();
-
-    debug!("Resolved project {path:?}");
-
-    path.push("nilla.nix");
-
-    match path.try_exists() {
-        Ok(false) | Err(_) => return error!("File not found"),
-        _ => {}
-    }
-
-    let hostname = if let Some(name) = args.name.clone() {
-        if name.contains('.') {
-            return error!("Invalid hostname {}", name);
-        } else {
-            name
-        }
-    } else {
-        gethostname::gethostname().into_string().unwrap()
-    };
-
-    let attribute = &format!("systems.nixos.\"{hostname}\".result");
-
-    let sudo = match which::which("sudo") {
-        Ok(s) => s,
-        Err(_e) => match which::which("doas") {
-            Ok(d) => d,
-            Err(_e) => return error!("Could not find sudo or doas"),
-        },
-    };
+pub async fn switch_cmd(cli: &Cli, args: &SwitchArgs) {
+    super::run_with_context(cli, async |ctx| switch(ctx, cli, args).await).await
+}
 
-    info!("Switching system {hostname}");
-    Command::new(sudo)
-        .arg("nixos-rebuild")
-        .arg("switch")
-        .arg("--file")
-        .arg(path.display().to_string())
-        .arg("--attr")
-        .arg(attribute)
-        .status()
+pub async fn switch<B: NixBackend>(
+    ctx: &Context<B>,
+    cli: &Cli,
+    args: &SwitchArgs,
+) -> anyhow::Result<()> {
+    let target = super::system_target(ctx, cli, args.name.as_deref()).await?;
+
+    info!("Switching system {}", target.hostname);
+    ctx.nix
+        .rebuild(RebuildOpts {
+            action: RebuildAction::Switch,
+            file: target.file,
+            attribute: target.attribute,
+            elevate: true,
+            out_link: args.build.out_link.clone(),
+            tuning: super::build_tuning(&args.build),
+        })
         .await
-        .unwrap();
 }
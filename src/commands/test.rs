@@ -1,52 +1,31 @@
-use log::{debug, error, info};
-use tokio::process::Command;
+use log::info;
+use nixos_cli_def::{Cli, commands::test::TestArgs};
 
-pub async fn test_cmd(cli: &nixos_cli_def::Cli, args: &nixos_cli_def::commands::test::TestArgs) {
-    debug!("Resolving project {}", cli.project);
-    let Ok(project) = crate::util::project::resolve(&cli.project).await else {
-        return error!("Could not find project {}", cli.project);
-    };
+use crate::util::{
+    context::Context,
+    nix::{NixBackend, RebuildAction, RebuildOpts},
+};
 
-    let mut path = project.get_path();
-
-    debug!("Resolved project {path:?}");
-
-    path.push("nilla.nix");
-
-    match path.try_exists() {
-        Ok(false) | Err(_) => return error!("File not found"),
-        _ => {}
-    }
-
-    let hostname = if let Some(name) = args.name.clone() {
-        if name.contains('.') {
-            return error!("Invalid hostname {}", name);
-        } else {
-            name
-        }
-    } else {
-        gethostname::gethostname().into_string().unwrap()
-    };
-
-    let attribute = &format!("systems.nixos.\"{hostname}\".result");
-
-    let sudo = match which::which("sudo") {
-        Ok(s) => s,
-        Err(_e) => match which::which("doas") {
-            Ok(d) => d,
-            Err(_e) => return error!("Could not find sudo or doas"),
-        },
-    };
+pub async fn test_cmd(cli: &Cli, args: &TestArgs) {
+    super::run_with_context(cli, async |ctx| test(ctx, cli, args).await).await
+}
 
-    info!("Testing system {hostname}");
-    Command::new(sudo)
-        .arg("nixos-rebuild")
-        .arg("test")
-        .arg("--file")
-        .arg(path.display().to_string())
-        .arg("--attr")
-        .arg(attribute)
-        .status()
+pub async fn test<B: NixBackend>(
+    ctx: &Context<B>,
+    cli: &Cli,
+    args: &TestArgs,
+) -> anyhow::Result<()> {
+    let target = super::system_target(ctx, cli, args.name.as_deref()).await?;
+
+    info!("Testing system {}", target.hostname);
+    ctx.nix
+        .rebuild(RebuildOpts {
+            action: RebuildAction::Test,
+            file: target.file,
+            attribute: target.attribute,
+            elevate: true,
+            out_link: args.build.out_link.clone(),
+            tuning: super::build_tuning(&args.build),
+        })
         .await
-        .unwrap();
 }
@@ -0,0 +1,126 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::bail;
+use log::{info, warn};
+use nixos_cli_def::{Cli, commands::why_depends::WhyDependsArgs};
+use serde_json::json;
+
+use crate::util::{
+    closure::{self, Chain, Introducers, package_name},
+    context::Context,
+    nix::{BuildOpts, EvalOpts, EvalResult, NixBackend},
+};
+
+use super::SystemTarget;
+
+pub async fn why_depends_cmd(cli: &Cli, args: &WhyDependsArgs) {
+    super::run_with_context(cli, async |ctx| why_depends(ctx, cli, args).await).await
+}
+
+// The system's toplevel: its derivation, or the built system
+async fn toplevel<B: NixBackend>(
+    ctx: &Context<B>,
+    target: &SystemTarget,
+    derivation: bool,
+) -> anyhow::Result<PathBuf> {
+    if derivation {
+        let drv_path = target
+            .system_expr()
+            .select(["config", "system", "build", "toplevel", "drvPath"]);
+        let opts = EvalOpts {
+            json: true,
+            impure: false,
+            ..Default::default()
+        };
+        return match ctx.nix.evaluate(&drv_path, opts).await? {
+            EvalResult::Json(serde_json::Value::String(path)) => Ok(PathBuf::from(path)),
+            result => bail!("Expected the path of the toplevel derivation, got {result:?}"),
+        };
+    }
+
+    info!("Building system {}", target.hostname);
+    let built = ctx
+        .nix
+        .build(
+            &target.file,
+            &format!("{}.config.system.build.toplevel", target.attribute),
+            BuildOpts::default(),
+        )
+        .await?
+        .ensure_success()?;
+    match built.outputs.into_iter().next() {
+        Some(output) => Ok(output),
+        None => bail!("Building the system did not produce a toplevel"),
+    }
+}
+
+pub async fn why_depends<B: NixBackend>(
+    ctx: &Context<B>,
+    cli: &Cli,
+    args: &WhyDependsArgs,
+) -> anyhow::Result<()> {
+    let target = super::system_target(ctx, cli, Some(&args.name)).await?;
+    let root = toplevel(ctx, &target, args.derivation).await?;
+
+    let references = ctx.nix.closure(&root).await?;
+    let chains = closure::why_depends(&references, &root, &args.package);
+    if chains.is_empty() {
+        if args.json {
+            println!("[]");
+        } else {
+            info!("{} does not depend on {}", target.hostname, args.package);
+        }
+        return Ok(());
+    }
+
+    // Only used to explain the chains, which stand on their own
+    let introducers =
+        match closure::query_introducers(&ctx.nix, &target.system_expr(), args.derivation).await {
+            Ok(introducers) => introducers,
+            Err(e) => {
+                warn!("Could not tell which packages and services the system has: {e:#}");
+                Introducers::default()
+            }
+        };
+
+    if args.json {
+        let chains: Vec<_> = chains
+            .iter()
+            .map(|chain| {
+                json!({
+                    "paths": chain.paths,
+                    "introducedBy": introducers.explain(chain),
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&chains)?);
+    } else {
+        print!("{}", describe(&chains, &introducers));
+    }
+    Ok(())
+}
+
+// The chains, grouped by the path they lead to
+pub fn describe(chains: &[Chain], introducers: &Introducers) -> String {
+    let mut out = String::new();
+    let mut previous: Option<&Path> = None;
+    for chain in chains {
+        if previous != Some(chain.target()) {
+            if previous.is_some() {
+                out.push('\n');
+            }
+            out.push_str(&format!("{}\n", chain.target().display()));
+            previous = Some(chain.target());
+        }
+
+        match introducers.explain(chain) {
+            Some(introducer) => out.push_str(&format!("  introduced by {introducer}\n")),
+            None => out.push_str("  not traced back to a system package or unit\n"),
+        }
+        for (i, path) in chain.paths.iter().enumerate() {
+            let arrow = if i == 0 { "" } else { "→ " };
+            out.push_str(&format!("    {arrow}{}\n", package_name(path)));
+        }
+    }
+    out
+}
@@ -4,8 +4,8 @@ use clap::{
 };
 use fern::colors::{Color, ColoredLevelConfig};
 use log::{LevelFilter, debug, error, trace};
+use nilla_nixos::util::context::Verbosity;
 use nixos_cli_def::{Cli, Commands, commands::completions};
-use tokio;
 
 const B: Style = Style::new().bold();
 const D: Style = Style::new().dimmed();
@@ -21,15 +21,7 @@ async fn main() -> anyhow::Result<()> {
         .error(Color::Red);
 
     let cli = Cli::parse();
-    let mut filter_level = match cli.verbose {
-        0 => LevelFilter::Info,
-        1 => LevelFilter::Debug,
-        _ => LevelFilter::Trace,
-    };
-
-    if cli.quiet {
-        filter_level = LevelFilter::Error;
-    }
+    let filter_level = Verbosity::from_cli(&cli).level_filter();
 
     fern::Dispatch::new()
         .format(move |out, message, record| {
@@ -63,12 +55,22 @@ async fn main() -> anyhow::Result<()> {
             Commands::Test(args) => nilla_nixos::commands::test::test_cmd(&cli, args).await,
             Commands::Switch(args) => nilla_nixos::commands::switch::switch_cmd(&cli, args).await,
             Commands::Build(args) => nilla_nixos::commands::build::build_cmd(&cli, args).await,
+            Commands::Info(args) => nilla_nixos::commands::info::info_cmd(&cli, args).await,
+            Commands::Option(args) => nilla_nixos::commands::option::option_cmd(&cli, args).await,
+            Commands::Options(args) => {
+                nilla_nixos::commands::options::options_cmd(&cli, args).await
+            }
+            Commands::Registry(args) => nilla_nixos::commands::registry::registry_cmd(&cli, args),
+            Commands::Repl(args) => nilla_nixos::commands::repl::repl_cmd(&cli, args).await,
+            Commands::WhyDepends(args) => {
+                nilla_nixos::commands::why_depends::why_depends_cmd(&cli, args).await
+            }
             Commands::Completions(args) => completions::completions_cmd(args, &mut Cli::command()),
             Commands::External(items) => debug!("got external subcommand: {items:?}"),
         },
         None => {
             error!("No subcommand found");
-            println!("{}", Cli::command().render_long_help().to_string());
+            println!("{}", Cli::command().render_long_help());
         }
     };
     Ok(())
@@ -0,0 +1,107 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, bail};
+use log::{debug, trace};
+use tokio::process::Command;
+
+use crate::util::hash::Sha256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    TarGz,
+    TarXz,
+    TarZst,
+    Zip,
+}
+
+// Longest suffixes first, so `.tar.gz` is not mistaken for something else.
+const EXTENSIONS: &[(&str, ArchiveFormat)] = &[
+    (".tar.gz", ArchiveFormat::TarGz),
+    (".tar.xz", ArchiveFormat::TarXz),
+    (".tar.zst", ArchiveFormat::TarZst),
+    (".tgz", ArchiveFormat::TarGz),
+    (".txz", ArchiveFormat::TarXz),
+    (".tzst", ArchiveFormat::TarZst),
+    (".tar", ArchiveFormat::Tar),
+    (".zip", ArchiveFormat::Zip),
+];
+
+impl ArchiveFormat {
+    pub fn from_path(path: &str) -> Option<Self> {
+        let path = path.to_ascii_lowercase();
+        EXTENSIONS
+            .iter()
+            .find(|(extension, _)| path.ends_with(extension))
+            .map(|(_, format)| *format)
+    }
+
+    pub fn extensions() -> impl Iterator<Item = &'static str> {
+        EXTENSIONS.iter().map(|(extension, _)| *extension)
+    }
+
+    fn command(self, archive: &Path, dest: &Path) -> Command {
+        if self == ArchiveFormat::Zip {
+            let mut command = Command::new("unzip");
+            command.args(["-q", "-o"]).arg(archive).arg("-d").arg(dest);
+            return command;
+        }
+
+        let mut command = Command::new("tar");
+        command.arg("-x");
+        match self {
+            ArchiveFormat::TarGz => {
+                command.arg("--gzip");
+            }
+            ArchiveFormat::TarXz => {
+                command.arg("--xz");
+            }
+            ArchiveFormat::TarZst => {
+                command.arg("--zstd");
+            }
+            ArchiveFormat::Tar | ArchiveFormat::Zip => {}
+        }
+        command.arg("-f").arg(archive).arg("-C").arg(dest);
+        command
+    }
+}
+
+// Unpack `archive` below `dest`, returning the directory to use as the project root.
+// As with `fetchTarball`, an archive holding a single top-level directory is unwrapped,
+// and the root is always called `source` so the store path does not depend on it.
+pub async fn unpack(archive: &Path, format: ArchiveFormat, dest: &Path) -> Result<PathBuf> {
+    let unpacked = dest.join("unpacked");
+    std::fs::create_dir_all(&unpacked)?;
+
+    let mut command = format.command(archive, &unpacked);
+    trace!("Unpacking {archive:?} with {command:?}");
+    let output = command.output().await?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("Could not unpack {archive:?}:\n{stderr}");
+    }
+
+    let entries = std::fs::read_dir(&unpacked)?.collect::<Result<Vec<_>, _>>()?;
+    let root = match entries.as_slice() {
+        [entry] if entry.file_type()?.is_dir() => entry.path(),
+        _ => unpacked,
+    };
+
+    let source = dest.join("source");
+    std::fs::rename(&root, &source)?;
+    debug!("Unpacked {archive:?} to {source:?}");
+
+    Ok(source)
+}
+
+// Hash an archive, checking it against `expected` when one was given.
+pub fn verify(archive: &Path, expected: Option<&str>) -> Result<Sha256> {
+    let actual = Sha256::of_file(archive)?;
+    if let Some(expected) = expected {
+        let expected: Sha256 = expected.parse()?;
+        if actual != expected {
+            bail!("Hash mismatch:\n  specified: {expected}\n  got:       {actual}");
+        }
+    }
+    Ok(actual)
+}
@@ -0,0 +1,137 @@
+use anyhow::{Result, anyhow};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use url::Url;
+
+use crate::util::{
+    config::{HostConfig, Transport},
+    expand::expand_path,
+};
+
+// Everything needed to authenticate a fetch against one host. Nothing here is ever
+// put on a command line, it is only passed to the child through its environment.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FetchSettings {
+    pub git_ssh_command: Option<String>,
+    pub git_config: Vec<(String, String)>,
+    pub nix_config: Vec<(String, String)>,
+}
+
+// Work out which host a fetch will talk to, for both URLs and scp-like git remotes
+// (`git@host:owner/repo.git`).
+pub fn host_of_url(url: &str) -> Option<String> {
+    if let Ok(parsed) = Url::parse(url) {
+        if let Some(host) = parsed.host_str() {
+            return Some(host.to_string());
+        }
+        // `Url` happily parses `git@host:path` as scheme `git@host`
+        if parsed.scheme() == "file" || !url.contains('@') {
+            return None;
+        }
+    }
+
+    let (remote, _) = url.split_once(':')?;
+    let host = remote.rsplit('@').next()?;
+    (!host.is_empty() && !host.contains('/')).then(|| host.to_string())
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+pub fn fetch_settings(host: &str, config: &HostConfig) -> Result<FetchSettings> {
+    fetch_settings_with(host, config, |name| std::env::var(name).ok())
+}
+
+pub fn fetch_settings_with<E>(host: &str, config: &HostConfig, env: E) -> Result<FetchSettings>
+where
+    E: Fn(&str) -> Option<String>,
+{
+    let mut settings = FetchSettings::default();
+
+    if let Some(identity) = &config.ssh_identity {
+        let identity = expand_path(identity)?;
+        settings.git_ssh_command = Some(format!(
+            "ssh -i {} -o IdentitiesOnly=yes",
+            shell_quote(&identity.to_string_lossy())
+        ));
+    }
+
+    if let Some(var) = &config.token_env {
+        let token = env(var)
+            .filter(|t| !t.is_empty())
+            .ok_or_else(|| anyhow!("Environment variable {var} for host {host} is not set"))?;
+
+        settings
+            .nix_config
+            .push(("access-tokens".to_string(), format!("{host}={token}")));
+
+        if config.transport() == Transport::Https {
+            let user = config.token_user.as_deref().unwrap_or("x-access-token");
+            let credentials = BASE64_STANDARD.encode(format!("{user}:{token}"));
+            settings.git_config.push((
+                format!("http.https://{host}/.extraHeader"),
+                format!("Authorization: Basic {credentials}"),
+            ));
+        }
+    }
+
+    if let Some(netrc) = &config.netrc {
+        let netrc = expand_path(netrc)?;
+        settings.nix_config.push((
+            "netrc-file".to_string(),
+            netrc.to_string_lossy().into_owned(),
+        ));
+    }
+
+    Ok(settings)
+}
+
+impl FetchSettings {
+    pub fn is_empty(&self) -> bool {
+        self.git_ssh_command.is_none() && self.git_config.is_empty() && self.nix_config.is_empty()
+    }
+
+    // Environment variables to set on the child process. Existing `NIX_CONFIG` and
+    // `GIT_CONFIG_*` entries are kept, with ours appended after them.
+    pub fn env(&self) -> Vec<(String, String)> {
+        self.env_with(|name| std::env::var(name).ok())
+    }
+
+    pub fn env_with<E>(&self, env: E) -> Vec<(String, String)>
+    where
+        E: Fn(&str) -> Option<String>,
+    {
+        let mut vars = vec![];
+
+        if let Some(command) = &self.git_ssh_command {
+            vars.push(("GIT_SSH_COMMAND".to_string(), command.clone()));
+        }
+
+        if !self.git_config.is_empty() {
+            let start: usize = env("GIT_CONFIG_COUNT")
+                .and_then(|c| c.parse().ok())
+                .unwrap_or(0);
+            for (i, (key, value)) in self.git_config.iter().enumerate() {
+                vars.push((format!("GIT_CONFIG_KEY_{}", start + i), key.clone()));
+                vars.push((format!("GIT_CONFIG_VALUE_{}", start + i), value.clone()));
+            }
+            vars.push((
+                "GIT_CONFIG_COUNT".to_string(),
+                (start + self.git_config.len()).to_string(),
+            ));
+        }
+
+        if !self.nix_config.is_empty() {
+            let mut config = env("NIX_CONFIG").unwrap_or_default();
+            for (key, value) in &self.nix_config {
+                if !config.is_empty() && !config.ends_with('\n') {
+                    config.push('\n');
+                }
+                config.push_str(&format!("{key} = {value}\n"));
+            }
+            vars.push(("NIX_CONFIG".to_string(), config));
+        }
+
+        vars
+    }
+}
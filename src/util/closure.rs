@@ -0,0 +1,226 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::util::{
+    nix::{EvalOpts, EvalResult, NixBackend},
+    nix_expr::Expr,
+};
+
+// The store paths a path refers to, for every path in its closure
+pub type References = BTreeMap<PathBuf, Vec<PathBuf>>;
+
+// Read the references out of `nix-store --query --graph`. Every path of the closure is
+// a node named by its base name, and each edge points from a reference to the path
+// referring to it: `"<hash>-openssl-3.0.13" -> "<hash>-curl-8.5.0"`.
+pub fn parse_graph(dot: &str, store_dir: &Path) -> References {
+    let unquote = |node: &str| store_dir.join(node.trim().trim_matches('"'));
+    let mut references = References::new();
+    for line in dot.lines() {
+        let line = line.trim();
+        if line.starts_with('"') && !line.contains(" -> ") {
+            let node = line.split(" [").next().unwrap_or(line);
+            references.entry(unquote(node)).or_default();
+        }
+        let Some((reference, referrer)) = line.split_once(" -> ") else {
+            continue;
+        };
+        let referrer = referrer.split(" [").next().unwrap_or(referrer);
+        let referrer = referrer.trim_end_matches(';');
+        references
+            .entry(unquote(referrer))
+            .or_default()
+            .push(unquote(reference));
+        references.entry(unquote(reference)).or_default();
+    }
+    references
+}
+
+// The name of a store path without its hash, and the `.drv` of derivations
+pub fn package_name(path: &Path) -> &str {
+    let base = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    let name = base.split_once('-').map_or(base, |(_, name)| name);
+    name.strip_suffix(".drv").unwrap_or(name)
+}
+
+// Whether `path` is what was asked about: either that store path (or one inside it), or
+// a package given by name, with or without (part of) its version. `openssl-1.1` matches
+// `openssl-1.1.1w` and its `-bin` output, but neither `openssl-1.10.0` nor `openssl-3.0.13`.
+pub fn matches(path: &Path, query: &str) -> bool {
+    if query.starts_with('/') {
+        return Path::new(query).starts_with(path);
+    }
+    let Some(rest) = package_name(path).strip_prefix(query) else {
+        return false;
+    };
+    rest.is_empty() || rest.starts_with(['-', '.'])
+}
+
+// A way `root` refers to `target`, from the root down
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Chain {
+    pub paths: Vec<PathBuf>,
+}
+
+impl Chain {
+    pub fn target(&self) -> &Path {
+        self.paths.last().unwrap()
+    }
+}
+
+// The shortest chain of references from `from` to `target`, found breadth first.
+fn shortest_chain(references: &References, from: &Path, target: &Path) -> Option<Vec<PathBuf>> {
+    let mut previous: HashMap<&Path, &Path> = HashMap::new();
+    let mut queue = VecDeque::from([from]);
+    while let Some(path) = queue.pop_front() {
+        if path == target {
+            let mut chain = vec![path.to_path_buf()];
+            let mut current = path;
+            while let Some(&before) = previous.get(current) {
+                chain.push(before.to_path_buf());
+                current = before;
+            }
+            chain.reverse();
+            return Some(chain);
+        }
+        for reference in references.get(path).into_iter().flatten() {
+            if reference != from && !previous.contains_key(reference.as_path()) {
+                previous.insert(reference.as_path(), path);
+                queue.push_back(reference.as_path());
+            }
+        }
+    }
+    None
+}
+
+// Every way `root` depends on paths matching `query`. Listing each possible chain would
+// explode, so there is one per direct reference of the root that leads to a match: the
+// shortest one through it.
+pub fn why_depends(references: &References, root: &Path, query: &str) -> Vec<Chain> {
+    let targets: Vec<&PathBuf> = references
+        .keys()
+        .filter(|path| *path != root && matches(path, query))
+        .collect();
+
+    let mut chains = vec![];
+    for target in targets {
+        for first in references.get(root).into_iter().flatten() {
+            if first == root {
+                continue;
+            }
+            if let Some(mut paths) = shortest_chain(references, first, target) {
+                paths.insert(0, root.to_path_buf());
+                chains.push(Chain { paths });
+            }
+        }
+    }
+    chains
+}
+
+// The store paths a system was given directly: the outputs of its
+// `environment.systemPackages`, and its systemd units. Chains going through one of them
+// are explained by it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Introducers {
+    pub system_packages: Vec<Vec<Option<String>>>,
+    pub units: BTreeMap<String, Option<String>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", content = "name", rename_all = "camelCase")]
+pub enum Introducer {
+    SystemPackage(String),
+    Unit(String),
+}
+
+impl std::fmt::Display for Introducer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Introducer::SystemPackage(name) => write!(f, "environment.systemPackages ({name})"),
+            Introducer::Unit(name) => write!(f, "the systemd unit {name}"),
+        }
+    }
+}
+
+impl Introducers {
+    // What put the first of `chain`'s paths there that we know of
+    pub fn explain(&self, chain: &Chain) -> Option<Introducer> {
+        chain.paths.iter().skip(1).find_map(|path| {
+            let path = path.to_str()?;
+            let package = self
+                .system_packages
+                .iter()
+                .any(|outputs| outputs.iter().flatten().any(|output| output == path));
+            if package {
+                return Some(Introducer::SystemPackage(
+                    package_name(Path::new(path)).to_string(),
+                ));
+            }
+            self.units
+                .iter()
+                .find(|(_, unit)| unit.as_deref() == Some(path))
+                .map(|(name, _)| Introducer::Unit(name.clone()))
+        })
+    }
+}
+
+// Evaluate the outputs of `system`'s packages and units, as derivations when `derivations`
+// is set and the chains are between derivations too.
+pub async fn query_introducers<B: NixBackend>(
+    nix: &B,
+    system: &Expr,
+    derivations: bool,
+) -> Result<Introducers> {
+    let attr = if derivations { "drvPath" } else { "outPath" };
+    let config = Expr::var("config");
+    let outputs = Expr::lambda(
+        "package",
+        Expr::builtin("map")
+            .apply(Expr::lambda(
+                "output",
+                Expr::var("output").select_or([attr], Expr::Null),
+            ))
+            .apply(Expr::var("package").select_or(["all"], Expr::List(vec![Expr::var("package")]))),
+    );
+    let code = Expr::let_in(
+        [("config", system.clone().select(["config"]))],
+        Expr::attrs([
+            (
+                "systemPackages",
+                Expr::builtin("map")
+                    .apply(outputs)
+                    .apply(config.clone().select(["environment", "systemPackages"])),
+            ),
+            (
+                "units",
+                Expr::builtin("mapAttrs")
+                    .apply(Expr::lambda(
+                        "name",
+                        Expr::lambda(
+                            "unit",
+                            Expr::var("unit").select_or(["unit", attr], Expr::Null),
+                        ),
+                    ))
+                    .apply(config.select(["systemd", "units"])),
+            ),
+        ]),
+    );
+
+    let opts = EvalOpts {
+        json: true,
+        impure: false,
+        ..Default::default()
+    };
+    match nix.evaluate(&code, opts).await? {
+        EvalResult::Json(value) => Ok(serde_json::from_value(value)?),
+        EvalResult::Raw(raw) => bail!("Expected JSON, got {raw}"),
+    }
+}
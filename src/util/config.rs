@@ -0,0 +1,211 @@
+use std::{collections::BTreeMap, io::Write, path::PathBuf, time::Duration};
+
+use anyhow::{Context, Result};
+use log::{debug, trace};
+use serde::{Deserialize, Serialize};
+use toml_edit::DocumentMut;
+
+use crate::util::expand::home_dir;
+
+// User level configuration, read from `$XDG_CONFIG_HOME/nilla-nixos/config.toml`
+// (or the file named by `$NILLA_NIXOS_CONFIG`). A missing file is the same as an
+// empty one.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub hosts: BTreeMap<String, HostConfig>,
+    // Named project aliases, managed with `nilla-nixos registry`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub registry: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "NixConfig::is_empty")]
+    pub nix: NixConfig,
+    #[serde(default, skip_serializing_if = "Timeouts::is_empty")]
+    pub timeouts: Timeouts,
+}
+
+// Settings and arguments added to every Nix command we run, eg:
+//
+//   [nix]
+//   options = { substituters = "https://cache.example.com", sandbox = "relaxed" }
+//   extra-args = ["--accept-flake-config"]
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct NixConfig {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub options: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_args: Vec<String>,
+}
+
+impl NixConfig {
+    pub fn is_empty(&self) -> bool {
+        self.options.is_empty() && self.extra_args.is_empty()
+    }
+}
+
+// How long, in seconds, each stage of a rebuild may take before it is stopped, eg:
+//
+//   [timeouts]
+//   eval = 300
+//   build = 7200
+//   activation = 600
+//
+// Stages without a timeout run for as long as they need.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Timeouts {
+    pub eval: Option<u64>,
+    pub build: Option<u64>,
+    pub activation: Option<u64>,
+}
+
+impl Timeouts {
+    pub fn is_empty(&self) -> bool {
+        *self == Timeouts::default()
+    }
+
+    pub fn eval(&self) -> Option<Duration> {
+        self.eval.map(Duration::from_secs)
+    }
+
+    pub fn build(&self) -> Option<Duration> {
+        self.build.map(Duration::from_secs)
+    }
+
+    pub fn activation(&self) -> Option<Duration> {
+        self.activation.map(Duration::from_secs)
+    }
+}
+
+// Credentials used when fetching from a particular host, eg:
+//
+//   [hosts."github.com"]
+//   ssh-identity = "~/.ssh/id_infra"
+//   token-env = "GITHUB_TOKEN"
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct HostConfig {
+    pub ssh_identity: Option<String>,
+    pub token_env: Option<String>,
+    pub token_user: Option<String>,
+    pub netrc: Option<String>,
+    pub transport: Option<Transport>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Ssh,
+    Https,
+}
+
+impl HostConfig {
+    // Use HTTPS when explicitly asked to, or when a token is all we have to go on.
+    pub fn transport(&self) -> Transport {
+        match self.transport {
+            Some(transport) => transport,
+            None if self.token_env.is_some() && self.ssh_identity.is_none() => Transport::Https,
+            None => Transport::Ssh,
+        }
+    }
+}
+
+impl Config {
+    pub fn host(&self, host: &str) -> Option<&HostConfig> {
+        self.hosts.get(host)
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+}
+
+pub fn config_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("NILLA_NIXOS_CONFIG") {
+        return Some(PathBuf::from(path));
+    }
+
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => home_dir(None)?.join(".config"),
+    };
+
+    Some(base.join("nilla-nixos").join("config.toml"))
+}
+
+pub fn load() -> Result<Config> {
+    let Some(path) = config_path() else {
+        debug!("No config directory found, using defaults");
+        return Ok(Config::default());
+    };
+
+    trace!("Loading config from {path:?}");
+    match std::fs::read_to_string(&path) {
+        Ok(text) => Config::parse(&text).with_context(|| format!("Invalid config {path:?}")),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+        Err(e) => Err(e).with_context(|| format!("Could not read config {path:?}")),
+    }
+}
+
+// Replace the `[registry]` table of the config file's `text` with `registry`, leaving the
+// rest of the file, comments and formatting included, as it was.
+pub fn with_registry(text: &str, registry: &BTreeMap<String, String>) -> Result<String> {
+    let mut document: DocumentMut = text.parse()?;
+    if registry.is_empty() {
+        document.remove("registry");
+        return Ok(document.to_string());
+    }
+
+    let table = document
+        .entry("registry")
+        .or_insert(toml_edit::table())
+        .as_table_like_mut()
+        .context("`registry` is not a table")?;
+    let removed: Vec<String> = table
+        .iter()
+        .map(|(name, _)| name.to_string())
+        .filter(|name| !registry.contains_key(name))
+        .collect();
+    for name in removed {
+        table.remove(&name);
+    }
+    for (name, project) in registry {
+        if table.get(name).and_then(|item| item.as_str()) != Some(project.as_str()) {
+            table.insert(name, toml_edit::value(project));
+        }
+    }
+    Ok(document.to_string())
+}
+
+// Write the registry to the config file. Only the `[registry]` table is touched, and the
+// file is replaced at once so an interrupted write never leaves it half written.
+pub fn save_registry(registry: &BTreeMap<String, String>) -> Result<()> {
+    let path = config_path().context("Could not find a config directory")?;
+    let dir = path.parent().context("Could not find a config directory")?;
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Could not create config directory {dir:?}"))?;
+
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("Could not read config {path:?}")),
+    };
+    let text =
+        with_registry(&text, registry).with_context(|| format!("Invalid config {path:?}"))?;
+
+    trace!("Writing config to {path:?}");
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    file.write_all(text.as_bytes())?;
+    // The temporary file is only readable by its owner, which the config need not be
+    if let Ok(metadata) = std::fs::metadata(&path) {
+        file.as_file().set_permissions(metadata.permissions())?;
+    }
+    file.persist(&path)
+        .with_context(|| format!("Could not write config {path:?}"))?;
+    Ok(())
+}
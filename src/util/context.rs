@@ -0,0 +1,112 @@
+use anyhow::Result;
+use log::LevelFilter;
+use nixos_cli_def::Cli;
+use tokio_util::sync::CancellationToken;
+
+use crate::util::{
+    config::Config,
+    eval_cache::{CachedEval, EvalCache},
+    eval_session::EvalSession,
+    nix::{NixCli, NixSettings},
+    nix_version::DetectedVersion,
+    process::{self, Stage, Supervisor},
+};
+
+// How much to log, from `-v` (repeatable) and `-q`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Verbosity {
+    pub verbose: u8,
+    pub quiet: bool,
+}
+
+impl Verbosity {
+    pub fn from_cli(cli: &Cli) -> Self {
+        Verbosity {
+            verbose: cli.verbose,
+            quiet: cli.quiet,
+        }
+    }
+
+    pub fn level_filter(&self) -> LevelFilter {
+        if self.quiet {
+            return LevelFilter::Error;
+        }
+        match self.verbose {
+            0 => LevelFilter::Info,
+            1 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    }
+}
+
+// Everything a run needs besides the arguments of its command: the Nix backend, the
+// user's config and settings, and a token that stops whatever is running when
+// cancelled. Nothing below the commands reads the command line or the config file
+// itself, so the crate can be driven from other tools and from tests.
+pub struct Context<B> {
+    pub nix: B,
+    pub config: Config,
+    pub settings: NixSettings,
+    pub verbosity: Verbosity,
+    // Log the expressions we evaluate, for `--show-eval-commands`
+    pub show_eval_commands: bool,
+    pub cancel: CancellationToken,
+    // The Nix in use, shared with the backend so it is only asked once
+    pub nix_version: DetectedVersion,
+}
+
+impl<B> Context<B> {
+    // A quiet context around `nix`, with default settings
+    pub fn new(nix: B, config: Config) -> Self {
+        Context {
+            nix,
+            config,
+            settings: NixSettings::default(),
+            verbosity: Verbosity::default(),
+            show_eval_commands: false,
+            cancel: CancellationToken::new(),
+            nix_version: DetectedVersion::default(),
+        }
+    }
+
+    // Cancel the run on Ctrl-C or SIGTERM, rather than exiting with children left behind
+    pub fn cancel_on_signals(&self) -> Result<()> {
+        process::cancel_on_signals(self.cancel.clone())
+    }
+}
+
+impl Context<CachedEval<EvalSession<NixCli>>> {
+    // The context for a run of the binary, using the real Nix. Pure evaluations share one
+    // `nix repl`, and are cached across runs.
+    pub fn from_cli(cli: &Cli, config: Config) -> Self {
+        let settings = NixSettings::new(&config, cli);
+        let cancel = CancellationToken::new();
+        let nix_version = DetectedVersion::default();
+        let nix = NixCli {
+            settings: settings.clone(),
+            show_eval_commands: cli.show_eval_commands,
+            timeouts: config.timeouts,
+            cancel: cancel.clone(),
+            version: nix_version.clone(),
+        };
+        let mut session = EvalSession::new(nix, settings.clone());
+        session.supervisor = Supervisor::new(Stage::Evaluation, &cancel, config.timeouts.eval());
+        session.show_eval_commands = cli.show_eval_commands;
+        session.version = nix_version.clone();
+        let cache = if cli.no_eval_cache {
+            None
+        } else {
+            EvalCache::user()
+        };
+
+        Context {
+            nix: CachedEval::new(session, cache),
+            config,
+            settings,
+            verbosity: Verbosity::from_cli(cli),
+            show_eval_commands: cli.show_eval_commands,
+            cancel,
+            nix_version,
+        }
+    }
+}
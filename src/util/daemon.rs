@@ -0,0 +1,373 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use log::{debug, trace};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
+    net::{
+        UnixStream,
+        unix::{OwnedReadHalf, OwnedWriteHalf},
+    },
+};
+
+use crate::util::{
+    hash::Sha256,
+    nar::{self, Filter},
+    store_path::StorePath,
+};
+
+// A client for the worker protocol `nix-daemon` speaks over its socket, which is what
+// `nix-store` and friends use under the hood on multi-user installs. Asking the daemon
+// directly saves spawning a process for every query.
+//
+// Everything on the wire is a little-endian u64, or a string: its length as a u64, then
+// its bytes zero-padded to a multiple of 8. After each request the daemon sends log
+// messages until it either reports an error or says it is done, and then the reply.
+
+const DEFAULT_SOCKET: &str = "/nix/var/nix/daemon-socket/socket";
+
+const WORKER_MAGIC_1: u64 = 0x6e697863;
+const WORKER_MAGIC_2: u64 = 0x6478696f;
+
+// 1.35, as spoken by Nix 2.15 and later and by Lix
+pub const PROTOCOL_VERSION: u64 = 1 << 8 | 35;
+// 1.25 is the first to take `AddToStore` with a content address and a framed NAR
+const MIN_PROTOCOL_MINOR: u64 = 25;
+
+const STDERR_NEXT: u64 = 0x6f6c6d67;
+const STDERR_READ: u64 = 0x64617461;
+const STDERR_WRITE: u64 = 0x64617416;
+const STDERR_LAST: u64 = 0x616c7473;
+const STDERR_ERROR: u64 = 0x63787470;
+const STDERR_START_ACTIVITY: u64 = 0x53545254;
+const STDERR_STOP_ACTIVITY: u64 = 0x53544f50;
+const STDERR_RESULT: u64 = 0x52534c54;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Op {
+    IsValidPath = 1,
+    QueryReferrers = 6,
+    AddToStore = 7,
+    QueryPathInfo = 26,
+}
+
+// Where the daemon listens, `NIX_DAEMON_SOCKET_PATH` if set.
+pub fn socket_path() -> PathBuf {
+    std::env::var_os("NIX_DAEMON_SOCKET_PATH")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_SOCKET))
+}
+
+// What the store knows about a valid path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathInfo {
+    pub deriver: Option<StorePath>,
+    pub nar_hash: Sha256,
+    pub references: Vec<StorePath>,
+    pub registration_time: u64,
+    pub nar_size: u64,
+    pub ultimate: bool,
+    pub signatures: Vec<String>,
+    // The content address, eg. `fixed:r:sha256:...` for sources
+    pub ca: Option<String>,
+}
+
+pub struct DaemonClient {
+    reader: BufReader<OwnedReadHalf>,
+    writer: BufWriter<OwnedWriteHalf>,
+    store_dir: PathBuf,
+    version: u64,
+    daemon_version: Option<String>,
+}
+
+impl DaemonClient {
+    pub async fn connect(socket: &Path, store_dir: &Path) -> Result<Self> {
+        let stream = UnixStream::connect(socket)
+            .await
+            .with_context(|| format!("Could not connect to the Nix daemon at {socket:?}"))?;
+        DaemonClient::handshake(stream, store_dir).await
+    }
+
+    async fn handshake(stream: UnixStream, store_dir: &Path) -> Result<Self> {
+        let (reader, writer) = stream.into_split();
+        let mut client = DaemonClient {
+            reader: BufReader::new(reader),
+            writer: BufWriter::new(writer),
+            store_dir: store_dir.to_path_buf(),
+            version: PROTOCOL_VERSION,
+            daemon_version: None,
+        };
+
+        client.write_u64(WORKER_MAGIC_1).await?;
+        client.writer.flush().await?;
+        if client.read_u64().await? != WORKER_MAGIC_2 {
+            bail!("The Nix daemon did not answer with the worker protocol");
+        }
+        let theirs = client.read_u64().await?;
+        if theirs >> 8 != 1 || theirs & 0xff < MIN_PROTOCOL_MINOR {
+            bail!(
+                "The Nix daemon speaks protocol {}.{}, at least 1.{MIN_PROTOCOL_MINOR} is needed",
+                theirs >> 8,
+                theirs & 0xff
+            );
+        }
+        client.version = theirs.min(PROTOCOL_VERSION);
+        client.write_u64(PROTOCOL_VERSION).await?;
+
+        // Obsolete CPU affinity and reserve space settings
+        client.write_u64(0).await?;
+        client.write_u64(0).await?;
+        client.writer.flush().await?;
+
+        if client.minor() >= 33 {
+            client.daemon_version = Some(client.read_string().await?);
+        }
+        if client.minor() >= 35 {
+            // Whether we are a trusted user, which nothing here depends on
+            client.read_u64().await?;
+        }
+        client.process_stderr().await?;
+
+        debug!(
+            "Connected to the Nix daemon ({}, protocol 1.{})",
+            client
+                .daemon_version
+                .as_deref()
+                .unwrap_or("unknown version"),
+            client.minor()
+        );
+        Ok(client)
+    }
+
+    fn minor(&self) -> u64 {
+        self.version & 0xff
+    }
+
+    // The protocol version both sides agreed on.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    // The version of Nix the daemon runs, if it says.
+    pub fn daemon_version(&self) -> Option<&str> {
+        self.daemon_version.as_deref()
+    }
+
+    pub async fn is_valid_path(&mut self, path: &StorePath) -> Result<bool> {
+        self.request(Op::IsValidPath, path).await?;
+        Ok(self.read_u64().await? != 0)
+    }
+
+    // `None` for paths that are not valid.
+    pub async fn query_path_info(&mut self, path: &StorePath) -> Result<Option<PathInfo>> {
+        self.request(Op::QueryPathInfo, path).await?;
+        if self.read_u64().await? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(self.read_path_info().await?))
+    }
+
+    // The paths that refer to `path`.
+    pub async fn query_referrers(&mut self, path: &StorePath) -> Result<Vec<StorePath>> {
+        self.request(Op::QueryReferrers, path).await?;
+        self.read_store_paths().await
+    }
+
+    // Add `path` as a fixed-output source called `name`, like
+    // `nix-store --recursive --add-fixed sha256` would.
+    pub async fn add_to_store(
+        &mut self,
+        name: &str,
+        path: &Path,
+        filter: Filter,
+    ) -> Result<(StorePath, PathInfo)> {
+        let source = path.to_path_buf();
+        let dump = tokio::task::spawn_blocking(move || -> Result<Vec<u8>> {
+            let mut out = vec![];
+            nar::dump(&source, filter, &mut out)?;
+            Ok(out)
+        })
+        .await??;
+
+        trace!("daemon> AddToStore {name} ({} bytes)", dump.len());
+        self.write_u64(Op::AddToStore as u64).await?;
+        self.write_string(name).await?;
+        self.write_string("fixed:r:sha256").await?;
+        // No references, no repair
+        self.write_u64(0).await?;
+        self.write_u64(0).await?;
+        // The NAR goes in frames, each its length then its bytes, ending with an empty one
+        for frame in dump.chunks(32 * 1024) {
+            self.write_u64(frame.len() as u64).await?;
+            self.writer.write_all(frame).await?;
+        }
+        self.write_u64(0).await?;
+        self.writer.flush().await?;
+        self.process_stderr().await?;
+
+        let added = self.read_store_path().await?;
+        let info = self.read_path_info().await?;
+        Ok((added, info))
+    }
+
+    async fn request(&mut self, op: Op, path: &StorePath) -> Result<()> {
+        trace!("daemon> {op:?} {path}");
+        self.write_u64(op as u64).await?;
+        self.write_string(&path.root().to_string()).await?;
+        self.writer.flush().await?;
+        self.process_stderr().await
+    }
+
+    // Read log messages until the daemon is done with the request.
+    async fn process_stderr(&mut self) -> Result<()> {
+        loop {
+            match self.read_u64().await? {
+                STDERR_LAST => return Ok(()),
+                STDERR_ERROR => bail!(self.read_error().await?),
+                STDERR_NEXT => {
+                    let message = self.read_string().await?;
+                    debug!("nix-daemon: {}", message.trim_end());
+                }
+                STDERR_WRITE => {
+                    self.read_string().await?;
+                }
+                STDERR_READ => bail!("The Nix daemon asked for data we did not offer"),
+                STDERR_START_ACTIVITY => {
+                    let _id = self.read_u64().await?;
+                    let _level = self.read_u64().await?;
+                    let _kind = self.read_u64().await?;
+                    let text = self.read_string().await?;
+                    self.read_fields().await?;
+                    let _parent = self.read_u64().await?;
+                    if !text.is_empty() {
+                        debug!("nix-daemon: {text}");
+                    }
+                }
+                STDERR_STOP_ACTIVITY => {
+                    self.read_u64().await?;
+                }
+                STDERR_RESULT => {
+                    let _id = self.read_u64().await?;
+                    let _kind = self.read_u64().await?;
+                    self.read_fields().await?;
+                }
+                other => bail!("Unexpected message {other:#x} from the Nix daemon"),
+            }
+        }
+    }
+
+    async fn read_error(&mut self) -> Result<String> {
+        let _kind = self.read_string().await?;
+        let _level = self.read_u64().await?;
+        let _name = self.read_string().await?;
+        let mut message = self.read_string().await?;
+        let _have_pos = self.read_u64().await?;
+        for _ in 0..self.read_u64().await? {
+            let _have_pos = self.read_u64().await?;
+            message.push('\n');
+            message.push_str(&self.read_string().await?);
+        }
+        Ok(message)
+    }
+
+    async fn read_fields(&mut self) -> Result<()> {
+        for _ in 0..self.read_u64().await? {
+            match self.read_u64().await? {
+                0 => {
+                    self.read_u64().await?;
+                }
+                1 => {
+                    self.read_string().await?;
+                }
+                other => bail!("Unexpected field type {other} from the Nix daemon"),
+            }
+        }
+        Ok(())
+    }
+
+    async fn read_path_info(&mut self) -> Result<PathInfo> {
+        let deriver = self.read_string().await?;
+        let deriver = match deriver.as_str() {
+            "" => None,
+            path => Some(self.parse_store_path(path)?),
+        };
+        let nar_hash = self.read_string().await?;
+        let nar_hash = nar_hash
+            .parse()
+            .with_context(|| format!("The Nix daemon sent an invalid hash {nar_hash}"))?;
+        let references = self.read_store_paths().await?;
+        let registration_time = self.read_u64().await?;
+        let nar_size = self.read_u64().await?;
+        let ultimate = self.read_u64().await? != 0;
+        let signatures = self.read_strings().await?;
+        let ca = self.read_string().await?;
+
+        Ok(PathInfo {
+            deriver,
+            nar_hash,
+            references,
+            registration_time,
+            nar_size,
+            ultimate,
+            signatures,
+            ca: (!ca.is_empty()).then_some(ca),
+        })
+    }
+
+    fn parse_store_path(&self, path: &str) -> Result<StorePath> {
+        Ok(StorePath::parse_in(&self.store_dir, path)?)
+    }
+
+    async fn read_store_path(&mut self) -> Result<StorePath> {
+        let path = self.read_string().await?;
+        self.parse_store_path(&path)
+    }
+
+    async fn read_store_paths(&mut self) -> Result<Vec<StorePath>> {
+        self.read_strings()
+            .await?
+            .iter()
+            .map(|path| self.parse_store_path(path))
+            .collect()
+    }
+
+    async fn read_u64(&mut self) -> Result<u64> {
+        self.reader
+            .read_u64_le()
+            .await
+            .context("The Nix daemon closed the connection")
+    }
+
+    async fn read_string(&mut self) -> Result<String> {
+        let len = self.read_u64().await? as usize;
+        let mut bytes = vec![0; len.next_multiple_of(8)];
+        self.reader
+            .read_exact(&mut bytes)
+            .await
+            .context("The Nix daemon closed the connection")?;
+        bytes.truncate(len);
+        Ok(String::from_utf8(bytes)?)
+    }
+
+    async fn read_strings(&mut self) -> Result<Vec<String>> {
+        let count = self.read_u64().await?;
+        let mut strings = vec![];
+        for _ in 0..count {
+            strings.push(self.read_string().await?);
+        }
+        Ok(strings)
+    }
+
+    async fn write_u64(&mut self, n: u64) -> Result<()> {
+        Ok(self.writer.write_u64_le(n).await?)
+    }
+
+    async fn write_string(&mut self, s: &str) -> Result<()> {
+        self.write_u64(s.len() as u64).await?;
+        self.writer.write_all(s.as_bytes()).await?;
+        let padding = s.len().next_multiple_of(8) - s.len();
+        self.writer.write_all(&[0; 8][..padding]).await?;
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use once_cell::sync::Lazy;
 use regex::Regex;
 
@@ -48,3 +50,19 @@ pub fn handle_error(stderr: &str) -> Vec<NixError> {
 
     errors
 }
+
+// The derivations a build reports as failed. Nix before 2.19 (and Lix) say
+// "builder for '<drv>' failed", newer Nix says "Cannot build '<drv>'".
+pub fn failed_derivations(stderr: &str) -> Vec<PathBuf> {
+    static FAILED_BUILDER: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"(?:builder for|Cannot build) '([^']+\.drv)'").unwrap());
+
+    let mut failed: Vec<PathBuf> = vec![];
+    for captures in FAILED_BUILDER.captures_iter(stderr) {
+        let drv = PathBuf::from(&captures[1]);
+        if !failed.contains(&drv) {
+            failed.push(drv);
+        }
+    }
+    failed
+}
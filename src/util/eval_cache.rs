@@ -0,0 +1,189 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, trace};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::util::{
+    closure::References,
+    expand::home_dir,
+    hash::Sha256,
+    nar::Filter,
+    nix::{
+        BuildOpts, BuildResult, EvalOpts, EvalResult, FixedOutputStoreEntry, NixBackend,
+        RebuildOpts,
+    },
+    nix_expr::Expr,
+    store_path::StorePath,
+};
+
+// Evaluation results kept on disk between runs. A pure evaluation of an expression that
+// imports the project by hash (see `nix::project_expr`) always has the same answer, so
+// it is stored under `<cache>/eval/<project hash>/` and never has to be checked again.
+// Deleting the directory is always safe.
+#[derive(Debug, Clone)]
+pub struct EvalCache {
+    dir: PathBuf,
+}
+
+// What is written for each result. The expression is kept to rule out a clash of names.
+#[derive(Debug, Deserialize, Serialize)]
+struct Entry {
+    expr: String,
+    json: bool,
+    value: Value,
+}
+
+pub fn cache_dir() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => home_dir(None)?.join(".cache"),
+    };
+
+    Some(base.join("nilla-nixos"))
+}
+
+impl EvalCache {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        EvalCache { dir: dir.into() }
+    }
+
+    // The cache in the user's cache directory, if they have one
+    pub fn user() -> Option<Self> {
+        cache_dir().map(EvalCache::new)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    // Where the result of `code` would be kept. Expressions pinning more than one entry
+    // are filed under all of their hashes together.
+    fn path(&self, hashes: &[&str], code: &str, json: bool) -> PathBuf {
+        let key = Sha256::digest(format!("{json}\n{code}"));
+        self.dir
+            .join("eval")
+            .join(hashes.join("-"))
+            .join(format!("{}.json", key.to_hex()))
+    }
+
+    pub fn get(&self, hashes: &[&str], code: &str, json: bool) -> Option<EvalResult> {
+        let path = self.path(hashes, code, json);
+        let text = std::fs::read_to_string(&path).ok()?;
+        let entry: Entry = match serde_json::from_str(&text) {
+            Ok(entry) => entry,
+            Err(e) => {
+                debug!("Ignoring unreadable cache entry {path:?}: {e}");
+                return None;
+            }
+        };
+        if entry.expr != code || entry.json != json {
+            debug!("Ignoring cache entry {path:?}, it is for another expression");
+            return None;
+        }
+
+        trace!("Found {code} in the evaluation cache");
+        match (json, entry.value) {
+            (true, value) => Some(EvalResult::Json(value)),
+            (false, Value::String(raw)) => Some(EvalResult::Raw(raw)),
+            (false, _) => None,
+        }
+    }
+
+    pub fn put(&self, hashes: &[&str], code: &str, result: &EvalResult) -> Result<()> {
+        let (json, value) = match result {
+            EvalResult::Json(value) => (true, value.clone()),
+            EvalResult::Raw(raw) => (false, Value::String(raw.clone())),
+        };
+        let path = self.path(hashes, code, json);
+        let dir = path.parent().unwrap();
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Could not create cache directory {dir:?}"))?;
+
+        // Written aside and renamed into place, so a concurrent run never reads half of it
+        let entry = Entry {
+            expr: code.to_string(),
+            json,
+            value,
+        };
+        let mut file = tempfile::NamedTempFile::new_in(dir)?;
+        file.write_all(serde_json::to_string(&entry)?.as_bytes())?;
+        file.persist(&path)
+            .with_context(|| format!("Could not write cache entry {path:?}"))?;
+        Ok(())
+    }
+}
+
+// A backend answering pure evaluations of pinned expressions from an `EvalCache`, and
+// everything else from the backend it wraps.
+pub struct CachedEval<B> {
+    nix: B,
+    cache: Option<EvalCache>,
+}
+
+impl<B: NixBackend> CachedEval<B> {
+    // Without a cache this passes everything through
+    pub fn new(nix: B, cache: Option<EvalCache>) -> Self {
+        CachedEval { nix, cache }
+    }
+
+    pub fn inner(&self) -> &B {
+        &self.nix
+    }
+}
+
+impl<B: NixBackend> NixBackend for CachedEval<B> {
+    fn store_dir(&self) -> PathBuf {
+        self.nix.store_dir()
+    }
+
+    async fn evaluate(&self, expr: &Expr, opts: EvalOpts) -> Result<EvalResult> {
+        let Some(cache) = &self.cache else {
+            return self.nix.evaluate(expr, opts).await;
+        };
+        let hashes = expr.pinned_hashes();
+        // Impure evaluations can see the environment, and unpinned ones the filesystem
+        if opts.impure || !opts.env.is_empty() || hashes.is_empty() {
+            return self.nix.evaluate(expr, opts).await;
+        }
+
+        let code = expr.to_string();
+        if let Some(result) = cache.get(&hashes, &code, opts.json) {
+            return Ok(result);
+        }
+
+        let result = self.nix.evaluate(expr, opts).await?;
+        if let Err(e) = cache.put(&hashes, &code, &result) {
+            debug!("{e:#}, not caching the result");
+        }
+        Ok(result)
+    }
+
+    async fn realise(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        self.nix.realise(path).await
+    }
+
+    async fn add_to_store(&self, path: &Path, filter: Filter) -> Result<FixedOutputStoreEntry> {
+        self.nix.add_to_store(path, filter).await
+    }
+
+    async fn store_hash(&self, path: &StorePath) -> Result<String> {
+        self.nix.store_hash(path).await
+    }
+
+    async fn build(&self, file: &Path, name: &str, opts: BuildOpts) -> Result<BuildResult> {
+        self.nix.build(file, name, opts).await
+    }
+
+    async fn rebuild(&self, opts: RebuildOpts) -> Result<()> {
+        self.nix.rebuild(opts).await
+    }
+
+    async fn closure(&self, path: &Path) -> Result<References> {
+        self.nix.closure(path).await
+    }
+}
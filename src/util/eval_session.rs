@@ -0,0 +1,298 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use anyhow::{Context, Result, bail};
+use log::{debug, info, trace};
+use regex::Regex;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, Lines},
+    process::{Child, Command},
+    sync::Mutex,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::util::{
+    closure::References,
+    nar::Filter,
+    nix::{
+        BuildOpts, BuildResult, EvalOpts, EvalResult, FixedOutputStoreEntry, NixBackend,
+        NixSettings, RebuildOpts, project_expr,
+    },
+    nix_expr::{Expr, is_identifier},
+    nix_version::{DetectedVersion, NixVersion},
+    process::{Stage, Supervisor},
+    store_path::StorePath,
+};
+
+// Every `nix eval` parses the project, and usually nixpkgs, from scratch. A session keeps
+// one `nix repl` running instead, so imports and evaluated values are shared between all
+// the queries made through it.
+//
+// The repl is driven line by line. Each query is followed by a marker expression, which
+// `builtins.trace` prints to both stderr and stdout, so we know where the output of the
+// query ends on each stream. Results are passed through `builtins.toJSON`, which the repl
+// prints as a quoted Nix string.
+//
+// The repl evaluates purely, as `nix eval` does without `--impure`, so what it returns
+// can be cached like any other pure evaluation.
+
+type Reader = Lines<BufReader<Box<dyn AsyncRead + Send + Unpin>>>;
+
+pub struct Repl {
+    // Kept so the process is killed along with the session
+    _child: Option<Child>,
+    stdin: Box<dyn AsyncWrite + Send + Unpin>,
+    stdout: Reader,
+    stderr: Reader,
+    next_marker: u64,
+}
+
+impl Repl {
+    pub fn spawn(nix: &NixVersion, settings: &NixSettings) -> Result<Self> {
+        let mut child = Command::new("nix")
+            .args(nix.nix_command_args())
+            .process_group(0)
+            .arg("repl")
+            .args(["--option", "pure-eval", "true"])
+            .args(settings.args())
+            .env("NO_COLOR", "1")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .context("Could not start nix repl")?;
+        debug!("Started nix repl (pid {:?})", child.id());
+
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let stderr = child.stderr.take().unwrap();
+        let mut repl = Repl::from_streams(stdin, stdout, stderr);
+        repl._child = Some(child);
+        Ok(repl)
+    }
+
+    // Drive something that behaves like `nix repl` over the given streams.
+    pub fn from_streams<W, O, E>(stdin: W, stdout: O, stderr: E) -> Self
+    where
+        W: AsyncWrite + Send + Unpin + 'static,
+        O: AsyncRead + Send + Unpin + 'static,
+        E: AsyncRead + Send + Unpin + 'static,
+    {
+        let stdout: Box<dyn AsyncRead + Send + Unpin> = Box::new(stdout);
+        let stderr: Box<dyn AsyncRead + Send + Unpin> = Box::new(stderr);
+        Repl {
+            _child: None,
+            stdin: Box::new(stdin),
+            stdout: BufReader::new(stdout).lines(),
+            stderr: BufReader::new(stderr).lines(),
+            next_marker: 0,
+        }
+    }
+
+    async fn send(&mut self, line: &str) -> Result<()> {
+        trace!("nix repl> {line}");
+        self.stdin.write_all(line.as_bytes()).await?;
+        self.stdin.write_all(b"\n").await?;
+        self.stdin.flush().await?;
+        Ok(())
+    }
+
+    // Run one line and return what it printed on stdout and stderr.
+    async fn run(&mut self, line: &str) -> Result<(Vec<String>, Vec<String>)> {
+        let marker = format!("nilla-session-{}", self.next_marker);
+        self.next_marker += 1;
+
+        self.send(line).await?;
+        let trace = Expr::builtin("trace")
+            .apply(Expr::str(&marker))
+            .apply(Expr::str(&marker));
+        self.send(&trace.to_string()).await?;
+
+        let quoted = format!("\"{marker}\"");
+        let stdout = read_until(&mut self.stdout, |line| line == quoted).await?;
+        let stderr = read_until(&mut self.stderr, |line| line.contains(&marker)).await?;
+        Ok((stdout, stderr))
+    }
+
+    // Evaluate `expr` to JSON.
+    pub async fn eval(&mut self, expr: &Expr) -> Result<serde_json::Value> {
+        let line = Expr::builtin("toJSON").apply(expr.clone()).to_string();
+        let (stdout, stderr) = self.run(&line).await?;
+
+        // Anything else on stdout is the banner or other noise from the repl itself
+        let Some(json) = stdout.iter().rev().find_map(|line| unquote(line)) else {
+            bail!("nix eval failed\n{}", stderr.join("\n"));
+        };
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    // Bind `name` to `expr` for the rest of the session. Like any Nix binding this is
+    // lazy, so errors in `expr` only show up once it is used.
+    pub async fn bind(&mut self, name: &str, expr: &Expr) -> Result<()> {
+        if !is_identifier(name) {
+            bail!("Cannot bind `{name}` in nix repl, it is not a valid identifier");
+        }
+        let (_, stderr) = self.run(&format!("{name} = {expr}")).await?;
+        if stderr.iter().any(|line| line.starts_with("error")) {
+            bail!("Could not bind {name} in nix repl\n{}", stderr.join("\n"));
+        }
+        Ok(())
+    }
+}
+
+// Read lines up to and including the one `is_end` accepts, returning those before it.
+async fn read_until(reader: &mut Reader, is_end: impl Fn(&str) -> bool) -> Result<Vec<String>> {
+    let ansi = Regex::new(r"\x1b\[[0-9;]*[A-Za-z]").unwrap();
+    let mut lines = vec![];
+    loop {
+        let Some(line) = reader.next_line().await? else {
+            bail!("nix repl exited unexpectedly\n{}", lines.join("\n"));
+        };
+        let line = ansi.replace_all(&line, "").into_owned();
+        if is_end(line.trim()) {
+            return Ok(lines);
+        }
+        lines.push(line);
+    }
+}
+
+// The contents of a Nix string literal as the repl prints it.
+fn unquote(line: &str) -> Option<String> {
+    let inner = line.trim().strip_prefix('"')?.strip_suffix('"')?;
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next()? {
+                'n' => out.push('\n'),
+                'r' => out.push('\r'),
+                't' => out.push('\t'),
+                c => out.push(c),
+            },
+            '"' => return None,
+            c => out.push(c),
+        }
+    }
+    Some(out)
+}
+
+// A backend that sends pure evaluations through one long-lived repl, and everything else
+// to the backend it wraps. The repl is started by the first evaluation that needs it.
+pub struct EvalSession<B> {
+    nix: B,
+    settings: NixSettings,
+    // Stops queries when the run is cancelled, or an evaluation takes longer than it may
+    pub supervisor: Supervisor,
+    // Log the expressions we evaluate, as `NixCli` does
+    pub show_eval_commands: bool,
+    pub version: DetectedVersion,
+    repl: Mutex<Option<Repl>>,
+}
+
+impl<B: NixBackend> EvalSession<B> {
+    pub fn new(nix: B, settings: NixSettings) -> Self {
+        EvalSession {
+            nix,
+            settings,
+            supervisor: Supervisor::new(Stage::Evaluation, &CancellationToken::new(), None),
+            show_eval_commands: false,
+            version: DetectedVersion::default(),
+            repl: Mutex::new(None),
+        }
+    }
+
+    pub fn with_repl(nix: B, repl: Repl) -> Self {
+        EvalSession {
+            repl: Mutex::new(Some(repl)),
+            ..EvalSession::new(nix, NixSettings::default())
+        }
+    }
+
+    pub fn inner(&self) -> &B {
+        &self.nix
+    }
+
+    // Run `query` on the repl, starting one first if need be. A query that is stopped
+    // leaves the repl in a state we know nothing about, so it is thrown away, and the next
+    // query starts a new one.
+    async fn query<T>(&self, query: impl AsyncFnOnce(&mut Repl) -> Result<T>) -> Result<T> {
+        let mut repl = self.repl.lock().await;
+        if repl.is_none() {
+            let version = self.version.get(&self.settings).await?;
+            *repl = Some(Repl::spawn(version, &self.settings)?);
+        }
+        let stopped = self.supervisor.abandon(query(repl.as_mut().unwrap())).await;
+        match stopped {
+            Ok(result) => result,
+            Err(e) => {
+                debug!("Stopping nix repl");
+                *repl = None;
+                Err(e)
+            }
+        }
+    }
+
+    pub async fn bind(&self, name: &str, expr: &Expr) -> Result<()> {
+        self.query(async |repl| repl.bind(name, expr).await).await
+    }
+
+    // Bind `project` to the project's entry file, for queries such as
+    // `project.systems.nixos.web01.result.config.networking.hostName`.
+    pub async fn load_project(&self, file: &str, entry: &FixedOutputStoreEntry) -> Result<()> {
+        self.bind("project", &project_expr(file, entry)).await
+    }
+}
+
+impl<B: NixBackend> NixBackend for EvalSession<B> {
+    fn store_dir(&self) -> PathBuf {
+        self.nix.store_dir()
+    }
+
+    async fn evaluate(&self, expr: &Expr, opts: EvalOpts) -> Result<EvalResult> {
+        // The environment and purity of a running repl are fixed, so these need a process
+        // of their own
+        if opts.impure || !opts.env.is_empty() {
+            return self.nix.evaluate(expr, opts).await;
+        }
+
+        if self.show_eval_commands {
+            info!("{expr}");
+        }
+        debug!("Evaluating in nix repl:\n{expr}");
+        let value = self.query(async |repl| repl.eval(expr).await).await?;
+        if opts.json {
+            return Ok(EvalResult::Json(value));
+        }
+        match value {
+            serde_json::Value::String(s) => Ok(EvalResult::Raw(s)),
+            value => Ok(EvalResult::Raw(value.to_string())),
+        }
+    }
+
+    async fn realise(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        self.nix.realise(path).await
+    }
+
+    async fn add_to_store(&self, path: &Path, filter: Filter) -> Result<FixedOutputStoreEntry> {
+        self.nix.add_to_store(path, filter).await
+    }
+
+    async fn store_hash(&self, path: &StorePath) -> Result<String> {
+        self.nix.store_hash(path).await
+    }
+
+    async fn build(&self, file: &Path, name: &str, opts: BuildOpts) -> Result<BuildResult> {
+        self.nix.build(file, name, opts).await
+    }
+
+    async fn rebuild(&self, opts: RebuildOpts) -> Result<()> {
+        self.nix.rebuild(opts).await
+    }
+
+    async fn closure(&self, path: &Path) -> Result<References> {
+        self.nix.closure(path).await
+    }
+}
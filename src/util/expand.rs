@@ -0,0 +1,137 @@
+use std::{
+    ffi::{CStr, CString},
+    os::unix::ffi::OsStrExt,
+    path::PathBuf,
+};
+
+use anyhow::{Result, anyhow, bail};
+use log::trace;
+
+// Expand a local path the way a shell would: a leading `~` or `~user`, then any `$VAR`
+// or `${VAR}` references. Unset variables and unknown users are errors rather than
+// being silently replaced with nothing.
+pub fn expand_path(input: &str) -> Result<PathBuf> {
+    expand_path_with(input, |name| std::env::var(name).ok(), home_dir)
+}
+
+pub fn expand_path_with<E, H>(input: &str, env: E, home: H) -> Result<PathBuf>
+where
+    E: Fn(&str) -> Option<String>,
+    H: Fn(Option<&str>) -> Option<PathBuf>,
+{
+    let (mut out, rest) = match input.strip_prefix('~') {
+        Some(after) => {
+            let (user, rest) = match after.find('/') {
+                Some(i) => after.split_at(i),
+                None => (after, ""),
+            };
+            let user = (!user.is_empty()).then_some(user);
+            let dir = home(user).ok_or_else(|| match user {
+                Some(user) => anyhow!("Could not find home directory for user {user}"),
+                None => anyhow!("Could not find home directory for the current user"),
+            })?;
+            (dir.to_string_lossy().into_owned(), rest)
+        }
+        None => (String::new(), input),
+    };
+
+    let mut chars = rest.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if c != '$' {
+            out.push(c);
+            continue;
+        }
+
+        let name = if rest[i + 1..].starts_with('{') {
+            let Some(len) = rest[i + 2..].find('}') else {
+                bail!("Unterminated variable reference in {input}");
+            };
+            let name = &rest[i + 2..i + 2 + len];
+            for _ in 0..len + 2 {
+                chars.next();
+            }
+            name
+        } else {
+            let len = rest[i + 1..]
+                .char_indices()
+                .take_while(|(j, c)| {
+                    c.is_ascii_alphabetic() || *c == '_' || (*j > 0 && c.is_ascii_digit())
+                })
+                .count();
+            if len == 0 {
+                out.push('$');
+                continue;
+            }
+            for _ in 0..len {
+                chars.next();
+            }
+            &rest[i + 1..i + 1 + len]
+        };
+
+        if name.is_empty() {
+            bail!("Empty variable reference in {input}");
+        }
+        let Some(value) = env(name) else {
+            bail!("Environment variable {name} is not set (used in {input})");
+        };
+        out.push_str(&value);
+    }
+
+    trace!("Expanded {input} to {out}");
+    Ok(PathBuf::from(out))
+}
+
+// Home directory lookup: `$HOME` for the current user (as shells do), otherwise the
+// passwd database.
+pub fn home_dir(user: Option<&str>) -> Option<PathBuf> {
+    match user {
+        None => match std::env::var_os("HOME") {
+            Some(home) if !home.is_empty() => Some(PathBuf::from(home)),
+            _ => passwd_home(None),
+        },
+        Some(user) => passwd_home(Some(user)),
+    }
+}
+
+fn passwd_home(user: Option<&str>) -> Option<PathBuf> {
+    let name = match user {
+        Some(user) => Some(CString::new(user).ok()?),
+        None => None,
+    };
+
+    let mut buf = vec![0 as libc::c_char; 4096];
+    loop {
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result: *mut libc::passwd = std::ptr::null_mut();
+
+        let status = unsafe {
+            match &name {
+                Some(name) => libc::getpwnam_r(
+                    name.as_ptr(),
+                    &mut pwd,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut result,
+                ),
+                None => libc::getpwuid_r(
+                    libc::getuid(),
+                    &mut pwd,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut result,
+                ),
+            }
+        };
+
+        if status == libc::ERANGE && buf.len() < 1 << 20 {
+            buf.resize(buf.len() * 2, 0);
+            continue;
+        }
+        if status != 0 || result.is_null() || pwd.pw_dir.is_null() {
+            return None;
+        }
+
+        let dir = unsafe { CStr::from_ptr(pwd.pw_dir) };
+        return Some(PathBuf::from(std::ffi::OsStr::from_bytes(dir.to_bytes())));
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    fmt,
+    io::{self, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{Context, Result};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use sha2::{Digest, Sha256 as Sha256Hasher};
+
+// Nix's base-32 alphabet (no `e`, `o`, `u` or `t`).
+pub const NIX32_ALPHABET: &[u8; 32] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+// A SHA-256 digest. Parses every notation Nix prints or accepts: hex, Nix base-32 and
+// SRI (`sha256-<base64>`), optionally prefixed with `sha256:`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Sha256([u8; 32]);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidHash(pub String);
+
+impl fmt::Display for InvalidHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid SHA-256 hash `{}`", self.0)
+    }
+}
+
+impl std::error::Error for InvalidHash {}
+
+impl Sha256 {
+    pub fn digest(data: impl AsRef<[u8]>) -> Self {
+        Sha256(Sha256Hasher::digest(data).into())
+    }
+
+    pub fn of_file(path: &Path) -> Result<Self> {
+        let mut file =
+            std::fs::File::open(path).with_context(|| format!("Could not open {path:?}"))?;
+        let mut hasher = Hasher::new();
+        io::copy(&mut file, &mut hasher).with_context(|| format!("Could not read {path:?}"))?;
+        Ok(hasher.finish())
+    }
+
+    pub fn bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    pub fn to_nix32(&self) -> String {
+        nix32(&self.0)
+    }
+
+    pub fn to_sri(&self) -> String {
+        format!("sha256-{}", BASE64_STANDARD.encode(self.0))
+    }
+
+    fn from_hex(s: &str) -> Option<Self> {
+        let mut bytes = [0; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+        }
+        Some(Sha256(bytes))
+    }
+
+    fn from_nix32(s: &str) -> Option<Self> {
+        let mut bytes = [0u8; 32];
+        for (n, c) in s.bytes().rev().enumerate() {
+            let digit = NIX32_ALPHABET.iter().position(|a| *a == c)? as u16;
+            let (i, j) = (n * 5 / 8, n * 5 % 8);
+            let shifted = digit << j;
+            bytes[i] |= shifted as u8;
+            match bytes.get_mut(i + 1) {
+                Some(next) => *next |= (shifted >> 8) as u8,
+                // The top bits of the first character have nowhere to go
+                None if shifted >> 8 != 0 => return None,
+                None => {}
+            }
+        }
+        Some(Sha256(bytes))
+    }
+
+    fn from_base64(s: &str) -> Option<Self> {
+        BASE64_STANDARD.decode(s).ok()?.try_into().ok().map(Sha256)
+    }
+}
+
+// Encode bytes in Nix's base-32, which reads the bytes backwards, five bits at a time.
+pub fn nix32(bytes: &[u8]) -> String {
+    let len = (bytes.len() * 8).div_ceil(5);
+    (0..len)
+        .rev()
+        .map(|n| {
+            let (i, j) = (n * 5 / 8, n * 5 % 8);
+            let window = bytes[i] as u16 | (*bytes.get(i + 1).unwrap_or(&0) as u16) << 8;
+            NIX32_ALPHABET[((window >> j) & 0x1f) as usize] as char
+        })
+        .collect()
+}
+
+// Incremental hashing for data that is written out piece by piece, like a NAR.
+#[derive(Default)]
+pub struct Hasher {
+    hasher: Sha256Hasher,
+    written: u64,
+}
+
+impl Hasher {
+    pub fn new() -> Self {
+        Hasher::default()
+    }
+
+    // The number of bytes hashed so far.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    pub fn finish(self) -> Sha256 {
+        Sha256(self.hasher.finalize().into())
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.hasher.update(buf);
+        self.written += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl FromStr for Sha256 {
+    type Err = InvalidHash;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hash = if let Some(sri) = s.strip_prefix("sha256-") {
+            Sha256::from_base64(sri)
+        } else {
+            let bare = s.strip_prefix("sha256:").unwrap_or(s);
+            match bare.len() {
+                64 if bare.bytes().all(|b| b.is_ascii_hexdigit()) => Sha256::from_hex(bare),
+                52 => Sha256::from_nix32(bare),
+                44 => Sha256::from_base64(bare),
+                _ => None,
+            }
+        };
+
+        hash.ok_or_else(|| InvalidHash(s.to_string()))
+    }
+}
+
+impl fmt::Display for Sha256 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_sri())
+    }
+}
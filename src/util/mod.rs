@@ -1,5 +1,25 @@
+pub mod archive;
+pub mod auth;
+pub mod closure;
+pub mod config;
+pub mod context;
+pub mod daemon;
 pub mod errors;
+pub mod eval_cache;
+pub mod eval_session;
+pub mod expand;
 pub mod git;
+pub mod hash;
+pub mod nar;
 pub mod nix;
+pub mod nix_expr;
+pub mod nix_mock;
+pub mod nix_version;
+pub mod options;
+pub mod process;
 pub mod project;
+pub mod project_ref;
+pub mod registry;
 pub mod search;
+pub mod store_path;
+pub mod system_info;
@@ -0,0 +1,222 @@
+use std::{
+    ffi::OsString,
+    fs::Metadata,
+    io::{self, Write},
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use log::{debug, trace};
+
+use crate::util::hash::{Hasher, Sha256};
+
+// NAR (Nix ARchive) is the serialisation Nix hashes and stores paths as. It is a tree
+// of tokens, each written as its length (a little-endian u64) followed by its bytes,
+// zero-padded to a multiple of 8. Directory entries are sorted by name, and only the
+// executable bit of a file's permissions is recorded, so the same tree always gives
+// the same archive.
+
+const MAGIC: &str = "nix-archive-1";
+
+// Which files go into an archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    // Everything, as `nix hash path` and `nix-store --add` see it
+    All,
+    // Leave out `.git` and whatever the `.gitignore` files in the tree ignore
+    GitIgnore,
+}
+
+// The result of hashing a path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NarHash {
+    pub hash: Sha256,
+    pub size: u64,
+    // How many files or directories the filter left out
+    pub skipped: usize,
+}
+
+// The `.gitignore` files in effect while walking a tree, innermost last.
+struct Ignores {
+    filter: Filter,
+    stack: Vec<Gitignore>,
+    skipped: usize,
+}
+
+impl Ignores {
+    fn new(filter: Filter) -> Self {
+        Ignores {
+            filter,
+            stack: vec![],
+            skipped: 0,
+        }
+    }
+
+    fn enter(&mut self, dir: &Path) -> Result<()> {
+        if self.filter == Filter::All {
+            return Ok(());
+        }
+        let file = dir.join(".gitignore");
+        let mut builder = GitignoreBuilder::new(dir);
+        if let Some(e) = file.is_file().then(|| builder.add(&file)).flatten() {
+            bail!("Could not read {file:?}: {e}");
+        }
+        self.stack.push(builder.build()?);
+        Ok(())
+    }
+
+    fn leave(&mut self) {
+        self.stack.pop();
+    }
+
+    fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        if self.filter == Filter::All {
+            return false;
+        }
+        if path.file_name().is_some_and(|name| name == ".git") {
+            return true;
+        }
+        // Like git, the closest `.gitignore` with an opinion wins
+        self.stack
+            .iter()
+            .rev()
+            .map(|ignore| ignore.matched(path, is_dir))
+            .find(|m| !m.is_none())
+            .is_some_and(|m| m.is_ignore())
+    }
+
+    // The entries of `dir` that pass the filter, in NAR order.
+    fn entries(&mut self, dir: &Path) -> Result<Vec<(OsString, PathBuf, Metadata)>> {
+        let mut entries = vec![];
+        for entry in std::fs::read_dir(dir).with_context(|| format!("Could not read {dir:?}"))? {
+            let entry = entry?;
+            let path = entry.path();
+            let metadata = std::fs::symlink_metadata(&path)?;
+            if self.is_ignored(&path, metadata.is_dir()) {
+                trace!("Leaving out {path:?}");
+                self.skipped += 1;
+                continue;
+            }
+            entries.push((entry.file_name(), path, metadata));
+        }
+        entries.sort_by(|(a, ..), (b, ..)| a.as_bytes().cmp(b.as_bytes()));
+        Ok(entries)
+    }
+}
+
+fn write_bytes<W: Write>(out: &mut W, bytes: &[u8]) -> io::Result<()> {
+    out.write_all(&(bytes.len() as u64).to_le_bytes())?;
+    out.write_all(bytes)?;
+    write_padding(out, bytes.len() as u64)
+}
+
+fn write_padding<W: Write>(out: &mut W, len: u64) -> io::Result<()> {
+    let padding = (8 - len % 8) % 8;
+    out.write_all(&[0; 8][..padding as usize])
+}
+
+fn write_tokens<W: Write>(out: &mut W, tokens: &[&str]) -> io::Result<()> {
+    tokens
+        .iter()
+        .try_for_each(|token| write_bytes(out, token.as_bytes()))
+}
+
+fn dump_node<W: Write>(
+    path: &Path,
+    metadata: &Metadata,
+    ignores: &mut Ignores,
+    out: &mut W,
+) -> Result<()> {
+    write_tokens(out, &["(", "type"])?;
+
+    if metadata.is_symlink() {
+        let target = std::fs::read_link(path)?;
+        write_tokens(out, &["symlink", "target"])?;
+        write_bytes(out, target.as_os_str().as_bytes())?;
+    } else if metadata.is_dir() {
+        write_tokens(out, &["directory"])?;
+        ignores.enter(path)?;
+        for (name, path, metadata) in ignores.entries(path)? {
+            write_tokens(out, &["entry", "(", "name"])?;
+            write_bytes(out, name.as_bytes())?;
+            write_tokens(out, &["node"])?;
+            dump_node(&path, &metadata, ignores, out)?;
+            write_tokens(out, &[")"])?;
+        }
+        ignores.leave();
+    } else if metadata.is_file() {
+        write_tokens(out, &["regular"])?;
+        if metadata.permissions().mode() & 0o100 != 0 {
+            write_tokens(out, &["executable", ""])?;
+        }
+        write_tokens(out, &["contents"])?;
+
+        let len = metadata.len();
+        out.write_all(&len.to_le_bytes())?;
+        let mut file =
+            std::fs::File::open(path).with_context(|| format!("Could not open {path:?}"))?;
+        let copied =
+            io::copy(&mut file, out).with_context(|| format!("Could not read {path:?}"))?;
+        if copied != len {
+            bail!("{path:?} changed while it was being read");
+        }
+        write_padding(out, len)?;
+    } else {
+        bail!("{path:?} is not a regular file, directory or symlink");
+    }
+
+    write_tokens(out, &[")"])?;
+    Ok(())
+}
+
+// Serialise `path` as a NAR, returning how many entries the filter left out.
+pub fn dump<W: Write>(path: &Path, filter: Filter, out: &mut W) -> Result<usize> {
+    let metadata =
+        std::fs::symlink_metadata(path).with_context(|| format!("Could not find {path:?}"))?;
+    let mut ignores = Ignores::new(filter);
+    write_tokens(out, &[MAGIC])?;
+    dump_node(path, &metadata, &mut ignores, out)?;
+    Ok(ignores.skipped)
+}
+
+// The NAR hash of `path`, which is what `nix hash path` prints and what Nix records for
+// paths added with `--recursive`.
+pub fn hash(path: &Path, filter: Filter) -> Result<NarHash> {
+    let mut hasher = Hasher::new();
+    let skipped = dump(path, filter, &mut hasher)?;
+    let size = hasher.written();
+    let hash = hasher.finish();
+    debug!("Hashed {path:?} as {hash} ({size} bytes, {skipped} entries skipped)");
+
+    Ok(NarHash {
+        hash,
+        size,
+        skipped,
+    })
+}
+
+fn copy_node(from: &Path, metadata: &Metadata, to: &Path, ignores: &mut Ignores) -> Result<()> {
+    if metadata.is_symlink() {
+        std::os::unix::fs::symlink(std::fs::read_link(from)?, to)?;
+    } else if metadata.is_dir() {
+        std::fs::create_dir(to).with_context(|| format!("Could not create {to:?}"))?;
+        ignores.enter(from)?;
+        for (name, path, metadata) in ignores.entries(from)? {
+            copy_node(&path, &metadata, &to.join(name), ignores)?;
+        }
+        ignores.leave();
+    } else {
+        std::fs::copy(from, to).with_context(|| format!("Could not copy {from:?}"))?;
+    }
+    Ok(())
+}
+
+// Copy `from` to `to`, leaving out whatever the filter does, so that the copy has the
+// same NAR hash as `from` with that filter.
+pub fn copy(from: &Path, to: &Path, filter: Filter) -> Result<()> {
+    let metadata =
+        std::fs::symlink_metadata(from).with_context(|| format!("Could not find {from:?}"))?;
+    copy_node(from, &metadata, to, &mut Ignores::new(filter))
+}
@@ -1,18 +1,37 @@
 use std::{
+    future::Future,
     path::{Path, PathBuf},
     process::Stdio,
 };
 
-use anyhow::{Result, anyhow, bail};
+use anyhow::{Context, Result, bail};
 use log::{debug, info, trace};
 use serde_json::Value;
-use tokio::process::Command;
-
-use crate::util::project::remove_filename_from_path;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, BufReader},
+    process::Command,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::util::{
+    closure::{self, References},
+    config::{Config, Timeouts},
+    daemon::{self, DaemonClient},
+    errors,
+    hash::Sha256,
+    nar::{self, Filter},
+    nix_expr::{AttrPath, Expr},
+    nix_version::{DetectedVersion, NixVersion},
+    process::{self, Elevation, Stage, Supervisor},
+    project::remove_filename_from_path,
+    store_path::{StorePath, store_dir},
+};
 
+#[derive(Debug, Clone)]
 pub struct EvalOpts {
     pub json: bool,
     pub impure: bool,
+    pub env: Vec<(String, String)>,
 }
 
 impl Default for EvalOpts {
@@ -20,74 +39,397 @@ impl Default for EvalOpts {
         Self {
             json: false,
             impure: true,
+            env: vec![],
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum EvalResult {
     Json(serde_json::Value),
     Raw(String),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct FixedOutputStoreEntry {
-    pub path: PathBuf,
+    // May point inside the entry, eg. at the `dir=` of a project
+    pub path: StorePath,
+    // The NAR hash of the whole entry, in Nix's base-32
     pub hash: String,
 }
 
-// We need a helper for getting the `name` of a store entry. Older versions of Lix/Nix
-// suported calling `builtins.path` without the `name` attribute, but newer versions may
-// require it (although it is unclear if this is a bug).
-//
-// See: https://git.lix.systems/lix-project/lix/issues/776
-pub fn get_store_path_name<P>(path: P) -> String
-where
-    P: Into<PathBuf>,
-{
-    // Store paths typically take the form of:
-    // /nix/store/lplzlyk8ldz821dl6pmlhk3md1ms69md-config
-    //
-    // To handle both of these we will want to do the following:
-    // 1. Split the path by `/` and get the entry after `store` (index 3)
-    // 2. Split the entry by `-` and get the last entry
-    // 3. Return the last entry
+// The profile holding the system's generations
+pub const SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
 
-    let path: PathBuf = path.into();
-    let path_str = path.to_str().unwrap();
-    let path_parts = path_str.split("/").collect::<Vec<&str>>();
-    trace!("Split path: {:?}", path_parts);
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RebuildAction {
+    Build,
+    Switch,
+    Test,
+}
+
+impl RebuildAction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RebuildAction::Build => "build",
+            RebuildAction::Switch => "switch",
+            RebuildAction::Test => "test",
+        }
+    }
+}
 
-    let store_path = path_parts[3];
-    trace!("Store path: {store_path}");
-    let store_parts = store_path.split("-").collect::<Vec<&str>>();
-    trace!("Removing {}", store_parts[0]);
-    let store_name = store_parts[1..].join("-");
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RebuildOpts {
+    pub action: RebuildAction,
+    pub file: PathBuf,
+    pub attribute: String,
+    // Run through sudo (or doas), for actions that activate the system
+    pub elevate: bool,
+    // Where to link the built system, `./result` by default when only building
+    pub out_link: Option<PathBuf>,
+    pub tuning: BuildTuning,
+}
 
-    debug!("Got store path name {}", store_name);
+// Everything we ask of Nix. `NixCli` shells out to the real tools, while tests use the
+// scriptable `nix_mock::MockNix` so resolution and the commands can run without Nix.
+pub trait NixBackend {
+    // Where store paths live, which every path handed back is expected to be in
+    fn store_dir(&self) -> PathBuf;
 
-    store_name
+    fn evaluate(&self, expr: &Expr, opts: EvalOpts) -> impl Future<Output = Result<EvalResult>>;
+
+    fn realise(&self, path: &Path) -> impl Future<Output = Result<Vec<PathBuf>>>;
+
+    fn add_to_store(
+        &self,
+        path: &Path,
+        filter: Filter,
+    ) -> impl Future<Output = Result<FixedOutputStoreEntry>>;
+
+    // The NAR hash of the store entry containing `path`
+    fn store_hash(&self, path: &StorePath) -> impl Future<Output = Result<String>>;
+
+    fn build(
+        &self,
+        file: &Path,
+        name: &str,
+        opts: BuildOpts,
+    ) -> impl Future<Output = Result<BuildResult>>;
+
+    fn rebuild(&self, opts: RebuildOpts) -> impl Future<Output = Result<()>>;
+
+    // The references of every path in the closure of `path`, which has to be valid
+    fn closure(&self, path: &Path) -> impl Future<Output = Result<References>>;
 }
 
-pub async fn evaluate(code: &str, opts: EvalOpts) -> Result<EvalResult> {
-    if <nixos_cli_def::Cli as clap::Parser>::parse().show_eval_commands {
-        info!("{code}");
+impl<B: NixBackend> NixBackend for &B {
+    fn store_dir(&self) -> PathBuf {
+        (**self).store_dir()
+    }
+
+    fn evaluate(&self, expr: &Expr, opts: EvalOpts) -> impl Future<Output = Result<EvalResult>> {
+        (**self).evaluate(expr, opts)
+    }
+
+    fn realise(&self, path: &Path) -> impl Future<Output = Result<Vec<PathBuf>>> {
+        (**self).realise(path)
+    }
+
+    fn add_to_store(
+        &self,
+        path: &Path,
+        filter: Filter,
+    ) -> impl Future<Output = Result<FixedOutputStoreEntry>> {
+        (**self).add_to_store(path, filter)
+    }
+
+    fn store_hash(&self, path: &StorePath) -> impl Future<Output = Result<String>> {
+        (**self).store_hash(path)
+    }
+
+    fn build(
+        &self,
+        file: &Path,
+        name: &str,
+        opts: BuildOpts,
+    ) -> impl Future<Output = Result<BuildResult>> {
+        (**self).build(file, name, opts)
+    }
+
+    fn rebuild(&self, opts: RebuildOpts) -> impl Future<Output = Result<()>> {
+        (**self).rebuild(opts)
+    }
+
+    fn closure(&self, path: &Path) -> impl Future<Output = Result<References>> {
+        (**self).closure(path)
+    }
+}
+
+// Settings and arguments added to every Nix command, from the `[nix]` section of the
+// config file and then `--nix-option` and `-- <args>` on the command line, so that the
+// command line wins.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NixSettings {
+    pub options: Vec<(String, String)>,
+    pub extra_args: Vec<String>,
+}
+
+impl NixSettings {
+    pub fn new(config: &Config, cli: &nixos_cli_def::Cli) -> Self {
+        let options = config
+            .nix
+            .options
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .chain(cli.nix_options.iter().cloned())
+            .collect();
+        let extra_args = config
+            .nix
+            .extra_args
+            .iter()
+            .chain(&cli.nix_args)
+            .cloned()
+            .collect();
+        NixSettings {
+            options,
+            extra_args,
+        }
+    }
+
+    // The settings alone. Every Nix command takes these, where the extra arguments are
+    // meant for evaluations and builds, and may well be flags other commands reject.
+    pub fn option_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        for (name, value) in &self.options {
+            args.extend(["--option".to_string(), name.clone(), value.clone()]);
+        }
+        args
+    }
+
+    pub fn args(&self) -> Vec<String> {
+        let mut args = self.option_args();
+        args.extend(self.extra_args.iter().cloned());
+        args
+    }
+}
+
+// `nix-store`, for the queries and additions that go around the daemon. These only take
+// the settings, not the extra arguments.
+pub fn nix_store_command(settings: &NixSettings) -> Command {
+    let mut command = Command::new("nix-store");
+    command.args(settings.option_args());
+    command
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct NixCli {
+    pub settings: NixSettings,
+    // Log the expressions we evaluate
+    pub show_eval_commands: bool,
+    pub timeouts: Timeouts,
+    // Stops whatever is running when cancelled
+    pub cancel: CancellationToken,
+    pub version: DetectedVersion,
+}
+
+impl NixCli {
+    pub fn new(settings: NixSettings) -> Self {
+        NixCli {
+            settings,
+            ..Default::default()
+        }
+    }
+
+    fn supervisor(&self, stage: Stage) -> Supervisor {
+        let timeout = match stage {
+            Stage::Evaluation => self.timeouts.eval(),
+            Stage::Build => self.timeouts.build(),
+            Stage::ProfileSwitch => None,
+            Stage::Activation => self.timeouts.activation(),
+        };
+        Supervisor::new(stage, &self.cancel, timeout)
+    }
+}
+
+impl NixBackend for NixCli {
+    fn store_dir(&self) -> PathBuf {
+        store_dir()
     }
 
+    async fn evaluate(&self, expr: &Expr, opts: EvalOpts) -> Result<EvalResult> {
+        evaluate(self, expr, opts).await
+    }
+
+    async fn realise(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        realise(self, path).await
+    }
+
+    async fn add_to_store(&self, path: &Path, filter: Filter) -> Result<FixedOutputStoreEntry> {
+        add_to_store(&self.settings, path, filter).await
+    }
+
+    async fn store_hash(&self, path: &StorePath) -> Result<String> {
+        get_store_hash(&self.settings, path).await
+    }
+
+    async fn build(&self, file: &Path, name: &str, opts: BuildOpts) -> Result<BuildResult> {
+        build(self, file, name, opts).await
+    }
+
+    async fn closure(&self, path: &Path) -> Result<References> {
+        query_closure(&self.settings, path).await
+    }
+
+    // Build the system, then point the system profile at it and activate it, as
+    // `nixos-rebuild` would. Doing the steps ourselves lets us link the result where
+    // asked, and keeps an interruption from landing in the middle of the profile switch,
+    // while the build and activation can still be stopped.
+    async fn rebuild(&self, opts: RebuildOpts) -> Result<()> {
+        let action = opts.action;
+        let out_link = match (&opts.out_link, action) {
+            (Some(link), _) => Some(link.clone()),
+            (None, RebuildAction::Build) => Some(PathBuf::from("result")),
+            (None, _) => None,
+        };
+        let toplevel = format!("{}.config.system.build.toplevel", opts.attribute);
+        let built = build(
+            self,
+            &opts.file,
+            &toplevel,
+            BuildOpts {
+                out_link,
+                system: None,
+                tuning: opts.tuning.clone(),
+            },
+        )
+        .await?
+        .ensure_success()?;
+        for output in &built.outputs {
+            info!("Built {}", output.display());
+        }
+        if action == RebuildAction::Build {
+            return Ok(());
+        }
+        let [system] = built.outputs.as_slice() else {
+            bail!("Expected one system to be built, got {:?}", built.outputs);
+        };
+
+        let elevation = if opts.elevate {
+            let elevation = Elevation::find()?;
+            elevation.authenticate().await?;
+            elevation
+        } else {
+            Elevation::None
+        };
+
+        if action == RebuildAction::Switch {
+            let mut command = elevation.command("nix-env");
+            command
+                .args(["--profile", SYSTEM_PROFILE, "--set"])
+                .arg(system);
+            debug!("Running {command:?}");
+            let status = self
+                .supervisor(Stage::ProfileSwitch)
+                .status(&mut command)
+                .await?;
+            if !status.success() {
+                bail!("Switching the system profile failed ({status})");
+            }
+        }
+
+        let mut command = elevation.command(system.join("bin/switch-to-configuration"));
+        command.arg(action.as_str());
+        debug!("Running {command:?}");
+        let status = self
+            .supervisor(Stage::Activation)
+            .status(&mut command)
+            .await?;
+        if !status.success() {
+            bail!(
+                "switch-to-configuration {} failed ({status})",
+                action.as_str()
+            );
+        }
+        Ok(())
+    }
+}
+
+// The program and arguments that evaluate `code` with the Nix in use. Without the `nix`
+// command, `nix-instantiate` does the same, but has to be told to be pure.
+pub fn eval_command(
+    nix: &NixVersion,
+    settings: &NixSettings,
+    code: &str,
+    opts: &EvalOpts,
+) -> (&'static str, Vec<String>) {
     let mut args: Vec<&str> = vec![];
-    args.append(&mut vec!["eval", "--show-trace"]);
+    let program = if nix.has_nix_command() {
+        args.extend(nix.nix_command_args());
+        args.extend(["eval", "--show-trace"]);
+        if opts.json {
+            args.push("--json");
+        }
+        if opts.impure {
+            args.push("--impure");
+        }
+        "nix"
+    } else {
+        args.extend(["--eval", "--show-trace"]);
+        if opts.json {
+            args.extend(["--json", "--strict"]);
+        }
+        if !opts.impure {
+            args.extend(["--option", "pure-eval", "true"]);
+        }
+        "nix-instantiate"
+    };
 
-    if opts.json {
-        args.push("--json");
+    let mut args: Vec<String> = args.into_iter().map(String::from).collect();
+    args.extend(settings.args());
+    args.extend(["--expr".to_string(), code.to_string()]);
+    (program, args)
+}
+
+// The program and arguments for an interactive `nix repl` with the attributes of `file`
+// in scope. Since Nix 2.19 the repl takes installables, which makes a bare path a flake
+// reference, so the file has to be passed with `--file`. Lix kept the older behaviour.
+pub fn repl_command(
+    nix: &NixVersion,
+    settings: &NixSettings,
+    file: &Path,
+) -> (&'static str, Vec<String>) {
+    let mut args: Vec<String> = nix
+        .nix_command_args()
+        .into_iter()
+        .map(String::from)
+        .collect();
+    args.push("repl".to_string());
+    args.extend(settings.args());
+    if nix.nix_version() >= (2, 19, 0) {
+        args.push("--file".to_string());
     }
-    if opts.impure {
-        args.push("--impure");
+    args.push(file.to_string_lossy().into_owned());
+    ("nix", args)
+}
+
+async fn evaluate(nix: &NixCli, expr: &Expr, opts: EvalOpts) -> Result<EvalResult> {
+    let code = expr.to_string();
+    if nix.show_eval_commands {
+        info!("{code}");
     }
 
-    args.append(&mut vec!["--expr", &code]);
+    let (program, args) = eval_command(
+        nix.version.get(&nix.settings).await?,
+        &nix.settings,
+        &code,
+        &opts,
+    );
 
-    debug!("Running nix eval:\nnix {}", args.join(" "));
-    let output = Command::new("nix").args(args).output().await?;
+    debug!("Running nix eval:\n{program} {}", args.join(" "));
+    let output = nix
+        .supervisor(Stage::Evaluation)
+        .output(Command::new(program).args(args).envs(opts.env))
+        .await?;
 
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
@@ -103,26 +445,28 @@ pub async fn evaluate(code: &str, opts: EvalOpts) -> Result<EvalResult> {
     }
 }
 
-pub async fn get_system() -> Result<String> {
+pub async fn get_system<B: NixBackend>(nix: &B) -> Result<String> {
     trace!("Getting system platform");
-    match evaluate(
-        "builtins.currentSystem",
-        EvalOpts {
-            json: true,
-            impure: true,
-        },
-    )
-    .await?
+    match nix
+        .evaluate(
+            &Expr::builtin("currentSystem"),
+            EvalOpts {
+                json: true,
+                impure: true,
+                ..Default::default()
+            },
+        )
+        .await?
     {
         EvalResult::Json(value) => match &value {
             serde_json::Value::String(s) => {
                 debug!("Got system {s}");
-                return Ok(value.as_str().unwrap().to_string());
+                Ok(s.clone())
             }
             _ => bail!("Got: '{value:?}', Expected String"),
         },
         EvalResult::Raw(v) => bail!("Somehow returned raw with value: '{v}'"),
-    };
+    }
 }
 
 pub async fn get_path_hash<P>(path: P) -> Result<String>
@@ -133,23 +477,11 @@ where
     trace!("Getting hash for {path:?}");
 
     let dir = remove_filename_from_path(path.clone());
+    let hash = tokio::task::spawn_blocking(move || nar::hash(&dir, Filter::All)).await??;
 
-    let output = Command::new("nix")
-        .args(["hash", "path", dir.to_str().unwrap(), "--type", "sha256"])
-        .output()
-        .await?;
-
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        bail!("nix-hash failed:\n{stderr}");
-    }
+    debug!("Got hash {} for path {path:?}", hash.hash);
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let hash = stdout.trim().to_string();
-
-    debug!("Got hash {hash:?} for path {path:?}");
-
-    Ok(hash)
+    Ok(hash.hash.to_sri())
 }
 
 pub async fn get_file_hash<P>(path: P) -> Result<String>
@@ -159,8 +491,50 @@ where
     let path: PathBuf = path.into();
     trace!("Getting hash for {path:?}");
 
-    let output = Command::new("nix")
-        .args(["hash", "file", path.to_str().unwrap(), "--type", "sha256"])
+    let file = path.clone();
+    let hash = tokio::task::spawn_blocking(move || Sha256::of_file(&file)).await??;
+
+    debug!("Got hash {hash} for path {path:?}");
+
+    Ok(hash.to_sri())
+}
+
+// A connection to the Nix daemon, when there is one. Without it (single-user installs, or
+// a daemon we cannot talk to) store queries go through the CLI instead.
+async fn connect_daemon() -> Option<DaemonClient> {
+    let socket = daemon::socket_path();
+    if !socket.exists() {
+        return None;
+    }
+    match DaemonClient::connect(&socket, &store_dir()).await {
+        Ok(client) => Some(client),
+        Err(e) => {
+            debug!("{e:#}, falling back to the Nix CLI");
+            None
+        }
+    }
+}
+
+async fn get_store_hash(settings: &NixSettings, path: &StorePath) -> Result<String> {
+    let root = path.root();
+    trace!("Getting hash for {root}");
+
+    if let Some(mut client) = connect_daemon().await {
+        match client.query_path_info(&root).await {
+            Ok(Some(info)) => {
+                let hash = info.nar_hash.to_nix32();
+                debug!("Got hash {hash:?} for path {root} from the daemon");
+                return Ok(hash);
+            }
+            Ok(None) => bail!("{root} is not a valid store path"),
+            Err(e) => debug!("{e:#}, falling back to nix-store"),
+        }
+    }
+
+    let output = nix_store_command(settings)
+        .arg("--query")
+        .arg(root.as_path())
+        .arg("--hash")
         .output()
         .await?;
 
@@ -170,55 +544,142 @@ where
     }
 
     let stdout = String::from_utf8_lossy(&output.stdout);
-    let hash = stdout.trim().to_string();
 
-    debug!("Got hash {hash:?} for path {path:?}");
+    let hash = stdout.trim().split(":").last().unwrap().to_string();
+
+    debug!("Got hash {hash:?} for path {root}");
 
     Ok(hash)
 }
 
-pub async fn get_store_hash<P>(path: P) -> Result<String>
-where
-    P: Into<PathBuf>,
-{
-    let path: PathBuf = path.into();
-    trace!("Getting hash for {path:?}");
+// Walk the closure of `root` through the daemon, one path at a time. The inner error is
+// a path of the closure the daemon does not know.
+async fn daemon_closure(
+    client: &mut DaemonClient,
+    root: &StorePath,
+) -> Result<Result<References, StorePath>> {
+    let mut references = References::new();
+    let mut queue = vec![root.root()];
+    while let Some(path) = queue.pop() {
+        if references.contains_key(path.as_path()) {
+            continue;
+        }
+        let Some(info) = client.query_path_info(&path).await? else {
+            return Ok(Err(path));
+        };
+        let refs: Vec<PathBuf> = info
+            .references
+            .iter()
+            .filter(|reference| **reference != path)
+            .map(|reference| reference.as_path().to_path_buf())
+            .collect();
+        queue.extend(info.references.into_iter().filter(|r| *r != path));
+        references.insert(path.as_path().to_path_buf(), refs);
+    }
+    Ok(Ok(references))
+}
 
-    let dir = remove_filename_from_path(path.clone());
+// The closure of `path`, from the daemon, or from `nix-store` printing the whole graph at
+// once when there is no daemon to ask or it fails.
+async fn query_closure(settings: &NixSettings, path: &Path) -> Result<References> {
+    let root = StorePath::parse(path)?;
+    trace!("Querying the closure of {root}");
+
+    if let Some(mut client) = connect_daemon().await {
+        match daemon_closure(&mut client, &root).await {
+            Ok(Ok(references)) => {
+                debug!("Got {} paths in the closure of {root}", references.len());
+                return Ok(references);
+            }
+            Ok(Err(invalid)) => bail!("{invalid} is not a valid store path"),
+            Err(e) => debug!("{e:#}, falling back to nix-store"),
+        }
+    }
 
-    let output = Command::new("nix-store")
-        .args(["--query", dir.to_str().unwrap(), "--hash"])
+    let output = nix_store_command(settings)
+        .args(["--query", "--graph"])
+        .arg(root.root().as_path())
         .output()
         .await?;
 
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
-        bail!("nix-hash failed:\n{stderr}");
+        bail!("nix-store query failed:\n{stderr}");
     }
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-
-    let hash = stdout.trim().split(":").last().unwrap().to_string();
-
-    debug!("Got hash {hash:?} for path {path:?}");
-
-    Ok(hash)
+    let references = closure::parse_graph(&String::from_utf8_lossy(&output.stdout), &store_dir());
+    debug!("Got {} paths in the closure of {root}", references.len());
+    Ok(references)
 }
 
-pub async fn add_to_store<P>(path: P) -> Result<FixedOutputStoreEntry>
+// Add a directory to the store as a fixed-output path. The hash and the resulting store
+// path are worked out here first, so a path that is already in the store is neither
+// copied nor hashed again by Nix. The daemon is handed the filtered NAR directly, while
+// `nix-store` needs a copy of the filtered tree.
+async fn add_to_store<P>(
+    settings: &NixSettings,
+    path: P,
+    filter: Filter,
+) -> Result<FixedOutputStoreEntry>
 where
     P: Into<PathBuf>,
 {
     let path: PathBuf = path.into();
     trace!("Adding {path:?} to store");
 
-    let output = Command::new("nix-store")
-        .args([
-            "--recursive",
-            "--add-fixed",
-            "sha256",
-            path.to_str().unwrap(),
-        ])
+    let Some(name) = path.file_name().map(|n| n.to_string_lossy().into_owned()) else {
+        bail!("Cannot add {path:?} to the store, it has no name");
+    };
+
+    let source = path.clone();
+    let hash = tokio::task::spawn_blocking(move || nar::hash(&source, filter)).await??;
+    let store_path = StorePath::for_source(&store_dir(), &name, &hash.hash)?;
+    let entry = FixedOutputStoreEntry {
+        path: store_path.clone(),
+        hash: hash.hash.to_nix32(),
+    };
+
+    let mut client = connect_daemon().await;
+    let already_added = match client.as_mut() {
+        Some(daemon) => match daemon.is_valid_path(&store_path).await {
+            Ok(valid) => valid,
+            Err(e) => {
+                debug!("{e:#}, falling back to nix-store");
+                client = None;
+                store_path.as_path().exists()
+            }
+        },
+        None => store_path.as_path().exists(),
+    };
+    if already_added {
+        debug!("{path:?} is already in the store as {store_path}");
+        return Ok(entry);
+    }
+
+    if let Some(client) = client.as_mut() {
+        let (added, _) = client.add_to_store(&name, &path, filter).await?;
+        if added != store_path {
+            bail!("The Nix daemon added {path:?} as {added}, but it should have been {store_path}");
+        }
+        debug!("Added {path:?} to the store as {store_path}");
+        return Ok(entry);
+    }
+
+    // When the filter left something out, hand Nix a copy holding exactly what was hashed
+    let staging = tempfile::Builder::new().prefix("nilla-add-").tempdir()?;
+    let added = if hash.skipped > 0 {
+        let staged = staging.path().join(&name);
+        let source = path.clone();
+        let target = staged.clone();
+        tokio::task::spawn_blocking(move || nar::copy(&source, &target, filter)).await??;
+        staged
+    } else {
+        path.clone()
+    };
+
+    let output = nix_store_command(settings)
+        .args(["--recursive", "--add-fixed", "sha256"])
+        .arg(&added)
         .output()
         .await?;
 
@@ -228,25 +689,30 @@ where
     }
 
     let stdout = String::from_utf8_lossy(&output.stdout);
+    if Path::new(stdout.trim()) != store_path.as_path() {
+        bail!(
+            "nix-store added {path:?} as {}, but it should have been {store_path}",
+            stdout.trim()
+        );
+    }
 
-    let store_path = PathBuf::from(stdout.trim());
-    let hash = get_store_hash(&store_path).await?;
-
-    Ok(FixedOutputStoreEntry {
-        path: store_path,
-        hash,
-    })
+    debug!("Added {path:?} to the store as {store_path}");
+    Ok(entry)
 }
 
-pub async fn realise<P>(path: P) -> Result<Vec<PathBuf>>
+async fn realise<P>(nix: &NixCli, path: P) -> Result<Vec<PathBuf>>
 where
     P: Into<PathBuf> + std::fmt::Debug,
 {
     let path: PathBuf = path.into();
     trace!("Realising {path:?}");
-    let output = Command::new("nix-store")
-        .args(["--realise", path.to_str().unwrap()])
-        .output()
+    let output = nix
+        .supervisor(Stage::Build)
+        .output(
+            Command::new("nix-store")
+                .args(["--realise", path.to_str().unwrap()])
+                .args(nix.settings.args()),
+        )
         .await?;
 
     if !output.status.success() {
@@ -256,48 +722,221 @@ where
 
     let stdout = String::from_utf8_lossy(&output.stdout);
 
-    Ok(stdout
-        .lines()
-        .map(|i| PathBuf::from(i))
-        .collect::<Vec<PathBuf>>())
+    Ok(stdout.lines().map(PathBuf::from).collect::<Vec<PathBuf>>())
 }
 
-pub struct BuildOpts<'a> {
-    pub link: bool,
-    pub report: bool,
-    pub system: Option<&'a str>,
+// Settings that change how a build runs rather than what it builds.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BuildTuning {
+    pub keep_going: bool,
+    // A number, or `auto` for one per core
+    pub max_jobs: Option<String>,
+    pub cores: Option<u32>,
+    // Build again even if the outputs are valid, checking they come out the same
+    pub rebuild: bool,
+    pub options: Vec<(String, String)>,
 }
 
-pub async fn build<P>(file: P, name: &str, opts: BuildOpts<'_>) -> Result<Vec<String>>
-where
-    P: AsRef<Path>,
-{
-    let mut args = vec!["build"];
-    if !opts.link {
-        args.push("--no-link");
+impl BuildTuning {
+    // The flags `nix build`, `nix-build` and `nixos-rebuild` share. Checking outputs is
+    // spelt differently by each, so it is left to the caller.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if self.keep_going {
+            args.push("--keep-going".to_string());
+        }
+        if let Some(jobs) = &self.max_jobs {
+            args.extend(["--max-jobs".to_string(), jobs.clone()]);
+        }
+        if let Some(cores) = self.cores {
+            args.extend(["--cores".to_string(), cores.to_string()]);
+        }
+        for (name, value) in &self.options {
+            args.extend(["--option".to_string(), name.clone(), value.clone()]);
+        }
+        args
     }
-    if opts.report {
-        args.push("--print-out-paths");
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BuildOpts {
+    // Where to link the result, if anywhere
+    pub out_link: Option<PathBuf>,
+    pub system: Option<String>,
+    pub tuning: BuildTuning,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildResult {
+    // `None` when the build was killed by a signal
+    pub exit_code: Option<i32>,
+    pub outputs: Vec<PathBuf>,
+    // The derivations whose builders failed
+    pub failed: Vec<PathBuf>,
+}
+
+impl BuildResult {
+    pub fn success(&self) -> bool {
+        self.exit_code == Some(0)
     }
-    args.push("-f");
-    args.push(file.as_ref().to_str().unwrap());
-    if let Some(system) = opts.system {
-        args.push("--system");
-        args.push(system);
+
+    // Turn a failed build into an error naming what failed.
+    pub fn ensure_success(self) -> Result<Self> {
+        if self.success() {
+            return Ok(self);
+        }
+        let status = match self.exit_code {
+            Some(code) => format!("exit code {code}"),
+            None => "killed by a signal".to_string(),
+        };
+        let mut message = format!("nix build failed ({status})");
+        if !self.failed.is_empty() {
+            message.push_str("\nFailed to build:");
+            for drv in &self.failed {
+                message.push_str(&format!("\n  {}", drv.display()));
+            }
+        }
+        bail!(message)
+    }
+}
+
+// The program and arguments that build attribute `name` of `file`. `nix build` reports
+// its outputs as JSON, while `nix-build` prints them one per line and sets the system
+// like any other setting.
+pub fn build_command(
+    nix: &NixVersion,
+    settings: &NixSettings,
+    file: &Path,
+    name: &str,
+    opts: &BuildOpts,
+) -> (&'static str, Vec<String>) {
+    let file = file.to_string_lossy().into_owned();
+    let mut args: Vec<String> = vec![];
+    let link = opts
+        .out_link
+        .as_ref()
+        .map(|link| link.to_string_lossy().into_owned());
+
+    let program = if nix.has_nix_command() {
+        args.extend(nix.nix_command_args().into_iter().map(String::from));
+        args.extend(["build".to_string(), "--json".to_string()]);
+        match link {
+            Some(link) => args.extend(["--out-link".to_string(), link]),
+            None => args.push("--no-link".to_string()),
+        }
+        if opts.tuning.rebuild {
+            args.push("--rebuild".to_string());
+        }
+        args.extend(opts.tuning.args());
+        args.extend(settings.args());
+        args.extend(["-f".to_string(), file]);
+        if let Some(system) = &opts.system {
+            args.extend(["--system".to_string(), system.clone()]);
+        }
+        args.push(name.to_string());
+        "nix"
+    } else {
+        args.extend([file, "-A".to_string(), name.to_string()]);
+        match link {
+            Some(link) => args.extend(["--out-link".to_string(), link]),
+            None => args.push("--no-out-link".to_string()),
+        }
+        if opts.tuning.rebuild {
+            args.push("--check".to_string());
+        }
+        args.extend(opts.tuning.args());
+        if let Some(system) = &opts.system {
+            args.extend(["--option".to_string(), "system".to_string(), system.clone()]);
+        }
+        args.extend(settings.args());
+        "nix-build"
     };
-    args.push(&name);
-    debug!("Running nix build:\nnix {}", args.join(" "));
-    let cmd = Command::new("nix")
-        .stdout(Stdio::piped())
-        .args(args)
-        .spawn()?;
-
-    return Ok(
-        String::from_utf8_lossy(&cmd.wait_with_output().await.unwrap().stdout)
+
+    (program, args)
+}
+
+// The output paths a build printed, either `nix build --json` or one path per line.
+pub fn parse_build_outputs(stdout: &str) -> Result<Vec<PathBuf>> {
+    if !stdout.trim_start().starts_with('[') {
+        return Ok(stdout
             .lines()
-            .map(|s| s.to_owned())
-            .collect(),
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| PathBuf::from(line.trim()))
+            .collect());
+    }
+
+    let built: Vec<Value> = serde_json::from_str(stdout)?;
+    let mut outputs = vec![];
+    for drv in built {
+        let Some(Value::Object(paths)) = drv.get("outputs") else {
+            bail!("Unexpected output from nix build: {drv}");
+        };
+        for path in paths.values() {
+            match path {
+                Value::String(path) => outputs.push(PathBuf::from(path)),
+                _ => bail!("Unexpected output path from nix build: {path}"),
+            }
+        }
+    }
+    Ok(outputs)
+}
+
+// Run a build, passing its log through to the user while keeping it to find out what
+// failed.
+async fn build<P>(nix: &NixCli, file: P, name: &str, opts: BuildOpts) -> Result<BuildResult>
+where
+    P: AsRef<Path>,
+{
+    let (program, args) = build_command(
+        nix.version.get(&nix.settings).await?,
+        &nix.settings,
+        file.as_ref(),
+        name,
+        &opts,
     );
+    debug!("Running nix build:\n{program} {}", args.join(" "));
+    let mut child = process::spawn(
+        Command::new(program)
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped()),
+    )
+    .with_context(|| format!("Could not run {program}"))?;
+    let pgid = child.id();
+
+    let mut stdout = child.stdout.take().unwrap();
+    let mut stderr = BufReader::new(child.stderr.take().unwrap()).lines();
+    let read_stdout = async {
+        let mut out = String::new();
+        stdout.read_to_string(&mut out).await.map(|_| out)
+    };
+    let read_stderr = async {
+        let mut log = String::new();
+        while let Some(line) = stderr.next_line().await? {
+            eprintln!("{line}");
+            log.push_str(&line);
+            log.push('\n');
+        }
+        Ok::<_, std::io::Error>(log)
+    };
+    let (stdout, stderr, status) = nix
+        .supervisor(Stage::Build)
+        .wait(pgid, async {
+            tokio::try_join!(read_stdout, read_stderr, child.wait())
+        })
+        .await?;
+
+    let result = BuildResult {
+        exit_code: status.code(),
+        outputs: if status.success() {
+            parse_build_outputs(&stdout)?
+        } else {
+            vec![]
+        },
+        failed: errors::failed_derivations(&stderr),
+    };
+    debug!("Build of {name} finished: {result:?}");
+    Ok(result)
 }
 
 pub struct ShellOpts<'a> {
@@ -309,7 +948,7 @@ where
     P: AsRef<Path>,
 {
     let mut args = vec![file.as_ref().to_str().unwrap()];
-    if opts.system != "" {
+    if !opts.system.is_empty() {
         args.push("--system");
         args.push(opts.system);
     }
@@ -325,45 +964,63 @@ where
     std::process::exit(0);
 }
 
+// Import a project that has already been added to the store, pinning it by hash so that
+// the evaluation can stay pure. The whole store entry is pinned, since that is what the
+// hash covers, and the entry file is then looked up inside it.
+//
+// `name` has to be given: older versions of Lix/Nix supported calling `builtins.path`
+// without it, but newer versions may require it (although it is unclear if this is a
+// bug). See: https://git.lix.systems/lix-project/lix/issues/776
+pub fn project_expr(file: &str, entry: &FixedOutputStoreEntry) -> Expr {
+    let root = entry.path.root();
+    let source = Expr::builtin("path").apply(Expr::attrs([
+        ("path", Expr::str(root.to_string())),
+        ("sha256", Expr::str(&entry.hash)),
+        ("name", Expr::str(root.name())),
+    ]));
+
+    let file = match entry.path.subpath() {
+        Some(subpath) => format!("/{}/{file}", subpath.display()),
+        None => format!("/{file}"),
+    };
+    Expr::var("import").apply(source.concat(Expr::str(file)))
+}
+
 pub struct GetMainProgramOpts<'a> {
     pub system: &'a str,
 }
 
-pub async fn get_main_program(
+pub async fn get_main_program<B: NixBackend>(
+    nix: &B,
     file: &str,
     entry: FixedOutputStoreEntry,
     name: &str,
     opts: GetMainProgramOpts<'_>,
 ) -> Result<String> {
-    let file_str = entry.path.to_str().unwrap();
-
-    let hash = entry.hash;
-
-    let store_path_name = get_store_path_name(&entry.path);
-
-    let main = evaluate(
-        &format!(
-            "
-			let
-        source = builtins.path {{ path = \"{file_str}\"; sha256 = \"{hash}\"; name = \"{store_path_name}\"; }};
-        project = import \"${{source}}/{file}\";
-				system = \"{}\";
-				name = \"{name}\";
-			in
-				project.packages.${{name}}.result.${{system}}.meta.mainProgram or name
-			",
-            if opts.system == "" {
-                get_system().await?
-            } else {
-                opts.system.to_string()
-            }
+    let system = if opts.system.is_empty() {
+        get_system(nix).await?
+    } else {
+        opts.system.to_string()
+    };
+
+    let code = Expr::let_in(
+        [("project", project_expr(file, &entry))],
+        Expr::var("project").select_or(
+            AttrPath::from(["packages", name, "result", &system, "meta", "mainProgram"]),
+            Expr::str(name),
         ),
-        EvalOpts {
-            json: true,
-            impure: false,
-        },
-    )
-    .await?;
+    );
+
+    let main = nix
+        .evaluate(
+            &code,
+            EvalOpts {
+                json: true,
+                impure: false,
+                ..Default::default()
+            },
+        )
+        .await?;
 
     match main {
         EvalResult::Json(Value::String(s)) => Ok(s),
@@ -371,51 +1028,29 @@ pub async fn get_main_program(
     }
 }
 
-pub async fn exists_in_project(
+pub async fn exists_in_project<B: NixBackend>(
+    nix: &B,
     file: &str,
     entry: FixedOutputStoreEntry,
     name: &str,
 ) -> Result<bool> {
     info!("Checking that target exists");
-    let file_str = entry.path.to_str().unwrap();
-
-    let hash = entry.hash;
-
-    let store_path_name = get_store_path_name(&entry.path);
-
-    let code = if name.contains('.') {
-        let parts = name.split('.').collect::<Vec<&str>>();
-        let last = parts.last().ok_or(anyhow!("How did we get here"))?;
-        let init = &parts[0..parts.len() - 1].join(".");
-        format!(
-            "
-            let
-              source = builtins.path {{ path = \"{file_str}\"; sha256 = \"{hash}\"; name = \"{store_path_name}\"; }};
-              project = import \"${{source}}/{file}\";
-            in
-              (project.{init} or {{}}) ? {last}
-            "
-        )
-    } else {
-        format!(
-            "
-		let
-      source = builtins.path {{ path = \"{file_str}\"; sha256 = \"{hash}\"; name = \"{store_path_name}\"; }};
-      project = import \"${{source}}/{file}\";
-		in
-			project ? {name}
-		"
-        )
-    };
 
-    let result = evaluate(
-        &code,
-        EvalOpts {
-            json: true,
-            impure: false,
-        },
-    )
-    .await?;
+    let code = Expr::let_in(
+        [("project", project_expr(file, &entry))],
+        Expr::var("project").has_attr(AttrPath::from_dotted(name)),
+    );
+
+    let result = nix
+        .evaluate(
+            &code,
+            EvalOpts {
+                json: true,
+                impure: false,
+                ..Default::default()
+            },
+        )
+        .await?;
 
     match result {
         EvalResult::Json(Value::Bool(b)) => Ok(b),
@@ -0,0 +1,475 @@
+use std::fmt::{self, Display, Write};
+
+use anyhow::{Result, bail};
+
+// A tiny Nix AST for the expressions we generate. Everything that comes from outside
+// the program (paths, URLs, hashes, attribute names) goes in as data and is escaped
+// when rendering, so it can never change the shape of the expression.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Str(String),
+    Var(String),
+    List(Vec<Expr>),
+    Attrs(Vec<(String, Expr)>),
+    Select {
+        expr: Box<Expr>,
+        path: AttrPath,
+        default: Option<Box<Expr>>,
+    },
+    HasAttr {
+        expr: Box<Expr>,
+        path: AttrPath,
+    },
+    Apply {
+        func: Box<Expr>,
+        arg: Box<Expr>,
+    },
+    Add(Box<Expr>, Box<Expr>),
+    Update(Box<Expr>, Box<Expr>),
+    Let {
+        bindings: Vec<(String, Expr)>,
+        body: Box<Expr>,
+    },
+    Lambda {
+        arg: String,
+        body: Box<Expr>,
+    },
+    If {
+        cond: Box<Expr>,
+        then: Box<Expr>,
+        otherwise: Box<Expr>,
+    },
+}
+
+impl Expr {
+    pub fn str<S: Into<String>>(s: S) -> Self {
+        Expr::Str(s.into())
+    }
+
+    // Variables are always written by us, so an invalid name is a programming error.
+    pub fn var<S: Into<String>>(name: S) -> Self {
+        let name = name.into();
+        assert!(is_identifier(&name), "invalid Nix identifier {name:?}");
+        Expr::Var(name)
+    }
+
+    pub fn builtin(name: &str) -> Self {
+        Expr::var("builtins").select(AttrPath::from([name]))
+    }
+
+    pub fn attrs<I, K>(entries: I) -> Self
+    where
+        I: IntoIterator<Item = (K, Expr)>,
+        K: Into<String>,
+    {
+        Expr::Attrs(entries.into_iter().map(|(k, v)| (k.into(), v)).collect())
+    }
+
+    pub fn let_in<I, K>(bindings: I, body: Expr) -> Self
+    where
+        I: IntoIterator<Item = (K, Expr)>,
+        K: Into<String>,
+    {
+        let bindings: Vec<(String, Expr)> =
+            bindings.into_iter().map(|(k, v)| (k.into(), v)).collect();
+        for (name, _) in &bindings {
+            assert!(is_identifier(name), "invalid Nix identifier {name:?}");
+        }
+        Expr::Let {
+            bindings,
+            body: Box::new(body),
+        }
+    }
+
+    pub fn select<P: Into<AttrPath>>(self, path: P) -> Self {
+        Expr::Select {
+            expr: Box::new(self),
+            path: path.into(),
+            default: None,
+        }
+    }
+
+    pub fn select_or<P: Into<AttrPath>>(self, path: P, default: Expr) -> Self {
+        Expr::Select {
+            expr: Box::new(self),
+            path: path.into(),
+            default: Some(Box::new(default)),
+        }
+    }
+
+    pub fn has_attr<P: Into<AttrPath>>(self, path: P) -> Self {
+        Expr::HasAttr {
+            expr: Box::new(self),
+            path: path.into(),
+        }
+    }
+
+    // A function of one argument, eg. `option: option.name`.
+    pub fn lambda<S: Into<String>>(arg: S, body: Expr) -> Self {
+        let arg = arg.into();
+        assert!(is_identifier(&arg), "invalid Nix identifier {arg:?}");
+        Expr::Lambda {
+            arg,
+            body: Box::new(body),
+        }
+    }
+
+    pub fn if_then_else(cond: Expr, then: Expr, otherwise: Expr) -> Self {
+        Expr::If {
+            cond: Box::new(cond),
+            then: Box::new(then),
+            otherwise: Box::new(otherwise),
+        }
+    }
+
+    pub fn apply(self, arg: Expr) -> Self {
+        Expr::Apply {
+            func: Box::new(self),
+            arg: Box::new(arg),
+        }
+    }
+
+    pub fn concat(self, other: Expr) -> Self {
+        Expr::Add(Box::new(self), Box::new(other))
+    }
+
+    pub fn update(self, other: Expr) -> Self {
+        Expr::Update(Box::new(self), Box::new(other))
+    }
+
+    // The hashes this expression pins store entries to, as `builtins.path { sha256 = ..; }`
+    pub fn pinned_hashes(&self) -> Vec<&str> {
+        let mut hashes = vec![];
+        self.collect_pins(&mut hashes);
+        hashes
+    }
+
+    fn collect_pins<'a>(&'a self, hashes: &mut Vec<&'a str>) {
+        match self {
+            Expr::Apply { func, arg } => {
+                if **func == Expr::builtin("path")
+                    && let Expr::Attrs(entries) = &**arg
+                {
+                    for (name, value) in entries {
+                        if let ("sha256", Expr::Str(hash)) = (name.as_str(), value) {
+                            hashes.push(hash);
+                        }
+                    }
+                }
+                func.collect_pins(hashes);
+                arg.collect_pins(hashes);
+            }
+            Expr::List(items) => items.iter().for_each(|item| item.collect_pins(hashes)),
+            Expr::Attrs(entries) => entries
+                .iter()
+                .for_each(|(_, value)| value.collect_pins(hashes)),
+            Expr::Select { expr, default, .. } => {
+                expr.collect_pins(hashes);
+                if let Some(default) = default {
+                    default.collect_pins(hashes);
+                }
+            }
+            Expr::HasAttr { expr, .. } => expr.collect_pins(hashes),
+            Expr::Add(a, b) | Expr::Update(a, b) => {
+                a.collect_pins(hashes);
+                b.collect_pins(hashes);
+            }
+            Expr::Let { bindings, body } => {
+                for (_, value) in bindings {
+                    value.collect_pins(hashes);
+                }
+                body.collect_pins(hashes);
+            }
+            Expr::Lambda { body, .. } => body.collect_pins(hashes),
+            Expr::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                cond.collect_pins(hashes);
+                then.collect_pins(hashes);
+                otherwise.collect_pins(hashes);
+            }
+            Expr::Null | Expr::Bool(_) | Expr::Int(_) | Expr::Str(_) | Expr::Var(_) => {}
+        }
+    }
+
+    // Expressions that never need parentheses when used as an operand.
+    fn is_atomic(&self) -> bool {
+        match self {
+            Expr::Null
+            | Expr::Bool(_)
+            | Expr::Str(_)
+            | Expr::Var(_)
+            | Expr::List(_)
+            | Expr::Attrs(_) => true,
+            Expr::Int(i) => *i >= 0,
+            Expr::Select { default, .. } => default.is_none(),
+            _ => false,
+        }
+    }
+
+    fn fmt_operand(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_atomic() {
+            write!(f, "{self}")
+        } else {
+            write!(f, "({self})")
+        }
+    }
+}
+
+impl From<&str> for Expr {
+    fn from(value: &str) -> Self {
+        Expr::str(value)
+    }
+}
+
+impl From<String> for Expr {
+    fn from(value: String) -> Self {
+        Expr::Str(value)
+    }
+}
+
+impl From<bool> for Expr {
+    fn from(value: bool) -> Self {
+        Expr::Bool(value)
+    }
+}
+
+impl<T: Into<Expr>> From<Option<T>> for Expr {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Expr::Null, Into::into)
+    }
+}
+
+const KEYWORDS: &[&str] = &[
+    "assert", "else", "if", "in", "inherit", "let", "or", "rec", "then", "with",
+];
+
+pub fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    let valid_start = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_');
+    valid_start
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '\'' | '-'))
+        && !KEYWORDS.contains(&s)
+}
+
+pub fn escape_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '$' if chars.peek() == Some(&'{') => out.push_str("\\$"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn fmt_attr_name(f: &mut fmt::Formatter<'_>, name: &str) -> fmt::Result {
+    if is_identifier(name) {
+        f.write_str(name)
+    } else {
+        f.write_str(&escape_string(name))
+    }
+}
+
+impl Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Null => f.write_str("null"),
+            Expr::Bool(b) => write!(f, "{b}"),
+            Expr::Int(i) => write!(f, "{i}"),
+            Expr::Str(s) => f.write_str(&escape_string(s)),
+            Expr::Var(name) => f.write_str(name),
+            Expr::List(items) => {
+                f.write_char('[')?;
+                for item in items {
+                    f.write_char(' ')?;
+                    item.fmt_operand(f)?;
+                }
+                f.write_str(" ]")
+            }
+            Expr::Attrs(entries) => {
+                f.write_char('{')?;
+                for (name, value) in entries {
+                    f.write_char(' ')?;
+                    fmt_attr_name(f, name)?;
+                    write!(f, " = {value};")?;
+                }
+                f.write_str(" }")
+            }
+            Expr::Select {
+                expr,
+                path,
+                default,
+            } => {
+                expr.fmt_operand(f)?;
+                write!(f, ".{path}")?;
+                if let Some(default) = default {
+                    f.write_str(" or ")?;
+                    default.fmt_operand(f)?;
+                }
+                Ok(())
+            }
+            Expr::HasAttr { expr, path } => {
+                expr.fmt_operand(f)?;
+                write!(f, " ? {path}")
+            }
+            Expr::Apply { func, arg } => {
+                match func.as_ref() {
+                    Expr::Apply { .. } => write!(f, "{func}")?,
+                    _ => func.fmt_operand(f)?,
+                }
+                f.write_char(' ')?;
+                arg.fmt_operand(f)
+            }
+            Expr::Add(lhs, rhs) => {
+                lhs.fmt_operand(f)?;
+                f.write_str(" + ")?;
+                rhs.fmt_operand(f)
+            }
+            Expr::Update(lhs, rhs) => {
+                lhs.fmt_operand(f)?;
+                f.write_str(" // ")?;
+                rhs.fmt_operand(f)
+            }
+            Expr::Let { bindings, body } => {
+                f.write_str("let")?;
+                for (name, value) in bindings {
+                    write!(f, " {name} = {value};")?;
+                }
+                write!(f, " in {body}")
+            }
+            Expr::Lambda { arg, body } => write!(f, "{arg}: {body}"),
+            Expr::If {
+                cond,
+                then,
+                otherwise,
+            } => write!(f, "if {cond} then {then} else {otherwise}"),
+        }
+    }
+}
+
+// A sequence of attribute names, eg: `systems.nixos."my-host".result`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct AttrPath(Vec<String>);
+
+impl AttrPath {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Split a user supplied dotted path such as `packages.hello`. Segments are taken
+    // literally, so quoting is not interpreted.
+    pub fn from_dotted(path: &str) -> Self {
+        Self(path.split('.').map(str::to_string).collect())
+    }
+
+    // Parse a path as written in Nix, where names that are not identifiers are quoted, eg.
+    // `services.nginx.virtualHosts."example.com"`. As on the command line, quoted names
+    // have no escape sequences.
+    pub fn parse(path: &str) -> Result<Self> {
+        let mut segments = vec![];
+        let mut rest = path;
+        loop {
+            let (segment, after) = match rest.strip_prefix('"') {
+                Some(quoted) => match quoted.split_once('"') {
+                    Some((segment, after)) => (segment, after),
+                    None => bail!("Unterminated quote in attribute path `{path}`"),
+                },
+                None => rest.split_at(rest.find('.').unwrap_or(rest.len())),
+            };
+            if segment.is_empty() {
+                bail!("Attribute names can not be empty, in `{path}`");
+            }
+            segments.push(segment.to_string());
+            if after.is_empty() {
+                return Ok(Self(segments));
+            }
+            rest = match after.strip_prefix('.') {
+                Some(rest) => rest,
+                None => bail!("Expected `.` after `\"{segment}\"` in attribute path `{path}`"),
+            };
+        }
+    }
+
+    pub fn push<S: Into<String>>(&mut self, segment: S) {
+        self.0.push(segment.into());
+    }
+
+    pub fn join<P: Into<AttrPath>>(mut self, other: P) -> Self {
+        self.0.extend(other.into().0);
+        self
+    }
+
+    pub fn segments(&self) -> &[String] {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // Render the path for the `--attr`/`-A` flag of the Nix CLI tools. Their attribute
+    // path parser understands double quotes but has no escape sequences, so some names
+    // simply cannot be expressed.
+    pub fn to_cli_arg(&self) -> Result<String> {
+        let mut out = String::new();
+        for (i, segment) in self.0.iter().enumerate() {
+            if i > 0 {
+                out.push('.');
+            }
+            if segment.is_empty() {
+                bail!("Attribute names can not be empty");
+            }
+            if segment.contains('"') {
+                bail!("Attribute name {segment:?} can not contain `\"`");
+            }
+            if is_identifier(segment) {
+                out.push_str(segment);
+            } else {
+                out.push('"');
+                out.push_str(segment);
+                out.push('"');
+            }
+        }
+        Ok(out)
+    }
+}
+
+impl Display for AttrPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, segment) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_char('.')?;
+            }
+            fmt_attr_name(f, segment)?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: Into<String>, const N: usize> From<[S; N]> for AttrPath {
+    fn from(value: [S; N]) -> Self {
+        Self(value.into_iter().map(Into::into).collect())
+    }
+}
+
+impl From<Vec<String>> for AttrPath {
+    fn from(value: Vec<String>) -> Self {
+        Self(value)
+    }
+}
@@ -0,0 +1,322 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use serde_json::Value;
+
+use crate::util::{
+    closure::References,
+    errors,
+    nar::{self, Filter},
+    nix::{
+        BuildOpts, BuildResult, EvalOpts, EvalResult, FixedOutputStoreEntry, NixBackend,
+        RebuildOpts,
+    },
+    nix_expr::Expr,
+    store_path::StorePath,
+};
+
+// Every call made to the mock, in order, so tests can check what would have been run.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Call {
+    Evaluate {
+        expr: String,
+        json: bool,
+        impure: bool,
+        env: Vec<(String, String)>,
+    },
+    Realise(PathBuf),
+    AddToStore(PathBuf, Filter),
+    StoreHash(StorePath),
+    Build {
+        file: PathBuf,
+        name: String,
+        opts: BuildOpts,
+    },
+    Rebuild(RebuildOpts),
+    Closure(PathBuf),
+}
+
+#[derive(Default)]
+struct State {
+    evals: Vec<(String, Result<EvalResult, String>)>,
+    realisations: Vec<(PathBuf, Result<Vec<PathBuf>, String>)>,
+    builds: Vec<(String, Result<Vec<PathBuf>, String>)>,
+    add_to_store_error: Option<String>,
+    rebuild_error: Option<String>,
+    references: References,
+    calls: Vec<Call>,
+}
+
+// A scriptable stand-in for Nix. Evaluations are answered from rules matched against
+// the rendered expression, with the most recently added rule winning. The store is a
+// plain directory: `add_to_store` copies into it, so resolved paths exist on disk just
+// as they would with the real thing.
+pub struct MockNix {
+    store: PathBuf,
+    state: Mutex<State>,
+}
+
+impl MockNix {
+    pub fn new<P: Into<PathBuf>>(store: P) -> Self {
+        MockNix {
+            store: store.into(),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn store(&self) -> &Path {
+        &self.store
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    // Answer evaluations containing `pattern` with a JSON value.
+    pub fn on_eval(&self, pattern: &str, value: Value) -> &Self {
+        self.state()
+            .evals
+            .push((pattern.to_string(), Ok(EvalResult::Json(value))));
+        self
+    }
+
+    // Answer evaluations containing `pattern` with raw (non-JSON) output.
+    pub fn on_eval_raw(&self, pattern: &str, output: &str) -> &Self {
+        self.state()
+            .evals
+            .push((pattern.to_string(), Ok(EvalResult::Raw(output.to_string()))));
+        self
+    }
+
+    // Fail evaluations containing `pattern`, as if Nix printed `stderr`.
+    pub fn fail_eval(&self, pattern: &str, stderr: &str) -> &Self {
+        self.state()
+            .evals
+            .push((pattern.to_string(), Err(stderr.to_string())));
+        self
+    }
+
+    pub fn on_realise<P: Into<PathBuf>>(&self, path: P, outputs: Vec<PathBuf>) -> &Self {
+        self.state().realisations.push((path.into(), Ok(outputs)));
+        self
+    }
+
+    pub fn fail_realise<P: Into<PathBuf>>(&self, path: P, stderr: &str) -> &Self {
+        self.state()
+            .realisations
+            .push((path.into(), Err(stderr.to_string())));
+        self
+    }
+
+    pub fn on_build(&self, name: &str, outputs: Vec<PathBuf>) -> &Self {
+        self.state().builds.push((name.to_string(), Ok(outputs)));
+        self
+    }
+
+    pub fn fail_build(&self, name: &str, stderr: &str) -> &Self {
+        self.state()
+            .builds
+            .push((name.to_string(), Err(stderr.to_string())));
+        self
+    }
+
+    pub fn fail_add_to_store(&self, stderr: &str) -> &Self {
+        self.state().add_to_store_error = Some(stderr.to_string());
+        self
+    }
+
+    pub fn fail_rebuild(&self, message: &str) -> &Self {
+        self.state().rebuild_error = Some(message.to_string());
+        self
+    }
+
+    // Make `path` a valid store path referring to `references`.
+    pub fn on_references<P: Into<PathBuf>>(&self, path: P, references: Vec<PathBuf>) -> &Self {
+        self.state().references.insert(path.into(), references);
+        self
+    }
+
+    pub fn calls(&self) -> Vec<Call> {
+        self.state().calls.clone()
+    }
+
+    // The rendered expressions evaluated so far.
+    pub fn evaluations(&self) -> Vec<String> {
+        self.calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::Evaluate { expr, .. } => Some(expr),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn rebuilds(&self) -> Vec<RebuildOpts> {
+        self.calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::Rebuild(opts) => Some(opts),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Copy `source` into the store as if it had been fetched, returning its store path.
+    pub fn add_fixture(&self, source: &Path, name: &str) -> Result<PathBuf> {
+        Ok(self.add(source, name, Filter::All)?.to_path_buf())
+    }
+
+    fn add(&self, source: &Path, name: &str, filter: Filter) -> Result<StorePath> {
+        let hash = nar::hash(source, filter)?;
+        let path = StorePath::for_source(&self.store, name, &hash.hash)?;
+        if !path.as_path().exists() {
+            nar::copy(source, path.as_path(), filter)
+                .with_context(|| format!("Could not copy {source:?} into the mock store"))?;
+        }
+        Ok(path)
+    }
+}
+
+impl NixBackend for MockNix {
+    fn store_dir(&self) -> PathBuf {
+        self.store.clone()
+    }
+
+    async fn evaluate(&self, expr: &Expr, opts: EvalOpts) -> Result<EvalResult> {
+        let expr = expr.to_string();
+        let mut state = self.state();
+        state.calls.push(Call::Evaluate {
+            expr: expr.clone(),
+            json: opts.json,
+            impure: opts.impure,
+            env: opts.env,
+        });
+
+        let rule = state
+            .evals
+            .iter()
+            .rev()
+            .find(|(pattern, _)| expr.contains(pattern.as_str()));
+        match rule {
+            Some((_, Ok(result))) => Ok(result.clone()),
+            Some((_, Err(stderr))) => bail!("nix eval failed\n{stderr}"),
+            None => bail!("nix eval failed\nMockNix: no rule for `{expr}`"),
+        }
+    }
+
+    async fn realise(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        let mut state = self.state();
+        state.calls.push(Call::Realise(path.to_path_buf()));
+
+        let rule = state.realisations.iter().rev().find(|(p, _)| p == path);
+        match rule {
+            Some((_, Ok(outputs))) => Ok(outputs.clone()),
+            Some((_, Err(stderr))) => bail!("nix-store realise failed:\n{stderr}"),
+            // Anything already in the store realises to itself
+            None if StorePath::parse_in(&self.store, path).is_ok() && path.exists() => {
+                Ok(vec![path.to_path_buf()])
+            }
+            None => bail!("nix-store realise failed:\nMockNix: no rule for {path:?}"),
+        }
+    }
+
+    async fn add_to_store(&self, path: &Path, filter: Filter) -> Result<FixedOutputStoreEntry> {
+        let error = {
+            let mut state = self.state();
+            state
+                .calls
+                .push(Call::AddToStore(path.to_path_buf(), filter));
+            state.add_to_store_error.clone()
+        };
+        if let Some(stderr) = error {
+            bail!("nix-store add failed:\n{stderr}");
+        }
+
+        let name = path
+            .file_name()
+            .ok_or_else(|| anyhow!("Cannot add {path:?} to the store"))?
+            .to_string_lossy();
+        let store_path = self.add(path, &name, filter)?;
+        let hash = self.store_hash(&store_path).await?;
+
+        Ok(FixedOutputStoreEntry {
+            path: store_path,
+            hash,
+        })
+    }
+
+    async fn store_hash(&self, path: &StorePath) -> Result<String> {
+        self.state().calls.push(Call::StoreHash(path.clone()));
+
+        let root = path.root();
+        if root.store_dir() != self.store || !root.as_path().exists() {
+            bail!("nix-hash failed:\npath '{root}' is not valid");
+        }
+        Ok(nar::hash(root.as_path(), Filter::All)?.hash.to_nix32())
+    }
+
+    async fn build(&self, file: &Path, name: &str, opts: BuildOpts) -> Result<BuildResult> {
+        let mut state = self.state();
+        state.calls.push(Call::Build {
+            file: file.to_path_buf(),
+            name: name.to_string(),
+            opts,
+        });
+
+        // Failed builds still finish, with the failures in the result
+        let result = match state.builds.iter().rev().find(|(n, _)| n == name) {
+            Some((_, Ok(outputs))) => BuildResult {
+                exit_code: Some(0),
+                outputs: outputs.clone(),
+                failed: vec![],
+            },
+            Some((_, Err(stderr))) => BuildResult {
+                exit_code: Some(1),
+                outputs: vec![],
+                failed: errors::failed_derivations(stderr),
+            },
+            None => BuildResult {
+                exit_code: Some(0),
+                outputs: vec![],
+                failed: vec![],
+            },
+        };
+        Ok(result)
+    }
+
+    async fn closure(&self, path: &Path) -> Result<References> {
+        let mut state = self.state();
+        state.calls.push(Call::Closure(path.to_path_buf()));
+
+        let mut closure = References::new();
+        let mut queue = vec![path.to_path_buf()];
+        while let Some(path) = queue.pop() {
+            if closure.contains_key(&path) {
+                continue;
+            }
+            let Some(references) = state.references.get(&path) else {
+                bail!(
+                    "nix-store query failed:\npath '{}' is not valid",
+                    path.display()
+                );
+            };
+            queue.extend(references.iter().cloned());
+            closure.insert(path, references.clone());
+        }
+        Ok(closure)
+    }
+
+    async fn rebuild(&self, opts: RebuildOpts) -> Result<()> {
+        let mut state = self.state();
+        let action = opts.action.as_str();
+        state.calls.push(Call::Rebuild(opts));
+
+        match &state.rebuild_error {
+            Some(message) => bail!("nixos-rebuild {action} failed ({message})"),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use std::{fmt, sync::Arc};
+
+use anyhow::{Context, Result, bail};
+use log::{debug, info};
+use tokio::{process::Command, sync::OnceCell};
+
+use crate::util::nix::NixSettings;
+
+// Lix and the various Nix distributions all install a `nix` binary, but they differ in
+// which commands need experimental features turned on. We ask once per run which one we
+// have, and shape every invocation to suit.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Implementation {
+    Nix,
+    Lix,
+    // Determinate Systems' distribution of Nix, which has a version of its own
+    Determinate,
+}
+
+impl fmt::Display for Implementation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Implementation::Nix => write!(f, "Nix"),
+            Implementation::Lix => write!(f, "Lix"),
+            Implementation::Determinate => write!(f, "Determinate Nix"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NixVersion {
+    pub implementation: Implementation,
+    // The version of the implementation itself, eg. 2.91.1 for Lix
+    pub version: (u32, u32, u32),
+    // The upstream Nix version Determinate Nix is based on
+    pub nix_version: Option<(u32, u32, u32)>,
+    pub experimental_features: Vec<String>,
+}
+
+fn parse_version(s: &str) -> Option<(u32, u32, u32)> {
+    let mut parts = s.split('.').map(|part| {
+        let digits = part
+            .split(|c: char| !c.is_ascii_digit())
+            .next()
+            .unwrap_or("");
+        digits.parse::<u32>().ok()
+    });
+    let major = parts.next()??;
+    let minor = parts.next()??;
+    let patch = parts.next().flatten().unwrap_or(0);
+    Some((major, minor, patch))
+}
+
+impl NixVersion {
+    // Parse what `nix --version` prints, which is one of
+    //
+    //   nix (Nix) 2.24.10
+    //   nix (Lix, like Nix) 2.91.1
+    //   nix (Determinate Nix 3.6.2) 2.29.0
+    pub fn parse(output: &str) -> Result<Self> {
+        let line = output.lines().next().unwrap_or("").trim();
+        let invalid = || anyhow::anyhow!("Could not make sense of `nix --version`: {line}");
+
+        let rest = line.strip_prefix("nix (").ok_or_else(invalid)?;
+        let (name, version) = rest.split_once(')').ok_or_else(invalid)?;
+        let version = parse_version(version.trim()).ok_or_else(invalid)?;
+
+        let (implementation, version, nix_version) = if name.starts_with("Lix") {
+            (Implementation::Lix, version, None)
+        } else if let Some(own) = name.strip_prefix("Determinate Nix ") {
+            let own = parse_version(own).ok_or_else(invalid)?;
+            (Implementation::Determinate, own, Some(version))
+        } else if name == "Nix" {
+            (Implementation::Nix, version, None)
+        } else {
+            return Err(invalid());
+        };
+
+        Ok(NixVersion {
+            implementation,
+            version,
+            nix_version,
+            experimental_features: vec![],
+        })
+    }
+
+    // The Nix version this behaves like, for comparing against upstream releases.
+    pub fn nix_version(&self) -> (u32, u32, u32) {
+        match self.implementation {
+            // Lix forked from Nix 2.18
+            Implementation::Lix => (2, 18, 0),
+            _ => self.nix_version.unwrap_or(self.version),
+        }
+    }
+
+    // Whether the `nix eval`, `nix build` and `nix repl` we use exist at all. Before 2.4
+    // there was only a `nix` command that needs falling back to `nix-instantiate` and
+    // `nix-build`.
+    pub fn has_nix_command(&self) -> bool {
+        self.nix_version() >= (2, 4, 0)
+    }
+
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.experimental_features.iter().any(|f| f == feature)
+    }
+
+    // The arguments that turn on the `nix` command, where it is not on already.
+    pub fn nix_command_args(&self) -> Vec<&'static str> {
+        if self.has_nix_command() && !self.has_feature("nix-command") {
+            vec!["--extra-experimental-features", "nix-command"]
+        } else {
+            vec![]
+        }
+    }
+
+    // Pick the enabled features out of `nix config show` (or the older `nix show-config`).
+    pub fn parse_features(config: &str) -> Vec<String> {
+        config
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .find(|(key, _)| key.trim() == "experimental-features")
+            .map(|(_, value)| value.split_whitespace().map(String::from).collect())
+            .unwrap_or_default()
+    }
+}
+
+impl fmt::Display for NixVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (major, minor, patch) = self.version;
+        write!(f, "{} {major}.{minor}.{patch}", self.implementation)?;
+        if let Some((major, minor, patch)) = self.nix_version {
+            write!(f, " (Nix {major}.{minor}.{patch})")?;
+        }
+        Ok(())
+    }
+}
+
+// The version does not depend on any settings, but the features enabled can, as with
+// `--option experimental-features`.
+async fn probe(settings: &NixSettings) -> Result<NixVersion> {
+    let output = Command::new("nix")
+        .arg("--version")
+        .output()
+        .await
+        .context("Could not run `nix --version`, is Nix installed?")?;
+    if !output.status.success() {
+        bail!(
+            "`nix --version` failed:\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    let mut nix = NixVersion::parse(&String::from_utf8_lossy(&output.stdout))?;
+
+    if nix.has_nix_command() {
+        for show in [["config", "show"].as_slice(), ["show-config"].as_slice()] {
+            let output = Command::new("nix")
+                .args(["--extra-experimental-features", "nix-command"])
+                .args(show)
+                .args(settings.option_args())
+                .output()
+                .await?;
+            if output.status.success() {
+                let config = String::from_utf8_lossy(&output.stdout);
+                nix.experimental_features = NixVersion::parse_features(&config);
+                break;
+            }
+        }
+    }
+
+    debug!(
+        "Experimental features enabled: {:?}",
+        nix.experimental_features
+    );
+    Ok(nix)
+}
+
+// The Nix in use, asked about on first use and remembered from then on. Clones share
+// what was found, so one is made per run, whose settings are the same throughout, and
+// handed to everything that runs Nix.
+#[derive(Debug, Clone, Default)]
+pub struct DetectedVersion(Arc<OnceCell<NixVersion>>);
+
+impl DetectedVersion {
+    pub async fn get(&self, settings: &NixSettings) -> Result<&NixVersion> {
+        self.0
+            .get_or_try_init(|| async {
+                let nix = probe(settings).await?;
+                info!("Using {nix}");
+                if !nix.has_nix_command() {
+                    info!("Falling back to nix-instantiate and nix-build for this version");
+                }
+                Ok(nix)
+            })
+            .await
+    }
+}
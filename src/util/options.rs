@@ -0,0 +1,487 @@
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+use log::debug;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::util::{
+    nix::{EvalOpts, EvalResult, NixBackend},
+    nix_expr::{AttrPath, Expr},
+};
+
+// Looking up NixOS options in an evaluated system (`systems.nixos.<name>.result`), which
+// has the declared `options` next to the final `config`.
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Definition {
+    pub file: String,
+    pub value: Value,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OptionInfo {
+    // The path asked about
+    pub path: AttrPath,
+    // The declared option holding it. Paths inside an option, such as one host of
+    // `services.nginx.virtualHosts`, are described by the option they are in.
+    pub option: AttrPath,
+    pub value: Value,
+    pub r#type: Option<String>,
+    pub description: Option<String>,
+    // Either the default value, or the text given to show in its place
+    pub default: Option<Value>,
+    pub declarations: Vec<String>,
+    pub definitions: Vec<Definition>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawOption {
+    value: Value,
+    r#type: Option<String>,
+    description: Option<Value>,
+    has_default: bool,
+    default: Value,
+    declarations: Vec<Value>,
+    definitions: Vec<Definition>,
+}
+
+fn pure_json() -> EvalOpts {
+    EvalOpts {
+        json: true,
+        impure: false,
+        ..Default::default()
+    }
+}
+
+async fn evaluate_json<B: NixBackend>(nix: &B, expr: &Expr) -> Result<Value> {
+    match nix.evaluate(expr, pure_json()).await? {
+        EvalResult::Json(value) => Ok(value),
+        EvalResult::Raw(raw) => bail!("Expected JSON, got {raw}"),
+    }
+}
+
+// Documentation is either plain text or wrapped, as in `lib.mdDoc` and
+// `lib.literalExpression`, with the text inside.
+pub fn doc_text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(text) => Some(text.clone()),
+        Value::Object(doc) => doc.get("text")?.as_str().map(str::to_string),
+        _ => None,
+    }
+}
+
+// What an option value is shown as when it cannot be written as JSON
+const LAMBDA: &str = "«lambda»";
+const ERROR: &str = "«error»";
+const TRUNCATED: &str = "«…»";
+
+// How many lists and sets deep a value is shown before the rest is left out
+const MAX_DEPTH: i64 = 8;
+
+// `builtins.toJSON` fails on functions, which options such as `nixpkgs.overlays` hold, so
+// `sanitise` replaces them with a placeholder, as nixos-option does. Packages are shown
+// as their store path. Inside the value, parts that fail to evaluate, such as a broken
+// package in `boot.kernelPackages`, are replaced too, rather than failing the whole.
+//
+// Values can be as large as all of nixpkgs (`nixpkgs.pkgs`) or refer to themselves, so
+// the walk does not go into derivations or typed values such as `pkgs` and
+// `lib.literalExpression`, of which only the type and text are kept, and stops at
+// `MAX_DEPTH`.
+fn sanitisers() -> [(&'static str, Expr); 2] {
+    let value = Expr::var("value");
+    let depth = Expr::var("depth");
+    let is = |check: &str| Expr::builtin(check).apply(value.clone());
+    let deeper = || {
+        Expr::var("safe").apply(
+            Expr::builtin("sub")
+                .apply(depth.clone())
+                .apply(Expr::Int(1)),
+        )
+    };
+    let truncated = |otherwise: Expr| {
+        Expr::if_then_else(
+            Expr::builtin("lessThan")
+                .apply(depth.clone())
+                .apply(Expr::Int(1)),
+            Expr::str(TRUNCATED),
+            otherwise,
+        )
+    };
+    let typed = Expr::builtin("mapAttrs")
+        .apply(Expr::lambda("name", Expr::var("safe").apply(Expr::Int(0))))
+        .apply(
+            Expr::builtin("intersectAttrs")
+                .apply(Expr::attrs([("_type", Expr::Null), ("text", Expr::Null)]))
+                .apply(value.clone()),
+        );
+    let store_path = Expr::builtin("toString").apply(value.clone().select(["outPath"]));
+    let attrs = Expr::if_then_else(
+        value.clone().has_attr(["_type"]),
+        typed,
+        Expr::if_then_else(
+            Expr::builtin("elem")
+                .apply(value.clone().select_or(["type"], Expr::Null))
+                .apply(Expr::List(vec![Expr::str("derivation")])),
+            store_path.clone(),
+            Expr::if_then_else(
+                value.clone().has_attr(["outPath"]),
+                store_path,
+                Expr::if_then_else(
+                    value.clone().has_attr(["__functor"]),
+                    Expr::str(LAMBDA),
+                    truncated(
+                        Expr::builtin("mapAttrs")
+                            .apply(Expr::lambda("name", deeper()))
+                            .apply(value.clone()),
+                    ),
+                ),
+            ),
+        ),
+    );
+    let sanitise = Expr::lambda(
+        "depth",
+        Expr::lambda(
+            "value",
+            Expr::if_then_else(
+                is("isFunction"),
+                Expr::str(LAMBDA),
+                Expr::if_then_else(
+                    is("isList"),
+                    truncated(Expr::builtin("map").apply(deeper()).apply(value.clone())),
+                    Expr::if_then_else(is("isAttrs"), attrs, value.clone()),
+                ),
+            ),
+        ),
+    );
+    let result = Expr::var("result");
+    let safe = Expr::lambda(
+        "depth",
+        Expr::lambda(
+            "value",
+            Expr::let_in(
+                [(
+                    "result",
+                    Expr::builtin("tryEval").apply(Expr::var("sanitise").apply(depth).apply(value)),
+                )],
+                Expr::if_then_else(
+                    result.clone().select(["success"]),
+                    result.select(["value"]),
+                    Expr::str(ERROR),
+                ),
+            ),
+        ),
+    );
+    [("sanitise", sanitise), ("safe", safe)]
+}
+
+// The longest leading part of `path` that is a declared option, if any.
+async fn find_option<B: NixBackend>(
+    nix: &B,
+    system: &Expr,
+    path: &AttrPath,
+) -> Result<Option<AttrPath>> {
+    let segments = path.segments();
+    let prefixes: Vec<AttrPath> = (1..=segments.len())
+        .map(|len| AttrPath::from(segments[..len].to_vec()))
+        .collect();
+    let kinds = Expr::List(
+        prefixes
+            .iter()
+            .map(|prefix| {
+                let kind = AttrPath::from(["options"])
+                    .join(prefix.clone())
+                    .join(["_type"]);
+                system.clone().select_or(kind, Expr::Null)
+            })
+            .collect(),
+    );
+
+    let Value::Array(kinds) = evaluate_json(nix, &kinds).await? else {
+        bail!("Expected a list of option kinds");
+    };
+    Ok(prefixes
+        .into_iter()
+        .zip(kinds)
+        .rev()
+        .find(|(_, kind)| kind.as_str() == Some("option"))
+        .map(|(prefix, _)| prefix))
+}
+
+// Describe the option at `path` of `system`, with its value.
+pub async fn query_option<B: NixBackend>(
+    nix: &B,
+    system: &Expr,
+    path: &AttrPath,
+) -> Result<OptionInfo> {
+    let Some(option) = find_option(nix, system, path).await? else {
+        // Perhaps a set of options, which is worth listing
+        let options = system.clone().select_or(
+            AttrPath::from(["options"]).join(path.clone()),
+            Expr::attrs::<_, String>([]),
+        );
+        let names = Expr::builtin("attrNames").apply(options);
+        let names: Vec<String> = serde_json::from_value(evaluate_json(nix, &names).await?)?;
+        if names.is_empty() {
+            bail!("There is no option `{path}`");
+        }
+        let names: Vec<String> = names
+            .into_iter()
+            .map(|name| format!("  {}", path.clone().join([name])))
+            .collect();
+        bail!(
+            "`{path}` is a set of options, pick one of:\n{}",
+            names.join("\n")
+        );
+    };
+    debug!("Found option {option} for {path}");
+
+    let declared = Expr::var("option");
+    let sanitise = |value: Expr| {
+        Expr::var("sanitise")
+            .apply(Expr::Int(MAX_DEPTH))
+            .apply(value)
+    };
+    let mut bindings = vec![(
+        "option",
+        system
+            .clone()
+            .select(AttrPath::from(["options"]).join(option.clone())),
+    )];
+    bindings.extend(sanitisers());
+    let code = Expr::let_in(
+        bindings,
+        Expr::attrs([
+            (
+                "value",
+                sanitise(
+                    system
+                        .clone()
+                        .select(AttrPath::from(["config"]).join(path.clone())),
+                ),
+            ),
+            (
+                "type",
+                declared
+                    .clone()
+                    .select_or(["type", "description"], Expr::Null),
+            ),
+            (
+                "description",
+                declared.clone().select_or(["description"], Expr::Null),
+            ),
+            ("hasDefault", declared.clone().has_attr(["default"])),
+            // Only fall back to the default itself when there is nothing to show instead,
+            // as the text is often there because the default cannot be evaluated alone
+            (
+                "default",
+                sanitise(declared.clone().select_or(
+                    ["defaultText"],
+                    declared.clone().select_or(["default"], Expr::Null),
+                )),
+            ),
+            (
+                "declarations",
+                declared
+                    .clone()
+                    .select_or(["declarations"], Expr::List(vec![])),
+            ),
+            (
+                "definitions",
+                sanitise(declared.select_or(["definitionsWithLocations"], Expr::List(vec![]))),
+            ),
+        ]),
+    );
+
+    let raw: RawOption = serde_json::from_value(evaluate_json(nix, &code).await?)?;
+    Ok(OptionInfo {
+        path: path.clone(),
+        option,
+        value: raw.value,
+        r#type: raw.r#type,
+        description: raw.description.as_ref().and_then(doc_text),
+        default: raw.has_default.then_some(raw.default),
+        declarations: raw
+            .declarations
+            .iter()
+            .filter_map(|file| file.as_str().map(str::to_string))
+            .collect(),
+        definitions: raw.definitions,
+    })
+}
+
+// One entry of the option tree, as listed in the NixOS manual. Options inside submodules
+// are listed too, with placeholders in their names: `services.nginx.virtualHosts.<name>.root`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OptionDoc {
+    pub name: String,
+    pub r#type: Option<String>,
+    pub description: Option<String>,
+    pub declarations: Vec<String>,
+}
+
+impl OptionDoc {
+    // Whether the option is declared by one of the project's modules, rather than
+    // nixpkgs or another input.
+    pub fn declared_in(&self, source: &Path) -> bool {
+        self.declarations
+            .iter()
+            .any(|file| Path::new(file).starts_with(source))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RawOptionDoc {
+    name: String,
+    r#type: Option<String>,
+    description: Option<Value>,
+    declarations: Vec<Value>,
+    // `true`, `false` or `"shallow"`, the latter hiding only the options below it
+    visible: Value,
+    internal: bool,
+}
+
+// Every visible option of `system`, from nixpkgs' own `optionAttrSetToDocList`. Only what
+// a search needs is evaluated, defaults and examples can be expensive or even fail.
+//
+// Going through the pinned project, the list is cached with the rest of its pure
+// evaluations, and only gathered again once the project changes.
+pub async fn option_tree<B: NixBackend>(nix: &B, system: &Expr) -> Result<Vec<OptionDoc>> {
+    let option = Expr::var("option");
+    let fields = Expr::attrs(
+        [
+            "name",
+            "type",
+            "description",
+            "declarations",
+            "visible",
+            "internal",
+        ]
+        .map(|field| (field, option.clone().select([field]))),
+    );
+    let code = Expr::let_in(
+        [("system", system.clone())],
+        Expr::builtin("map")
+            .apply(Expr::lambda("option", fields))
+            .apply(
+                Expr::var("system")
+                    .select(["pkgs", "lib", "optionAttrSetToDocList"])
+                    .apply(Expr::var("system").select(["options"])),
+            ),
+    );
+
+    let raw: Vec<RawOptionDoc> = serde_json::from_value(evaluate_json(nix, &code).await?)
+        .context("Could not read the list of options")?;
+    Ok(raw
+        .into_iter()
+        .filter(|option| option.visible != Value::Bool(false) && !option.internal)
+        .map(|option| OptionDoc {
+            name: option.name,
+            r#type: option.r#type,
+            description: option.description.as_ref().and_then(doc_text),
+            declarations: option
+                .declarations
+                .iter()
+                .filter_map(|file| file.as_str().map(str::to_string))
+                .collect(),
+        })
+        .collect())
+}
+
+pub enum Query {
+    // Words that all have to match the name or description, in any order and
+    // forgiving typos in the name
+    Fuzzy(Vec<String>),
+    Regex(Regex),
+}
+
+impl Query {
+    pub fn fuzzy<S: AsRef<str>>(words: &[S]) -> Self {
+        Query::Fuzzy(
+            words
+                .iter()
+                .flat_map(|word| word.as_ref().split_whitespace())
+                .map(str::to_lowercase)
+                .collect(),
+        )
+    }
+
+    // Case insensitive, as the fuzzy search is
+    pub fn regex(pattern: &str) -> Result<Self> {
+        let regex = RegexBuilder::new(pattern)
+            .case_insensitive(true)
+            .build()
+            .with_context(|| format!("Invalid regular expression `{pattern}`"))?;
+        Ok(Query::Regex(regex))
+    }
+
+    // How well `option` matches, or `None` when it does not. Matches in the name count
+    // for more than matches in the description.
+    fn score(&self, option: &OptionDoc) -> Option<u32> {
+        let description = option.description.as_deref().unwrap_or_default();
+        match self {
+            Query::Regex(regex) => {
+                match (regex.is_match(&option.name), regex.is_match(description)) {
+                    (true, _) => Some(2),
+                    (false, true) => Some(1),
+                    (false, false) => None,
+                }
+            }
+            Query::Fuzzy(words) => {
+                let name = option.name.to_lowercase();
+                let segments: Vec<&str> = name.split('.').collect();
+                let description = description.to_lowercase();
+                words
+                    .iter()
+                    .map(|word| score_word(word, &name, &segments, &description))
+                    .sum()
+            }
+        }
+    }
+}
+
+fn score_word(word: &str, name: &str, segments: &[&str], description: &str) -> Option<u32> {
+    if segments.contains(&word) {
+        Some(100)
+    } else if segments.iter().any(|segment| segment.starts_with(word)) {
+        Some(70)
+    } else if name.contains(word) {
+        Some(50)
+    } else if segments
+        .iter()
+        .any(|segment| strsim::jaro_winkler(segment, word) >= 0.9)
+    {
+        Some(30)
+    } else if description.contains(word) {
+        Some(20)
+    } else if word.len() >= 3 && segments.iter().any(|segment| is_subsequence(word, segment)) {
+        Some(10)
+    } else {
+        None
+    }
+}
+
+// Whether the characters of `needle` appear in `haystack` in order, eg. `vhosts` in
+// `virtualhosts`
+fn is_subsequence(needle: &str, haystack: &str) -> bool {
+    let mut haystack = haystack.chars();
+    needle.chars().all(|c| haystack.any(|h| h == c))
+}
+
+// The options matching `query`, best first. Ties go to the shorter name, which is
+// usually the more general option.
+pub fn search<'a>(options: &'a [OptionDoc], query: &Query) -> Vec<&'a OptionDoc> {
+    let mut found: Vec<(u32, &OptionDoc)> = options
+        .iter()
+        .filter_map(|option| Some((query.score(option)?, option)))
+        .collect();
+    found.sort_by(|(a_score, a), (b_score, b)| {
+        b_score
+            .cmp(a_score)
+            .then(a.name.len().cmp(&b.name.len()))
+            .then(a.name.cmp(&b.name))
+    });
+    found.into_iter().map(|(_, option)| option).collect()
+}
@@ -0,0 +1,289 @@
+use std::{
+    ffi::OsStr,
+    fmt,
+    future::Future,
+    path::PathBuf,
+    process::{ExitStatus, Output, Stdio},
+    sync::atomic::{AtomicI32, Ordering},
+    time::Duration,
+};
+
+use anyhow::{Result, bail};
+use log::{debug, warn};
+use tokio::{
+    process::{Child, Command},
+    signal::unix::{SignalKind, signal},
+};
+use tokio_util::sync::CancellationToken;
+
+// Children run in process groups of their own, so that Ctrl-C reaches us rather than
+// them. When the run is cancelled, or a stage takes longer than it may, we pass the
+// signal on to the whole group (Nix forks builders, sudo forks what it runs) and give
+// it a grace period to clean up before it is killed.
+
+// How long a stopped child has to exit before it is killed
+pub const GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+// The signal that cancelled the run, passed on to children as it is
+static RECEIVED: AtomicI32 = AtomicI32::new(libc::SIGINT);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Evaluation,
+    Build,
+    // Pointing the system profile at the new generation, which is never interrupted
+    ProfileSwitch,
+    Activation,
+}
+
+impl Stage {
+    // Stages that would leave the system in a mess if stopped halfway. These are left to
+    // finish, however long they take, and the run stops after them instead.
+    pub fn is_critical(self) -> bool {
+        self == Stage::ProfileSwitch
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stage::Evaluation => write!(f, "evaluation"),
+            Stage::Build => write!(f, "the build"),
+            Stage::ProfileSwitch => write!(f, "switching the system profile"),
+            Stage::Activation => write!(f, "activation"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopError {
+    Interrupted(Stage),
+    // Cancelled during a critical stage, which was allowed to finish first
+    InterruptedAfter(Stage),
+    TimedOut(Stage, Duration),
+}
+
+impl fmt::Display for StopError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StopError::Interrupted(stage) => write!(f, "Interrupted during {stage}"),
+            StopError::InterruptedAfter(stage) => {
+                write!(f, "Interrupted, stopping after {stage} finished")
+            }
+            StopError::TimedOut(stage, after) => {
+                write!(f, "Timed out after {}s during {stage}", after.as_secs())
+            }
+        }
+    }
+}
+
+impl std::error::Error for StopError {}
+
+// Cancel `cancel` on the first SIGINT or SIGTERM. Running children are stopped by
+// whatever is supervising them.
+pub fn cancel_on_signals(cancel: CancellationToken) -> Result<()> {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::spawn(async move {
+        loop {
+            let received = tokio::select! {
+                _ = interrupt.recv() => libc::SIGINT,
+                _ = terminate.recv() => libc::SIGTERM,
+            };
+            RECEIVED.store(received, Ordering::SeqCst);
+            if cancel.is_cancelled() {
+                warn!("Already stopping, waiting for running commands to exit");
+            } else {
+                warn!("Stopping");
+                cancel.cancel();
+            }
+        }
+    });
+    Ok(())
+}
+
+// Start `command` in a process group of its own. Isolated children cannot read from the
+// terminal, so their stdin is closed.
+pub fn spawn(command: &mut Command) -> std::io::Result<Child> {
+    command
+        .process_group(0)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+}
+
+fn signal_group(pgid: Option<u32>, signal: i32) {
+    let Some(pgid) = pgid else {
+        return;
+    };
+    debug!("Sending signal {signal} to process group {pgid}");
+    // SAFETY: killpg has no memory safety requirements, at worst the group is gone
+    unsafe {
+        libc::killpg(pgid as libc::pid_t, signal);
+    }
+}
+
+// Watches over a child running one stage of the run.
+#[derive(Debug, Clone)]
+pub struct Supervisor {
+    pub stage: Stage,
+    pub cancel: CancellationToken,
+    pub timeout: Option<Duration>,
+    pub grace: Duration,
+}
+
+impl Supervisor {
+    pub fn new(stage: Stage, cancel: &CancellationToken, timeout: Option<Duration>) -> Self {
+        Supervisor {
+            stage,
+            cancel: cancel.clone(),
+            timeout,
+            grace: GRACE_PERIOD,
+        }
+    }
+
+    // Run `command` isolated, collecting its output.
+    pub async fn output(&self, command: &mut Command) -> Result<Output> {
+        let child = spawn(command.stdout(Stdio::piped()).stderr(Stdio::piped()))?;
+        let pgid = child.id();
+        self.wait(pgid, child.wait_with_output()).await
+    }
+
+    // Run `command` isolated, with its output going where ours does.
+    pub async fn status(&self, command: &mut Command) -> Result<ExitStatus> {
+        let mut child = spawn(command)?;
+        let pgid = child.id();
+        self.wait(pgid, child.wait()).await
+    }
+
+    // Wait for `work`, which finishes once the child leading process group `pgid` has
+    // exited, stopping the group if the run is cancelled or the stage times out.
+    pub async fn wait<T, F>(&self, pgid: Option<u32>, work: F) -> Result<T>
+    where
+        F: Future<Output = std::io::Result<T>>,
+    {
+        if self.stage.is_critical() {
+            let result = work.await?;
+            if self.cancel.is_cancelled() {
+                return Err(StopError::InterruptedAfter(self.stage).into());
+            }
+            return Ok(result);
+        }
+
+        tokio::pin!(work);
+        let (error, signal) = tokio::select! {
+            result = &mut work => return Ok(result?),
+            _ = self.cancel.cancelled() => {
+                (StopError::Interrupted(self.stage), RECEIVED.load(Ordering::SeqCst))
+            }
+            _ = self.deadline() => {
+                let timeout = self.timeout.unwrap_or_default();
+                (StopError::TimedOut(self.stage, timeout), libc::SIGTERM)
+            }
+        };
+
+        warn!("Stopping {}", self.stage);
+        signal_group(pgid, signal);
+        if tokio::time::timeout(self.grace, &mut work).await.is_err() {
+            warn!(
+                "Killing what is left of {}, it did not stop within {}s",
+                self.stage,
+                self.grace.as_secs()
+            );
+            signal_group(pgid, libc::SIGKILL);
+            let _ = work.await;
+        }
+        Err(error.into())
+    }
+
+    // Wait for `work`, which has no process of its own to stop, giving up on it when the
+    // run is cancelled or the stage times out. Whatever `work` was talking to is then left
+    // for the caller to throw away.
+    pub async fn abandon<T, F: Future<Output = T>>(&self, work: F) -> Result<T> {
+        tokio::select! {
+            result = work => Ok(result),
+            _ = self.cancel.cancelled() => Err(StopError::Interrupted(self.stage).into()),
+            _ = self.deadline() => {
+                let timeout = self.timeout.unwrap_or_default();
+                Err(StopError::TimedOut(self.stage, timeout).into())
+            }
+        }
+    }
+
+    async fn deadline(&self) {
+        match self.timeout {
+            Some(timeout) => tokio::time::sleep(timeout).await,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+// How commands are run as root. Isolated commands cannot ask for a password, so it is
+// asked for once up front, and the commands then run non-interactively.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Elevation {
+    // Already root
+    None,
+    Sudo(PathBuf),
+    Doas(PathBuf),
+}
+
+impl Elevation {
+    pub fn find() -> Result<Self> {
+        // SAFETY: geteuid cannot fail
+        if unsafe { libc::geteuid() } == 0 {
+            return Ok(Elevation::None);
+        }
+        if let Ok(sudo) = which::which("sudo") {
+            return Ok(Elevation::Sudo(sudo));
+        }
+        if let Ok(doas) = which::which("doas") {
+            return Ok(Elevation::Doas(doas));
+        }
+        bail!("Could not find sudo or doas")
+    }
+
+    // Ask for the password now, in the foreground, where it can be typed in.
+    pub async fn authenticate(&self) -> Result<()> {
+        match self {
+            Elevation::None => Ok(()),
+            Elevation::Sudo(sudo) => {
+                let status = Command::new(sudo).arg("-v").status().await?;
+                if !status.success() {
+                    bail!("Could not authenticate with sudo ({status})");
+                }
+                Ok(())
+            }
+            Elevation::Doas(doas) => {
+                let status = Command::new(doas).arg("true").status().await?;
+                if !status.success() {
+                    bail!("Could not authenticate with doas ({status})");
+                }
+                // Without `persist` (or `nopass`), every command would ask again
+                let remembered = Command::new(doas)
+                    .args(["-n", "true"])
+                    .stdin(Stdio::null())
+                    .status()
+                    .await?;
+                if !remembered.success() {
+                    bail!(
+                        "doas asks for a password for every command, add `persist` to its rule in doas.conf"
+                    );
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // `program` run as root, without asking for a password
+    pub fn command<P: AsRef<OsStr>>(&self, program: P) -> Command {
+        match self {
+            Elevation::None => Command::new(program),
+            Elevation::Sudo(elevate) | Elevation::Doas(elevate) => {
+                let mut command = Command::new(elevate);
+                command.arg("-n").arg(program);
+                command
+            }
+        }
+    }
+}
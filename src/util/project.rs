@@ -1,17 +1,22 @@
-use anyhow::{anyhow, bail};
+use anyhow::{Context, anyhow, bail};
 use log::{debug, info, trace, warn};
 use serde::Serialize;
-use std::{
-    borrow::Cow,
-    path::{Path, PathBuf},
-    str::FromStr,
-};
-use url::Url;
+use std::path::{Component, Path, PathBuf};
 
 use crate::util::{
+    archive::{self, ArchiveFormat},
+    auth,
+    config::{Config, HostConfig, Transport},
+    context,
+    expand::expand_path,
     git,
-    nix::{self, EvalResult},
-    search::search_up_for_file,
+    nar::Filter,
+    nix::{EvalOpts, EvalResult, NixBackend},
+    nix_expr::Expr,
+    project_ref::{Forge, GitParams, Installable, ProjectRef},
+    registry,
+    search::search_up_for_files,
+    store_path::StorePath,
 };
 
 use super::nix::FixedOutputStoreEntry;
@@ -25,10 +30,6 @@ pub enum Source {
         info: GitInfo,
         entry: FixedOutputStoreEntry,
     },
-    Sourcehut {
-        info: GitXInfo,
-        entry: FixedOutputStoreEntry,
-    },
     Tarball {
         url: String,
         entry: FixedOutputStoreEntry,
@@ -38,10 +39,9 @@ pub enum Source {
 impl Source {
     pub fn get_path(self) -> PathBuf {
         match self {
-            Source::Path { entry } => entry.path,
-            Source::Git { info: _, entry } => entry.path,
-            Source::Sourcehut { info: _, entry } => entry.path,
-            Source::Tarball { url: _, entry } => entry.path,
+            Source::Path { entry } => entry.path.to_path_buf(),
+            Source::Git { info: _, entry } => entry.path.to_path_buf(),
+            Source::Tarball { url: _, entry } => entry.path.to_path_buf(),
         }
     }
 
@@ -49,7 +49,6 @@ impl Source {
         match self {
             Source::Path { entry } => entry.hash,
             Source::Git { info: _, entry } => entry.hash,
-            Source::Sourcehut { info: _, entry } => entry.hash,
             Source::Tarball { url: _, entry } => entry.hash,
         }
     }
@@ -58,7 +57,6 @@ impl Source {
         match self {
             Source::Path { entry } => entry,
             Source::Git { info: _, entry } => entry,
-            Source::Sourcehut { info: _, entry } => entry,
             Source::Tarball { url: _, entry } => entry,
         }
     }
@@ -84,18 +82,44 @@ pub struct GitXInfo {
     pub submodules: bool,
 }
 
-impl From<GitXInfo> for GitInfo {
-    fn from(value: GitXInfo) -> Self {
+impl GitXInfo {
+    pub fn url(&self, transport: Transport) -> String {
+        match transport {
+            Transport::Ssh => format!("git@{}:{}/{}.git", self.host, self.owner, self.repo),
+            Transport::Https => format!("https://{}/{}/{}.git", self.host, self.owner, self.repo),
+        }
+    }
+
+    pub fn into_git_info(self, transport: Transport) -> GitInfo {
         GitInfo {
-            url: format!("git@{}:{}/{}.git", value.host, value.owner, value.repo),
-            rev: value.rev,
-            r#ref: value.r#ref,
-            dir: value.dir,
-            submodules: value.submodules,
+            url: self.url(transport),
+            rev: self.rev,
+            r#ref: self.r#ref,
+            dir: self.dir,
+            submodules: self.submodules,
         }
     }
 }
 
+impl From<GitXInfo> for GitInfo {
+    fn from(value: GitXInfo) -> Self {
+        value.into_git_info(Transport::Ssh)
+    }
+}
+
+// Credentials for whichever host `url` points at, as configured by the user.
+fn fetch_env(config: &Config, url: &str) -> anyhow::Result<Vec<(String, String)>> {
+    let Some(host) = auth::host_of_url(url) else {
+        return Ok(vec![]);
+    };
+    let Some(host_config) = config.host(&host) else {
+        return Ok(vec![]);
+    };
+
+    debug!("Using configured credentials for {host}");
+    Ok(auth::fetch_settings(&host, host_config)?.env())
+}
+
 pub fn remove_filename_from_path<P>(path: P) -> PathBuf
 where
     P: Into<PathBuf>,
@@ -108,65 +132,77 @@ where
     path
 }
 
-async fn resolve_git(info: GitInfo) -> anyhow::Result<Source> {
+// The store path a fetch was realised to
+fn fetched_store_path<B: NixBackend>(nix: &B, paths: &[PathBuf]) -> anyhow::Result<StorePath> {
+    let Some(path) = paths.first() else {
+        bail!("Realising the fetched source gave no paths");
+    };
+    Ok(StorePath::parse_in(&nix.store_dir(), path)?)
+}
+
+// The store path a fetcher expression evaluated to
+fn fetched_path(result: anyhow::Result<EvalResult>) -> anyhow::Result<PathBuf> {
+    match result? {
+        EvalResult::Json(serde_json::Value::String(path)) => Ok(PathBuf::from(path)),
+        EvalResult::Json(other) => bail!("Expected a store path, got {other}"),
+        EvalResult::Raw(_) => bail!("Got raw, expected JSON"),
+    }
+}
+
+async fn resolve_git<B: NixBackend>(
+    nix: &B,
+    info: GitInfo,
+    config: &Config,
+) -> anyhow::Result<Source> {
     debug!("Resolving git for {info:?}");
-    let code = format!(
-        "
-		let
-			info = builtins.fromJSON ''{}'';
-		in
-			builtins.fetchGit (
-				{{ url = info.url; }}
-				// (if info.rev != null then {{ rev = info.rev; }} else {{}})
-				// (if info.ref != null then {{ ref = info.ref; }} else {{}})
-				// (if info.submodules != null then {{ submodules = info.submodules; }} else {{}})
-			)
-	",
-        serde_json::to_string(&info).unwrap()
-    );
-
-    let root = nix::evaluate(
-        &code,
-        nix::EvalOpts {
-            impure: true,
-            json: true,
-        },
-    )
-    .await;
+    let mut args = vec![("url", Expr::str(&info.url))];
+    if let Some(rev) = &info.rev {
+        args.push(("rev", Expr::str(rev)));
+    }
+    if let Some(r#ref) = &info.r#ref {
+        args.push(("ref", Expr::str(r#ref)));
+    }
+    args.push(("submodules", Expr::Bool(info.submodules)));
 
-    let root_path = match root {
-        Ok(EvalResult::Json(res)) => res.as_str().unwrap().to_string(),
-        Ok(EvalResult::Raw(_)) => {
-            bail!("Got raw, expected JSON");
-        }
-        _ => {
-            bail!("{}", root.unwrap_err());
-        }
-    };
+    let code = Expr::builtin("fetchGit").apply(Expr::attrs(args));
+
+    let root = nix
+        .evaluate(
+            &code,
+            EvalOpts {
+                impure: true,
+                json: true,
+                env: fetch_env(config, &info.url)?,
+            },
+        )
+        .await;
+
+    let root_path = fetched_path(root)?;
 
-    let store_path = nix::realise(root_path).await;
+    let store_path = nix.realise(&root_path).await;
 
     let Ok(paths) = store_path else {
         bail!("{}", store_path.unwrap_err());
     };
 
-    let mut final_path = paths[0].clone();
+    let mut final_path = fetched_store_path(nix, &paths)?;
 
-    if info.dir.is_some() {
-        final_path.push(info.dir.clone().unwrap())
+    if let Some(dir) = &info.dir {
+        final_path = final_path.join(dir)?;
     }
 
-    return Ok(Source::Git {
-        info,
+    Ok(Source::Git {
         entry: FixedOutputStoreEntry {
-            path: final_path.clone(),
-            hash: nix::get_store_hash(&final_path).await?,
+            hash: nix.store_hash(&final_path).await?,
+            path: final_path,
         },
-    });
+        info,
+    })
 }
 
-async fn resolve_git_path<P>(path: P) -> anyhow::Result<Source>
+async fn resolve_git_path<B, P>(nix: &B, path: P) -> anyhow::Result<Source>
 where
+    B: NixBackend,
     P: AsRef<Path>,
 {
     let path: &Path = path.as_ref();
@@ -185,303 +221,360 @@ where
         );
     }
 
-    let code = format!(
-        "
-		let
-      path = builtins.toPath \"{}\";
-		in
-			builtins.fetchGit path
-	",
-        path.to_str().unwrap()
-    );
-
-    let root = nix::evaluate(
-        &code,
-        nix::EvalOpts {
-            impure: true,
-            json: true,
-        },
-    )
-    .await;
+    let code = Expr::builtin("fetchGit")
+        .apply(Expr::builtin("toPath").apply(Expr::str(path.to_string_lossy())));
 
-    let root_path = match root {
-        Ok(EvalResult::Json(res)) => res.as_str().unwrap().to_string(),
-        Ok(EvalResult::Raw(_)) => {
-            bail!("Got raw, expected JSON");
-        }
-        _ => {
-            bail!("{}", root.unwrap_err());
-        }
-    };
+    let root = nix
+        .evaluate(
+            &code,
+            EvalOpts {
+                impure: true,
+                json: true,
+                ..Default::default()
+            },
+        )
+        .await;
 
-    let store_path = nix::realise(root_path).await;
+    let root_path = fetched_path(root)?;
+
+    let store_path = nix.realise(&root_path).await;
 
     let Ok(paths) = store_path else {
         bail!("{}", store_path.unwrap_err());
     };
 
-    let final_path = paths[0].clone();
+    let final_path = fetched_store_path(nix, &paths)?;
 
-    return Ok(Source::Path {
+    Ok(Source::Path {
         entry: FixedOutputStoreEntry {
-            path: final_path.clone(),
-            hash: nix::get_store_hash(final_path).await?,
+            hash: nix.store_hash(&final_path).await?,
+            path: final_path,
         },
-    });
+    })
 }
 
-async fn resolve_tar(url: &str) -> anyhow::Result<Source> {
+async fn resolve_tar<B: NixBackend>(nix: &B, url: &str, config: &Config) -> anyhow::Result<Source> {
     debug!("Resolving tarball at {url:?}");
-    let code = format!(
-        "
-		builtins.fetchTarball {{
-			url = \"{url}\";
-		}}
-	"
-    );
-
-    let root = nix::evaluate(
-        &code.trim(),
-        nix::EvalOpts {
-            impure: true,
-            json: true,
-        },
-    )
-    .await;
+    let code = Expr::builtin("fetchTarball").apply(Expr::attrs([("url", Expr::str(url))]));
 
-    let root_path = match root {
-        Ok(EvalResult::Json(res)) => res.as_str().unwrap().to_string(),
-        Ok(EvalResult::Raw(_)) => {
-            bail!("Got raw, expected JSON");
-        }
-        _ => {
-            bail!("{}", root.unwrap_err());
-        }
-    };
+    let root = nix
+        .evaluate(
+            &code,
+            EvalOpts {
+                impure: true,
+                json: true,
+                env: fetch_env(config, url)?,
+            },
+        )
+        .await;
+
+    let root_path = fetched_path(root)?;
 
-    let store_path = nix::realise(root_path).await;
+    let store_path = nix.realise(&root_path).await;
 
     let Ok(paths) = store_path else {
         bail!("{}", store_path.unwrap_err());
     };
 
-    return Ok(Source::Tarball {
+    let final_path = fetched_store_path(nix, &paths)?;
+
+    Ok(Source::Tarball {
         url: url.to_string(),
         entry: FixedOutputStoreEntry {
-            path: paths[0].clone(),
-            hash: nix::get_store_hash(&paths[0]).await?,
+            hash: nix.store_hash(&final_path).await?,
+            path: final_path,
         },
-    });
+    })
 }
 
-pub async fn resolve(uri: &str) -> anyhow::Result<Source> {
-    info!("Looking for project at {uri}");
+// Keep what the user typed in error messages, they may not recognise the expansion
+fn shown_path(path: &str, expanded: &Path) -> String {
+    if Path::new(path) == expanded {
+        path.to_string()
+    } else {
+        format!("{path} (expanded to {})", expanded.display())
+    }
+}
 
-    trace!("Trying {uri} as local path");
-    if uri.starts_with(".") || uri.starts_with("/") || uri.starts_with("~") {
-        if let Ok(real_path) = {
-            let Ok(path) = PathBuf::from_str(uri);
-            path.canonicalize()
-        } {
-            debug!("Found path {}", real_path.display());
+// Check a local archive against its expected hash, then unpack it into the store.
+async fn resolve_archive<B: NixBackend>(
+    nix: &B,
+    path: &str,
+    sha256: Option<&str>,
+) -> anyhow::Result<Source> {
+    let expanded = expand_path(path)?;
+    let shown = shown_path(path, &expanded);
+    debug!("Resolving archive at {shown}");
+
+    let Ok(real_path) = expanded.canonicalize() else {
+        bail!("Could not find archive {shown}");
+    };
+    let Some(format) = ArchiveFormat::from_path(&real_path.to_string_lossy()) else {
+        bail!("Unsupported archive {shown}");
+    };
 
-            let dir_path = remove_filename_from_path(real_path.clone());
+    let hash =
+        archive::verify(&real_path, sha256).with_context(|| format!("Could not verify {shown}"))?;
+    debug!("Archive {shown} has hash {hash}");
 
-            let Some(resolved_path) = search_up_for_file(&dir_path, "nilla.nix") else {
-                bail!("Could not find nilla.nix in {dir_path:?}");
-            };
+    let dir = tempfile::Builder::new()
+        .prefix("nilla-archive-")
+        .tempdir()?;
+    let root = archive::unpack(&real_path, format, dir.path()).await?;
 
-            let resolved_dir_path = remove_filename_from_path(resolved_path.clone());
+    let Ok(entry) = nix.add_to_store(&root, Filter::All).await else {
+        bail!("Could not add {shown} to store");
+    };
+    debug!("Added {real_path:?} to store as {}", entry.path);
 
-            let is_git_dir = resolved_dir_path.join(".git").is_dir();
+    Ok(Source::Tarball {
+        url: format!("file://{}", real_path.display()),
+        entry,
+    })
+}
 
-            if is_git_dir {
-                let source = resolve_git_path(&resolved_dir_path).await?;
+// Where a local project lives: the directory to copy into the store, and the
+// directory within it that holds the entry file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalProject {
+    pub root: PathBuf,
+    pub dir: Option<PathBuf>,
+}
 
-                return Ok(source);
-            } else {
-                match nix::add_to_store(&resolved_dir_path).await {
-                    Ok(entry) => {
-                        debug!("Added {real_path:?} to store as {:?}", entry.path);
+// `dir=` and `--file` name something inside the project, never outside it
+fn relative_path<'a>(kind: &str, path: &'a str) -> anyhow::Result<&'a Path> {
+    let relative = Path::new(path);
+    let escapes = relative
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir));
+    if path.is_empty() || escapes {
+        bail!("{kind} must be a relative path inside the project, got `{path}`");
+    }
+    Ok(relative)
+}
 
-                        return Ok(Source::Path { entry });
-                    }
-                    _ => {
-                        bail!("Could not add {real_path:?} to store");
-                    }
-                }
+// The directory an entry file was found in, which is not simply its parent when `file`
+// is in a subdirectory itself, as with `--file hosts/nilla.nix`.
+fn entry_dir(candidate: &Path, file: &str) -> PathBuf {
+    let depth = Path::new(file)
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .count();
+    candidate
+        .ancestors()
+        .nth(depth)
+        .unwrap_or(candidate)
+        .to_path_buf()
+}
+
+// Find the project for a local path. With `dir` the path is taken as the project root,
+// otherwise the entry file is searched for upwards and must be unambiguous.
+pub fn locate_project(path: &str, dir: Option<&str>, file: &str) -> anyhow::Result<LocalProject> {
+    let expanded = expand_path(path)?;
+    let shown = shown_path(path, &expanded);
+    relative_path("The entry file", file)?;
+
+    let Ok(real_path) = expanded.canonicalize() else {
+        bail!("Could not find path {shown}");
+    };
+    debug!("Found path {}", real_path.display());
+
+    if let Some(dir) = dir {
+        if !real_path.is_dir() {
+            bail!("{shown} is not a directory, so `dir={dir}` cannot be used with it");
+        }
+        let relative = relative_path("dir", dir)?;
+        if !real_path.join(relative).join(file).is_file() {
+            bail!("Could not find {file} in {shown} (with dir={dir})");
+        }
+        let dir =
+            (relative.components().next().is_some() && dir != ".").then(|| relative.to_path_buf());
+        return Ok(LocalProject {
+            root: real_path,
+            dir,
+        });
+    }
+
+    let dir_path = remove_filename_from_path(real_path.clone());
+    let candidates = search_up_for_files(&dir_path, file);
+
+    match candidates.as_slice() {
+        [] => bail!("Could not find {file} in {shown}"),
+        [found] => Ok(LocalProject {
+            root: entry_dir(found, file),
+            dir: None,
+        }),
+        [.., outermost] => {
+            let root = entry_dir(outermost, file);
+            let choices = candidates
+                .iter()
+                .rev()
+                .map(|candidate| {
+                    let dir = entry_dir(candidate, file);
+                    let relative = dir.strip_prefix(&root).unwrap_or(&dir);
+                    let relative = match relative.to_string_lossy() {
+                        r if r.is_empty() => ".".to_string(),
+                        r => r.into_owned(),
+                    };
+                    format!("\n  path:{}?dir={relative}", root.display())
+                })
+                .collect::<String>();
+            bail!("Found several {file} files above {shown}, choose one with:{choices}")
+        }
+    }
+}
+
+async fn resolve_path<B: NixBackend>(
+    nix: &B,
+    path: &str,
+    dir: Option<&str>,
+    file: &str,
+) -> anyhow::Result<Source> {
+    let shown = shown_path(path, &expand_path(path)?);
+    let project = locate_project(path, dir, file)?;
+
+    let is_git_dir = project.root.join(".git").is_dir();
+
+    let mut source = if is_git_dir {
+        resolve_git_path(nix, &project.root).await?
+    } else {
+        match nix.add_to_store(&project.root, Filter::GitIgnore).await {
+            Ok(entry) => {
+                debug!("Added {:?} to store as {}", project.root, entry.path);
+                Source::Path { entry }
+            }
+            _ => {
+                bail!("Could not add {shown} to store");
             }
-        } else {
-            bail!("Could not find path {uri}");
         }
+    };
+
+    if let (Some(dir), Source::Path { entry }) = (&project.dir, &mut source) {
+        entry.path = entry.path.join(dir)?;
     }
-    if uri.starts_with("path:") {
-        if let Ok(real_path) = {
-            let Ok(path) = PathBuf::from_str(&uri[4..]);
-            path.canonicalize()
-        } {
-            debug!("Found path {}", real_path.display());
 
-            let dir_path = remove_filename_from_path(real_path.clone());
+    Ok(source)
+}
 
-            let Some(resolved_path) = search_up_for_file(&dir_path, "nilla.nix") else {
-                bail!("Could not find nilla.nix in {dir_path:?}");
-            };
+// Resolve a `--project` argument, returning the source along with the system name
+// given in its `#<system>` fragment, if any. `file` is the project's entry file
+// (`nilla.nix` unless `--file` says otherwise).
+pub async fn resolve<B: NixBackend>(
+    ctx: &context::Context<B>,
+    uri: &str,
+    file: &str,
+) -> anyhow::Result<(Source, Option<String>)> {
+    resolve_with(&ctx.nix, &ctx.config, uri, file).await
+}
 
-            let resolved_dir_path = remove_filename_from_path(resolved_path.clone());
+pub async fn resolve_with<B: NixBackend>(
+    nix: &B,
+    config: &Config,
+    uri: &str,
+    file: &str,
+) -> anyhow::Result<(Source, Option<String>)> {
+    info!("Looking for project at {uri}");
 
-            let is_git_dir = resolved_dir_path.join(".git").is_dir();
+    let installable = parse_project(uri, config)?;
 
-            if is_git_dir {
-                let source = resolve_git_path(&resolved_dir_path).await?;
+    Ok((
+        resolve_ref_with(nix, &installable.project, config, file).await?,
+        installable.system,
+    ))
+}
 
-                return Ok(source);
-            } else {
-                match nix::add_to_store(&resolved_path).await {
-                    Ok(entry) => {
-                        debug!("Added {real_path:?} to store as {:?}", entry.path);
-                        return Ok(Source::Path { entry });
-                    }
-                    _ => {
-                        bail!("Could not add {real_path:?} to store");
-                    }
-                }
-            }
-        } else {
-            bail!("Could not find path {}", &uri[4..])
-        };
+// Parse a `--project` argument, looking it up in the registry before treating it as
+// a reference.
+pub fn parse_project(uri: &str, config: &Config) -> anyhow::Result<Installable> {
+    match registry::expand(uri, config) {
+        Some(expanded) => {
+            debug!("Using registry entry {uri} -> {expanded}");
+            expanded
+                .parse()
+                .map_err(|e| anyhow!("Invalid registry entry {uri} ({expanded}): {e}"))
+        }
+        None => Ok(uri.parse()?),
     }
+}
 
-    trace!("Trying as URL");
-    if uri.starts_with("git:") {
-        trace!("matched as git");
-        let url = Url::parse(uri).unwrap();
-        let qps = url.query_pairs();
-        let info = GitInfo {
-            url: url.path().to_string(),
-            rev: qps
-                .clone()
-                .find(|(k, _)| k == "rev")
-                .map(|(_, v)| v.to_string()),
-            r#ref: qps
-                .clone()
-                .find(|(k, _)| k == "ref")
-                .map(|(_, v)| v.to_string()),
-            dir: qps
-                .clone()
-                .find(|(k, _)| k == "dir")
-                .map(|(_, v)| v.to_string()),
-            submodules: qps
-                .clone()
-                .find(|(k, _)| k == "submodules")
-                .unwrap_or(("".into(), "false".into()))
-                .1
-                .to_string()
-                == "true",
-        };
-        return resolve_git(info).await;
-    } else if uri.starts_with("github:") {
-        trace!("matched as github");
-        let url = Url::parse(&format!("github://{}", &uri[7..])).unwrap();
-        let mut parsed = url
-            .path_segments()
-            .ok_or_else(|| anyhow!("cannot be base"))?;
-        let owner = url.host().unwrap().to_string();
-        let repo = parsed
-            .next()
-            .ok_or_else(|| anyhow!("could not get repo"))?
-            .to_string();
-
-        let qps = url.query_pairs();
-
-        let info = GitXInfo {
-            owner,
-            repo,
-            r#ref: qps
-                .clone()
-                .find(|(k, _)| k == "ref")
-                .map(|(_, v)| v.to_string()),
-            rev: qps
-                .clone()
-                .find(|(k, _)| k == "rev")
-                .map(|(_, v)| v.to_string()),
-            dir: qps
-                .clone()
-                .find(|(k, _)| k == "dir")
-                .map(|(_, v)| v.to_string()),
-            host: qps
-                .clone()
-                .find(|(k, _)| k == "host")
-                .or(Some((Cow::from(""), Cow::from("github.com"))))
-                .unwrap()
-                .1
-                .to_string(),
-            submodules: qps
-                .clone()
-                .find(|(k, _)| k == "submodules")
-                .unwrap_or(("".into(), "false".into()))
-                .1
-                .to_string()
-                == "true",
-        };
-        return resolve_git(info.into()).await;
-    } else if uri.starts_with("gitlab:") {
-        trace!("matched as gitlab");
-        let url = Url::parse(&format!("gitlab://{}", &uri[7..])).unwrap();
-        let mut parsed = url
-            .path_segments()
-            .ok_or_else(|| anyhow!("cannot be base"))?;
-        let owner = url.host().unwrap().to_string();
-        let repo = parsed
-            .next()
-            .ok_or_else(|| anyhow!("could not get repo"))?
-            .to_string();
-
-        let qps = url.query_pairs();
-
-        let info = GitXInfo {
+// The git checkout a reference points at, if it is a git reference at all. Forge
+// shorthands are turned into a full URL using the transport configured for their host.
+pub fn git_info(project_ref: &ProjectRef, config: &Config) -> Option<GitInfo> {
+    let with_params = |url: String, params: &GitParams| GitInfo {
+        url,
+        rev: params.rev.clone(),
+        r#ref: params.r#ref.clone(),
+        dir: params.dir.clone(),
+        submodules: params.submodules,
+    };
+
+    match project_ref {
+        ProjectRef::Git { url, params } => Some(with_params(url.clone(), params)),
+        ProjectRef::GitUrl { url, params, .. } => Some(with_params(url.clone(), params)),
+        ProjectRef::Forge {
+            forge,
             owner,
             repo,
-            r#ref: qps
-                .clone()
-                .find(|(k, _)| k == "ref")
-                .map(|(_, v)| v.to_string()),
-            rev: qps
-                .clone()
-                .find(|(k, _)| k == "rev")
-                .map(|(_, v)| v.to_string()),
-            dir: qps
-                .clone()
-                .find(|(k, _)| k == "dir")
-                .map(|(_, v)| v.to_string()),
-            host: qps
-                .clone()
-                .find(|(k, _)| k == "host")
-                .or(Some((Cow::from(""), Cow::from("gitlab.com"))))
-                .unwrap()
-                .1
-                .to_string(),
-            submodules: qps
-                .clone()
-                .find(|(k, _)| k == "submodules")
-                .unwrap_or(("".into(), "false".into()))
-                .1
-                .to_string()
-                == "true",
-        };
-
-        return resolve_git(info.into()).await;
-    } else if uri.starts_with("tarball:") {
-        trace!("matched as tarball");
-        let mut minus_tar = uri[8..].to_string();
-        if !minus_tar.starts_with("http://") && !minus_tar.starts_with("https://") {
-            minus_tar = format!("http://{minus_tar}");
+            host,
+            params,
+        } => {
+            let info = GitXInfo {
+                owner: owner.clone(),
+                repo: repo.clone(),
+                rev: params.rev.clone(),
+                r#ref: params.r#ref.clone(),
+                dir: params.dir.clone(),
+                host: host.clone().or(forge.default_host().map(str::to_string))?,
+                submodules: params.submodules,
+            };
+            let transport = config
+                .host(&info.host)
+                .map_or(Transport::Ssh, HostConfig::transport);
+            let mut info = info.into_git_info(transport);
+            // sourcehut serves repositories without the `.git` suffix
+            if *forge == Forge::Sourcehut {
+                info.url.truncate(info.url.len() - ".git".len());
+            }
+            Some(info)
+        }
+        ProjectRef::Path { .. } | ProjectRef::Tarball { .. } | ProjectRef::Archive { .. } => None,
+    }
+}
+
+pub async fn resolve_ref<B: NixBackend>(
+    ctx: &context::Context<B>,
+    project_ref: &ProjectRef,
+    file: &str,
+) -> anyhow::Result<Source> {
+    resolve_ref_with(&ctx.nix, project_ref, &ctx.config, file).await
+}
+
+pub async fn resolve_ref_with<B: NixBackend>(
+    nix: &B,
+    project_ref: &ProjectRef,
+    config: &Config,
+    file: &str,
+) -> anyhow::Result<Source> {
+    trace!("Resolving {project_ref:?}");
+
+    match project_ref {
+        ProjectRef::Path { path, dir, .. } => resolve_path(nix, path, dir.as_deref(), file).await,
+        ProjectRef::Git { .. } | ProjectRef::GitUrl { .. } | ProjectRef::Forge { .. } => {
+            let info = git_info(project_ref, config)
+                .ok_or_else(|| anyhow!("Could not work out a git URL for {project_ref}"))?;
+            resolve_git(nix, info, config).await
+        }
+        ProjectRef::Tarball { .. } => {
+            let url = project_ref
+                .tarball_url()
+                .ok_or_else(|| anyhow!("Expected a tarball reference"))?;
+            resolve_tar(nix, &url, config).await
+        }
+        ProjectRef::Archive { sha256, .. } => {
+            let path = project_ref
+                .archive_path()
+                .ok_or_else(|| anyhow!("Expected an archive reference"))?;
+            resolve_archive(nix, path, sha256.as_deref()).await
         }
-        return resolve_tar(&minus_tar).await;
-    } else if uri.starts_with("http://") || uri.starts_with("https://") {
-        trace!("matched as http(s)");
-        return resolve_tar(uri).await;
-    } else {
-        bail!("Could not parse URL Scheme for {uri}")
     }
 }
@@ -0,0 +1,771 @@
+use std::{fmt, str::FromStr};
+
+use url::{Url, form_urlencoded};
+
+use crate::util::{archive::ArchiveFormat, hash::Sha256};
+
+// Parsed form of the `--project` argument. Parsing is kept entirely separate from
+// fetching so that references can be validated (and tested) without calling Nix.
+//
+// Every reference round-trips: `s.parse::<ProjectRef>()?.to_string().parse()` yields
+// the same value, although the string form may be normalised (eg: parameter order).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProjectRef {
+    // A local path, either written bare (`./infra`, `/etc/nixos`, `~/infra`,
+    // `$HOME/infra`) or with an explicit `path:` prefix. Paths are kept as written and
+    // only expanded when resolving. With `?dir=<dir>` the path is the project root and
+    // no search for the entry file happens.
+    Path {
+        path: String,
+        dir: Option<String>,
+        explicit: bool,
+    },
+    // `git:<url>?rev=<rev>&ref=<ref>&submodules=true&dir=<dir>`
+    Git {
+        url: String,
+        params: GitParams,
+    },
+    // Flake-style `git+https://`, `git+ssh://` and `git+file://` URLs. The URL is kept
+    // without the `git+` prefix, exactly as it will be handed to git.
+    GitUrl {
+        scheme: GitScheme,
+        url: String,
+        params: GitParams,
+    },
+    // `github:<owner>/<repo>` and friends, with an optional `host` override (which is
+    // required for self-hosted forges). As with flakes, a ref or rev may also be given
+    // as a third path segment: `github:<owner>/<repo>/<ref-or-rev>`.
+    Forge {
+        forge: Forge,
+        owner: String,
+        repo: String,
+        host: Option<String>,
+        params: GitParams,
+    },
+    // `tarball:<url>`, or a bare `http(s)://` URL.
+    Tarball {
+        url: String,
+        explicit: bool,
+    },
+    // A local archive, either `file:<path>` (or `file:///<path>`) or a bare path ending
+    // in a known archive extension. `sha256` is checked against the archive itself.
+    Archive {
+        path: String,
+        sha256: Option<String>,
+        explicit: bool,
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GitParams {
+    pub rev: Option<String>,
+    pub r#ref: Option<String>,
+    pub dir: Option<String>,
+    pub submodules: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Forge {
+    GitHub,
+    GitLab,
+    Codeberg,
+    Forgejo,
+    Gitea,
+    Sourcehut,
+}
+
+impl Forge {
+    pub fn scheme(self) -> &'static str {
+        match self {
+            Forge::GitHub => "github",
+            Forge::GitLab => "gitlab",
+            Forge::Codeberg => "codeberg",
+            Forge::Forgejo => "forgejo",
+            Forge::Gitea => "gitea",
+            Forge::Sourcehut => "sourcehut",
+        }
+    }
+
+    // Self-hosted forges have no sensible default and need an explicit `host`.
+    pub fn default_host(self) -> Option<&'static str> {
+        match self {
+            Forge::GitHub => Some("github.com"),
+            Forge::GitLab => Some("gitlab.com"),
+            Forge::Codeberg => Some("codeberg.org"),
+            Forge::Sourcehut => Some("git.sr.ht"),
+            Forge::Forgejo | Forge::Gitea => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GitScheme {
+    Http,
+    Https,
+    Ssh,
+    File,
+}
+
+impl GitScheme {
+    pub fn scheme(self) -> &'static str {
+        match self {
+            GitScheme::Http => "git+http",
+            GitScheme::Https => "git+https",
+            GitScheme::Ssh => "git+ssh",
+            GitScheme::File => "git+file",
+        }
+    }
+
+    // The URL scheme git itself expects.
+    pub fn transport(self) -> &'static str {
+        match self {
+            GitScheme::Http => "http",
+            GitScheme::Https => "https",
+            GitScheme::Ssh => "ssh",
+            GitScheme::File => "file",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProjectRefError {
+    Empty,
+    MissingScheme {
+        input: String,
+    },
+    UnknownScheme {
+        scheme: String,
+        suggestion: Option<&'static str>,
+    },
+    Missing {
+        scheme: &'static str,
+        component: &'static str,
+    },
+    UnexpectedSegment {
+        scheme: &'static str,
+        segment: String,
+    },
+    UnknownParameter {
+        scheme: &'static str,
+        name: String,
+        suggestion: Option<&'static str>,
+    },
+    DuplicateParameter {
+        name: String,
+    },
+    InvalidParameter {
+        name: &'static str,
+        value: String,
+        expected: &'static str,
+    },
+    InvalidUrl {
+        url: String,
+        reason: String,
+    },
+    MissingSystem,
+    UnsupportedArchive {
+        path: String,
+    },
+}
+
+impl fmt::Display for ProjectRefError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProjectRefError::Empty => write!(f, "Project reference is empty"),
+            ProjectRefError::MissingScheme { input } => write!(
+                f,
+                "Could not parse URL scheme for {input} (prefix local paths with `./` or `path:`)"
+            ),
+            ProjectRefError::UnknownScheme { scheme, suggestion } => {
+                write!(f, "Unknown project scheme `{scheme}:`")?;
+                if let Some(suggestion) = suggestion {
+                    write!(f, ", did you mean `{suggestion}:`?")?;
+                }
+                Ok(())
+            }
+            ProjectRefError::Missing { scheme, component } => {
+                write!(f, "Missing {component} in `{scheme}:` reference")
+            }
+            ProjectRefError::UnexpectedSegment { scheme, segment } => {
+                write!(
+                    f,
+                    "Unexpected path segment `{segment}` in `{scheme}:` reference"
+                )
+            }
+            ProjectRefError::UnknownParameter {
+                scheme,
+                name,
+                suggestion,
+            } => {
+                write!(f, "Unknown parameter `{name}` for `{scheme}:` reference")?;
+                if let Some(suggestion) = suggestion {
+                    write!(f, ", did you mean `{suggestion}`?")?;
+                }
+                Ok(())
+            }
+            ProjectRefError::DuplicateParameter { name } => {
+                write!(f, "Parameter `{name}` was given more than once")
+            }
+            ProjectRefError::InvalidParameter {
+                name,
+                value,
+                expected,
+            } => write!(
+                f,
+                "Invalid value `{value}` for `{name}`, expected {expected}"
+            ),
+            ProjectRefError::InvalidUrl { url, reason } => {
+                write!(f, "Invalid URL `{url}`: {reason}")
+            }
+            ProjectRefError::MissingSystem => write!(f, "Missing system name after `#`"),
+            ProjectRefError::UnsupportedArchive { path } => {
+                write!(f, "Unsupported archive `{path}`, expected one of: ")?;
+                let extensions = ArchiveFormat::extensions().collect::<Vec<_>>();
+                write!(f, "{}", extensions.join(", "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProjectRefError {}
+
+const SCHEMES: &[&str] = &[
+    "path",
+    "git",
+    "git+http",
+    "git+https",
+    "git+ssh",
+    "git+file",
+    "github",
+    "gitlab",
+    "codeberg",
+    "forgejo",
+    "gitea",
+    "sourcehut",
+    "tarball",
+    "file",
+    "http",
+    "https",
+];
+
+const GIT_PARAMS: &[&str] = &["rev", "ref", "dir", "submodules"];
+const FORGE_PARAMS: &[&str] = &["rev", "ref", "dir", "host", "submodules"];
+const ARCHIVE_PARAMS: &[&str] = &["sha256"];
+const PATH_PARAMS: &[&str] = &["dir"];
+
+// Find the closest candidate to a misspelled name, if any is close enough to be a
+// plausible typo.
+pub(crate) fn suggest(input: &str, candidates: &[&'static str]) -> Option<&'static str> {
+    candidates
+        .iter()
+        .map(|c| (strsim::damerau_levenshtein(input, c), *c))
+        .filter(|(distance, c)| *distance <= 2 && *distance < c.len())
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, c)| c)
+}
+
+fn split_query(input: &str) -> (&str, Option<&str>) {
+    match input.split_once('?') {
+        Some((base, query)) => (base, Some(query)),
+        None => (input, None),
+    }
+}
+
+struct Params {
+    pairs: Vec<(String, String)>,
+}
+
+impl Params {
+    fn parse(
+        scheme: &'static str,
+        query: Option<&str>,
+        allowed: &[&'static str],
+    ) -> Result<Self, ProjectRefError> {
+        let mut pairs: Vec<(String, String)> = vec![];
+
+        for (name, value) in form_urlencoded::parse(query.unwrap_or("").as_bytes()) {
+            if !allowed.contains(&name.as_ref()) {
+                return Err(ProjectRefError::UnknownParameter {
+                    scheme,
+                    suggestion: suggest(&name, allowed),
+                    name: name.into_owned(),
+                });
+            }
+            if pairs.iter().any(|(n, _)| *n == name) {
+                return Err(ProjectRefError::DuplicateParameter {
+                    name: name.into_owned(),
+                });
+            }
+            pairs.push((name.into_owned(), value.into_owned()));
+        }
+
+        Ok(Self { pairs })
+    }
+
+    fn take(&mut self, name: &str) -> Option<String> {
+        let index = self.pairs.iter().position(|(n, _)| n == name)?;
+        Some(self.pairs.remove(index).1)
+    }
+
+    fn take_bool(&mut self, name: &'static str) -> Result<bool, ProjectRefError> {
+        match self.take(name).as_deref() {
+            None | Some("false") | Some("0") => Ok(false),
+            Some("true") | Some("1") => Ok(true),
+            Some(value) => Err(ProjectRefError::InvalidParameter {
+                name,
+                value: value.to_string(),
+                expected: "`true` or `false`",
+            }),
+        }
+    }
+
+    fn take_git(&mut self) -> Result<GitParams, ProjectRefError> {
+        Ok(GitParams {
+            rev: self.take("rev"),
+            r#ref: self.take("ref"),
+            dir: self.take("dir"),
+            submodules: self.take_bool("submodules")?,
+        })
+    }
+}
+
+fn parse_git(rest: &str) -> Result<ProjectRef, ProjectRefError> {
+    let (url, query) = split_query(rest);
+    if url.is_empty() {
+        return Err(ProjectRefError::Missing {
+            scheme: "git",
+            component: "url",
+        });
+    }
+
+    let params = Params::parse("git", query, GIT_PARAMS)?.take_git()?;
+
+    Ok(ProjectRef::Git {
+        url: url.to_string(),
+        params,
+    })
+}
+
+fn parse_git_url(scheme: GitScheme, rest: &str) -> Result<ProjectRef, ProjectRefError> {
+    let (rest, query) = split_query(rest);
+    let url = format!("{}:{rest}", scheme.transport());
+
+    let parsed = Url::parse(&url).map_err(|e| ProjectRefError::InvalidUrl {
+        url: url.clone(),
+        reason: e.to_string(),
+    })?;
+    match scheme {
+        GitScheme::File => {
+            if parsed.path().is_empty() || parsed.path() == "/" {
+                return Err(ProjectRefError::Missing {
+                    scheme: scheme.scheme(),
+                    component: "path",
+                });
+            }
+        }
+        _ => {
+            if parsed.host_str().is_none_or(str::is_empty) {
+                return Err(ProjectRefError::Missing {
+                    scheme: scheme.scheme(),
+                    component: "host",
+                });
+            }
+        }
+    }
+
+    let params = Params::parse(scheme.scheme(), query, GIT_PARAMS)?.take_git()?;
+
+    Ok(ProjectRef::GitUrl {
+        scheme,
+        url: parsed.to_string(),
+        params,
+    })
+}
+
+fn parse_forge(forge: Forge, rest: &str) -> Result<ProjectRef, ProjectRefError> {
+    let scheme = forge.scheme();
+    let (path, query) = split_query(rest);
+    let mut segments = path.split('/');
+
+    let owner = segments.next().filter(|s| !s.is_empty());
+    let Some(owner) = owner else {
+        return Err(ProjectRefError::Missing {
+            scheme,
+            component: "owner",
+        });
+    };
+    let repo = segments.next().filter(|s| !s.is_empty());
+    let Some(repo) = repo else {
+        return Err(ProjectRefError::Missing {
+            scheme,
+            component: "repository",
+        });
+    };
+    // Anything after the repository is a ref (which may itself contain slashes) or a rev
+    let rest: Vec<&str> = segments.collect();
+    if rest.len() > 1 && rest.iter().any(|s| s.is_empty()) {
+        return Err(ProjectRefError::UnexpectedSegment {
+            scheme,
+            segment: rest.join("/"),
+        });
+    }
+    let ref_or_rev = rest.join("/");
+
+    let mut params = Params::parse(scheme, query, FORGE_PARAMS)?;
+    let mut git = params.take_git()?;
+    if !ref_or_rev.is_empty() {
+        // Name the parameter that was given along with the path segment
+        let given = match (&git.rev, &git.r#ref) {
+            (Some(_), _) => Some("rev"),
+            (None, Some(_)) => Some("ref"),
+            (None, None) => None,
+        };
+        if let Some(name) = given {
+            return Err(ProjectRefError::DuplicateParameter {
+                name: name.to_string(),
+            });
+        }
+        if is_rev(&ref_or_rev) {
+            git.rev = Some(ref_or_rev);
+        } else {
+            git.r#ref = Some(ref_or_rev);
+        }
+    }
+
+    let host = params.take("host");
+    if host.is_none() && forge.default_host().is_none() {
+        return Err(ProjectRefError::Missing {
+            scheme,
+            component: "host",
+        });
+    }
+
+    Ok(ProjectRef::Forge {
+        forge,
+        owner: owner.to_string(),
+        repo: repo.to_string(),
+        host,
+        params: git,
+    })
+}
+
+// Full SHA-1 or SHA-256 commit hashes, the same rule flakes use to tell a rev from a
+// ref in `github:<owner>/<repo>/<ref-or-rev>`.
+fn is_rev(s: &str) -> bool {
+    matches!(s.len(), 40 | 64) && s.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+}
+
+fn parse_tarball(url: &str, explicit: bool) -> Result<ProjectRef, ProjectRefError> {
+    if url.is_empty() {
+        return Err(ProjectRefError::Missing {
+            scheme: "tarball",
+            component: "url",
+        });
+    }
+
+    // Local archives used to be turned into `http://./...`, which was never useful
+    if let Some(path) = url.strip_prefix("file:") {
+        return parse_archive(path, true);
+    }
+    if is_bare_path(url) {
+        return parse_archive(url, true);
+    }
+
+    let project_ref = ProjectRef::Tarball {
+        url: url.to_string(),
+        explicit,
+    };
+
+    // The URL's own query string belongs to the download, so it is not validated
+    // against a parameter list, but it does need to be a URL Nix can fetch.
+    let fetch_url = project_ref.tarball_url().unwrap();
+    if let Err(e) = Url::parse(&fetch_url) {
+        return Err(ProjectRefError::InvalidUrl {
+            url: fetch_url,
+            reason: e.to_string(),
+        });
+    }
+
+    Ok(project_ref)
+}
+
+fn parse_path(rest: &str, explicit: bool) -> Result<ProjectRef, ProjectRefError> {
+    let (path, query) = split_query(rest);
+    if path.is_empty() {
+        return Err(ProjectRefError::Missing {
+            scheme: "path",
+            component: "path",
+        });
+    }
+
+    let dir = Params::parse("path", query, PATH_PARAMS)?.take("dir");
+
+    Ok(ProjectRef::Path {
+        path: path.to_string(),
+        dir,
+        explicit,
+    })
+}
+
+fn parse_archive(rest: &str, explicit: bool) -> Result<ProjectRef, ProjectRefError> {
+    let (path, query) = split_query(rest);
+    if path.is_empty() || path == "//" {
+        return Err(ProjectRefError::Missing {
+            scheme: "file",
+            component: "path",
+        });
+    }
+    let authority = path
+        .strip_prefix("//")
+        .filter(|p| explicit && !p.starts_with('/'));
+    if let Some(authority) = authority {
+        return Err(ProjectRefError::InvalidUrl {
+            url: format!("file:{path}"),
+            reason: format!(
+                "only local files are supported, not host `{}`",
+                authority.split('/').next().unwrap_or(authority)
+            ),
+        });
+    }
+    if ArchiveFormat::from_path(path).is_none() {
+        return Err(ProjectRefError::UnsupportedArchive {
+            path: path.to_string(),
+        });
+    }
+
+    let mut params = Params::parse("file", query, ARCHIVE_PARAMS)?;
+    // SRI hashes contain `+`, which query decoding would otherwise turn into a space
+    let sha256 = params.take("sha256").map(|hash| hash.replace(' ', "+"));
+    if let Some(hash) = sha256
+        .as_ref()
+        .filter(|hash| hash.parse::<Sha256>().is_err())
+    {
+        return Err(ProjectRefError::InvalidParameter {
+            name: "sha256",
+            value: hash.clone(),
+            expected: "a SHA-256 hash in hex, base-32 or SRI form",
+        });
+    }
+
+    Ok(ProjectRef::Archive {
+        path: path.to_string(),
+        sha256,
+        explicit,
+    })
+}
+
+fn is_bare_path(s: &str) -> bool {
+    s.starts_with('.') || s.starts_with('/') || s.starts_with('~') || s.starts_with('$')
+}
+
+fn is_scheme(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+}
+
+impl FromStr for ProjectRef {
+    type Err = ProjectRefError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err(ProjectRefError::Empty);
+        }
+
+        if is_bare_path(s) {
+            if ArchiveFormat::from_path(split_query(s).0).is_some() {
+                return parse_archive(s, false);
+            }
+            return parse_path(s, false);
+        }
+
+        if s.starts_with("http://") || s.starts_with("https://") {
+            return parse_tarball(s, false);
+        }
+
+        let Some((scheme, rest)) = s.split_once(':').filter(|(scheme, _)| is_scheme(scheme)) else {
+            return Err(ProjectRefError::MissingScheme {
+                input: s.to_string(),
+            });
+        };
+
+        match scheme {
+            "path" => parse_path(rest, true),
+            "git" => parse_git(rest),
+            "git+http" => parse_git_url(GitScheme::Http, rest),
+            "git+https" => parse_git_url(GitScheme::Https, rest),
+            "git+ssh" => parse_git_url(GitScheme::Ssh, rest),
+            "git+file" => parse_git_url(GitScheme::File, rest),
+            "github" => parse_forge(Forge::GitHub, rest),
+            "gitlab" => parse_forge(Forge::GitLab, rest),
+            "codeberg" => parse_forge(Forge::Codeberg, rest),
+            "forgejo" => parse_forge(Forge::Forgejo, rest),
+            "gitea" => parse_forge(Forge::Gitea, rest),
+            "sourcehut" => parse_forge(Forge::Sourcehut, rest),
+            "tarball" => parse_tarball(rest, true),
+            "file" => parse_archive(rest, true),
+            _ => Err(ProjectRefError::UnknownScheme {
+                scheme: scheme.to_string(),
+                suggestion: suggest(scheme, SCHEMES),
+            }),
+        }
+    }
+}
+
+fn write_params(f: &mut fmt::Formatter<'_>, pairs: &[(&str, Option<&str>)]) -> fmt::Result {
+    let mut query = form_urlencoded::Serializer::new(String::new());
+    let mut empty = true;
+    for (name, value) in pairs {
+        if let Some(value) = value {
+            query.append_pair(name, value);
+            empty = false;
+        }
+    }
+    if empty {
+        Ok(())
+    } else {
+        write!(f, "?{}", query.finish())
+    }
+}
+
+fn git_param_pairs<'a>(
+    params: &'a GitParams,
+    host: Option<&'a str>,
+) -> [(&'static str, Option<&'a str>); 5] {
+    [
+        ("rev", params.rev.as_deref()),
+        ("ref", params.r#ref.as_deref()),
+        ("dir", params.dir.as_deref()),
+        ("host", host),
+        ("submodules", params.submodules.then_some("true")),
+    ]
+}
+
+impl fmt::Display for ProjectRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProjectRef::Path {
+                path,
+                dir,
+                explicit,
+            } => {
+                if *explicit {
+                    write!(f, "path:{path}")?;
+                } else {
+                    write!(f, "{path}")?;
+                }
+                write_params(f, &[("dir", dir.as_deref())])
+            }
+            ProjectRef::Git { url, params } => {
+                write!(f, "git:{url}")?;
+                write_params(f, &git_param_pairs(params, None))
+            }
+            ProjectRef::GitUrl { url, params, .. } => {
+                write!(f, "git+{url}")?;
+                write_params(f, &git_param_pairs(params, None))
+            }
+            ProjectRef::Forge {
+                forge,
+                owner,
+                repo,
+                host,
+                params,
+            } => {
+                write!(f, "{}:{owner}/{repo}", forge.scheme())?;
+                write_params(f, &git_param_pairs(params, host.as_deref()))
+            }
+            ProjectRef::Tarball { url, explicit } => {
+                if *explicit {
+                    write!(f, "tarball:{url}")
+                } else {
+                    write!(f, "{url}")
+                }
+            }
+            ProjectRef::Archive {
+                path,
+                sha256,
+                explicit,
+            } => {
+                if *explicit {
+                    write!(f, "file:{path}")?;
+                } else {
+                    write!(f, "{path}")?;
+                }
+                write_params(f, &[("sha256", sha256.as_deref())])
+            }
+        }
+    }
+}
+
+impl ProjectRef {
+    // The URL to hand to `builtins.fetchTarball`. Tarball references without a protocol
+    // default to plain `http://`.
+    pub fn tarball_url(&self) -> Option<String> {
+        match self {
+            ProjectRef::Tarball { url, .. } => {
+                if url.starts_with("http://") || url.starts_with("https://") {
+                    Some(url.clone())
+                } else {
+                    Some(format!("http://{url}"))
+                }
+            }
+            _ => None,
+        }
+    }
+
+    // The local path of an archive reference, with the `//` of `file:///` URLs removed.
+    // Like other local paths it still needs expanding.
+    pub fn archive_path(&self) -> Option<&str> {
+        match self {
+            ProjectRef::Archive { path, .. } => Some(path.strip_prefix("//").unwrap_or(path)),
+            _ => None,
+        }
+    }
+}
+
+// A project reference with an optional `#<system>` fragment naming the system to
+// use, eg: `github:org/infra/main#web01`. The fragment is split off before the
+// reference itself is parsed, so it works with every kind of source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Installable {
+    pub project: ProjectRef,
+    pub system: Option<String>,
+}
+
+impl FromStr for Installable {
+    type Err = ProjectRefError;
+
+    // URLs and paths may contain `#` themselves, so only what follows the last one is
+    // the system, and only if it could be a system name.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (project, system) = match s.rsplit_once('#') {
+            Some((_, "")) => return Err(ProjectRefError::MissingSystem),
+            Some((project, system)) if is_system_name(system) => {
+                (project, Some(system.to_string()))
+            }
+            _ => (s, None),
+        };
+
+        Ok(Installable {
+            project: project.parse()?,
+            system,
+        })
+    }
+}
+
+// Host names, which is what systems are usually named after
+fn is_system_name(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+impl fmt::Display for Installable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.project)?;
+        if let Some(system) = &self.system {
+            write!(f, "#{system}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,76 @@
+use anyhow::{Result, anyhow, bail};
+use url::form_urlencoded;
+
+use crate::util::{config::Config, project_ref::Installable};
+
+// Registry names are plain words, so they can never be mistaken for a path or a
+// `scheme:` reference.
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphanumeric())
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+}
+
+// Add (or replace) an entry, returning the normalised reference that was stored.
+pub fn add(config: &mut Config, name: &str, project: &str) -> Result<String> {
+    if !is_valid_name(name) {
+        bail!("Invalid registry name `{name}` (use letters, digits, `-` and `_`)");
+    }
+
+    let installable: Installable = project
+        .parse()
+        .map_err(|e| anyhow!("Invalid project {project}: {e}"))?;
+    let project = installable.to_string();
+
+    config.registry.insert(name.to_string(), project.clone());
+    Ok(project)
+}
+
+pub fn remove(config: &mut Config, name: &str) -> Result<String> {
+    config
+        .registry
+        .remove(name)
+        .ok_or_else(|| anyhow!("No registry entry named `{name}`"))
+}
+
+fn split_at(input: &str, delimiter: char) -> (&str, Option<&str>) {
+    match input.split_once(delimiter) {
+        Some((base, rest)) => (base, Some(rest)),
+        None => (input, None),
+    }
+}
+
+// Expand `<name>[?<params>][#<system>]` using the registry. Parameters and the system
+// given here override the defaults stored with the entry. Returns `None` when `input`
+// does not name an entry, in which case it should be parsed as a reference.
+pub fn expand(input: &str, config: &Config) -> Option<String> {
+    let (input, system) = split_at(input, '#');
+    let (name, query) = split_at(input, '?');
+    let entry = config.registry.get(name)?;
+
+    let (entry, entry_system) = split_at(entry, '#');
+    let (base, entry_query) = split_at(entry, '?');
+
+    let overrides: Vec<(String, String)> = form_urlencoded::parse(query.unwrap_or("").as_bytes())
+        .into_owned()
+        .collect();
+    let defaults = form_urlencoded::parse(entry_query.unwrap_or("").as_bytes())
+        .into_owned()
+        .filter(|(name, _)| !overrides.iter().any(|(n, _)| n == name));
+
+    let mut params = form_urlencoded::Serializer::new(String::new());
+    params.extend_pairs(defaults.chain(overrides.iter().cloned()));
+    let params = params.finish();
+
+    let mut expanded = base.to_string();
+    if !params.is_empty() {
+        expanded.push('?');
+        expanded.push_str(&params);
+    }
+    if let Some(system) = system.or(entry_system) {
+        expanded.push('#');
+        expanded.push_str(system);
+    }
+
+    Some(expanded)
+}
@@ -34,3 +34,24 @@ where
         }
     }
 }
+
+// Every `file` from `start` upwards, nearest first. The search stops at the root of the
+// enclosing git repository, if there is one, as a project never spans repositories.
+pub fn search_up_for_files<P>(start: P, file: &str) -> Vec<PathBuf>
+where
+    P: Into<PathBuf>,
+{
+    let mut current: PathBuf = start.into();
+    trace!("Searching up for all files '{file}' starting at {current:?}");
+    let mut found = vec![];
+    loop {
+        let candidate = current.join(file);
+        if candidate.is_file() {
+            found.push(candidate);
+        }
+        // `.git` is a file in worktrees and submodules
+        if current.join(".git").exists() || !current.pop() {
+            return found;
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use std::{
+    fmt,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::util::hash::{self, NIX32_ALPHABET, Sha256};
+
+// Nix refuses longer names, as they would not fit in a file name with the hash.
+const MAX_NAME_LEN: usize = 211;
+
+// The store directory in use, `/nix/store` unless `NIX_STORE_DIR` says otherwise.
+pub fn store_dir() -> PathBuf {
+    std::env::var_os("NIX_STORE_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/nix/store"))
+}
+
+// A path in the Nix store, split into the store directory, the entry's hash and name,
+// and the subdirectory within the entry when the path points inside one:
+//
+//   /nix/store/lplzlyk8ldz821dl6pmlhk3md1ms69md-source/hosts/prod
+//   ^ store    ^ hash                           ^ name ^ subpath
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StorePath {
+    store_dir: PathBuf,
+    hash: String,
+    name: String,
+    subpath: PathBuf,
+    path: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StorePathError {
+    NotInStore { path: PathBuf, store_dir: PathBuf },
+    InvalidHash { path: PathBuf },
+    InvalidName { name: String },
+    InvalidSubpath { subpath: PathBuf },
+}
+
+impl fmt::Display for StorePathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorePathError::NotInStore { path, store_dir } => {
+                write!(
+                    f,
+                    "{} is not in the Nix store ({})",
+                    path.display(),
+                    store_dir.display()
+                )
+            }
+            StorePathError::InvalidHash { path } => {
+                write!(
+                    f,
+                    "{} does not start with a valid store path hash",
+                    path.display()
+                )
+            }
+            StorePathError::InvalidName { name } => {
+                write!(f, "Invalid store path name `{name}`")
+            }
+            StorePathError::InvalidSubpath { subpath } => {
+                write!(
+                    f,
+                    "Invalid path inside a store path `{}`",
+                    subpath.display()
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for StorePathError {}
+
+fn is_valid_hash(hash: &str) -> bool {
+    hash.len() == 32 && hash.bytes().all(|b| NIX32_ALPHABET.contains(&b))
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name != "."
+        && name != ".."
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"+-._?=".contains(&b))
+}
+
+fn relative_subpath(subpath: &Path) -> Result<PathBuf, StorePathError> {
+    let mut relative = PathBuf::new();
+    for component in subpath.components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::CurDir => {}
+            _ => {
+                return Err(StorePathError::InvalidSubpath {
+                    subpath: subpath.to_path_buf(),
+                });
+            }
+        }
+    }
+    Ok(relative)
+}
+
+impl StorePath {
+    fn new(store_dir: &Path, hash: &str, name: &str, subpath: PathBuf) -> Self {
+        let mut path = store_dir.join(format!("{hash}-{name}"));
+        // Joining an empty subpath would leave a trailing `/`
+        if !subpath.as_os_str().is_empty() {
+            path.push(&subpath);
+        }
+        StorePath {
+            store_dir: store_dir.to_path_buf(),
+            hash: hash.to_string(),
+            name: name.to_string(),
+            subpath,
+            path,
+        }
+    }
+
+    // Parse a path in the store directory in use (see `store_dir`).
+    pub fn parse<P: AsRef<Path>>(path: P) -> Result<Self, StorePathError> {
+        StorePath::parse_in(&store_dir(), path)
+    }
+
+    pub fn parse_in<P: AsRef<Path>>(store_dir: &Path, path: P) -> Result<Self, StorePathError> {
+        let path = path.as_ref();
+        let not_in_store = || StorePathError::NotInStore {
+            path: path.to_path_buf(),
+            store_dir: store_dir.to_path_buf(),
+        };
+
+        let relative = path.strip_prefix(store_dir).map_err(|_| not_in_store())?;
+        let mut components = relative.components();
+        let Some(Component::Normal(entry)) = components.next() else {
+            return Err(not_in_store());
+        };
+
+        let entry = entry.to_string_lossy();
+        let Some((hash, name)) = entry
+            .split_once('-')
+            .filter(|(hash, _)| is_valid_hash(hash))
+        else {
+            return Err(StorePathError::InvalidHash {
+                path: path.to_path_buf(),
+            });
+        };
+        if !is_valid_name(name) {
+            return Err(StorePathError::InvalidName {
+                name: name.to_string(),
+            });
+        }
+
+        let subpath = relative_subpath(components.as_path())?;
+        Ok(StorePath::new(store_dir, hash, name, subpath))
+    }
+
+    // The path `nix-store --recursive --add-fixed sha256` gives a directory called `name`
+    // whose NAR hash is `nar_hash`. Nix hashes a fingerprint of the contents and the name,
+    // then folds the digest down to 20 bytes for the base-32 part of the path.
+    pub fn for_source(
+        store_dir: &Path,
+        name: &str,
+        nar_hash: &Sha256,
+    ) -> Result<Self, StorePathError> {
+        if !is_valid_name(name) {
+            return Err(StorePathError::InvalidName {
+                name: name.to_string(),
+            });
+        }
+
+        let fingerprint = format!(
+            "source:sha256:{}:{}:{name}",
+            nar_hash.to_hex(),
+            store_dir.display()
+        );
+        let digest = Sha256::digest(fingerprint);
+        let mut folded = [0u8; 20];
+        for (i, byte) in digest.bytes().iter().enumerate() {
+            folded[i % 20] ^= byte;
+        }
+
+        Ok(StorePath::new(
+            store_dir,
+            &hash::nix32(&folded),
+            name,
+            PathBuf::new(),
+        ))
+    }
+
+    pub fn store_dir(&self) -> &Path {
+        &self.store_dir
+    }
+
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // The path within the store entry, if this points inside one.
+    pub fn subpath(&self) -> Option<&Path> {
+        (!self.subpath.as_os_str().is_empty()).then_some(self.subpath.as_path())
+    }
+
+    // The store entry itself, without any subpath.
+    pub fn root(&self) -> StorePath {
+        StorePath::new(&self.store_dir, &self.hash, &self.name, PathBuf::new())
+    }
+
+    // A path further inside the same store entry.
+    pub fn join<P: AsRef<Path>>(&self, subpath: P) -> Result<StorePath, StorePathError> {
+        let mut joined = self.subpath.clone();
+        for part in relative_subpath(subpath.as_ref())?.iter() {
+            joined.push(part);
+        }
+        Ok(StorePath::new(
+            &self.store_dir,
+            &self.hash,
+            &self.name,
+            joined,
+        ))
+    }
+
+    pub fn as_path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn to_path_buf(&self) -> PathBuf {
+        self.path.clone()
+    }
+}
+
+impl AsRef<Path> for StorePath {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl fmt::Display for StorePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path.display())
+    }
+}
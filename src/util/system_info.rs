@@ -0,0 +1,201 @@
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::util::{
+    nix::{EvalOpts, EvalResult, NixBackend},
+    nix_expr::Expr,
+};
+
+// Where NixOS links the system it is running, and the one it booted
+pub const CURRENT_SYSTEM: &str = "/run/current-system";
+pub const BOOTED_SYSTEM: &str = "/run/booted-system";
+
+// A summary of an evaluated system (`systems.nixos.<name>.result`).
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SystemInfo {
+    pub nixpkgs: String,
+    // Only known when nixpkgs comes from git or a flake
+    pub revision: Option<String>,
+    pub version: Option<String>,
+    pub release: Option<String>,
+    pub code_name: Option<String>,
+    pub state_version: Option<String>,
+    pub kernel: Option<String>,
+    pub bootloader: Option<String>,
+    pub host_platform: Option<String>,
+    // The modules NixOS ships with, and those the system was given
+    pub base_modules: u64,
+    pub modules: u64,
+    pub enabled_services: Vec<String>,
+    pub drv_path: String,
+    pub out_path: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawInfo {
+    nixpkgs: String,
+    revision: Option<String>,
+    version: Option<String>,
+    release: Option<String>,
+    code_name: Option<String>,
+    state_version: Option<String>,
+    kernel: Option<String>,
+    bootloaders: Vec<(String, bool)>,
+    is_container: bool,
+    host_platform: Option<String>,
+    base_modules: u64,
+    modules: u64,
+    services: serde_json::Map<String, Value>,
+    drv_path: String,
+    out_path: String,
+}
+
+// The bootloader options we know of, in the order they are checked
+const BOOTLOADERS: [(&str, &str); 4] = [
+    ("systemd-boot", "systemd-boot"),
+    ("grub", "GRUB"),
+    ("generic-extlinux-compatible", "extlinux"),
+    ("limine", "Limine"),
+];
+
+fn info_expr(system: &Expr) -> Expr {
+    let config = Expr::var("config");
+    let nixos = config.clone().select(["system", "nixos"]);
+    let args = config.clone().select(["_module", "args"]);
+    let count = |modules: &str| {
+        Expr::builtin("length").apply(args.clone().select_or([modules], Expr::List(vec![])))
+    };
+    let bootloaders = Expr::List(
+        BOOTLOADERS
+            .iter()
+            .map(|(option, _)| {
+                Expr::List(vec![
+                    Expr::str(*option),
+                    config
+                        .clone()
+                        .select_or(["boot", "loader", *option, "enable"], Expr::Bool(false)),
+                ])
+            })
+            .collect(),
+    );
+    // Services without an `enable` option, such as `services.udev`, count as disabled
+    let services = Expr::builtin("mapAttrs")
+        .apply(Expr::lambda(
+            "name",
+            Expr::lambda(
+                "service",
+                Expr::var("service").select_or(["enable"], Expr::Bool(false)),
+            ),
+        ))
+        .apply(config.clone().select(["services"]));
+    let toplevel = config.clone().select(["system", "build", "toplevel"]);
+
+    Expr::let_in(
+        [
+            ("system", system.clone()),
+            ("config", Expr::var("system").select(["config"])),
+        ],
+        Expr::attrs([
+            (
+                "nixpkgs",
+                Expr::builtin("toString").apply(Expr::var("system").select(["pkgs", "path"])),
+            ),
+            (
+                "revision",
+                nixos.clone().select_or(["revision"], Expr::Null),
+            ),
+            ("version", nixos.clone().select_or(["version"], Expr::Null)),
+            ("release", nixos.clone().select_or(["release"], Expr::Null)),
+            ("codeName", nixos.select_or(["codeName"], Expr::Null)),
+            (
+                "stateVersion",
+                config
+                    .clone()
+                    .select_or(["system", "stateVersion"], Expr::Null),
+            ),
+            (
+                "kernel",
+                config
+                    .clone()
+                    .select_or(["boot", "kernelPackages", "kernel", "version"], Expr::Null),
+            ),
+            ("bootloaders", bootloaders),
+            (
+                "isContainer",
+                config
+                    .clone()
+                    .select_or(["boot", "isContainer"], Expr::Bool(false)),
+            ),
+            (
+                "hostPlatform",
+                config
+                    .clone()
+                    .select_or(["nixpkgs", "hostPlatform", "system"], Expr::Null),
+            ),
+            ("baseModules", count("baseModules")),
+            ("modules", count("modules")),
+            ("services", services),
+            ("drvPath", toplevel.clone().select(["drvPath"])),
+            ("outPath", toplevel.select(["outPath"])),
+        ]),
+    )
+}
+
+// Evaluate the summary of `system`. This instantiates the toplevel derivation, but builds
+// nothing.
+pub async fn query_info<B: NixBackend>(nix: &B, system: &Expr) -> Result<SystemInfo> {
+    let opts = EvalOpts {
+        json: true,
+        impure: false,
+        ..Default::default()
+    };
+    let value = match nix.evaluate(&info_expr(system), opts).await? {
+        EvalResult::Json(value) => value,
+        EvalResult::Raw(raw) => bail!("Expected JSON, got {raw}"),
+    };
+    let raw: RawInfo =
+        serde_json::from_value(value).context("Could not read the system's information")?;
+
+    let bootloader = raw
+        .bootloaders
+        .iter()
+        .find(|(_, enabled)| *enabled)
+        .and_then(|(option, _)| BOOTLOADERS.iter().find(|(known, _)| known == option))
+        .map(|(_, name)| name.to_string())
+        .or_else(|| raw.is_container.then(|| "none (container)".to_string()));
+    let enabled_services = raw
+        .services
+        .into_iter()
+        .filter(|(_, enabled)| *enabled == Value::Bool(true))
+        .map(|(name, _)| name)
+        .collect();
+
+    Ok(SystemInfo {
+        nixpkgs: raw.nixpkgs,
+        revision: raw.revision,
+        version: raw.version,
+        release: raw.release,
+        code_name: raw.code_name,
+        state_version: raw.state_version,
+        kernel: raw.kernel,
+        bootloader,
+        host_platform: raw.host_platform,
+        base_modules: raw.base_modules,
+        modules: raw.modules,
+        enabled_services,
+        drv_path: raw.drv_path,
+        out_path: raw.out_path,
+    })
+}
+
+// Whether `link`, such as /run/current-system, points at `toplevel`. `None` when there is
+// no such link, as on a machine that is not running NixOS.
+pub fn links_to(link: &Path, toplevel: &str) -> Option<bool> {
+    let target = std::fs::read_link(link).ok()?;
+    Some(target == Path::new(toplevel))
+}
@@ -0,0 +1,139 @@
+use std::{path::Path, process::Command};
+
+use nilla_nixos::util::{
+    archive::{ArchiveFormat, unpack, verify},
+    hash::Sha256,
+};
+
+fn run(dir: &Path, program: &str, args: &[&str]) {
+    let output = Command::new(program)
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{program} {args:?} failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+// A project checked out as `infra-main/`, the way CI artifacts are usually laid out.
+fn project(root: &Path) {
+    let project = root.join("infra-main");
+    std::fs::create_dir_all(project.join("hosts")).unwrap();
+    std::fs::write(project.join("nilla.nix"), "{ }\n").unwrap();
+    std::fs::write(project.join("hosts/web01.nix"), "{ }\n").unwrap();
+}
+
+fn create(root: &Path, name: &str) {
+    match ArchiveFormat::from_path(name).unwrap() {
+        ArchiveFormat::Zip => run(root, "zip", &["-q", "-r", name, "infra-main"]),
+        ArchiveFormat::Tar => run(root, "tar", &["-cf", name, "infra-main"]),
+        ArchiveFormat::TarGz => run(root, "tar", &["-czf", name, "infra-main"]),
+        ArchiveFormat::TarXz => run(root, "tar", &["-cJf", name, "infra-main"]),
+        ArchiveFormat::TarZst => run(root, "tar", &["--zstd", "-cf", name, "infra-main"]),
+    }
+}
+
+#[test]
+fn formats() {
+    let cases = [
+        ("a.tar", ArchiveFormat::Tar),
+        ("a.tar.gz", ArchiveFormat::TarGz),
+        ("a.tgz", ArchiveFormat::TarGz),
+        ("a.tar.xz", ArchiveFormat::TarXz),
+        ("a.txz", ArchiveFormat::TarXz),
+        ("a.tar.zst", ArchiveFormat::TarZst),
+        ("a.tzst", ArchiveFormat::TarZst),
+        ("A.ZIP", ArchiveFormat::Zip),
+        ("./ci/infra.tar.gz", ArchiveFormat::TarGz),
+    ];
+    for (path, format) in cases {
+        assert_eq!(ArchiveFormat::from_path(path), Some(format), "{path}");
+    }
+    for path in ["a.gz", "a.tar.bz2", "a.rar", "./infra", "a.zip.txt"] {
+        assert_eq!(ArchiveFormat::from_path(path), None, "{path}");
+    }
+}
+
+#[tokio::test]
+async fn unpacks_every_format() {
+    for name in [
+        "infra.tar",
+        "infra.tar.gz",
+        "infra.tar.xz",
+        "infra.tar.zst",
+        "infra.zip",
+    ] {
+        let dir = tempfile::tempdir().unwrap();
+        project(dir.path());
+        create(dir.path(), name);
+
+        let dest = dir.path().join("dest");
+        let format = ArchiveFormat::from_path(name).unwrap();
+        let root = unpack(&dir.path().join(name), format, &dest).await.unwrap();
+
+        // The single top-level directory is unwrapped
+        assert_eq!(root, dest.join("source"), "{name}");
+        assert!(root.join("nilla.nix").is_file(), "{name}");
+        assert!(root.join("hosts/web01.nix").is_file(), "{name}");
+    }
+}
+
+#[tokio::test]
+async fn flat_archives_are_kept_as_is() {
+    let dir = tempfile::tempdir().unwrap();
+    project(dir.path());
+    run(
+        &dir.path().join("infra-main"),
+        "tar",
+        &["-czf", "../flat.tar.gz", "nilla.nix", "hosts"],
+    );
+
+    let root = unpack(
+        &dir.path().join("flat.tar.gz"),
+        ArchiveFormat::TarGz,
+        &dir.path().join("dest"),
+    )
+    .await
+    .unwrap();
+    assert!(root.join("nilla.nix").is_file());
+    assert!(root.join("hosts").is_dir());
+}
+
+#[tokio::test]
+async fn corrupt_archives() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("broken.tar.zst");
+    std::fs::write(&path, "not an archive").unwrap();
+
+    let err = unpack(&path, ArchiveFormat::TarZst, &dir.path().join("dest"))
+        .await
+        .unwrap_err();
+    assert!(err.to_string().starts_with("Could not unpack"), "{err}");
+}
+
+#[test]
+fn verification() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("empty.zip");
+    std::fs::write(&path, "").unwrap();
+
+    let sri = "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=";
+    let hex = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    assert_eq!(verify(&path, None).unwrap().to_sri(), sri);
+    assert!(verify(&path, Some(sri)).is_ok());
+    assert!(verify(&path, Some(hex)).is_ok());
+
+    std::fs::write(&path, "changed").unwrap();
+    let err = verify(&path, Some(hex)).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "Hash mismatch:\n  specified: {sri}\n  got:       {}",
+            Sha256::digest("changed")
+        )
+    );
+    assert!(verify(&dir.path().join("missing.zip"), None).is_err());
+}
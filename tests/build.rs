@@ -0,0 +1,188 @@
+use std::path::{Path, PathBuf};
+
+use nilla_nixos::util::{
+    errors::failed_derivations,
+    nix::{
+        BuildOpts, BuildResult, BuildTuning, NixBackend, NixSettings, build_command,
+        parse_build_outputs,
+    },
+    nix_mock::{Call, MockNix},
+    nix_version::NixVersion,
+};
+
+fn tuning() -> BuildTuning {
+    BuildTuning {
+        keep_going: true,
+        max_jobs: Some("auto".to_string()),
+        cores: Some(4),
+        rebuild: true,
+        options: vec![
+            ("sandbox".to_string(), "relaxed".to_string()),
+            ("substitute".to_string(), "false".to_string()),
+        ],
+    }
+}
+
+#[test]
+fn tuning_flags() {
+    assert!(BuildTuning::default().args().is_empty());
+    assert_eq!(
+        tuning().args(),
+        [
+            "--keep-going",
+            "--max-jobs",
+            "auto",
+            "--cores",
+            "4",
+            "--option",
+            "sandbox",
+            "relaxed",
+            "--option",
+            "substitute",
+            "false"
+        ]
+    );
+
+    let opts = BuildOpts {
+        out_link: Some(PathBuf::from("/tmp/web01")),
+        system: None,
+        tuning: tuning(),
+    };
+    let file = Path::new("/nix/store/x-source/nilla.nix");
+
+    let nix = NixVersion::parse("nix (Nix) 2.24.10").unwrap();
+    let (_, args) = build_command(&nix, &NixSettings::default(), file, "web01", &opts);
+    let expected = [
+        "build",
+        "--json",
+        "--out-link",
+        "/tmp/web01",
+        "--rebuild",
+        "--keep-going",
+    ];
+    assert_eq!(args[2..8], expected);
+    assert_eq!(args.last().unwrap(), "web01");
+
+    // `nix-build` calls checking a build `--check`
+    let nix = NixVersion::parse("nix (Nix) 2.3.16").unwrap();
+    let (_, args) = build_command(&nix, &NixSettings::default(), file, "web01", &opts);
+    assert_eq!(
+        args[..6],
+        [
+            "/nix/store/x-source/nilla.nix",
+            "-A",
+            "web01",
+            "--out-link",
+            "/tmp/web01",
+            "--check"
+        ]
+    );
+}
+
+#[test]
+fn outputs() {
+    let json = r#"[
+        {"drvPath": "/nix/store/a-hello.drv", "outputs": {"out": "/nix/store/b-hello", "man": "/nix/store/c-hello-man"}},
+        {"drvPath": "/nix/store/d-system.drv", "outputs": {"out": "/nix/store/e-system"}}
+    ]"#;
+    assert_eq!(
+        parse_build_outputs(json).unwrap(),
+        [
+            "/nix/store/c-hello-man",
+            "/nix/store/b-hello",
+            "/nix/store/e-system"
+        ]
+        .map(PathBuf::from)
+    );
+
+    assert_eq!(
+        parse_build_outputs("/nix/store/b-hello\n/nix/store/e-system\n\n").unwrap(),
+        ["/nix/store/b-hello", "/nix/store/e-system"].map(PathBuf::from)
+    );
+    assert!(parse_build_outputs("").unwrap().is_empty());
+    assert!(parse_build_outputs(r#"[{"drvPath": "/nix/store/a.drv"}]"#).is_err());
+}
+
+#[test]
+fn failures() {
+    // Nix before 2.19, and Lix
+    let old = "\
+error: builder for '/nix/store/a-hello.drv' failed with exit code 1;
+       last 10 log lines:
+       > make: *** [Makefile:2: all] Error 1
+error: 1 dependencies of derivation '/nix/store/d-system.drv' failed to build
+";
+    assert_eq!(
+        failed_derivations(old),
+        [PathBuf::from("/nix/store/a-hello.drv")]
+    );
+
+    // Newer Nix, with --keep-going reporting the same failure twice
+    let new = "\
+error: Cannot build '/nix/store/a-hello.drv'.
+       Reason: builder failed with exit code 1.
+error: Cannot build '/nix/store/f-world.drv'.
+       Reason: builder failed with exit code 2.
+error: Cannot build '/nix/store/a-hello.drv'.
+";
+    assert_eq!(
+        failed_derivations(new),
+        ["/nix/store/a-hello.drv", "/nix/store/f-world.drv"].map(PathBuf::from)
+    );
+
+    let result = BuildResult {
+        exit_code: Some(1),
+        outputs: vec![],
+        failed: failed_derivations(new),
+    };
+    assert!(!result.success());
+    assert_eq!(
+        result.ensure_success().unwrap_err().to_string(),
+        "nix build failed (exit code 1)\nFailed to build:\n  /nix/store/a-hello.drv\n  /nix/store/f-world.drv"
+    );
+
+    let killed = BuildResult {
+        exit_code: None,
+        outputs: vec![],
+        failed: vec![],
+    };
+    assert_eq!(
+        killed.ensure_success().unwrap_err().to_string(),
+        "nix build failed (killed by a signal)"
+    );
+}
+
+#[tokio::test]
+async fn mocked_builds() {
+    let nix = MockNix::new("/nix/store");
+    nix.on_build("hello", vec![PathBuf::from("/nix/store/b-hello")]);
+    nix.fail_build(
+        "broken",
+        "error: builder for '/nix/store/a-broken.drv' failed with exit code 1",
+    );
+
+    let file = Path::new("nilla.nix");
+    let opts = BuildOpts {
+        tuning: tuning(),
+        ..Default::default()
+    };
+    let built = nix.build(file, "hello", opts.clone()).await.unwrap();
+    assert!(built.success());
+    assert_eq!(built.outputs, [PathBuf::from("/nix/store/b-hello")]);
+
+    let broken = nix
+        .build(file, "broken", BuildOpts::default())
+        .await
+        .unwrap();
+    assert_eq!(broken.exit_code, Some(1));
+    assert_eq!(broken.failed, [PathBuf::from("/nix/store/a-broken.drv")]);
+
+    assert_eq!(
+        nix.calls()[0],
+        Call::Build {
+            file: file.to_path_buf(),
+            name: "hello".to_string(),
+            opts,
+        }
+    );
+}
@@ -0,0 +1,223 @@
+use std::path::{Path, PathBuf};
+
+use nilla_nixos::{
+    commands::why_depends::describe,
+    util::{
+        closure::{Introducer, Introducers, References, matches, parse_graph, why_depends},
+        nix::NixBackend,
+        nix_mock::MockNix,
+    },
+};
+
+fn store(name: &str) -> PathBuf {
+    PathBuf::from(format!("/nix/store/{name}"))
+}
+
+const TOPLEVEL: &str = "0c7jbpvqazdbq2wlcbg9cmf9j6c7f3nz-nixos-system-web01-24.11";
+const OPENSSL: &str = "ys2kyf2ig5qx8q4xrpdqm3f5q8bkwy3n-openssl-1.1.1w";
+
+// toplevel
+//   system-path -> curl-8.5.0-bin -> curl-8.5.0 -> openssl-1.1.1w
+//   etc -> system-units -> unit-nginx.service -> nginx-1.26.2 -> openssl-1.1.1w
+//   kernel-6.6.68
+fn graph() -> References {
+    let edges: [(&str, &[&str]); 9] = [
+        (
+            TOPLEVEL,
+            &[
+                "1h8r6ikxfmdkmf2b0vjfwjb8pb87gddk-system-path",
+                "2m9v3r1zxd7g0zw0bx3q0q1nfqgpgj0v-etc",
+                "3bxm4r5a1z0w2s8l6n9a5l1vkk8f5j1f-kernel-6.6.68",
+            ],
+        ),
+        (
+            "1h8r6ikxfmdkmf2b0vjfwjb8pb87gddk-system-path",
+            &["4a7mdxz6bmf2pg8i1rjmqv5p7kq0ab1c-curl-8.5.0-bin"],
+        ),
+        (
+            "4a7mdxz6bmf2pg8i1rjmqv5p7kq0ab1c-curl-8.5.0-bin",
+            &["5g0hpkq4r8h9lfy5wj0d4d4b1p6jj8x2-curl-8.5.0"],
+        ),
+        ("5g0hpkq4r8h9lfy5wj0d4d4b1p6jj8x2-curl-8.5.0", &[OPENSSL]),
+        (
+            "2m9v3r1zxd7g0zw0bx3q0q1nfqgpgj0v-etc",
+            &["6k0i7c4b3a9z2y1x0w9v8u7t6s5r4q3p-system-units"],
+        ),
+        (
+            "6k0i7c4b3a9z2y1x0w9v8u7t6s5r4q3p-system-units",
+            &["7p6o5n4m3l2k1j0i9h8g7f6e5d4c3b2a-unit-nginx.service"],
+        ),
+        (
+            "7p6o5n4m3l2k1j0i9h8g7f6e5d4c3b2a-unit-nginx.service",
+            &["8z7y6x5w4v3s2r1q0p9n8m7l6k5j4i3h-nginx-1.26.2"],
+        ),
+        ("8z7y6x5w4v3s2r1q0p9n8m7l6k5j4i3h-nginx-1.26.2", &[OPENSSL]),
+        ("3bxm4r5a1z0w2s8l6n9a5l1vkk8f5j1f-kernel-6.6.68", &[]),
+    ];
+    let mut references: References = edges
+        .iter()
+        .map(|(path, refs)| (store(path), refs.iter().map(|r| store(r)).collect()))
+        .collect();
+    references.insert(store(OPENSSL), vec![]);
+    references
+}
+
+#[test]
+fn graphs() {
+    let dot = format!(
+        r##"digraph G {{
+"{TOPLEVEL}" [label = "nixos-system-web01-24.11", shape = box, style = filled, fillcolor = "#ff0000"];
+"{OPENSSL}" [label = "openssl-1.1.1w", shape = box, style = filled, fillcolor = "#ff0000"];
+"5g0hpkq4r8h9lfy5wj0d4d4b1p6jj8x2-curl-8.5.0" [label = "curl-8.5.0", shape = box, style = filled, fillcolor = "#ff0000"];
+"{OPENSSL}" -> "5g0hpkq4r8h9lfy5wj0d4d4b1p6jj8x2-curl-8.5.0";
+"5g0hpkq4r8h9lfy5wj0d4d4b1p6jj8x2-curl-8.5.0" -> "{TOPLEVEL}";
+}}
+"##
+    );
+    let references = parse_graph(&dot, Path::new("/nix/store"));
+    assert_eq!(
+        references,
+        References::from([
+            (
+                store(TOPLEVEL),
+                vec![store("5g0hpkq4r8h9lfy5wj0d4d4b1p6jj8x2-curl-8.5.0")]
+            ),
+            (
+                store("5g0hpkq4r8h9lfy5wj0d4d4b1p6jj8x2-curl-8.5.0"),
+                vec![store(OPENSSL)]
+            ),
+            (store(OPENSSL), vec![]),
+        ])
+    );
+}
+
+#[test]
+fn matching() {
+    let openssl = store(OPENSSL);
+    for query in [
+        "openssl",
+        "openssl-1.1",
+        "openssl-1.1.1w",
+        &openssl.to_string_lossy(),
+    ] {
+        assert!(matches(&openssl, query), "{query}");
+    }
+    assert!(matches(
+        &openssl,
+        &format!("/nix/store/{OPENSSL}/lib/libssl.so")
+    ));
+    for query in [
+        "openssl-3",
+        "openssl-1.10",
+        "ssl",
+        "/nix/store/other-openssl-1.1.1w",
+    ] {
+        assert!(!matches(&openssl, query), "{query}");
+    }
+
+    assert!(matches(&store("a-openssl-1.1.1w-bin"), "openssl-1.1.1w"));
+    assert!(matches(&store("a-gcc-13.2.0.drv"), "gcc-13.2.0"));
+}
+
+#[test]
+fn chains() {
+    let references = graph();
+    let root = store(TOPLEVEL);
+    let chains = why_depends(&references, &root, "openssl-1.1");
+    assert_eq!(chains.len(), 2, "{chains:?}");
+    assert_eq!(
+        chains[0].paths,
+        [
+            root.clone(),
+            store("1h8r6ikxfmdkmf2b0vjfwjb8pb87gddk-system-path"),
+            store("4a7mdxz6bmf2pg8i1rjmqv5p7kq0ab1c-curl-8.5.0-bin"),
+            store("5g0hpkq4r8h9lfy5wj0d4d4b1p6jj8x2-curl-8.5.0"),
+            store(OPENSSL),
+        ]
+    );
+    assert_eq!(chains[1].paths.len(), 6);
+    assert!(chains.iter().all(|chain| chain.target() == store(OPENSSL)));
+
+    assert!(why_depends(&references, &root, "openssl-3").is_empty());
+    // The kernel is referred to by the toplevel itself
+    let chains = why_depends(&references, &root, "kernel");
+    assert_eq!(chains.len(), 1);
+    assert_eq!(chains[0].paths.len(), 2);
+
+    let introducers = Introducers {
+        system_packages: vec![vec![
+            Some(
+                store("4a7mdxz6bmf2pg8i1rjmqv5p7kq0ab1c-curl-8.5.0-bin")
+                    .to_string_lossy()
+                    .into_owned(),
+            ),
+            Some(
+                store("9c8b7a6z5y4x3w2v1u0t9s8r7q6p5o4n-curl-8.5.0-man")
+                    .to_string_lossy()
+                    .into_owned(),
+            ),
+        ]],
+        units: [
+            (
+                "nginx.service".to_string(),
+                Some(
+                    store("7p6o5n4m3l2k1j0i9h8g7f6e5d4c3b2a-unit-nginx.service")
+                        .to_string_lossy()
+                        .into_owned(),
+                ),
+            ),
+            ("sshd.service".to_string(), None),
+        ]
+        .into(),
+    };
+    let chains = why_depends(&references, &root, "openssl");
+    assert_eq!(
+        introducers.explain(&chains[0]),
+        Some(Introducer::SystemPackage("curl-8.5.0-bin".to_string()))
+    );
+    assert_eq!(
+        introducers.explain(&chains[1]),
+        Some(Introducer::Unit("nginx.service".to_string()))
+    );
+
+    let chains = [chains, why_depends(&references, &root, "kernel")].concat();
+    assert_eq!(
+        describe(&chains, &introducers),
+        format!(
+            "/nix/store/{OPENSSL}
+  introduced by environment.systemPackages (curl-8.5.0-bin)
+    nixos-system-web01-24.11
+    → system-path
+    → curl-8.5.0-bin
+    → curl-8.5.0
+    → openssl-1.1.1w
+  introduced by the systemd unit nginx.service
+    nixos-system-web01-24.11
+    → etc
+    → system-units
+    → unit-nginx.service
+    → nginx-1.26.2
+    → openssl-1.1.1w
+
+/nix/store/3bxm4r5a1z0w2s8l6n9a5l1vkk8f5j1f-kernel-6.6.68
+  not traced back to a system package or unit
+    nixos-system-web01-24.11
+    → kernel-6.6.68
+"
+        )
+    );
+}
+
+#[tokio::test]
+async fn mock_closures() {
+    let nix = MockNix::new("/nix/store");
+    for (path, references) in graph() {
+        nix.on_references(path, references);
+    }
+    let closure = nix
+        .closure(&store("5g0hpkq4r8h9lfy5wj0d4d4b1p6jj8x2-curl-8.5.0"))
+        .await
+        .unwrap();
+    assert_eq!(closure.len(), 2);
+    assert!(nix.closure(&store("missing")).await.is_err());
+}
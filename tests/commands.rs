@@ -0,0 +1,363 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use nilla_nixos::{
+    commands::{
+        build::build, repl, switch::switch, system_target, test::test, why_depends::why_depends,
+    },
+    util::{
+        config::Config,
+        context::Context,
+        nix::{BuildTuning, RebuildAction, RebuildOpts},
+        nix_expr::AttrPath,
+        nix_mock::{Call, MockNix},
+    },
+};
+use nixos_cli_def::{Cli, Commands};
+use serde_json::json;
+
+mod common;
+
+use common::setup;
+
+async fn run(nix: &MockNix, args: &[&str]) -> anyhow::Result<()> {
+    let cli = Cli::parse_from(std::iter::once("nilla-nixos").chain(args.iter().copied()));
+    let ctx = Context::new(nix, Config::default());
+    match &cli.command {
+        Some(Commands::Build(args)) => build(&ctx, &cli, args).await,
+        Some(Commands::Switch(args)) => switch(&ctx, &cli, args).await,
+        Some(Commands::Test(args)) => test(&ctx, &cli, args).await,
+        command => panic!("not a rebuild command: {command:?}"),
+    }
+}
+
+fn rebuild(nix: &MockNix) -> RebuildOpts {
+    let rebuilds = nix.rebuilds();
+    assert_eq!(rebuilds.len(), 1, "{rebuilds:?}");
+    rebuilds[0].clone()
+}
+
+#[tokio::test]
+async fn actions() {
+    let cases = [
+        ("build", RebuildAction::Build, false),
+        ("switch", RebuildAction::Switch, true),
+        ("test", RebuildAction::Test, true),
+    ];
+    for (command, action, elevate) in cases {
+        let (_dir, infra, nix) = setup();
+        run(
+            &nix,
+            &["--project", infra.to_str().unwrap(), command, "web01"],
+        )
+        .await
+        .unwrap();
+
+        let opts = rebuild(&nix);
+        assert_eq!(opts.action, action, "{command}");
+        assert_eq!(opts.elevate, elevate, "{command}");
+        assert_eq!(opts.attribute, "systems.nixos.web01.result", "{command}");
+        assert!(opts.file.starts_with(nix.store()), "{command}");
+        assert!(opts.file.ends_with("nilla.nix"), "{command}");
+    }
+}
+
+#[tokio::test]
+async fn system_selection() {
+    let (_dir, infra, nix) = setup();
+    let project = format!("{}#web02", infra.display());
+
+    // The fragment names the system unless one is given on the command line
+    run(&nix, &["--project", &project, "switch"]).await.unwrap();
+    run(&nix, &["--project", &project, "switch", "web03"])
+        .await
+        .unwrap();
+    let attributes = nix
+        .rebuilds()
+        .into_iter()
+        .map(|opts| opts.attribute)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        attributes,
+        ["systems.nixos.web02.result", "systems.nixos.web03.result"]
+    );
+
+    // Falling back to this machine's hostname
+    let (_dir, infra, nix) = setup();
+    run(&nix, &["--project", infra.to_str().unwrap(), "build"])
+        .await
+        .unwrap();
+    let hostname = gethostname::gethostname().into_string().unwrap();
+    assert_eq!(
+        rebuild(&nix).attribute,
+        AttrPath::from(["systems", "nixos", &hostname, "result"])
+            .to_cli_arg()
+            .unwrap()
+    );
+}
+
+#[tokio::test]
+async fn hostnames_are_quoted() {
+    let (_dir, infra, nix) = setup();
+    run(
+        &nix,
+        &[
+            "--project",
+            infra.to_str().unwrap(),
+            "build",
+            "web01.example.com",
+        ],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        rebuild(&nix).attribute,
+        "systems.nixos.\"web01.example.com\".result"
+    );
+}
+
+#[tokio::test]
+async fn entry_files() {
+    let (_dir, infra, nix) = setup();
+    let project = format!("path:{}?dir=hosts/prod", infra.display());
+    run(&nix, &["--project", &project, "build", "web01"])
+        .await
+        .unwrap();
+    assert!(rebuild(&nix).file.ends_with("hosts/prod/nilla.nix"));
+
+    let (_dir, infra, nix) = setup();
+    let project = infra.to_str().unwrap();
+    run(
+        &nix,
+        &[
+            "--project",
+            project,
+            "--file",
+            "nixos.nix",
+            "build",
+            "web01",
+        ],
+    )
+    .await
+    .unwrap();
+    assert!(rebuild(&nix).file.ends_with("nixos.nix"));
+}
+
+#[tokio::test]
+async fn invalid_hostnames() {
+    let (_dir, infra, nix) = setup();
+    let err = run(
+        &nix,
+        &["--project", infra.to_str().unwrap(), "build", "web\"01"],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(err.to_string(), "Invalid hostname web\"01");
+    assert!(nix.rebuilds().is_empty());
+}
+
+#[tokio::test]
+async fn missing_projects() {
+    let (_dir, infra, nix) = setup();
+    let missing = infra.join("missing");
+    let err = run(
+        &nix,
+        &["--project", missing.to_str().unwrap(), "build", "web01"],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        format!("{err:#}"),
+        format!(
+            "Could not find project {0}: Could not find path {0}",
+            missing.display()
+        )
+    );
+    assert!(nix.rebuilds().is_empty());
+}
+
+#[tokio::test]
+async fn missing_entry_files() {
+    let (_dir, infra, nix) = setup();
+    // The fetched checkout has no entry file at its root
+    let fetched = nix.add_fixture(&infra.join("hosts"), "source").unwrap();
+    nix.on_eval("builtins.fetchGit", json!(fetched));
+
+    let err = run(&nix, &["--project", "github:org/infra", "build", "web01"])
+        .await
+        .unwrap_err();
+    assert_eq!(err.to_string(), "Could not find nilla.nix in the project");
+    assert!(nix.rebuilds().is_empty());
+}
+
+#[tokio::test]
+async fn fetch_failures_stop_the_rebuild() {
+    let (_dir, _infra, nix) = setup();
+    nix.fail_eval("builtins.fetchGit", "error: repository not found");
+
+    let err = run(&nix, &["--project", "github:org/infra", "switch", "web01"])
+        .await
+        .unwrap_err();
+    assert_eq!(err.to_string(), "Could not find project github:org/infra");
+    assert!(
+        format!("{err:#}").contains("error: repository not found"),
+        "{err:#}"
+    );
+    assert!(nix.rebuilds().is_empty());
+}
+
+#[tokio::test]
+async fn rebuild_failures() {
+    let (_dir, infra, nix) = setup();
+    nix.fail_rebuild("exit status: 1");
+
+    let err = run(
+        &nix,
+        &["--project", infra.to_str().unwrap(), "switch", "web01"],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "nixos-rebuild switch failed (exit status: 1)"
+    );
+}
+
+#[tokio::test]
+async fn build_options() {
+    let (_dir, infra, nix) = setup();
+    run(
+        &nix,
+        &[
+            "--project",
+            infra.to_str().unwrap(),
+            "switch",
+            "web01",
+            "--keep-going",
+            "-j",
+            "8",
+            "--cores",
+            "2",
+            "--rebuild",
+            "--out-link",
+            "/tmp/web01",
+            "--option",
+            "sandbox",
+            "false",
+            "--option",
+            "fallback",
+            "true",
+        ],
+    )
+    .await
+    .unwrap();
+
+    let opts = rebuild(&nix);
+    assert_eq!(opts.out_link, Some(PathBuf::from("/tmp/web01")));
+    assert_eq!(
+        opts.tuning,
+        BuildTuning {
+            keep_going: true,
+            max_jobs: Some("8".to_string()),
+            cores: Some(2),
+            rebuild: true,
+            options: vec![
+                ("sandbox".to_string(), "false".to_string()),
+                ("fallback".to_string(), "true".to_string()),
+            ],
+        }
+    );
+
+    // Nothing given, nothing changed
+    let (_dir, infra, nix) = setup();
+    run(
+        &nix,
+        &["--project", infra.to_str().unwrap(), "build", "web01"],
+    )
+    .await
+    .unwrap();
+    let opts = rebuild(&nix);
+    assert_eq!(opts.out_link, None);
+    assert_eq!(opts.tuning, BuildTuning::default());
+}
+
+#[tokio::test]
+async fn repl_scope() {
+    let (_dir, infra, nix) = setup();
+    let cli = Cli::parse_from(["nilla-nixos", "--project", infra.to_str().unwrap(), "repl"]);
+    let ctx = Context::new(&nix, Config::default());
+    let target = system_target(&ctx, &cli, Some("web01")).await.unwrap();
+
+    let scope = repl::scope(&target).to_string();
+    assert!(
+        scope.starts_with("let project = import ((builtins.path { path = "),
+        "{scope}"
+    );
+    assert!(scope.contains("system = project.systems.nixos.web01.result;"));
+    assert!(scope.ends_with(
+        "in { project = project; system = system; config = system.config; \
+         options = system.options; pkgs = system.pkgs; lib = system.pkgs.lib; }"
+    ));
+    // Pinned like every other evaluation of the project
+    assert_eq!(
+        target.project.pinned_hashes(),
+        repl::scope(&target).pinned_hashes()
+    );
+    assert_eq!(target.project.pinned_hashes().len(), 1);
+}
+
+#[tokio::test]
+async fn why_depends_builds_or_instantiates() {
+    let toplevel = PathBuf::from("/nix/store/0c7jbpvqazdbq2wlcbg9cmf9j6c7f3nz-nixos-system-web01");
+    let openssl = PathBuf::from("/nix/store/ys2kyf2ig5qx8q4xrpdqm3f5q8bkwy3n-openssl-1.1.1w");
+    let drv = PathBuf::from("/nix/store/y3fd4mwv8a5a6vq3lfa7kl8hzj9ysrz8-nixos-system-web01.drv");
+
+    for derivation in [false, true] {
+        let (_dir, infra, nix) = setup();
+        let root = if derivation { &drv } else { &toplevel };
+        nix.on_build(
+            "systems.nixos.web01.result.config.system.build.toplevel",
+            vec![toplevel.clone()],
+        );
+        nix.on_eval("drvPath", json!(drv));
+        nix.on_eval(
+            "systemPackages",
+            json!({ "systemPackages": [], "units": {} }),
+        );
+        nix.on_references(root, vec![openssl.clone()]);
+        nix.on_references(&openssl, vec![]);
+
+        let mut args = vec![
+            "--project",
+            infra.to_str().unwrap(),
+            "why-depends",
+            "web01",
+            "openssl",
+        ];
+        if derivation {
+            args.push("--derivation");
+        }
+        let cli = Cli::parse_from(std::iter::once("nilla-nixos").chain(args));
+        let Some(Commands::WhyDepends(args)) = &cli.command else {
+            panic!("not why-depends");
+        };
+        let ctx = Context::new(&nix, Config::default());
+        why_depends(&ctx, &cli, args).await.unwrap();
+
+        let calls = nix.calls();
+        assert!(calls.contains(&Call::Closure(root.clone())), "{calls:?}");
+        let built = calls.iter().any(|call| matches!(call, Call::Build { .. }));
+        assert_eq!(built, !derivation);
+        // The introducers are looked up as derivations too
+        let introducers = nix
+            .evaluations()
+            .into_iter()
+            .find(|expr| expr.contains("systemPackages"))
+            .unwrap();
+        assert_eq!(
+            introducers.contains("output.drvPath"),
+            derivation,
+            "{introducers}"
+        );
+    }
+}
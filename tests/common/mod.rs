@@ -0,0 +1,47 @@
+// Helpers shared by the integration tests. Each test crate only uses some of them.
+#![allow(dead_code)]
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use nilla_nixos::util::nix_mock::MockNix;
+
+// Run `program` in `dir`, with a git identity for the commits it makes, and return what
+// it printed.
+pub fn run(dir: &Path, program: &str, args: &[&str]) -> String {
+    let output = Command::new(program)
+        .args(args)
+        .current_dir(dir)
+        .env("GIT_AUTHOR_NAME", "Nilla")
+        .env("GIT_AUTHOR_EMAIL", "nilla@example.com")
+        .env("GIT_COMMITTER_NAME", "Nilla")
+        .env("GIT_COMMITTER_EMAIL", "nilla@example.com")
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{program} {args:?} failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+// infra/
+//   nilla.nix
+//   nixos.nix
+//   hosts/prod/nilla.nix
+pub fn setup() -> (tempfile::TempDir, PathBuf, MockNix) {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().canonicalize().unwrap();
+    let infra = root.join("infra");
+    std::fs::create_dir_all(infra.join("hosts/prod")).unwrap();
+    std::fs::write(infra.join("nilla.nix"), "{ }\n").unwrap();
+    std::fs::write(infra.join("nixos.nix"), "{ }\n").unwrap();
+    std::fs::write(infra.join("hosts/prod/nilla.nix"), "{ }\n").unwrap();
+
+    let store = root.join("store");
+    std::fs::create_dir(&store).unwrap();
+    (dir, infra, MockNix::new(store))
+}
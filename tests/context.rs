@@ -0,0 +1,57 @@
+use clap::Parser;
+use log::LevelFilter;
+use nilla_nixos::util::{
+    config::Config,
+    context::{Context, Verbosity},
+};
+use nixos_cli_def::Cli;
+
+fn cli(args: &[&str]) -> Cli {
+    Cli::parse_from(std::iter::once("nilla-nixos").chain(args.iter().copied()))
+}
+
+#[test]
+fn verbosity() {
+    let cases = [
+        (&[][..], LevelFilter::Info),
+        (&["-v"][..], LevelFilter::Debug),
+        (&["-vvv"][..], LevelFilter::Trace),
+        // Quiet wins over any number of `-v`
+        (&["-vv", "-q"][..], LevelFilter::Error),
+    ];
+    for (args, level) in cases {
+        let cli = cli(&[args, &["build"]].concat());
+        assert_eq!(Verbosity::from_cli(&cli).level_filter(), level, "{args:?}");
+    }
+}
+
+#[test]
+fn from_command_line() {
+    let cli = cli(&[
+        "--show-eval-commands",
+        "--no-eval-cache",
+        "build",
+        "--nix-option",
+        "sandbox=relaxed",
+    ]);
+    let ctx = Context::from_cli(&cli, Config::default());
+    assert!(ctx.show_eval_commands);
+    assert!(ctx.nix.inner().inner().show_eval_commands);
+    assert!(ctx.nix.inner().show_eval_commands);
+    assert_eq!(ctx.nix.inner().inner().settings, ctx.settings);
+    assert_eq!(
+        ctx.settings.options,
+        [("sandbox".to_string(), "relaxed".to_string())]
+    );
+
+    // The backend, and the repl session in front of it, stop when the run is cancelled
+    assert!(!ctx.nix.inner().inner().cancel.is_cancelled());
+    ctx.cancel.cancel();
+    assert!(ctx.nix.inner().inner().cancel.is_cancelled());
+    assert!(ctx.nix.inner().supervisor.cancel.is_cancelled());
+
+    // Embedders start from a quiet context of their own
+    let ctx = Context::new((), Config::default());
+    assert!(!ctx.show_eval_commands);
+    assert_eq!(ctx.verbosity, Verbosity::default());
+}
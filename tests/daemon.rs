@@ -0,0 +1,383 @@
+use std::path::{Path, PathBuf};
+
+use nilla_nixos::util::{
+    daemon::{DaemonClient, PROTOCOL_VERSION, PathInfo},
+    hash::Sha256,
+    nar::{self, Filter},
+    store_path::StorePath,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixListener,
+    task::JoinHandle,
+};
+
+const STDERR_NEXT: u64 = 0x6f6c6d67;
+const STDERR_LAST: u64 = 0x616c7473;
+const STDERR_ERROR: u64 = 0x63787470;
+const STDERR_START_ACTIVITY: u64 = 0x53545254;
+const STDERR_STOP_ACTIVITY: u64 = 0x53544f50;
+const STDERR_RESULT: u64 = 0x52534c54;
+
+const SOURCE: &str = "/nix/store/lplzlyk8ldz821dl6pmlhk3md1ms69md-source";
+const GLIBC: &str = "/nix/store/0s6j3ikc8xmgm2bsyfxm9rwlh4k9jq5s-glibc-2.40-66";
+const SYSTEM: &str = "/nix/store/r3fv7y3m2ibgmapdwqbyh4s8hsrcfpj8-nixos-system-web01";
+
+// One side of a recorded conversation with the daemon: what the client should send, or
+// what the daemon answers.
+enum Step {
+    Expect(Vec<u8>),
+    Send(Vec<u8>),
+}
+
+fn num(n: u64) -> Vec<u8> {
+    n.to_le_bytes().to_vec()
+}
+
+fn string(s: &str) -> Vec<u8> {
+    let mut out = num(s.len() as u64);
+    out.extend_from_slice(s.as_bytes());
+    out.resize(out.len().next_multiple_of(8), 0);
+    out
+}
+
+fn strings(list: &[&str]) -> Vec<u8> {
+    let mut out = num(list.len() as u64);
+    for s in list {
+        out.extend(string(s));
+    }
+    out
+}
+
+fn handshake(version: u64) -> Vec<Step> {
+    let mut reply = [num(0x6478696f), num(version)].concat();
+    let mut steps = vec![Step::Expect(num(0x6e697863)), Step::Send(reply.clone())];
+    steps.push(Step::Expect(
+        [num(PROTOCOL_VERSION), num(0), num(0)].concat(),
+    ));
+    reply.clear();
+    if version & 0xff >= 33 {
+        reply.extend(string("2.24.10"));
+    }
+    if version & 0xff >= 35 {
+        reply.extend(num(1));
+    }
+    reply.extend(num(STDERR_LAST));
+    steps.push(Step::Send(reply));
+    steps
+}
+
+fn empty_nar_hash() -> Sha256 {
+    "sha256-pQpattmS9VmO3ZIQUFn66az8GSmB4IvYhTTCFn6SUmo="
+        .parse()
+        .unwrap()
+}
+
+// The daemon's answer to `QueryPathInfo` for the system, less the leading valid flag.
+fn system_info() -> Vec<u8> {
+    [
+        string("/nix/store/5h1nf4pqdpkbzvwl5l9zx8h3jv6v0mas-nixos-system-web01.drv"),
+        string(&empty_nar_hash().to_hex()),
+        strings(&[GLIBC, SYSTEM]),
+        num(1_750_000_000),
+        num(1232),
+        num(0),
+        strings(&["cache.nixos.org-1:abc="]),
+        string(""),
+    ]
+    .concat()
+}
+
+// Serve one connection on a socket in `dir`, following `steps`. The task fails if the
+// client says anything other than what was recorded.
+fn serve(dir: &Path, steps: Vec<Step>) -> (PathBuf, JoinHandle<()>) {
+    let socket = dir.join("socket");
+    let listener = UnixListener::bind(&socket).unwrap();
+    let server = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        for (i, step) in steps.into_iter().enumerate() {
+            match step {
+                Step::Expect(expected) => {
+                    let mut got = vec![0; expected.len()];
+                    stream.read_exact(&mut got).await.unwrap();
+                    assert_eq!(got, expected, "step {i}");
+                }
+                Step::Send(bytes) => stream.write_all(&bytes).await.unwrap(),
+            }
+        }
+    });
+    (socket, server)
+}
+
+async fn connect(socket: &Path) -> DaemonClient {
+    DaemonClient::connect(socket, Path::new("/nix/store"))
+        .await
+        .unwrap()
+}
+
+fn store_path(path: &str) -> StorePath {
+    StorePath::parse_in(Path::new("/nix/store"), path).unwrap()
+}
+
+#[tokio::test]
+async fn handshakes() {
+    let dir = tempfile::tempdir().unwrap();
+    let (socket, server) = serve(dir.path(), handshake(PROTOCOL_VERSION));
+    let client = connect(&socket).await;
+    assert_eq!(client.version(), PROTOCOL_VERSION);
+    assert_eq!(client.daemon_version(), Some("2.24.10"));
+    server.await.unwrap();
+
+    // Older daemons neither say which version they are nor whether we are trusted
+    let dir = tempfile::tempdir().unwrap();
+    let (socket, server) = serve(dir.path(), handshake(1 << 8 | 32));
+    let client = connect(&socket).await;
+    assert_eq!(client.version(), 1 << 8 | 32);
+    assert_eq!(client.daemon_version(), None);
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn old_daemons_are_refused() {
+    let dir = tempfile::tempdir().unwrap();
+    let steps = vec![
+        Step::Expect(num(0x6e697863)),
+        Step::Send([num(0x6478696f), num(1 << 8 | 21)].concat()),
+    ];
+    let (socket, server) = serve(dir.path(), steps);
+    let err = DaemonClient::connect(&socket, Path::new("/nix/store"))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        err.to_string(),
+        "The Nix daemon speaks protocol 1.21, at least 1.25 is needed"
+    );
+    server.await.unwrap();
+
+    let err = DaemonClient::connect(&dir.path().join("missing"), Path::new("/nix/store"))
+        .await
+        .err()
+        .unwrap();
+    assert!(
+        err.to_string()
+            .starts_with("Could not connect to the Nix daemon at"),
+        "{err}"
+    );
+}
+
+#[tokio::test]
+async fn valid_paths() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut steps = handshake(PROTOCOL_VERSION);
+    steps.extend([
+        // Subpaths are asked about as their store entry
+        Step::Expect([num(1), string(SOURCE)].concat()),
+        Step::Send(
+            [
+                num(STDERR_NEXT),
+                string("checking path\n"),
+                num(STDERR_LAST),
+                num(1),
+            ]
+            .concat(),
+        ),
+        Step::Expect([num(1), string(SYSTEM)].concat()),
+        Step::Send([num(STDERR_LAST), num(0)].concat()),
+    ]);
+    let (socket, server) = serve(dir.path(), steps);
+
+    let mut client = connect(&socket).await;
+    let source = store_path(&format!("{SOURCE}/hosts/prod"));
+    assert!(client.is_valid_path(&source).await.unwrap());
+    assert!(!client.is_valid_path(&store_path(SYSTEM)).await.unwrap());
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn path_info() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut steps = handshake(PROTOCOL_VERSION);
+    let activity = [
+        num(STDERR_START_ACTIVITY),
+        num(7),
+        num(3),
+        num(100),
+        string("querying info about '/nix/store/...'"),
+        // Two fields, a number and a string
+        num(2),
+        num(0),
+        num(42),
+        num(1),
+        string("https://cache.nixos.org"),
+        num(0),
+        num(STDERR_RESULT),
+        num(7),
+        num(105),
+        num(0),
+        num(STDERR_STOP_ACTIVITY),
+        num(7),
+        num(STDERR_LAST),
+    ]
+    .concat();
+    steps.extend([
+        Step::Expect([num(26), string(SYSTEM)].concat()),
+        Step::Send([activity, num(1), system_info()].concat()),
+        Step::Expect([num(26), string(GLIBC)].concat()),
+        Step::Send([num(STDERR_LAST), num(0)].concat()),
+    ]);
+    let (socket, server) = serve(dir.path(), steps);
+
+    let mut client = connect(&socket).await;
+    let info = client
+        .query_path_info(&store_path(SYSTEM))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        info,
+        PathInfo {
+            deriver: Some(store_path(
+                "/nix/store/5h1nf4pqdpkbzvwl5l9zx8h3jv6v0mas-nixos-system-web01.drv"
+            )),
+            nar_hash: empty_nar_hash(),
+            references: vec![store_path(GLIBC), store_path(SYSTEM)],
+            registration_time: 1_750_000_000,
+            nar_size: 1232,
+            ultimate: false,
+            signatures: vec!["cache.nixos.org-1:abc=".to_string()],
+            ca: None,
+        }
+    );
+    assert_eq!(
+        client.query_path_info(&store_path(GLIBC)).await.unwrap(),
+        None
+    );
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn referrers() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut steps = handshake(PROTOCOL_VERSION);
+    steps.extend([
+        Step::Expect([num(6), string(GLIBC)].concat()),
+        Step::Send([num(STDERR_LAST), strings(&[SYSTEM, SOURCE])].concat()),
+    ]);
+    let (socket, server) = serve(dir.path(), steps);
+
+    let mut client = connect(&socket).await;
+    assert_eq!(
+        client.query_referrers(&store_path(GLIBC)).await.unwrap(),
+        [store_path(SYSTEM), store_path(SOURCE)]
+    );
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn daemon_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut steps = handshake(PROTOCOL_VERSION);
+    steps.extend([
+        Step::Expect([num(26), string(SYSTEM)].concat()),
+        Step::Send(
+            [
+                num(STDERR_ERROR),
+                string("Error"),
+                num(0),
+                string("Error"),
+                string("cannot open connection to remote store"),
+                num(0),
+                // One trace
+                num(1),
+                num(0),
+                string("while querying path info"),
+            ]
+            .concat(),
+        ),
+    ]);
+    let (socket, server) = serve(dir.path(), steps);
+
+    let mut client = connect(&socket).await;
+    let err = client
+        .query_path_info(&store_path(SYSTEM))
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "cannot open connection to remote store\nwhile querying path info"
+    );
+    server.await.unwrap();
+
+    // A daemon hanging up mid-reply
+    let dir = tempfile::tempdir().unwrap();
+    let mut steps = handshake(PROTOCOL_VERSION);
+    steps.extend([
+        Step::Expect([num(1), string(SYSTEM)].concat()),
+        Step::Send(num(STDERR_LAST)),
+    ]);
+    let (socket, server) = serve(dir.path(), steps);
+    let mut client = connect(&socket).await;
+    let err = client.is_valid_path(&store_path(SYSTEM)).await.unwrap_err();
+    assert_eq!(err.to_string(), "The Nix daemon closed the connection");
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn adding_sources() {
+    let dir = tempfile::tempdir().unwrap();
+    let project = dir.path().join("infra");
+    std::fs::create_dir_all(project.join(".git")).unwrap();
+    std::fs::write(project.join("nilla.nix"), "{ }\n").unwrap();
+
+    // The NAR goes over framed, and leaves out what the filter does
+    let mut dump = vec![];
+    nar::dump(&project, Filter::GitIgnore, &mut dump).unwrap();
+    let hash = nar::hash(&project, Filter::GitIgnore).unwrap().hash;
+    let added = StorePath::for_source(Path::new("/nix/store"), "infra", &hash).unwrap();
+
+    let mut steps = handshake(PROTOCOL_VERSION);
+    steps.extend([
+        Step::Expect(
+            [
+                num(7),
+                string("infra"),
+                string("fixed:r:sha256"),
+                strings(&[]),
+                num(0),
+                num(dump.len() as u64),
+                dump.clone(),
+                num(0),
+            ]
+            .concat(),
+        ),
+        Step::Send(
+            [
+                num(STDERR_LAST),
+                string(&added.to_string()),
+                string(""),
+                string(&hash.to_hex()),
+                strings(&[]),
+                num(1_750_000_000),
+                num(dump.len() as u64),
+                num(1),
+                strings(&[]),
+                string(&format!("fixed:r:sha256:{}", hash.to_nix32())),
+            ]
+            .concat(),
+        ),
+    ]);
+    let (socket, server) = serve(dir.path(), steps);
+
+    let mut client = connect(&socket).await;
+    let (path, info) = client
+        .add_to_store("infra", &project, Filter::GitIgnore)
+        .await
+        .unwrap();
+    assert_eq!(path, added);
+    assert_eq!(info.nar_hash, hash);
+    assert_eq!(info.nar_size, dump.len() as u64);
+    assert!(info.ultimate);
+    assert_eq!(info.ca, Some(format!("fixed:r:sha256:{}", hash.to_nix32())));
+    server.await.unwrap();
+}
@@ -0,0 +1,143 @@
+use std::path::Path;
+
+use nilla_nixos::util::{
+    eval_cache::{CachedEval, EvalCache},
+    nix::{
+        EvalOpts, EvalResult, FixedOutputStoreEntry, GetMainProgramOpts, NixBackend,
+        exists_in_project, get_main_program, get_system, project_expr,
+    },
+    nix_expr::Expr,
+    nix_mock::MockNix,
+    store_path::StorePath,
+};
+use serde_json::json;
+
+fn entry(hash: &str) -> FixedOutputStoreEntry {
+    FixedOutputStoreEntry {
+        path: StorePath::parse_in(
+            Path::new("/nix/store"),
+            "/nix/store/lplzlyk8ldz821dl6pmlhk3md1ms69md-source",
+        )
+        .unwrap(),
+        hash: hash.to_string(),
+    }
+}
+
+const HASH: &str = "0sjjj9z1dhilhpc8pq4154czrb79z9cm044jvn75kxcjv6v5l2m5";
+const OTHER: &str = "1bwm2zd7m3w3rb5zwcfqz5fcxhm0rfbwxjkm6ghgk9mfwqbw7ng4";
+
+#[test]
+fn pinned_hashes() {
+    let pinned = Expr::let_in(
+        [("project", project_expr("nilla.nix", &entry(HASH)))],
+        Expr::var("project").has_attr(["systems"]),
+    );
+    assert_eq!(pinned.pinned_hashes(), [HASH]);
+    assert!(Expr::builtin("currentSystem").pinned_hashes().is_empty());
+
+    let both =
+        project_expr("nilla.nix", &entry(HASH)).concat(project_expr("nilla.nix", &entry(OTHER)));
+    assert_eq!(both.pinned_hashes(), [HASH, OTHER]);
+}
+
+#[tokio::test]
+async fn repeated_runs() {
+    let dir = tempfile::tempdir().unwrap();
+    let nix = MockNix::new("/nix/store");
+    nix.on_eval("systems", json!(true));
+    nix.on_eval("mainProgram", json!("hello"));
+
+    for _ in 0..3 {
+        // A new run, starting from what is on disk
+        let cached = CachedEval::new(&nix, Some(EvalCache::new(dir.path())));
+        assert!(
+            exists_in_project(&cached, "nilla.nix", entry(HASH), "systems.nixos.web01")
+                .await
+                .unwrap()
+        );
+        let opts = GetMainProgramOpts {
+            system: "x86_64-linux",
+        };
+        let main = get_main_program(&cached, "nilla.nix", entry(HASH), "hello", opts);
+        assert_eq!(main.await.unwrap(), "hello");
+    }
+    assert_eq!(nix.evaluations().len(), 2);
+    assert!(dir.path().join("eval").join(HASH).is_dir());
+
+    // The same question about another tree is asked again
+    let cached = CachedEval::new(&nix, Some(EvalCache::new(dir.path())));
+    exists_in_project(&cached, "nilla.nix", entry(OTHER), "systems.nixos.web01")
+        .await
+        .unwrap();
+    assert_eq!(nix.evaluations().len(), 3);
+}
+
+#[tokio::test]
+async fn impure_and_uncached() {
+    let dir = tempfile::tempdir().unwrap();
+    let nix = MockNix::new("/nix/store");
+    nix.on_eval("currentSystem", json!("x86_64-linux"));
+    nix.on_eval("systems", json!(false));
+
+    let cached = CachedEval::new(&nix, Some(EvalCache::new(dir.path())));
+    for _ in 0..2 {
+        assert_eq!(get_system(&cached).await.unwrap(), "x86_64-linux");
+    }
+    assert_eq!(nix.evaluations().len(), 2);
+    assert!(!dir.path().join("eval").exists());
+
+    let without = CachedEval::new(&nix, None);
+    for _ in 0..2 {
+        let exists = exists_in_project(&without, "nilla.nix", entry(HASH), "systems.nixos.web01");
+        assert!(!exists.await.unwrap());
+    }
+    assert_eq!(nix.evaluations().len(), 4);
+}
+
+#[tokio::test]
+async fn entries() {
+    let dir = tempfile::tempdir().unwrap();
+    let cache = EvalCache::new(dir.path());
+
+    cache
+        .put(&[HASH], "1 + 1", &EvalResult::Raw("2".to_string()))
+        .unwrap();
+    assert_eq!(
+        cache.get(&[HASH], "1 + 1", false),
+        Some(EvalResult::Raw("2".to_string()))
+    );
+    // Raw and JSON results are kept apart
+    assert_eq!(cache.get(&[HASH], "1 + 1", true), None);
+    assert_eq!(cache.get(&[OTHER], "1 + 1", false), None);
+
+    cache
+        .put(&[HASH], "[ 1 ]", &EvalResult::Json(json!([1])))
+        .unwrap();
+    assert_eq!(
+        cache.get(&[HASH], "[ 1 ]", true),
+        Some(EvalResult::Json(json!([1])))
+    );
+
+    // Anything unreadable is evaluated again
+    let files = std::fs::read_dir(dir.path().join("eval").join(HASH)).unwrap();
+    for file in files {
+        std::fs::write(file.unwrap().path(), "{").unwrap();
+    }
+    assert_eq!(cache.get(&[HASH], "[ 1 ]", true), None);
+
+    let nix = MockNix::new("/nix/store");
+    nix.fail_eval("systems", "error: infinite recursion encountered");
+    let cached = CachedEval::new(&nix, Some(cache));
+    let code = Expr::let_in(
+        [("project", project_expr("nilla.nix", &entry(OTHER)))],
+        Expr::var("project").select(["systems"]),
+    );
+    let opts = EvalOpts {
+        json: true,
+        impure: false,
+        ..Default::default()
+    };
+    assert!(cached.evaluate(&code, opts).await.is_err());
+    // Failures are not remembered
+    assert!(!dir.path().join("eval").join(OTHER).exists());
+}
@@ -0,0 +1,313 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use nilla_nixos::util::{
+    eval_session::{EvalSession, Repl},
+    nix::{EvalOpts, EvalResult, FixedOutputStoreEntry, NixBackend, exists_in_project, get_system},
+    nix_expr::{AttrPath, Expr, escape_string},
+    nix_mock::{Call, MockNix},
+    process::{Stage, StopError},
+    store_path::StorePath,
+};
+use serde_json::{Value, json};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, duplex};
+
+// Answers like `nix repl` would, for the lines a session sends it. Queries are answered
+// with the first rule whose pattern the line contains, either a JSON value or an error.
+// Queries for `forever` never finish.
+struct FakeRepl {
+    lines: Arc<Mutex<Vec<String>>>,
+}
+
+impl FakeRepl {
+    fn start(rules: Vec<(&'static str, Result<Value, &'static str>)>) -> (FakeRepl, Repl) {
+        let (stdin, mut input) = duplex(1 << 16);
+        let (mut stdout, stdout_reader) = duplex(1 << 16);
+        let (mut stderr, stderr_reader) = duplex(1 << 16);
+        let lines = Arc::new(Mutex::new(vec![]));
+
+        let seen = lines.clone();
+        tokio::spawn(async move {
+            stdout
+                .write_all(b"Welcome to Nix 2.24.0. Type :? for help.\n\n")
+                .await
+                .unwrap();
+            let mut input = BufReader::new(&mut input).lines();
+            while let Ok(Some(line)) = input.next_line().await {
+                seen.lock().unwrap().push(line.clone());
+                if line.ends_with(" exit") {
+                    break;
+                } else if let Some(marker) = line
+                    .strip_prefix("builtins.trace \"")
+                    .and_then(|rest| rest.split('"').next())
+                {
+                    stderr
+                        .write_all(format!("trace: {marker}\n").as_bytes())
+                        .await
+                        .unwrap();
+                    stdout
+                        .write_all(format!("\"{marker}\"\n\n").as_bytes())
+                        .await
+                        .unwrap();
+                } else if line.starts_with("builtins.toJSON forever") {
+                    std::future::pending::<()>().await;
+                } else if line.starts_with("builtins.toJSON ") {
+                    let rule = rules.iter().find(|(pattern, _)| line.contains(pattern));
+                    match rule {
+                        Some((_, Ok(value))) => {
+                            let printed = escape_string(&value.to_string());
+                            stdout
+                                .write_all(format!("\x1b[35m{printed}\x1b[0m\n\n").as_bytes())
+                                .await
+                                .unwrap();
+                        }
+                        Some((_, Err(message))) => {
+                            stderr
+                                .write_all(format!("error: {message}\n").as_bytes())
+                                .await
+                                .unwrap();
+                        }
+                        None => {
+                            stderr
+                                .write_all(b"error: undefined variable\n")
+                                .await
+                                .unwrap();
+                        }
+                    }
+                }
+            }
+        });
+
+        let repl = Repl::from_streams(stdin, stdout_reader, stderr_reader);
+        (FakeRepl { lines }, repl)
+    }
+
+    fn lines(&self) -> Vec<String> {
+        self.lines.lock().unwrap().clone()
+    }
+
+    // The lines sent, leaving out the markers after each one
+    fn queries(&self) -> Vec<String> {
+        self.lines()
+            .into_iter()
+            .filter(|line| !line.starts_with("builtins.trace "))
+            .collect()
+    }
+}
+
+fn json_opts() -> EvalOpts {
+    EvalOpts {
+        json: true,
+        impure: false,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn queries_share_one_process() {
+    let nix = MockNix::new("/nix/store");
+    let (fake, repl) = FakeRepl::start(vec![(
+        "web01",
+        Ok(json!({ "hostName": "web01", "ports": [22, 443] })),
+    )]);
+    let session = EvalSession::with_repl(&nix, repl);
+
+    let host = Expr::var("project").select(AttrPath::from(["systems", "nixos", "web01"]));
+    for _ in 0..3 {
+        assert_eq!(
+            session.evaluate(&host, json_opts()).await.unwrap(),
+            EvalResult::Json(json!({ "hostName": "web01", "ports": [22, 443] }))
+        );
+    }
+
+    assert_eq!(
+        fake.queries(),
+        [
+            "builtins.toJSON project.systems.nixos.web01",
+            "builtins.toJSON project.systems.nixos.web01",
+            "builtins.toJSON project.systems.nixos.web01",
+        ]
+    );
+    // Nothing went through a process of its own
+    assert!(nix.calls().is_empty());
+}
+
+#[tokio::test]
+async fn impure_evaluations_need_a_process_of_their_own() {
+    let nix = MockNix::new("/nix/store");
+    nix.on_eval("currentSystem", json!("x86_64-linux"));
+    let (fake, repl) = FakeRepl::start(vec![("currentSystem", Ok(json!("aarch64-linux")))]);
+    let session = EvalSession::with_repl(&nix, repl);
+
+    // The repl is pure, so what it evaluates can be cached as such
+    assert_eq!(get_system(&session).await.unwrap(), "x86_64-linux");
+    assert!(fake.lines().is_empty());
+    assert!(matches!(nix.calls()[..], [Call::Evaluate { .. }]));
+}
+
+#[tokio::test]
+async fn strings_survive_the_round_trip() {
+    let nix = MockNix::new("/nix/store");
+    let tricky = "say \"hi\"\\\n\tthen ${interpolate} $HOME";
+    let (_fake, repl) = FakeRepl::start(vec![("motd", Ok(json!(tricky)))]);
+    let session = EvalSession::with_repl(&nix, repl);
+
+    let motd = Expr::var("motd");
+    assert_eq!(
+        session.evaluate(&motd, json_opts()).await.unwrap(),
+        EvalResult::Json(json!(tricky))
+    );
+    // Raw strings come back as they are
+    assert_eq!(
+        session
+            .evaluate(
+                &motd,
+                EvalOpts {
+                    impure: false,
+                    ..Default::default()
+                }
+            )
+            .await
+            .unwrap(),
+        EvalResult::Raw(tricky.to_string())
+    );
+}
+
+#[tokio::test]
+async fn errors_do_not_end_the_session() {
+    let nix = MockNix::new("/nix/store");
+    let (_fake, repl) = FakeRepl::start(vec![
+        ("web02", Err("attribute 'web02' missing")),
+        ("web01", Ok(json!(true))),
+    ]);
+    let session = EvalSession::with_repl(&nix, repl);
+
+    let err = session
+        .evaluate(&Expr::var("web02"), json_opts())
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "nix eval failed\nerror: attribute 'web02' missing"
+    );
+
+    assert_eq!(
+        session
+            .evaluate(&Expr::var("web01"), json_opts())
+            .await
+            .unwrap(),
+        EvalResult::Json(json!(true))
+    );
+}
+
+#[tokio::test]
+async fn loaded_projects() {
+    let nix = MockNix::new("/nix/store");
+    let (fake, repl) = FakeRepl::start(vec![("project", Ok(json!(true)))]);
+    let session = EvalSession::with_repl(&nix, repl);
+
+    let entry = FixedOutputStoreEntry {
+        path: StorePath::parse_in(
+            Path::new("/nix/store"),
+            "/nix/store/lplzlyk8ldz821dl6pmlhk3md1ms69md-source",
+        )
+        .unwrap(),
+        hash: "0sjjj9z1dhilhpc8pq4154czrb79z9cm044jvn75kxcjv6v5l2m5".to_string(),
+    };
+    session.load_project("nilla.nix", &entry).await.unwrap();
+    assert!(
+        exists_in_project(&session, "nilla.nix", entry, "systems.nixos.web01")
+            .await
+            .unwrap()
+    );
+
+    let queries = fake.queries();
+    assert_eq!(queries.len(), 2, "{queries:?}");
+    assert!(
+        queries[0].starts_with("project = import ((builtins.path {"),
+        "{}",
+        queries[0]
+    );
+
+    let err = session.bind("not valid", &Expr::Null).await.unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Cannot bind `not valid` in nix repl, it is not a valid identifier"
+    );
+}
+
+#[tokio::test]
+async fn everything_else_goes_to_the_wrapped_backend() {
+    let nix = MockNix::new("/nix/store");
+    nix.on_eval("fetchGit", json!("/nix/store/x-source"));
+    nix.on_realise("/nix/store/x.drv", vec!["/nix/store/x".into()]);
+    let (fake, repl) = FakeRepl::start(vec![]);
+    let session = EvalSession::with_repl(&nix, repl);
+
+    // Evaluations with their own environment cannot use the running repl
+    let fetch = Expr::builtin("fetchGit").apply(Expr::str("https://example.com/infra"));
+    let opts = EvalOpts {
+        env: vec![("NIX_CONFIG".to_string(), "access-tokens = x".to_string())],
+        ..json_opts()
+    };
+    session.evaluate(&fetch, opts).await.unwrap();
+    assert!(session.realise(Path::new("/nix/store/x.drv")).await.is_ok());
+
+    assert!(fake.lines().is_empty());
+    let calls = nix.calls();
+    assert!(matches!(calls[0], Call::Evaluate { .. }), "{calls:?}");
+    assert!(matches!(calls[1], Call::Realise(_)), "{calls:?}");
+    assert_eq!(session.store_dir(), Path::new("/nix/store"));
+}
+
+#[tokio::test]
+async fn stopped_queries() {
+    let nix = MockNix::new("/nix/store");
+    let (fake, repl) = FakeRepl::start(vec![]);
+    let mut session = EvalSession::with_repl(&nix, repl);
+    let timeout = Duration::from_millis(100);
+    session.supervisor.timeout = Some(timeout);
+
+    let err = session
+        .evaluate(&Expr::var("forever"), json_opts())
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.downcast::<StopError>().unwrap(),
+        StopError::TimedOut(Stage::Evaluation, timeout)
+    );
+    assert_eq!(fake.queries(), ["builtins.toJSON forever"]);
+
+    let (_fake, repl) = FakeRepl::start(vec![]);
+    let session = EvalSession::with_repl(&nix, repl);
+    let cancel = session.supervisor.cancel.clone();
+    let stop = async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        cancel.cancel();
+    };
+    let forever = Expr::var("forever");
+    let (result, ()) = tokio::join!(session.evaluate(&forever, json_opts()), stop);
+    assert_eq!(
+        result.unwrap_err().downcast::<StopError>().unwrap(),
+        StopError::Interrupted(Stage::Evaluation)
+    );
+}
+
+#[tokio::test]
+async fn the_repl_going_away() {
+    let nix = MockNix::new("/nix/store");
+    let (_fake, repl) = FakeRepl::start(vec![]);
+    let session = EvalSession::with_repl(&nix, repl);
+
+    let err = session
+        .evaluate(&Expr::var("exit"), json_opts())
+        .await
+        .unwrap_err();
+    assert!(
+        err.to_string().starts_with("nix repl exited unexpectedly"),
+        "{err}"
+    );
+}
@@ -0,0 +1,98 @@
+use std::path::PathBuf;
+
+use nilla_nixos::util::expand::{expand_path, expand_path_with, home_dir};
+
+fn env(name: &str) -> Option<String> {
+    match name {
+        "INFRA" => Some("/srv/infra".to_string()),
+        "HOST" => Some("web01".to_string()),
+        "EMPTY" => Some(String::new()),
+        _ => None,
+    }
+}
+
+fn home(user: Option<&str>) -> Option<PathBuf> {
+    match user {
+        None => Some(PathBuf::from("/home/me")),
+        Some("alice") => Some(PathBuf::from("/home/alice")),
+        Some(_) => None,
+    }
+}
+
+fn expand(input: &str) -> anyhow::Result<PathBuf> {
+    expand_path_with(input, env, home)
+}
+
+#[test]
+fn plain_paths_are_untouched() {
+    for path in ["./infra", "/etc/nixos", "../a b", "relative", "a~b", "./$"] {
+        assert_eq!(expand(path).unwrap(), PathBuf::from(path));
+    }
+}
+
+#[test]
+fn tilde() {
+    assert_eq!(expand("~").unwrap(), PathBuf::from("/home/me"));
+    assert_eq!(expand("~/").unwrap(), PathBuf::from("/home/me/"));
+    assert_eq!(expand("~/infra").unwrap(), PathBuf::from("/home/me/infra"));
+    assert_eq!(
+        expand("~alice/infra").unwrap(),
+        PathBuf::from("/home/alice/infra")
+    );
+    assert_eq!(expand("~alice").unwrap(), PathBuf::from("/home/alice"));
+
+    // Only a leading tilde is special
+    assert_eq!(expand("./~/infra").unwrap(), PathBuf::from("./~/infra"));
+}
+
+#[test]
+fn unknown_user() {
+    let err = expand("~mallory/infra").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Could not find home directory for user mallory"
+    );
+}
+
+#[test]
+fn variables() {
+    assert_eq!(expand("$INFRA").unwrap(), PathBuf::from("/srv/infra"));
+    assert_eq!(
+        expand("$INFRA/hosts/$HOST").unwrap(),
+        PathBuf::from("/srv/infra/hosts/web01")
+    );
+    assert_eq!(
+        expand("${INFRA}2/${HOST}.nix").unwrap(),
+        PathBuf::from("/srv/infra2/web01.nix")
+    );
+    assert_eq!(expand("./$EMPTY/x").unwrap(), PathBuf::from(".//x"));
+    assert_eq!(
+        expand("~/$HOST-config").unwrap(),
+        PathBuf::from("/home/me/web01-config")
+    );
+}
+
+#[test]
+fn dollar_without_name_is_literal() {
+    assert_eq!(expand("./a$/b").unwrap(), PathBuf::from("./a$/b"));
+    assert_eq!(expand("./$1").unwrap(), PathBuf::from("./$1"));
+}
+
+#[test]
+fn variable_errors() {
+    assert_eq!(
+        expand("$MISSING/infra").unwrap_err().to_string(),
+        "Environment variable MISSING is not set (used in $MISSING/infra)"
+    );
+    assert!(expand("${INFRA").is_err());
+    assert!(expand("${}").is_err());
+}
+
+#[test]
+fn real_home_directory() {
+    assert_eq!(expand_path("~").unwrap(), home_dir(None).unwrap());
+    assert_eq!(
+        expand_path("~/infra").unwrap(),
+        home_dir(None).unwrap().join("infra")
+    );
+}
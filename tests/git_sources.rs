@@ -0,0 +1,152 @@
+use std::path::Path;
+
+use nilla_nixos::util::{
+    config::{Config, HostConfig, Transport},
+    project::git_info,
+    project_ref::ProjectRef,
+};
+
+mod common;
+
+use common::run;
+
+// A bare repository with a single commit on `main`, standing in for a remote.
+fn bare_repo(root: &Path) -> std::path::PathBuf {
+    let work = root.join("work");
+    let bare = root.join("infra.git");
+    std::fs::create_dir(&work).unwrap();
+    run(&work, "git", &["init", "--quiet", "--initial-branch=main"]);
+    std::fs::write(work.join("nilla.nix"), "{ }\n").unwrap();
+    run(&work, "git", &["add", "nilla.nix"]);
+    run(&work, "git", &["commit", "--quiet", "-m", "init"]);
+    run(
+        root,
+        "git",
+        &[
+            "clone",
+            "--quiet",
+            "--bare",
+            work.to_str().unwrap(),
+            "infra.git",
+        ],
+    );
+    bare
+}
+
+fn info(project: &str, config: &Config) -> nilla_nixos::util::project::GitInfo {
+    let project_ref: ProjectRef = project.parse().unwrap();
+    git_info(&project_ref, config).unwrap()
+}
+
+#[test]
+fn git_file_urls_reach_local_bare_repos() {
+    let dir = tempfile::tempdir().unwrap();
+    let bare = bare_repo(dir.path());
+
+    let info = info(
+        &format!("git+file://{}?ref=main&dir=hosts", bare.display()),
+        &Config::default(),
+    );
+    assert_eq!(info.url, format!("file://{}", bare.display()));
+    assert_eq!(info.r#ref.as_deref(), Some("main"));
+    assert_eq!(info.dir.as_deref(), Some("hosts"));
+
+    let refs = run(dir.path(), "git", &["ls-remote", &info.url]);
+    assert!(refs.contains("refs/heads/main"), "{refs}");
+}
+
+#[test]
+fn plain_git_urls_are_passed_through() {
+    let dir = tempfile::tempdir().unwrap();
+    let bare = bare_repo(dir.path());
+
+    let info = info(&format!("git:{}", bare.display()), &Config::default());
+    assert_eq!(info.url, bare.display().to_string());
+
+    let refs = run(dir.path(), "git", &["ls-remote", &info.url]);
+    assert!(refs.contains("refs/heads/main"), "{refs}");
+}
+
+#[test]
+fn remote_git_urls_keep_their_transport() {
+    let config = Config::default();
+    assert_eq!(
+        info("git+https://git.example.com/org/infra.git", &config).url,
+        "https://git.example.com/org/infra.git"
+    );
+    assert_eq!(
+        info("git+ssh://git@git.example.com:2222/org/infra.git", &config).url,
+        "ssh://git@git.example.com:2222/org/infra.git"
+    );
+    assert_eq!(
+        info("git+http://git.example.com/org/infra.git", &config).url,
+        "http://git.example.com/org/infra.git"
+    );
+}
+
+#[test]
+fn forge_shorthands() {
+    let config = Config::default();
+    assert_eq!(
+        info("codeberg:org/infra", &config).url,
+        "git@codeberg.org:org/infra.git"
+    );
+    assert_eq!(
+        info("forgejo:org/infra?host=git.example.com", &config).url,
+        "git@git.example.com:org/infra.git"
+    );
+    assert_eq!(
+        info("gitea:org/infra?host=gitea.example.com&rev=abc", &config).url,
+        "git@gitea.example.com:org/infra.git"
+    );
+    assert_eq!(
+        info("codeberg:org/infra?host=mirror.example.com", &config).url,
+        "git@mirror.example.com:org/infra.git"
+    );
+    assert_eq!(
+        info("sourcehut:~alice/infra", &config).url,
+        "git@git.sr.ht:~alice/infra"
+    );
+}
+
+#[test]
+fn flake_style_refs_reach_git() {
+    let config = Config::default();
+    let main = info("github:org/infra/main", &config);
+    assert_eq!(main.url, "git@github.com:org/infra.git");
+    assert_eq!(main.r#ref.as_deref(), Some("main"));
+    assert_eq!(main.rev, None);
+
+    let rev = "0123456789abcdef0123456789abcdef01234567";
+    let pinned = info(&format!("sourcehut:~alice/infra/{rev}?dir=hosts"), &config);
+    assert_eq!(pinned.rev.as_deref(), Some(rev));
+    assert_eq!(pinned.dir.as_deref(), Some("hosts"));
+}
+
+#[test]
+fn forge_shorthands_follow_host_transport() {
+    let mut config = Config::default();
+    config.hosts.insert(
+        "codeberg.org".to_string(),
+        HostConfig {
+            transport: Some(Transport::Https),
+            ..Default::default()
+        },
+    );
+    assert_eq!(
+        info("codeberg:org/infra", &config).url,
+        "https://codeberg.org/org/infra.git"
+    );
+    assert_eq!(
+        info("github:org/infra", &config).url,
+        "git@github.com:org/infra.git"
+    );
+}
+
+#[test]
+fn non_git_references() {
+    for project in ["./infra", "path:/srv/infra", "https://example.com/a.tar.gz"] {
+        let project_ref: ProjectRef = project.parse().unwrap();
+        assert!(git_info(&project_ref, &Config::default()).is_none());
+    }
+}
@@ -0,0 +1,71 @@
+use nilla_nixos::util::hash::Sha256;
+use proptest::prelude::*;
+
+// sha256("")
+const EMPTY_HEX: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+const EMPTY_NIX32: &str = "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73";
+const EMPTY_SRI: &str = "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=";
+
+#[test]
+fn notations() {
+    let empty = Sha256::digest(b"");
+    assert_eq!(empty.to_hex(), EMPTY_HEX);
+    assert_eq!(empty.to_nix32(), EMPTY_NIX32);
+    assert_eq!(empty.to_sri(), EMPTY_SRI);
+    assert_eq!(empty.to_string(), EMPTY_SRI);
+
+    for notation in [
+        EMPTY_HEX,
+        EMPTY_NIX32,
+        EMPTY_SRI,
+        &format!("sha256:{EMPTY_HEX}"),
+        &format!("sha256:{EMPTY_NIX32}"),
+        &EMPTY_HEX.to_uppercase(),
+        "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=",
+    ] {
+        assert_eq!(notation.parse::<Sha256>(), Ok(empty), "{notation}");
+    }
+}
+
+#[test]
+fn invalid_hashes() {
+    for hash in [
+        "",
+        "abc",
+        &EMPTY_HEX[1..],
+        &format!("{EMPTY_HEX}0"),
+        &EMPTY_HEX.replace('e', "g"),
+        // `e` is not part of the base-32 alphabet
+        &EMPTY_NIX32.replace('0', "e"),
+        // The first base-32 character only carries a single bit
+        &format!("z{}", &EMPTY_NIX32[1..]),
+        "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSu==",
+        "sha512-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=",
+        &format!("md5:{EMPTY_HEX}"),
+    ] {
+        let err = hash.parse::<Sha256>().unwrap_err();
+        assert_eq!(err.to_string(), format!("Invalid SHA-256 hash `{hash}`"));
+    }
+}
+
+#[test]
+fn files() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data");
+    std::fs::write(&path, vec![7u8; 200_000]).unwrap();
+    assert_eq!(
+        Sha256::of_file(&path).unwrap(),
+        Sha256::digest(vec![7u8; 200_000])
+    );
+    assert!(Sha256::of_file(&dir.path().join("missing")).is_err());
+}
+
+proptest! {
+    #[test]
+    fn notations_roundtrip(data in proptest::collection::vec(any::<u8>(), 0..64)) {
+        let hash = Sha256::digest(&data);
+        prop_assert_eq!(hash.to_hex().parse::<Sha256>(), Ok(hash));
+        prop_assert_eq!(hash.to_nix32().parse::<Sha256>(), Ok(hash));
+        prop_assert_eq!(hash.to_sri().parse::<Sha256>(), Ok(hash));
+    }
+}
@@ -0,0 +1,173 @@
+use std::path::{Path, PathBuf};
+
+use nilla_nixos::util::project::{LocalProject, locate_project};
+
+fn touch(path: PathBuf) {
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(path, "{ }\n").unwrap();
+}
+
+// repo/
+//   .git/
+//   nilla.nix
+//   hosts/prod/nilla.nix
+//   hosts/prod/modules/
+//   hosts/staging/nixos.nix
+fn monorepo() -> (tempfile::TempDir, PathBuf) {
+    let dir = tempfile::tempdir().unwrap();
+    let repo = dir.path().canonicalize().unwrap().join("repo");
+    std::fs::create_dir_all(repo.join(".git")).unwrap();
+    std::fs::create_dir_all(repo.join("hosts/prod/modules")).unwrap();
+    touch(repo.join("nilla.nix"));
+    touch(repo.join("hosts/prod/nilla.nix"));
+    touch(repo.join("hosts/staging/nixos.nix"));
+    (dir, repo)
+}
+
+fn locate(path: &Path, dir: Option<&str>, file: &str) -> anyhow::Result<LocalProject> {
+    locate_project(path.to_str().unwrap(), dir, file)
+}
+
+#[test]
+fn single_candidate() {
+    let (_dir, repo) = monorepo();
+    assert_eq!(
+        locate(&repo, None, "nilla.nix").unwrap(),
+        LocalProject {
+            root: repo.clone(),
+            dir: None,
+        }
+    );
+    // Pointing at the entry file itself works too
+    assert_eq!(
+        locate(&repo.join("nilla.nix"), None, "nilla.nix")
+            .unwrap()
+            .root,
+        repo
+    );
+}
+
+#[test]
+fn nested_candidates_are_ambiguous() {
+    let (_dir, repo) = monorepo();
+    let err = locate(&repo.join("hosts/prod/modules"), None, "nilla.nix").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "Found several nilla.nix files above {}, choose one with:\n  \
+             path:{root}?dir=.\n  \
+             path:{root}?dir=hosts/prod",
+            repo.join("hosts/prod/modules").display(),
+            root = repo.display(),
+        )
+    );
+}
+
+#[test]
+fn search_stops_at_the_repository_root() {
+    let (dir, repo) = monorepo();
+    // A stray entry file above the repository is never considered
+    touch(dir.path().join("nilla.nix"));
+    assert_eq!(locate(&repo, None, "nilla.nix").unwrap().root, repo);
+}
+
+#[test]
+fn explicit_dir() {
+    let (_dir, repo) = monorepo();
+    assert_eq!(
+        locate(&repo, Some("hosts/prod"), "nilla.nix").unwrap(),
+        LocalProject {
+            root: repo.clone(),
+            dir: Some(PathBuf::from("hosts/prod")),
+        }
+    );
+    assert_eq!(
+        locate(&repo, Some("."), "nilla.nix").unwrap(),
+        LocalProject {
+            root: repo.clone(),
+            dir: None,
+        }
+    );
+
+    let err = locate(&repo, Some("hosts/staging"), "nilla.nix").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "Could not find nilla.nix in {} (with dir=hosts/staging)",
+            repo.display()
+        )
+    );
+    for dir in ["../repo", "/etc", ""] {
+        let err = locate(&repo, Some(dir), "nilla.nix").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("dir must be a relative path inside the project, got `{dir}`")
+        );
+    }
+    assert!(locate(&repo.join("nilla.nix"), Some("hosts/prod"), "nilla.nix").is_err());
+}
+
+#[test]
+fn custom_entry_file() {
+    let (_dir, repo) = monorepo();
+    assert_eq!(
+        locate(&repo.join("hosts/staging"), None, "nixos.nix").unwrap(),
+        LocalProject {
+            root: repo.join("hosts/staging"),
+            dir: None,
+        }
+    );
+    assert_eq!(
+        locate(&repo, Some("hosts/staging"), "nixos.nix")
+            .unwrap()
+            .dir,
+        Some(PathBuf::from("hosts/staging"))
+    );
+    assert_eq!(
+        locate(&repo, None, "nixos.nix").unwrap_err().to_string(),
+        format!("Could not find nixos.nix in {}", repo.display())
+    );
+    assert!(locate(&repo, None, "../nilla.nix").is_err());
+}
+
+#[test]
+fn nested_entry_file() {
+    let (_dir, repo) = monorepo();
+    // The root is where the whole of `--file` was found, not the file's own directory
+    assert_eq!(
+        locate(&repo, None, "hosts/prod/nilla.nix").unwrap(),
+        LocalProject {
+            root: repo.clone(),
+            dir: None,
+        }
+    );
+    assert_eq!(
+        locate(&repo.join("hosts/prod/modules"), None, "./prod/nilla.nix")
+            .unwrap()
+            .root,
+        repo.join("hosts")
+    );
+
+    touch(repo.join("prod/nilla.nix"));
+    let err = locate(&repo.join("hosts/prod/modules"), None, "prod/nilla.nix").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "Found several prod/nilla.nix files above {}, choose one with:\n  \
+             path:{root}?dir=.\n  \
+             path:{root}?dir=hosts",
+            repo.join("hosts/prod/modules").display(),
+            root = repo.display(),
+        )
+    );
+}
+
+#[test]
+fn missing_paths() {
+    let (_dir, repo) = monorepo();
+    let missing = repo.join("missing");
+    assert_eq!(
+        locate(&missing, None, "nilla.nix").unwrap_err().to_string(),
+        format!("Could not find path {}", missing.display())
+    );
+}
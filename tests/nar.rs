@@ -0,0 +1,302 @@
+use std::{
+    os::unix::fs::{PermissionsExt, symlink},
+    path::{Path, PathBuf},
+};
+
+use nilla_nixos::util::{
+    hash::Sha256,
+    nar::{self, Filter},
+    store_path::StorePath,
+};
+
+// A NAR token: its length, its bytes and padding up to a multiple of 8.
+fn token(bytes: &[u8]) -> Vec<u8> {
+    let mut out = (bytes.len() as u64).to_le_bytes().to_vec();
+    out.extend_from_slice(bytes);
+    out.resize(out.len().next_multiple_of(8), 0);
+    out
+}
+
+fn tokens(parts: &[&[u8]]) -> Vec<u8> {
+    parts.iter().flat_map(|part| token(part)).collect()
+}
+
+fn write(path: PathBuf, contents: &str, mode: u32) {
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(&path, contents).unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+}
+
+// tree/
+//   README -> share/doc/README
+//   bin/hello (executable)
+//   share/doc/README
+fn tree(root: &Path) -> PathBuf {
+    let tree = root.join("tree");
+    write(tree.join("bin/hello"), "#!/bin/sh\necho hello\n", 0o755);
+    write(tree.join("share/doc/README"), "hello\n", 0o644);
+    symlink("share/doc/README", tree.join("README")).unwrap();
+    tree
+}
+
+fn dump(path: &Path, filter: Filter) -> Vec<u8> {
+    let mut out = vec![];
+    nar::dump(path, filter, &mut out).unwrap();
+    out
+}
+
+#[test]
+fn wire_format() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("hello.txt");
+    std::fs::write(&file, "Hello World!").unwrap();
+
+    assert_eq!(
+        dump(&file, Filter::All),
+        tokens(&[
+            b"nix-archive-1",
+            b"(",
+            b"type",
+            b"regular",
+            b"contents",
+            b"Hello World!",
+            b")",
+        ])
+    );
+
+    let tree = tree(dir.path());
+    let expected = [
+        tokens(&[b"nix-archive-1", b"(", b"type", b"directory"]),
+        // Symlinks keep their target as-is
+        tokens(&[b"entry", b"(", b"name", b"README", b"node"]),
+        tokens(&[
+            b"(",
+            b"type",
+            b"symlink",
+            b"target",
+            b"share/doc/README",
+            b")",
+        ]),
+        tokens(&[b")"]),
+        tokens(&[b"entry", b"(", b"name", b"bin", b"node"]),
+        tokens(&[b"(", b"type", b"directory"]),
+        tokens(&[b"entry", b"(", b"name", b"hello", b"node"]),
+        tokens(&[b"(", b"type", b"regular", b"executable", b"", b"contents"]),
+        token(b"#!/bin/sh\necho hello\n"),
+        tokens(&[b")", b")", b")", b")"]),
+        tokens(&[b"entry", b"(", b"name", b"share", b"node"]),
+        tokens(&[b"(", b"type", b"directory"]),
+        tokens(&[b"entry", b"(", b"name", b"doc", b"node"]),
+        tokens(&[b"(", b"type", b"directory"]),
+        tokens(&[b"entry", b"(", b"name", b"README", b"node"]),
+        tokens(&[b"(", b"type", b"regular", b"contents", b"hello\n", b")"]),
+        tokens(&[b")", b")", b")", b")", b")"]),
+        // Closing the top-level directory
+        tokens(&[b")"]),
+    ]
+    .concat();
+    assert_eq!(dump(&tree, Filter::All), expected);
+}
+
+#[test]
+fn known_hashes() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("hello.txt");
+    std::fs::write(&file, "Hello World!").unwrap();
+    let empty = dir.path().join("empty");
+    std::fs::create_dir(&empty).unwrap();
+    let tree = tree(dir.path());
+
+    let cases = [
+        (
+            &file,
+            "sha256-A+f2O+MLBl14vPYV9Uc1Rf2062mqQW9DSVtOBc37gEA=",
+            128,
+        ),
+        // The hash every empty `fetchzip` and friends end up with
+        (
+            &empty,
+            "sha256-pQpattmS9VmO3ZIQUFn66az8GSmB4IvYhTTCFn6SUmo=",
+            96,
+        ),
+        (
+            &tree,
+            "sha256-zoX8P8vnXh+RIk8PgjxIR3cx8TAlkLGQVcqCN7UBE5c=",
+            1232,
+        ),
+    ];
+    for (path, expected, size) in cases {
+        let hash = nar::hash(path, Filter::All).unwrap();
+        assert_eq!(hash.hash.to_sri(), expected, "{path:?}");
+        assert_eq!(hash.size, size, "{path:?}");
+        assert_eq!(hash.skipped, 0, "{path:?}");
+    }
+}
+
+#[test]
+fn only_the_executable_bit_matters() {
+    let dir = tempfile::tempdir().unwrap();
+    let tree = tree(dir.path());
+    let before = nar::hash(&tree, Filter::All).unwrap();
+
+    let readme = tree.join("share/doc/README");
+    std::fs::set_permissions(&readme, std::fs::Permissions::from_mode(0o600)).unwrap();
+    assert_eq!(nar::hash(&tree, Filter::All).unwrap(), before);
+
+    std::fs::set_permissions(&readme, std::fs::Permissions::from_mode(0o700)).unwrap();
+    assert_ne!(nar::hash(&tree, Filter::All).unwrap(), before);
+}
+
+#[test]
+fn entries_are_sorted_bytewise() {
+    let dir = tempfile::tempdir().unwrap();
+    for name in ["b", "a", "B", "_"] {
+        write(dir.path().join("sorted").join(name), "", 0o644);
+    }
+    let out = dump(&dir.path().join("sorted"), Filter::All);
+    let positions = ["B", "_", "a", "b"]
+        .map(|name| {
+            let needle = tokens(&[b"name", name.as_bytes()]);
+            out.windows(needle.len())
+                .position(|window| window == needle)
+                .unwrap()
+        })
+        .to_vec();
+    assert!(positions.is_sorted(), "{positions:?}");
+}
+
+// project/
+//   .git/HEAD
+//   .gitignore         result, *.log, !keep.log
+//   nilla.nix
+//   build.log
+//   keep.log
+//   result -> /nix/store/...
+//   hosts/.gitignore   secrets/
+//   hosts/web01.nix
+//   hosts/secrets/key
+fn project(root: &Path) -> PathBuf {
+    let project = root.join("project");
+    write(project.join(".git/HEAD"), "ref: refs/heads/main\n", 0o644);
+    write(
+        project.join(".gitignore"),
+        "result\n*.log\n!keep.log\n",
+        0o644,
+    );
+    write(project.join("nilla.nix"), "{ }\n", 0o644);
+    write(project.join("build.log"), "noise\n", 0o644);
+    write(project.join("keep.log"), "kept\n", 0o644);
+    symlink("/nix/store/x-system", project.join("result")).unwrap();
+    write(project.join("hosts/.gitignore"), "secrets/\n", 0o644);
+    write(project.join("hosts/web01.nix"), "{ }\n", 0o644);
+    write(project.join("hosts/secrets/key"), "hunter2\n", 0o600);
+    project
+}
+
+#[test]
+fn gitignore_filtering() {
+    let dir = tempfile::tempdir().unwrap();
+    let project = project(dir.path());
+
+    let all = nar::hash(&project, Filter::All).unwrap();
+    let filtered = nar::hash(&project, Filter::GitIgnore).unwrap();
+    assert_eq!(all.skipped, 0);
+    // .git, build.log, result and hosts/secrets
+    assert_eq!(filtered.skipped, 4);
+    assert_ne!(all.hash, filtered.hash);
+
+    let copy = dir.path().join("copy");
+    nar::copy(&project, &copy, Filter::GitIgnore).unwrap();
+    for kept in [
+        ".gitignore",
+        "nilla.nix",
+        "keep.log",
+        "hosts/.gitignore",
+        "hosts/web01.nix",
+    ] {
+        assert!(copy.join(kept).exists(), "{kept}");
+    }
+    for left_out in [".git", "build.log", "result", "hosts/secrets"] {
+        assert!(!copy.join(left_out).exists(), "{left_out}");
+    }
+
+    // The copy hashes the same as the filtered original
+    let copied = nar::hash(&copy, Filter::All).unwrap();
+    assert_eq!(copied.hash, filtered.hash);
+    assert_eq!(copied.size, filtered.size);
+}
+
+#[test]
+fn symlinks_are_not_followed() {
+    let dir = tempfile::tempdir().unwrap();
+    let tree = tree(dir.path());
+    let copy = dir.path().join("copy");
+    nar::copy(&tree, &copy, Filter::All).unwrap();
+
+    assert_eq!(
+        std::fs::read_link(copy.join("README")).unwrap(),
+        Path::new("share/doc/README")
+    );
+    assert!(
+        std::fs::metadata(copy.join("bin/hello"))
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o100
+            != 0
+    );
+    assert_eq!(
+        nar::hash(&copy, Filter::All).unwrap().hash,
+        nar::hash(&tree, Filter::All).unwrap().hash
+    );
+}
+
+#[test]
+fn store_paths() {
+    let store = Path::new("/nix/store");
+    let cases = [
+        (
+            "hello.txt",
+            "sha256-A+f2O+MLBl14vPYV9Uc1Rf2062mqQW9DSVtOBc37gEA=",
+            "/nix/store/925f1jb1ajrypjbyq7rylwryqwizvhp0-hello.txt",
+        ),
+        (
+            "empty",
+            "sha256-pQpattmS9VmO3ZIQUFn66az8GSmB4IvYhTTCFn6SUmo=",
+            "/nix/store/9ljssglw74jabzzsqsl3lim4d5jgh4ya-empty",
+        ),
+        (
+            "tree",
+            "sha256-zoX8P8vnXh+RIk8PgjxIR3cx8TAlkLGQVcqCN7UBE5c=",
+            "/nix/store/73r8gpf6cr1ssg1rkiy4ys4yv0k0ww0p-tree",
+        ),
+    ];
+    for (name, hash, expected) in cases {
+        let hash: Sha256 = hash.parse().unwrap();
+        assert_eq!(
+            StorePath::for_source(store, name, &hash).unwrap().as_path(),
+            Path::new(expected),
+            "{name}"
+        );
+    }
+
+    // The store directory is part of the fingerprint
+    let hash: Sha256 = cases[0].1.parse().unwrap();
+    let custom = StorePath::for_source(Path::new("/gnu/store"), "hello.txt", &hash).unwrap();
+    assert_eq!(custom.store_dir(), Path::new("/gnu/store"));
+    assert_eq!(custom.name(), "hello.txt");
+    assert_ne!(
+        custom.as_path().file_name(),
+        Path::new(cases[0].2).file_name()
+    );
+}
+
+#[test]
+fn errors() {
+    let dir = tempfile::tempdir().unwrap();
+    assert!(nar::hash(&dir.path().join("missing"), Filter::All).is_err());
+
+    let tree = tree(dir.path());
+    // The destination of a copy must not exist yet
+    assert!(nar::copy(&tree, &tree, Filter::All).is_err());
+}
@@ -0,0 +1,194 @@
+use nilla_nixos::util::nix_expr::{AttrPath, Expr, escape_string, is_identifier};
+use proptest::prelude::*;
+
+// Decode a rendered Nix string literal the way the Nix lexer would, failing if it
+// contains an interpolation or ends early.
+fn unescape(literal: &str) -> Option<String> {
+    let inner = literal.strip_prefix('"')?.strip_suffix('"')?;
+    let mut out = String::new();
+    let mut chars = inner.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next()? {
+                'n' => out.push('\n'),
+                'r' => out.push('\r'),
+                't' => out.push('\t'),
+                c => out.push(c),
+            },
+            '"' => return None,
+            '$' if chars.peek() == Some(&'{') => return None,
+            c => out.push(c),
+        }
+    }
+    Some(out)
+}
+
+#[test]
+fn strings_are_escaped() {
+    assert_eq!(escape_string("plain"), "\"plain\"");
+    assert_eq!(escape_string("a\"b"), "\"a\\\"b\"");
+    assert_eq!(escape_string("a\\b"), "\"a\\\\b\"");
+    assert_eq!(escape_string("${pkgs}"), "\"\\${pkgs}\"");
+    assert_eq!(escape_string("$$"), "\"$$\"");
+    assert_eq!(escape_string("line\nbreak\t"), "\"line\\nbreak\\t\"");
+}
+
+#[test]
+fn identifiers() {
+    assert!(is_identifier("nixos"));
+    assert!(is_identifier("my-host"));
+    assert!(is_identifier("x'"));
+    assert!(is_identifier("_private"));
+    assert!(!is_identifier(""));
+    assert!(!is_identifier("1host"));
+    assert!(!is_identifier("a.b"));
+    assert!(!is_identifier("in"));
+    assert!(!is_identifier("or"));
+}
+
+#[test]
+fn attr_paths() {
+    let path = AttrPath::from(["systems", "nixos", "web.example.com", "result"]);
+    assert_eq!(path.to_string(), "systems.nixos.\"web.example.com\".result");
+    assert_eq!(
+        path.to_cli_arg().unwrap(),
+        "systems.nixos.\"web.example.com\".result"
+    );
+
+    let path = AttrPath::from(["systems", "${x}", "if"]);
+    assert_eq!(path.to_string(), "systems.\"\\${x}\".\"if\"");
+
+    assert!(AttrPath::from(["systems", "a\"b"]).to_cli_arg().is_err());
+    assert!(AttrPath::from(["systems", ""]).to_cli_arg().is_err());
+
+    assert_eq!(
+        AttrPath::from_dotted("packages.hello"),
+        AttrPath::from(["packages", "hello"])
+    );
+}
+
+#[test]
+fn parsed_attr_paths() {
+    assert_eq!(
+        AttrPath::parse("services.nginx.enable").unwrap(),
+        AttrPath::from(["services", "nginx", "enable"])
+    );
+    assert_eq!(
+        AttrPath::parse("services.nginx.virtualHosts.\"example.com\".root").unwrap(),
+        AttrPath::from(["services", "nginx", "virtualHosts", "example.com", "root"])
+    );
+
+    for bad in ["", "a..b", "a.", "a.\"b", "a.\"b\"c", "a.\"\""] {
+        assert!(AttrPath::parse(bad).is_err(), "{bad}");
+    }
+}
+
+#[test]
+fn builtins_and_application() {
+    let expr = Expr::builtin("fetchTarball").apply(Expr::attrs([(
+        "url",
+        Expr::str("https://example.com/\"; builtins.abort \"x"),
+    )]));
+    assert_eq!(
+        expr.to_string(),
+        "builtins.fetchTarball { url = \"https://example.com/\\\"; builtins.abort \\\"x\"; }"
+    );
+
+    let nested = Expr::var("f")
+        .apply(Expr::var("a"))
+        .apply(Expr::var("g").apply(Expr::var("b")));
+    assert_eq!(nested.to_string(), "f a (g b)");
+}
+
+#[test]
+fn operands_are_parenthesised() {
+    let expr = Expr::var("import").apply(Expr::var("source").concat(Expr::str("/nilla.nix")));
+    assert_eq!(expr.to_string(), "import (source + \"/nilla.nix\")");
+
+    let expr = Expr::var("a")
+        .select_or(AttrPath::from(["b"]), Expr::Null)
+        .select(AttrPath::from(["c"]));
+    assert_eq!(expr.to_string(), "(a.b or null).c");
+
+    let expr = Expr::var("f").apply(Expr::Int(-1));
+    assert_eq!(expr.to_string(), "f (-1)");
+
+    let expr = Expr::attrs([("a", Expr::Int(1))]).update(Expr::attrs::<_, &str>([]));
+    assert_eq!(expr.to_string(), "{ a = 1; } // { }");
+
+    let expr = Expr::List(vec![Expr::var("f").apply(Expr::Bool(true)), Expr::Null]);
+    assert_eq!(expr.to_string(), "[ (f true) null ]");
+}
+
+#[test]
+fn lambdas() {
+    let names = Expr::builtin("map")
+        .apply(Expr::lambda("option", Expr::var("option").select(["name"])))
+        .apply(Expr::var("options"));
+    assert_eq!(
+        names.to_string(),
+        "builtins.map (option: option.name) options"
+    );
+
+    let pinned = Expr::lambda(
+        "x",
+        Expr::builtin("path").apply(Expr::attrs([("sha256", Expr::str("abc"))])),
+    );
+    assert_eq!(pinned.pinned_hashes(), ["abc"]);
+}
+
+#[test]
+fn conditionals() {
+    let expr = Expr::if_then_else(
+        Expr::builtin("isList").apply(Expr::var("x")),
+        Expr::var("x"),
+        Expr::List(vec![Expr::var("x")]),
+    );
+    assert_eq!(expr.to_string(), "if builtins.isList x then x else [ x ]");
+    // Nested in an operand, the conditional is parenthesised
+    assert_eq!(
+        Expr::var("f").apply(expr).to_string(),
+        "f (if builtins.isList x then x else [ x ])"
+    );
+}
+
+#[test]
+fn let_and_has_attr() {
+    let expr = Expr::let_in(
+        [("project", Expr::var("import").apply(Expr::str("/p")))],
+        Expr::var("project").has_attr(AttrPath::from_dotted("packages.hello")),
+    );
+    assert_eq!(
+        expr.to_string(),
+        "let project = import \"/p\"; in project ? packages.hello"
+    );
+}
+
+#[test]
+fn options_map_to_null() {
+    assert_eq!(Expr::from(None::<&str>), Expr::Null);
+    assert_eq!(Expr::from(Some("rev")), Expr::str("rev"));
+}
+
+#[test]
+#[should_panic]
+fn variables_must_be_identifiers() {
+    Expr::var("not valid");
+}
+
+proptest! {
+    #[test]
+    fn strings_roundtrip(s in any::<String>()) {
+        prop_assert_eq!(unescape(&escape_string(&s)), Some(s));
+    }
+
+    #[test]
+    fn attr_names_roundtrip(s in any::<String>()) {
+        let rendered = AttrPath::from([s.as_str()]).to_string();
+        if is_identifier(&s) {
+            prop_assert_eq!(rendered, s);
+        } else {
+            prop_assert_eq!(unescape(&rendered), Some(s));
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use std::path::Path;
+
+use clap::Parser;
+use nilla_nixos::util::{
+    config::Config,
+    nix::{BuildOpts, EvalOpts, NixSettings, build_command, eval_command, nix_store_command},
+    nix_version::NixVersion,
+};
+use nixos_cli_def::Cli;
+
+fn cli(args: &[&str]) -> Cli {
+    Cli::parse_from(std::iter::once("nilla-nixos").chain(args.iter().copied()))
+}
+
+fn settings() -> NixSettings {
+    NixSettings {
+        options: vec![("sandbox".to_string(), "relaxed".to_string())],
+        extra_args: vec!["--builders".to_string(), "ssh://builder".to_string()],
+    }
+}
+
+#[test]
+fn command_line() {
+    let parsed = cli(&[
+        "build",
+        "web01",
+        "--nix-option",
+        "sandbox=relaxed",
+        "--nix-option",
+        "substituters=https://a https://b",
+        "--nix-option",
+        "post-build-hook=",
+        "--",
+        "--builders",
+        "ssh://builder x86_64-linux",
+        "--accept-flake-config",
+    ]);
+    assert_eq!(
+        NixSettings::new(&Config::default(), &parsed),
+        NixSettings {
+            options: vec![
+                ("sandbox".to_string(), "relaxed".to_string()),
+                (
+                    "substituters".to_string(),
+                    "https://a https://b".to_string()
+                ),
+                ("post-build-hook".to_string(), String::new()),
+            ],
+            extra_args: vec![
+                "--builders".to_string(),
+                "ssh://builder x86_64-linux".to_string(),
+                "--accept-flake-config".to_string(),
+            ],
+        }
+    );
+
+    // As with any global option, giving it after the command replaces what came before
+    let replaced = cli(&[
+        "--nix-option",
+        "sandbox=false",
+        "build",
+        "--nix-option",
+        "sandbox=relaxed",
+    ]);
+    assert_eq!(
+        NixSettings::new(&Config::default(), &replaced).options,
+        [("sandbox".to_string(), "relaxed".to_string())]
+    );
+
+    for invalid in ["sandbox", "=relaxed"] {
+        let err = Cli::try_parse_from(["nilla-nixos", "--nix-option", invalid, "build"])
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("expected NAME=VALUE"), "{invalid}: {err}");
+    }
+}
+
+#[test]
+fn config_file() {
+    let config = Config::parse(
+        r#"
+[nix]
+options = { sandbox = "false", substituters = "https://cache.example.com" }
+extra-args = ["--fallback"]
+"#,
+    )
+    .unwrap();
+    assert_eq!(config.nix.extra_args, ["--fallback"]);
+    assert_eq!(Config::parse(&config.to_toml().unwrap()).unwrap(), config);
+
+    // The config file comes first, so the command line has the last word
+    let cli = cli(&["--nix-option", "sandbox=relaxed", "build", "--", "-L"]);
+    let settings = NixSettings::new(&config, &cli);
+    assert_eq!(
+        settings.args(),
+        [
+            "--option",
+            "sandbox",
+            "false",
+            "--option",
+            "substituters",
+            "https://cache.example.com",
+            "--option",
+            "sandbox",
+            "relaxed",
+            "--fallback",
+            "-L"
+        ]
+    );
+
+    assert!(!Config::default().to_toml().unwrap().contains("[nix]"));
+}
+
+#[test]
+fn every_invocation_gets_them() {
+    let nix = NixVersion::parse("nix (Lix, like Nix) 2.91.1").unwrap();
+    let extra = settings().args();
+
+    let (_, args) = eval_command(&nix, &settings(), "1 + 1", &EvalOpts::default());
+    // Before the expression, which has to come last
+    assert_eq!(args[args.len() - 2 - extra.len()..args.len() - 2], extra);
+    assert_eq!(args[args.len() - 2..], ["--expr", "1 + 1"]);
+
+    let file = Path::new("/nix/store/x-source/nilla.nix");
+    let (_, args) = build_command(&nix, &settings(), file, "web01", &BuildOpts::default());
+    let at = args.iter().position(|arg| arg == "-f").unwrap();
+    assert_eq!(args[at - extra.len()..at], extra);
+
+    let old = NixVersion::parse("nix (Nix) 2.3.16").unwrap();
+    let (_, args) = eval_command(&old, &settings(), "1 + 1", &EvalOpts::default());
+    assert!(args.windows(extra.len()).any(|window| window == extra));
+    let (_, args) = build_command(&old, &settings(), file, "web01", &BuildOpts::default());
+    assert!(args.ends_with(&extra));
+
+    assert!(NixSettings::default().args().is_empty());
+}
+
+#[test]
+fn store_commands_only_take_settings() {
+    // Flags for evaluations, which nix-store rejects
+    let settings = NixSettings {
+        extra_args: vec![
+            "--override-input".to_string(),
+            "nixpkgs".to_string(),
+            "/srv/nixpkgs".to_string(),
+        ],
+        ..settings()
+    };
+    let command = nix_store_command(&settings);
+    assert_eq!(command.as_std().get_program(), "nix-store");
+    assert_eq!(
+        command.as_std().get_args().collect::<Vec<_>>(),
+        ["--option", "sandbox", "relaxed"]
+    );
+
+    // Evaluations still get everything
+    let (_, args) = eval_command(
+        &NixVersion::parse("nix (Nix) 2.24.0").unwrap(),
+        &settings,
+        "1",
+        &EvalOpts::default(),
+    );
+    assert!(args.contains(&"--override-input".to_string()), "{args:?}");
+}
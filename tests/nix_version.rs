@@ -0,0 +1,243 @@
+use std::path::Path;
+
+use nilla_nixos::util::{
+    nix::{BuildOpts, EvalOpts, NixSettings, build_command, eval_command, repl_command},
+    nix_version::{Implementation, NixVersion},
+};
+
+fn version(output: &str, features: &[&str]) -> NixVersion {
+    NixVersion {
+        experimental_features: features.iter().map(|f| f.to_string()).collect(),
+        ..NixVersion::parse(output).unwrap()
+    }
+}
+
+#[test]
+fn implementations() {
+    let cases = [
+        (
+            "nix (Nix) 2.24.10\n",
+            Implementation::Nix,
+            (2, 24, 10),
+            None,
+            "Nix 2.24.10",
+        ),
+        (
+            "nix (Lix, like Nix) 2.91.1\n",
+            Implementation::Lix,
+            (2, 91, 1),
+            None,
+            "Lix 2.91.1",
+        ),
+        (
+            "nix (Determinate Nix 3.6.2) 2.29.0\n",
+            Implementation::Determinate,
+            (3, 6, 2),
+            Some((2, 29, 0)),
+            "Determinate Nix 3.6.2 (Nix 2.29.0)",
+        ),
+        // Pre-releases and versions without a patch number
+        (
+            "nix (Nix) 2.26.0pre20241210_dirty",
+            Implementation::Nix,
+            (2, 26, 0),
+            None,
+            "Nix 2.26.0",
+        ),
+        (
+            "nix (Nix) 2.3",
+            Implementation::Nix,
+            (2, 3, 0),
+            None,
+            "Nix 2.3.0",
+        ),
+    ];
+    for (output, implementation, expected, nix_version, display) in cases {
+        let nix = NixVersion::parse(output).unwrap();
+        assert_eq!(nix.implementation, implementation, "{output}");
+        assert_eq!(nix.version, expected, "{output}");
+        assert_eq!(nix.nix_version, nix_version, "{output}");
+        assert_eq!(nix.to_string(), display, "{output}");
+    }
+
+    for output in ["", "nix 2.24.10", "nix (Nix) unknown", "nix (Guix) 1.4.0"] {
+        let err = NixVersion::parse(output).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Could not make sense of `nix --version`"),
+            "{output}: {err}"
+        );
+    }
+}
+
+#[test]
+fn experimental_features() {
+    let config = "\
+accept-flake-config = false
+experimental-features = flakes nix-command
+extra-platforms = aarch64-linux
+";
+    assert_eq!(
+        NixVersion::parse_features(config),
+        ["flakes", "nix-command"]
+    );
+    assert!(NixVersion::parse_features("max-jobs = 8\n").is_empty());
+
+    // The flag is only added where the feature is not on already
+    let nix = version("nix (Nix) 2.24.10", &[]);
+    assert_eq!(
+        nix.nix_command_args(),
+        ["--extra-experimental-features", "nix-command"]
+    );
+    let nix = version("nix (Nix) 2.24.10", &["nix-command", "flakes"]);
+    assert!(nix.nix_command_args().is_empty());
+    let nix = version("nix (Lix, like Nix) 2.91.1", &[]);
+    assert!(nix.has_nix_command());
+    assert_eq!(nix.nix_command_args().len(), 2);
+
+    // Before 2.4 there is no feature to turn on
+    let nix = version("nix (Nix) 2.3.16", &[]);
+    assert!(!nix.has_nix_command());
+    assert!(nix.nix_command_args().is_empty());
+}
+
+#[test]
+fn evaluation_commands() {
+    let opts = EvalOpts {
+        json: true,
+        impure: false,
+        ..Default::default()
+    };
+
+    let nix = version("nix (Nix) 2.24.10", &[]);
+    assert_eq!(
+        eval_command(&nix, &NixSettings::default(), "1 + 1", &opts),
+        (
+            "nix",
+            [
+                "--extra-experimental-features",
+                "nix-command",
+                "eval",
+                "--show-trace",
+                "--json",
+                "--expr",
+                "1 + 1"
+            ]
+            .map(String::from)
+            .to_vec()
+        )
+    );
+
+    let nix = version("nix (Determinate Nix 3.6.2) 2.29.0", &["nix-command"]);
+    let (program, args) =
+        eval_command(&nix, &NixSettings::default(), "1 + 1", &EvalOpts::default());
+    assert_eq!(program, "nix");
+    assert_eq!(
+        args,
+        ["eval", "--show-trace", "--impure", "--expr", "1 + 1"]
+    );
+
+    let nix = version("nix (Nix) 2.3.16", &[]);
+    assert_eq!(
+        eval_command(&nix, &NixSettings::default(), "1 + 1", &opts),
+        (
+            "nix-instantiate",
+            [
+                "--eval",
+                "--show-trace",
+                "--json",
+                "--strict",
+                "--option",
+                "pure-eval",
+                "true",
+                "--expr",
+                "1 + 1"
+            ]
+            .map(String::from)
+            .to_vec()
+        )
+    );
+}
+
+#[test]
+fn build_commands() {
+    let file = Path::new("/nix/store/x-source/nilla.nix");
+    let opts = BuildOpts {
+        system: Some("aarch64-linux".to_string()),
+        ..Default::default()
+    };
+
+    let nix = version("nix (Lix, like Nix) 2.91.1", &[]);
+    let (program, args) =
+        build_command(&nix, &NixSettings::default(), file, "packages.hello", &opts);
+    assert_eq!(program, "nix");
+    assert_eq!(
+        args,
+        [
+            "--extra-experimental-features",
+            "nix-command",
+            "build",
+            "--json",
+            "--no-link",
+            "-f",
+            "/nix/store/x-source/nilla.nix",
+            "--system",
+            "aarch64-linux",
+            "packages.hello"
+        ]
+    );
+
+    let nix = version("nix (Nix) 2.3.16", &[]);
+    let (program, args) =
+        build_command(&nix, &NixSettings::default(), file, "packages.hello", &opts);
+    assert_eq!(program, "nix-build");
+    assert_eq!(
+        args,
+        [
+            "/nix/store/x-source/nilla.nix",
+            "-A",
+            "packages.hello",
+            "--no-out-link",
+            "--option",
+            "system",
+            "aarch64-linux"
+        ]
+    );
+}
+
+#[test]
+fn repl_commands() {
+    let file = Path::new("/tmp/nilla-repl-x.nix");
+    let settings = NixSettings {
+        options: vec![("sandbox".to_string(), "relaxed".to_string())],
+        ..Default::default()
+    };
+
+    let nix = version("nix (Nix) 2.24.10", &[]);
+    assert_eq!(
+        repl_command(&nix, &settings, file),
+        (
+            "nix",
+            [
+                "--extra-experimental-features",
+                "nix-command",
+                "repl",
+                "--option",
+                "sandbox",
+                "relaxed",
+                "--file",
+                "/tmp/nilla-repl-x.nix"
+            ]
+            .map(String::from)
+            .to_vec()
+        )
+    );
+
+    // Before installables, and in Lix, the repl loads any files it is given
+    for output in ["nix (Nix) 2.18.1", "nix (Lix, like Nix) 2.91.1"] {
+        let nix = version(output, &["nix-command"]);
+        let (program, args) = repl_command(&nix, &NixSettings::default(), file);
+        assert_eq!(program, "nix");
+        assert_eq!(args, ["repl", "/tmp/nilla-repl-x.nix"]);
+    }
+}
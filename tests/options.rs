@@ -0,0 +1,446 @@
+use std::path::Path;
+
+use clap::Parser;
+use nilla_nixos::{
+    commands::option::describe,
+    util::{
+        eval_cache::{CachedEval, EvalCache},
+        nix::{FixedOutputStoreEntry, project_expr},
+        nix_expr::{AttrPath, Expr},
+        nix_mock::MockNix,
+        options::{Definition, OptionDoc, Query, option_tree, query_option, search},
+        store_path::StorePath,
+    },
+};
+use nixos_cli_def::{Cli, Commands, commands::options::OptionsCommands};
+use serde_json::json;
+
+fn system() -> Expr {
+    Expr::var("system")
+}
+
+fn nginx(nix: &MockNix) {
+    nix.on_eval(
+        "definitionsWithLocations",
+        json!({
+            "value": true,
+            "type": "boolean",
+            "description": { "_type": "mdDoc", "text": "Whether to enable nginx.\n" },
+            "hasDefault": true,
+            "default": false,
+            "declarations": ["/nix/store/aaa-source/nixos/modules/nginx.nix"],
+            "definitions": [{ "file": "/nix/store/bbb-source/hosts/web01.nix", "value": true }],
+        }),
+    );
+}
+
+#[tokio::test]
+async fn an_option() {
+    let dir = tempfile::tempdir().unwrap();
+    let nix = MockNix::new(dir.path());
+    nginx(&nix);
+    nix.on_eval("_type or null", json!([null, null, "option"]));
+
+    let path = AttrPath::parse("services.nginx.enable").unwrap();
+    let info = query_option(&nix, &system(), &path).await.unwrap();
+    assert_eq!(info.option, path);
+    assert_eq!(info.value, json!(true));
+    assert_eq!(info.r#type.as_deref(), Some("boolean"));
+    assert_eq!(
+        info.description.as_deref(),
+        Some("Whether to enable nginx.\n")
+    );
+    assert_eq!(info.default, Some(json!(false)));
+    assert_eq!(
+        info.definitions,
+        [Definition {
+            file: "/nix/store/bbb-source/hosts/web01.nix".to_string(),
+            value: json!(true),
+        }]
+    );
+
+    let evaluations = nix.evaluations();
+    assert_eq!(evaluations.len(), 2, "{evaluations:?}");
+    assert!(evaluations[1].contains("sanitise 8 system.config.services.nginx.enable"));
+    // Functions, as in `nixpkgs.overlays`, cannot be written as JSON
+    assert!(
+        evaluations[1]
+            .contains("sanitise = depth: value: if builtins.isFunction value then \"«lambda»\""),
+        "{}",
+        evaluations[1]
+    );
+    assert!(evaluations[1].contains("sanitise 8 (option.definitionsWithLocations or [ ])"));
+
+    assert_eq!(
+        describe(&info),
+        "services.nginx.enable
+
+Value:
+  true
+
+Type:
+  boolean
+
+Default:
+  false
+
+Description:
+  Whether to enable nginx.
+
+Declared in:
+  /nix/store/aaa-source/nixos/modules/nginx.nix
+
+Defined in:
+  /nix/store/bbb-source/hosts/web01.nix
+    true
+"
+    );
+}
+
+#[tokio::test]
+async fn inside_an_option() {
+    let dir = tempfile::tempdir().unwrap();
+    let nix = MockNix::new(dir.path());
+    nginx(&nix);
+    // `virtualHosts` is the option, the host and its root are values of it
+    nix.on_eval("_type or null", json!([null, null, "option", null, null]));
+
+    let path = AttrPath::parse("services.nginx.virtualHosts.\"example.com\".root").unwrap();
+    let info = query_option(&nix, &system(), &path).await.unwrap();
+    assert_eq!(
+        info.option,
+        AttrPath::from(["services", "nginx", "virtualHosts"])
+    );
+    assert!(describe(&info).starts_with(
+        "services.nginx.virtualHosts.\"example.com\".root\n\
+             Part of option services.nginx.virtualHosts\n"
+    ));
+
+    let evaluations = nix.evaluations();
+    assert!(evaluations[1].contains("system.options.services.nginx.virtualHosts"));
+    assert!(
+        evaluations[1].contains("system.config.services.nginx.virtualHosts.\"example.com\".root")
+    );
+}
+
+// `_module.args` holds a set that refers to itself, as `lib.fix` makes them, and `pkgs`
+#[tokio::test]
+async fn a_value_that_refers_to_itself() {
+    let dir = tempfile::tempdir().unwrap();
+    let nix = MockNix::new(dir.path());
+    nix.on_eval("_type or null", json!([null, "option"]));
+    // What Nix gives for `lib.fix (self: { inherit self; })` and a package set
+    nix.on_eval(
+        "definitionsWithLocations",
+        json!({
+            "value": {
+                "self": { "self": { "self": { "self": { "self": { "self": { "self": {
+                    "self": "«…»"
+                } } } } } } },
+                "pkgs": { "_type": "pkgs" },
+            },
+            "type": "lazy attribute set of raw value",
+            "description": null,
+            "hasDefault": false,
+            "default": null,
+            "declarations": [],
+            "definitions": [],
+        }),
+    );
+
+    let path = AttrPath::parse("_module.args").unwrap();
+    let info = query_option(&nix, &system(), &path).await.unwrap();
+    assert_eq!(info.value["pkgs"], json!({ "_type": "pkgs" }));
+
+    let evaluations = nix.evaluations();
+    let code = &evaluations[1];
+    // Each set and list is one step deeper, until there are none left
+    assert!(
+        code.contains("sanitise 8 system.config._module.args"),
+        "{code}"
+    );
+    assert!(
+        code.contains("if builtins.lessThan depth 1 then \"«…»\" else builtins.mapAttrs (name: safe (builtins.sub depth 1)) value"),
+        "{code}"
+    );
+    assert!(
+        code.contains("if builtins.lessThan depth 1 then \"«…»\" else builtins.map (safe (builtins.sub depth 1)) value"),
+        "{code}"
+    );
+    // Typed values, such as `pkgs`, and derivations are not walked into
+    assert!(
+        code.contains("if value ? _type then builtins.mapAttrs (name: safe 0) (builtins.intersectAttrs { _type = null; text = null; } value)"),
+        "{code}"
+    );
+    assert!(
+        code.contains("if builtins.elem (value.type or null) [ \"derivation\" ] then builtins.toString value.outPath"),
+        "{code}"
+    );
+}
+
+#[tokio::test]
+async fn not_an_option() {
+    let dir = tempfile::tempdir().unwrap();
+    let nix = MockNix::new(dir.path());
+    nix.on_eval("_type or null", json!([null, null]));
+    nix.on_eval("attrNames", json!(["enable", "package"]));
+
+    let path = AttrPath::parse("services.nginx").unwrap();
+    let err = query_option(&nix, &system(), &path).await.unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "`services.nginx` is a set of options, pick one of:\n  \
+         services.nginx.enable\n  services.nginx.package"
+    );
+
+    nix.on_eval("attrNames", json!([]));
+    let err = query_option(&nix, &system(), &path).await.unwrap_err();
+    assert_eq!(err.to_string(), "There is no option `services.nginx`");
+}
+
+fn doc(name: &str, description: &str) -> OptionDoc {
+    OptionDoc {
+        name: name.to_string(),
+        r#type: Some("boolean".to_string()),
+        description: Some(description.to_string()),
+        declarations: vec![],
+    }
+}
+
+fn names<'a>(found: &[&'a OptionDoc]) -> Vec<&'a str> {
+    found.iter().map(|option| option.name.as_str()).collect()
+}
+
+#[tokio::test]
+async fn the_option_tree() {
+    let dir = tempfile::tempdir().unwrap();
+    let nix = MockNix::new(dir.path());
+    let source = "/nix/store/lplzlyk8ldz821dl6pmlhk3md1ms69md-source";
+    nix.on_eval(
+        "optionAttrSetToDocList",
+        json!([
+            {
+                "name": "services.nginx.enable",
+                "type": "boolean",
+                "description": { "_type": "mdDoc", "text": "Whether to enable nginx." },
+                "declarations": ["/nix/store/aaa-nixpkgs/nixos/modules/nginx.nix"],
+                "visible": true,
+                "internal": false,
+            },
+            {
+                "name": "services.nginx.virtualHosts.<name>.root",
+                "type": "null or path",
+                "description": null,
+                "declarations": [],
+                "visible": "shallow",
+                "internal": false,
+            },
+            {
+                "name": "infra.monitoring.enable",
+                "type": "boolean",
+                "description": "Whether to scrape this host.",
+                "declarations": [format!("{source}/modules/monitoring.nix")],
+                "visible": true,
+                "internal": false,
+            },
+            {
+                "name": "system.build.hidden",
+                "type": "unspecified",
+                "description": null,
+                "declarations": [],
+                "visible": false,
+                "internal": false,
+            },
+            {
+                "name": "system.build.internal",
+                "type": "unspecified",
+                "description": null,
+                "declarations": [],
+                "visible": true,
+                "internal": true,
+            },
+        ]),
+    );
+
+    let tree = option_tree(&nix, &system()).await.unwrap();
+    assert_eq!(
+        tree.iter()
+            .map(|option| option.name.as_str())
+            .collect::<Vec<_>>(),
+        [
+            "services.nginx.enable",
+            "services.nginx.virtualHosts.<name>.root",
+            "infra.monitoring.enable",
+        ]
+    );
+    assert_eq!(
+        tree[0].description.as_deref(),
+        Some("Whether to enable nginx.")
+    );
+    assert_eq!(tree[1].description, None);
+    assert!(!tree[0].declared_in(Path::new(source)));
+    assert!(tree[2].declared_in(Path::new(source)));
+
+    let evaluations = nix.evaluations();
+    assert_eq!(evaluations.len(), 1);
+    assert!(
+        evaluations[0].contains("builtins.map (option: { name = option.name;"),
+        "{}",
+        evaluations[0]
+    );
+    assert!(evaluations[0].contains("system.pkgs.lib.optionAttrSetToDocList system.options"));
+}
+
+#[tokio::test]
+async fn the_option_tree_is_cached_per_project() {
+    let dir = tempfile::tempdir().unwrap();
+    let nix = MockNix::new("/nix/store");
+    nix.on_eval("optionAttrSetToDocList", json!([]));
+    let system = |hash: &str| {
+        let entry = FixedOutputStoreEntry {
+            path: StorePath::parse_in(
+                Path::new("/nix/store"),
+                "/nix/store/lplzlyk8ldz821dl6pmlhk3md1ms69md-source",
+            )
+            .unwrap(),
+            hash: hash.to_string(),
+        };
+        project_expr("nilla.nix", &entry).select(["systems", "nixos", "web01", "result"])
+    };
+    let first = system("0sjjj9z1dhilhpc8pq4154czrb79z9cm044jvn75kxcjv6v5l2m5");
+    let changed = system("1bwm2zd7m3w3rb5zwcfqz5fcxhm0rfbwxjkm6ghgk9mfwqbw7ng4");
+
+    for system in [&first, &first, &changed, &first] {
+        let cached = CachedEval::new(&nix, Some(EvalCache::new(dir.path())));
+        option_tree(&cached, system).await.unwrap();
+    }
+    assert_eq!(nix.evaluations().len(), 2);
+}
+
+#[test]
+fn fuzzy_search() {
+    let options = [
+        doc(
+            "services.nginx.virtualHosts.<name>.root",
+            "The path of the web root directory.",
+        ),
+        doc(
+            "services.nginx.enable",
+            "Whether to enable Nginx Web Server.",
+        ),
+        doc("services.nginx.package", "The nginx package to use."),
+        doc("services.nginx.virtualHosts", "Declarative vhost config."),
+        doc(
+            "networking.firewall.allowedTCPPorts",
+            "List of TCP ports on which incoming connections are accepted.",
+        ),
+        doc(
+            "services.openssh.enable",
+            "Whether to enable the OpenSSH daemon.",
+        ),
+    ];
+
+    let found = search(&options, &Query::fuzzy(&["nginx virtual"]));
+    assert_eq!(
+        names(&found),
+        [
+            "services.nginx.virtualHosts",
+            "services.nginx.virtualHosts.<name>.root"
+        ]
+    );
+
+    // A whole name segment beats a match in the description
+    let found = search(&options, &Query::fuzzy(&["NGINX"]));
+    assert_eq!(
+        names(&found)[..3],
+        [
+            "services.nginx.enable",
+            "services.nginx.package",
+            "services.nginx.virtualHosts"
+        ]
+    );
+
+    // Typos in names
+    let found = search(&options, &Query::fuzzy(&["firewal", "tcp"]));
+    assert_eq!(names(&found), ["networking.firewall.allowedTCPPorts"]);
+    let found = search(&options, &Query::fuzzy(&["openshh"]));
+    assert_eq!(names(&found), ["services.openssh.enable"]);
+
+    let found = search(&options, &Query::fuzzy(&["vhosts"]));
+    assert_eq!(
+        names(&found),
+        [
+            "services.nginx.virtualHosts",
+            "services.nginx.virtualHosts.<name>.root"
+        ]
+    );
+
+    // Descriptions
+    let found = search(&options, &Query::fuzzy(&["daemon"]));
+    assert_eq!(names(&found), ["services.openssh.enable"]);
+
+    assert!(search(&options, &Query::fuzzy(&["nginx", "ssh"])).is_empty());
+}
+
+#[test]
+fn regex_search() {
+    let options = [
+        doc("services.nginx.virtualHosts.<name>.root", "The web root."),
+        doc("services.nginx.enable", "Whether to enable the web server."),
+        doc(
+            "services.httpd.virtualHosts.<name>.documentRoot",
+            "The document root.",
+        ),
+    ];
+
+    let query = Query::regex(r"virtualHosts\..*root$").unwrap();
+    assert_eq!(
+        names(&search(&options, &query)),
+        [
+            "services.nginx.virtualHosts.<name>.root",
+            "services.httpd.virtualHosts.<name>.documentRoot",
+        ]
+    );
+
+    // Names first, then descriptions
+    let query = Query::regex("web|enable").unwrap();
+    assert_eq!(
+        names(&search(&options, &query)),
+        [
+            "services.nginx.enable",
+            "services.nginx.virtualHosts.<name>.root",
+        ]
+    );
+
+    assert!(Query::regex("(unclosed").is_err());
+}
+
+#[test]
+fn search_arguments() {
+    let cli = Cli::parse_from([
+        "nilla-nixos",
+        "options",
+        "search",
+        "nginx",
+        "virtual",
+        "-s",
+        "web01",
+        "-n",
+        "5",
+    ]);
+    let Some(Commands::Options(args)) = cli.command else {
+        panic!("not the options command");
+    };
+    let OptionsCommands::Search {
+        query,
+        system,
+        regex,
+        limit,
+        json,
+    } = args.command;
+    assert_eq!(query, ["nginx", "virtual"]);
+    assert_eq!(system.as_deref(), Some("web01"));
+    assert_eq!((regex, limit, json), (false, 5, false));
+
+    assert!(Cli::try_parse_from(["nilla-nixos", "options", "search"]).is_err());
+}
@@ -0,0 +1,150 @@
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use nilla_nixos::util::{
+    config::{Config, Timeouts},
+    process::{Elevation, Stage, StopError, Supervisor},
+};
+use tokio::process::Command;
+use tokio_util::sync::CancellationToken;
+
+fn sh(script: &str) -> Command {
+    let mut command = Command::new("sh");
+    command.args(["-c", script]);
+    command
+}
+
+fn stop_error(err: anyhow::Error) -> StopError {
+    err.downcast::<StopError>().unwrap()
+}
+
+#[tokio::test]
+async fn finished() {
+    let cancel = CancellationToken::new();
+    let supervisor = Supervisor::new(Stage::Evaluation, &cancel, Some(Duration::from_secs(30)));
+    let output = supervisor.output(&mut sh("echo 42")).await.unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "42\n");
+
+    let status = supervisor.status(&mut sh("exit 3")).await.unwrap();
+    assert_eq!(status.code(), Some(3));
+}
+
+#[tokio::test]
+async fn interrupted() {
+    let cancel = CancellationToken::new();
+    let supervisor = Supervisor::new(Stage::Build, &cancel, None);
+    let started = Instant::now();
+    let stop = cancel.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        stop.cancel();
+    });
+
+    let err = supervisor
+        .output(&mut sh("exec sleep 30"))
+        .await
+        .unwrap_err();
+    assert_eq!(stop_error(err), StopError::Interrupted(Stage::Build));
+    // Stopped by the signal, not by running out the grace period
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn timed_out() {
+    let cancel = CancellationToken::new();
+    let timeout = Duration::from_millis(100);
+    let supervisor = Supervisor::new(Stage::Activation, &cancel, Some(timeout));
+    let err = supervisor
+        .status(&mut sh("exec sleep 30"))
+        .await
+        .unwrap_err();
+    assert_eq!(
+        stop_error(err),
+        StopError::TimedOut(Stage::Activation, timeout)
+    );
+    assert!(!cancel.is_cancelled());
+
+    assert_eq!(
+        StopError::TimedOut(Stage::Build, Duration::from_secs(600)).to_string(),
+        "Timed out after 600s during the build"
+    );
+    assert_eq!(
+        StopError::Interrupted(Stage::Build).to_string(),
+        "Interrupted during the build"
+    );
+}
+
+#[tokio::test]
+async fn killed_after_grace_period() {
+    let cancel = CancellationToken::new();
+    let supervisor = Supervisor {
+        grace: Duration::from_millis(200),
+        ..Supervisor::new(Stage::Build, &cancel, Some(Duration::from_millis(100)))
+    };
+    let started = Instant::now();
+    // Ignores the polite signals, as does the `sleep` it leaves holding the output open
+    let script = "trap '' INT TERM; sleep 30 & wait";
+    let err = supervisor.output(&mut sh(script)).await.unwrap_err();
+    assert!(matches!(
+        stop_error(err),
+        StopError::TimedOut(Stage::Build, _)
+    ));
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn critical_stages_finish() {
+    let dir = tempfile::tempdir().unwrap();
+    let done = dir.path().join("done");
+    let cancel = CancellationToken::new();
+    cancel.cancel();
+
+    // Neither the cancellation nor the timeout stops the profile switch
+    let supervisor = Supervisor::new(Stage::ProfileSwitch, &cancel, Some(Duration::ZERO));
+    let script = format!("sleep 0.2; touch '{}'", done.display());
+    let err = supervisor.status(&mut sh(&script)).await.unwrap_err();
+    assert!(done.exists());
+    assert_eq!(
+        stop_error(err).to_string(),
+        "Interrupted, stopping after switching the system profile finished"
+    );
+}
+
+#[test]
+fn elevation() {
+    let sudo = Elevation::Sudo(PathBuf::from("/run/wrappers/bin/sudo"));
+    let command = sudo.command("nixos-rebuild");
+    let command = command.as_std();
+    assert_eq!(command.get_program(), "/run/wrappers/bin/sudo");
+    assert_eq!(
+        command.get_args().collect::<Vec<_>>(),
+        ["-n", "nixos-rebuild"]
+    );
+
+    let command = Elevation::None.command("nixos-rebuild");
+    assert_eq!(command.as_std().get_program(), "nixos-rebuild");
+    assert_eq!(command.as_std().get_args().count(), 0);
+}
+
+#[test]
+fn configured_timeouts() {
+    let config = Config::parse(
+        r#"
+[timeouts]
+eval = 300
+activation = 600
+"#,
+    )
+    .unwrap();
+    assert_eq!(config.timeouts.eval(), Some(Duration::from_secs(300)));
+    assert_eq!(config.timeouts.build(), None);
+    assert_eq!(config.timeouts.activation(), Some(Duration::from_secs(600)));
+    assert_eq!(Config::parse(&config.to_toml().unwrap()).unwrap(), config);
+
+    assert!(Timeouts::default().is_empty());
+    assert!(!Config::default().to_toml().unwrap().contains("timeouts"));
+    assert!(Config::parse("[timeouts]\nswitch = 10\n").is_err());
+}
@@ -0,0 +1,849 @@
+use nilla_nixos::util::project_ref::{
+    Forge, GitParams, GitScheme, Installable, ProjectRef, ProjectRefError,
+};
+use nilla_nixos::util::{archive::ArchiveFormat, hash::Sha256};
+use proptest::prelude::*;
+
+fn parse(s: &str) -> Result<ProjectRef, ProjectRefError> {
+    s.parse()
+}
+
+fn roundtrip(s: &str) -> ProjectRef {
+    let parsed = parse(s).unwrap();
+    assert_eq!(parse(&parsed.to_string()).unwrap(), parsed, "{s}");
+    parsed
+}
+
+#[test]
+fn bare_paths() {
+    for path in [
+        "./",
+        ".",
+        "../infra",
+        "/etc/nixos",
+        "~/infra",
+        "~alice/infra",
+        "$HOME/infra",
+        "${INFRA}",
+    ] {
+        assert_eq!(
+            roundtrip(path),
+            ProjectRef::Path {
+                path: path.to_string(),
+                dir: None,
+                explicit: false,
+            }
+        );
+        assert_eq!(parse(path).unwrap().to_string(), path);
+    }
+}
+
+#[test]
+fn explicit_paths() {
+    assert_eq!(
+        roundtrip("path:./infra"),
+        ProjectRef::Path {
+            path: "./infra".to_string(),
+            dir: None,
+            explicit: true,
+        }
+    );
+    assert_eq!(
+        roundtrip("path:infra"),
+        ProjectRef::Path {
+            path: "infra".to_string(),
+            dir: None,
+            explicit: true,
+        }
+    );
+    assert_eq!(
+        parse("path:"),
+        Err(ProjectRefError::Missing {
+            scheme: "path",
+            component: "path",
+        })
+    );
+    assert_eq!(
+        parse("path:?dir=hosts"),
+        Err(ProjectRefError::Missing {
+            scheme: "path",
+            component: "path",
+        })
+    );
+}
+
+#[test]
+fn path_dirs() {
+    assert_eq!(
+        roundtrip("path:./repo?dir=hosts/prod"),
+        ProjectRef::Path {
+            path: "./repo".to_string(),
+            dir: Some("hosts/prod".to_string()),
+            explicit: true,
+        }
+    );
+    assert_eq!(
+        roundtrip("~/repo?dir=hosts"),
+        ProjectRef::Path {
+            path: "~/repo".to_string(),
+            dir: Some("hosts".to_string()),
+            explicit: false,
+        }
+    );
+    assert_eq!(
+        parse("./repo?dir=hosts/prod").unwrap().to_string(),
+        "./repo?dir=hosts%2Fprod"
+    );
+    assert_eq!(
+        parse("./repo?ref=main"),
+        Err(ProjectRefError::UnknownParameter {
+            scheme: "path",
+            name: "ref".to_string(),
+            suggestion: None,
+        })
+    );
+}
+
+#[test]
+fn git_urls() {
+    assert_eq!(
+        roundtrip("git:https://example.com/infra.git"),
+        ProjectRef::Git {
+            url: "https://example.com/infra.git".to_string(),
+            params: GitParams::default(),
+        }
+    );
+    assert_eq!(
+        roundtrip("git:git@example.com:org/infra.git?rev=abc&ref=main&dir=hosts&submodules=true"),
+        ProjectRef::Git {
+            url: "git@example.com:org/infra.git".to_string(),
+            params: GitParams {
+                rev: Some("abc".to_string()),
+                r#ref: Some("main".to_string()),
+                dir: Some("hosts".to_string()),
+                submodules: true,
+            },
+        }
+    );
+    assert_eq!(
+        parse("git:?rev=abc"),
+        Err(ProjectRefError::Missing {
+            scheme: "git",
+            component: "url",
+        })
+    );
+}
+
+#[test]
+fn git_params_are_normalised() {
+    let parsed = parse("git:/srv/infra?submodules=false&dir=a%20b&ref=main").unwrap();
+    assert_eq!(parsed.to_string(), "git:/srv/infra?ref=main&dir=a+b");
+}
+
+#[test]
+fn forges() {
+    assert_eq!(
+        roundtrip("github:nilla-nix/nixos"),
+        ProjectRef::Forge {
+            forge: Forge::GitHub,
+            owner: "nilla-nix".to_string(),
+            repo: "nixos".to_string(),
+            host: None,
+            params: GitParams::default(),
+        }
+    );
+    assert_eq!(
+        roundtrip("gitlab:Org/Infra?host=git.example.com&rev=abc"),
+        ProjectRef::Forge {
+            forge: Forge::GitLab,
+            owner: "Org".to_string(),
+            repo: "Infra".to_string(),
+            host: Some("git.example.com".to_string()),
+            params: GitParams {
+                rev: Some("abc".to_string()),
+                ..Default::default()
+            },
+        }
+    );
+}
+
+#[test]
+fn forge_components() {
+    assert_eq!(
+        parse("github:"),
+        Err(ProjectRefError::Missing {
+            scheme: "github",
+            component: "owner",
+        })
+    );
+    assert_eq!(
+        parse("github:owner"),
+        Err(ProjectRefError::Missing {
+            scheme: "github",
+            component: "repository",
+        })
+    );
+    assert_eq!(
+        parse("gitlab:owner/"),
+        Err(ProjectRefError::Missing {
+            scheme: "gitlab",
+            component: "repository",
+        })
+    );
+    assert_eq!(
+        parse("github:owner/repo/feature//x"),
+        Err(ProjectRefError::UnexpectedSegment {
+            scheme: "github",
+            segment: "feature//x".to_string(),
+        })
+    );
+}
+
+#[test]
+fn flake_style_forge_refs() {
+    let forge = |forge, params| ProjectRef::Forge {
+        forge,
+        owner: "org".to_string(),
+        repo: "infra".to_string(),
+        host: None,
+        params,
+    };
+    let r#ref = |r: &str| GitParams {
+        r#ref: Some(r.to_string()),
+        ..Default::default()
+    };
+    let rev = "0123456789abcdef0123456789abcdef01234567";
+
+    assert_eq!(
+        roundtrip("github:org/infra/main"),
+        forge(Forge::GitHub, r#ref("main"))
+    );
+    assert_eq!(
+        roundtrip("gitlab:org/infra/release/24.11"),
+        forge(Forge::GitLab, r#ref("release/24.11"))
+    );
+    assert_eq!(
+        roundtrip(&format!("github:org/infra/{rev}")),
+        forge(
+            Forge::GitHub,
+            GitParams {
+                rev: Some(rev.to_string()),
+                ..Default::default()
+            }
+        )
+    );
+    assert_eq!(
+        roundtrip("github:org/infra/main?dir=hosts"),
+        forge(
+            Forge::GitHub,
+            GitParams {
+                r#ref: Some("main".to_string()),
+                dir: Some("hosts".to_string()),
+                ..Default::default()
+            }
+        )
+    );
+    assert_eq!(
+        parse("github:org/infra/main").unwrap().to_string(),
+        "github:org/infra?ref=main"
+    );
+    assert_eq!(parse("github:org/infra/"), parse("github:org/infra"));
+
+    // Short or uppercase hashes are branch names, as they are for flakes
+    assert_eq!(
+        parse("github:org/infra/0123abc"),
+        Ok(forge(Forge::GitHub, r#ref("0123abc")))
+    );
+
+    assert_eq!(
+        parse("github:org/infra/main?ref=dev"),
+        Err(ProjectRefError::DuplicateParameter {
+            name: "ref".to_string()
+        })
+    );
+    assert_eq!(
+        parse(&format!("github:org/infra/{rev}?ref=dev")),
+        Err(ProjectRefError::DuplicateParameter {
+            name: "ref".to_string()
+        })
+    );
+    assert_eq!(
+        parse("github:org/infra/main?rev=0123456789abcdef0123456789abcdef01234567"),
+        Err(ProjectRefError::DuplicateParameter {
+            name: "rev".to_string()
+        })
+    );
+}
+
+#[test]
+fn sourcehut() {
+    assert_eq!(
+        roundtrip("sourcehut:~alice/infra/main"),
+        ProjectRef::Forge {
+            forge: Forge::Sourcehut,
+            owner: "~alice".to_string(),
+            repo: "infra".to_string(),
+            host: None,
+            params: GitParams {
+                r#ref: Some("main".to_string()),
+                ..Default::default()
+            },
+        }
+    );
+    assert_eq!(Forge::Sourcehut.default_host(), Some("git.sr.ht"));
+    assert_eq!(
+        parse("sorcehut:~alice/infra").unwrap_err().to_string(),
+        "Unknown project scheme `sorcehut:`, did you mean `sourcehut:`?"
+    );
+}
+
+#[test]
+fn installables() {
+    let installable = |s: &str| s.parse::<Installable>();
+
+    assert_eq!(
+        installable("github:org/infra/main#web01"),
+        Ok(Installable {
+            project: parse("github:org/infra?ref=main").unwrap(),
+            system: Some("web01".to_string()),
+        })
+    );
+    assert_eq!(
+        installable("github:org/infra?dir=hosts#web01"),
+        Ok(Installable {
+            project: parse("github:org/infra?dir=hosts").unwrap(),
+            system: Some("web01".to_string()),
+        })
+    );
+    assert_eq!(
+        installable("./infra"),
+        Ok(Installable {
+            project: parse("./infra").unwrap(),
+            system: None,
+        })
+    );
+    assert_eq!(
+        installable("./infra#web01").unwrap().to_string(),
+        "./infra#web01"
+    );
+    assert_eq!(
+        installable("github:org/infra#"),
+        Err(ProjectRefError::MissingSystem)
+    );
+
+    // Only the last `#` can start a system name, and only if it is one
+    assert_eq!(
+        installable("./infra#2#web01"),
+        Ok(Installable {
+            project: parse("./infra#2").unwrap(),
+            system: Some("web01".to_string()),
+        })
+    );
+    assert_eq!(
+        installable("https://example.com/infra.tar.gz#web01.example.com"),
+        Ok(Installable {
+            project: parse("https://example.com/infra.tar.gz").unwrap(),
+            system: Some("web01.example.com".to_string()),
+        })
+    );
+    assert_eq!(
+        installable("./build#2/infra"),
+        Ok(Installable {
+            project: parse("./build#2/infra").unwrap(),
+            system: None,
+        })
+    );
+    assert_eq!(
+        installable("githb:org/infra#web01"),
+        Err(ProjectRefError::UnknownScheme {
+            scheme: "githb".to_string(),
+            suggestion: Some("github"),
+        })
+    );
+}
+
+#[test]
+fn self_hosted_forges() {
+    assert_eq!(
+        roundtrip("codeberg:forgejo/forgejo"),
+        ProjectRef::Forge {
+            forge: Forge::Codeberg,
+            owner: "forgejo".to_string(),
+            repo: "forgejo".to_string(),
+            host: None,
+            params: GitParams::default(),
+        }
+    );
+    assert_eq!(Forge::Codeberg.default_host(), Some("codeberg.org"));
+
+    for (scheme, forge) in [("forgejo", Forge::Forgejo), ("gitea", Forge::Gitea)] {
+        assert_eq!(
+            roundtrip(&format!("{scheme}:org/infra?host=git.example.com&ref=main")),
+            ProjectRef::Forge {
+                forge,
+                owner: "org".to_string(),
+                repo: "infra".to_string(),
+                host: Some("git.example.com".to_string()),
+                params: GitParams {
+                    r#ref: Some("main".to_string()),
+                    ..Default::default()
+                },
+            }
+        );
+        assert_eq!(
+            parse(&format!("{scheme}:org/infra")),
+            Err(ProjectRefError::Missing {
+                scheme: forge.scheme(),
+                component: "host",
+            })
+        );
+    }
+}
+
+#[test]
+fn flake_style_git_urls() {
+    assert_eq!(
+        roundtrip("git+https://git.example.com/org/infra.git?ref=main&submodules=1"),
+        ProjectRef::GitUrl {
+            scheme: GitScheme::Https,
+            url: "https://git.example.com/org/infra.git".to_string(),
+            params: GitParams {
+                r#ref: Some("main".to_string()),
+                submodules: true,
+                ..Default::default()
+            },
+        }
+    );
+    assert_eq!(
+        roundtrip("git+ssh://git@git.example.com:2222/org/infra.git"),
+        ProjectRef::GitUrl {
+            scheme: GitScheme::Ssh,
+            url: "ssh://git@git.example.com:2222/org/infra.git".to_string(),
+            params: GitParams::default(),
+        }
+    );
+    assert_eq!(
+        roundtrip("git+file:///srv/git/infra?dir=hosts"),
+        ProjectRef::GitUrl {
+            scheme: GitScheme::File,
+            url: "file:///srv/git/infra".to_string(),
+            params: GitParams {
+                dir: Some("hosts".to_string()),
+                ..Default::default()
+            },
+        }
+    );
+    assert_eq!(
+        parse("git+http://example.com/infra").unwrap().to_string(),
+        "git+http://example.com/infra"
+    );
+}
+
+#[test]
+fn flake_style_git_url_errors() {
+    assert_eq!(
+        parse("git+ssh:///org/infra"),
+        Err(ProjectRefError::Missing {
+            scheme: "git+ssh",
+            component: "host",
+        })
+    );
+    assert_eq!(
+        parse("git+file://"),
+        Err(ProjectRefError::Missing {
+            scheme: "git+file",
+            component: "path",
+        })
+    );
+    assert!(matches!(
+        parse("git+https://[::1/infra"),
+        Err(ProjectRefError::InvalidUrl { .. })
+    ));
+    assert_eq!(
+        parse("git+ssh://git@example.com/infra?host=x"),
+        Err(ProjectRefError::UnknownParameter {
+            scheme: "git+ssh",
+            name: "host".to_string(),
+            suggestion: None,
+        })
+    );
+    assert_eq!(
+        parse("git+htps://example.com/infra"),
+        Err(ProjectRefError::UnknownScheme {
+            scheme: "git+htps".to_string(),
+            suggestion: Some("git+https"),
+        })
+    );
+}
+
+#[test]
+fn tarballs() {
+    assert_eq!(
+        roundtrip("https://example.com/infra.tar.gz"),
+        ProjectRef::Tarball {
+            url: "https://example.com/infra.tar.gz".to_string(),
+            explicit: false,
+        }
+    );
+
+    let explicit = roundtrip("tarball:example.com/infra.tar.gz?token=abc");
+    assert_eq!(
+        explicit,
+        ProjectRef::Tarball {
+            url: "example.com/infra.tar.gz?token=abc".to_string(),
+            explicit: true,
+        }
+    );
+    assert_eq!(
+        explicit.tarball_url().as_deref(),
+        Some("http://example.com/infra.tar.gz?token=abc")
+    );
+
+    assert!(matches!(
+        parse("tarball:http://[::1"),
+        Err(ProjectRefError::InvalidUrl { .. })
+    ));
+    assert_eq!(
+        parse("tarball:"),
+        Err(ProjectRefError::Missing {
+            scheme: "tarball",
+            component: "url",
+        })
+    );
+}
+
+#[test]
+fn archives() {
+    let hash = "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=";
+    let archive = |path: &str, sha256: Option<&str>, explicit| ProjectRef::Archive {
+        path: path.to_string(),
+        sha256: sha256.map(str::to_string),
+        explicit,
+    };
+
+    assert_eq!(
+        roundtrip("./ci/infra.tar.zst"),
+        archive("./ci/infra.tar.zst", None, false)
+    );
+    assert_eq!(
+        roundtrip(&format!("~/Downloads/infra.zip?sha256={hash}")),
+        archive("~/Downloads/infra.zip", Some(hash), false)
+    );
+    assert_eq!(
+        roundtrip("file:ci/infra.tar.xz"),
+        archive("ci/infra.tar.xz", None, true)
+    );
+
+    let url = roundtrip("file:///srv/artifacts/infra.tar.gz");
+    assert_eq!(url, archive("///srv/artifacts/infra.tar.gz", None, true));
+    assert_eq!(url.archive_path(), Some("/srv/artifacts/infra.tar.gz"));
+    assert_eq!(
+        roundtrip("file:/srv/infra.tgz").archive_path(),
+        Some("/srv/infra.tgz")
+    );
+
+    // Local tarballs no longer get `http://` glued on
+    assert_eq!(
+        parse("tarball:./infra.tar.gz"),
+        Ok(archive("./infra.tar.gz", None, true))
+    );
+    assert_eq!(
+        parse("tarball:file:///srv/infra.tar.gz"),
+        Ok(archive("///srv/infra.tar.gz", None, true))
+    );
+
+    // Directories, and other files, are still plain paths
+    assert!(matches!(parse("./infra"), Ok(ProjectRef::Path { .. })));
+    assert!(matches!(
+        parse("path:./infra.tar.gz"),
+        Ok(ProjectRef::Path { .. })
+    ));
+}
+
+#[test]
+fn archive_errors() {
+    assert_eq!(
+        parse("file:./infra.rar").unwrap_err().to_string(),
+        "Unsupported archive `./infra.rar`, expected one of: \
+         .tar.gz, .tar.xz, .tar.zst, .tgz, .txz, .tzst, .tar, .zip"
+    );
+    // A bare path starting with `//` is still just a path
+    assert!(parse("//srv/infra.zip").is_ok());
+    assert_eq!(
+        parse("file:"),
+        Err(ProjectRefError::Missing {
+            scheme: "file",
+            component: "path",
+        })
+    );
+    assert_eq!(
+        parse("file://ci.example.com/infra.zip")
+            .unwrap_err()
+            .to_string(),
+        "Invalid URL `file://ci.example.com/infra.zip`: \
+         only local files are supported, not host `ci.example.com`"
+    );
+    assert_eq!(
+        parse("./infra.zip?sha256=abc"),
+        Err(ProjectRefError::InvalidParameter {
+            name: "sha256",
+            value: "abc".to_string(),
+            expected: "a SHA-256 hash in hex, base-32 or SRI form",
+        })
+    );
+    assert_eq!(
+        parse("./infra.zip?sha265=abc"),
+        Err(ProjectRefError::UnknownParameter {
+            scheme: "file",
+            name: "sha265".to_string(),
+            suggestion: Some("sha256"),
+        })
+    );
+}
+
+#[test]
+fn unknown_parameters_are_rejected() {
+    assert_eq!(
+        parse("github:owner/repo?revv=abc"),
+        Err(ProjectRefError::UnknownParameter {
+            scheme: "github",
+            name: "revv".to_string(),
+            suggestion: Some("rev"),
+        })
+    );
+    assert_eq!(
+        parse("git:/srv/infra?submodule=true"),
+        Err(ProjectRefError::UnknownParameter {
+            scheme: "git",
+            name: "submodule".to_string(),
+            suggestion: Some("submodules"),
+        })
+    );
+    assert_eq!(
+        parse("git:/srv/infra?host=example.com"),
+        Err(ProjectRefError::UnknownParameter {
+            scheme: "git",
+            name: "host".to_string(),
+            suggestion: None,
+        })
+    );
+    assert_eq!(
+        parse("git:/srv/infra?frobnicate=1"),
+        Err(ProjectRefError::UnknownParameter {
+            scheme: "git",
+            name: "frobnicate".to_string(),
+            suggestion: None,
+        })
+    );
+}
+
+#[test]
+fn invalid_parameters_are_rejected() {
+    assert_eq!(
+        parse("github:owner/repo?rev=a&rev=b"),
+        Err(ProjectRefError::DuplicateParameter {
+            name: "rev".to_string(),
+        })
+    );
+    assert_eq!(
+        parse("github:owner/repo?submodules=yes"),
+        Err(ProjectRefError::InvalidParameter {
+            name: "submodules",
+            value: "yes".to_string(),
+            expected: "`true` or `false`",
+        })
+    );
+}
+
+#[test]
+fn unknown_schemes() {
+    assert_eq!(parse(""), Err(ProjectRefError::Empty));
+    assert_eq!(
+        parse("githb:owner/repo"),
+        Err(ProjectRefError::UnknownScheme {
+            scheme: "githb".to_string(),
+            suggestion: Some("github"),
+        })
+    );
+    assert_eq!(
+        parse("svn:https://example.com"),
+        Err(ProjectRefError::UnknownScheme {
+            scheme: "svn".to_string(),
+            suggestion: None,
+        })
+    );
+    assert_eq!(
+        parse("infra"),
+        Err(ProjectRefError::MissingScheme {
+            input: "infra".to_string(),
+        })
+    );
+}
+
+#[test]
+fn errors_mention_suggestions() {
+    let err = parse("github:owner/repo?revv=abc").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Unknown parameter `revv` for `github:` reference, did you mean `rev`?"
+    );
+}
+
+fn segment() -> impl Strategy<Value = String> {
+    "[A-Za-z0-9_.-]{1,16}"
+}
+
+fn value() -> impl Strategy<Value = String> {
+    // Anything goes in parameter values, they are percent-encoded on the way out.
+    ".{0,12}"
+}
+
+fn git_params() -> impl Strategy<Value = GitParams> {
+    (
+        proptest::option::of(value()),
+        proptest::option::of(value()),
+        proptest::option::of(value()),
+        any::<bool>(),
+    )
+        .prop_map(|(rev, r#ref, dir, submodules)| GitParams {
+            rev,
+            r#ref,
+            dir,
+            submodules,
+        })
+}
+
+fn project_ref() -> impl Strategy<Value = ProjectRef> {
+    prop_oneof![
+        (
+            "[./~$][^?]{0,24}".prop_filter("archive", |p| ArchiveFormat::from_path(p).is_none()),
+            proptest::option::of(value()),
+        )
+            .prop_map(|(path, dir)| ProjectRef::Path {
+                path,
+                dir,
+                explicit: false,
+            }),
+        ("[^?]{1,24}", proptest::option::of(value())).prop_map(|(path, dir)| ProjectRef::Path {
+            path,
+            dir,
+            explicit: true,
+        }),
+        ("[^?]{1,24}", git_params()).prop_map(|(url, params)| ProjectRef::Git { url, params }),
+        (
+            prop_oneof![
+                Just(GitScheme::Http),
+                Just(GitScheme::Https),
+                Just(GitScheme::Ssh)
+            ],
+            "[a-z]{1,8}\\.[a-z]{2,3}(/[a-z0-9][a-z0-9._-]{0,7}){1,3}",
+            git_params(),
+        )
+            .prop_map(|(scheme, rest, params)| ProjectRef::GitUrl {
+                scheme,
+                url: format!("{}://{rest}", scheme.transport()),
+                params,
+            }),
+        ("(/[a-z0-9][a-z0-9._-]{0,7}){1,4}", git_params()).prop_map(|(path, params)| {
+            ProjectRef::GitUrl {
+                scheme: GitScheme::File,
+                url: format!("file://{path}"),
+                params,
+            }
+        }),
+        (
+            prop_oneof![
+                Just(Forge::GitHub),
+                Just(Forge::GitLab),
+                Just(Forge::Codeberg),
+                Just(Forge::Sourcehut)
+            ],
+            segment(),
+            segment(),
+            proptest::option::of(value()),
+            git_params(),
+        )
+            .prop_map(|(forge, owner, repo, host, params)| ProjectRef::Forge {
+                forge,
+                owner,
+                repo,
+                host,
+                params,
+            }),
+        (
+            prop_oneof![Just(Forge::Forgejo), Just(Forge::Gitea)],
+            segment(),
+            segment(),
+            value(),
+            git_params(),
+        )
+            .prop_map(|(forge, owner, repo, host, params)| ProjectRef::Forge {
+                forge,
+                owner,
+                repo,
+                host: Some(host),
+                params,
+            }),
+        (
+            "https?://[a-z]{1,8}\\.[a-z]{2,3}/[a-z0-9/._-]{0,16}",
+            any::<bool>()
+        )
+            .prop_map(|(url, explicit)| ProjectRef::Tarball { url, explicit }),
+        (
+            "(\\./|/|~/)([a-z0-9_-][a-z0-9/_-]{0,11})?",
+            prop_oneof![
+                Just(".tar.gz"),
+                Just(".tar.xz"),
+                Just(".tar.zst"),
+                Just(".zip")
+            ],
+            proptest::option::of(proptest::collection::vec(any::<u8>(), 0..8)),
+            any::<bool>(),
+        )
+            .prop_map(|(path, extension, data, explicit)| ProjectRef::Archive {
+                path: format!("{path}{extension}"),
+                sha256: data.map(|data| Sha256::digest(data).to_sri()),
+                explicit,
+            }),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn display_roundtrips(project_ref in project_ref()) {
+        let printed = project_ref.to_string();
+        prop_assert_eq!(parse(&printed), Ok(project_ref));
+    }
+
+    #[test]
+    fn display_is_stable(project_ref in project_ref()) {
+        let printed = project_ref.to_string();
+        prop_assert_eq!(parse(&printed).unwrap().to_string(), printed);
+    }
+
+    #[test]
+    fn parsing_never_panics(input in ".{0,64}") {
+        let _ = parse(&input);
+        let _ = input.parse::<Installable>();
+    }
+
+    #[test]
+    fn installables_roundtrip(
+        project in project_ref(),
+        system in proptest::option::of("[a-z][a-z0-9-]{0,11}"),
+    ) {
+        // A `#` in the reference itself always starts the fragment
+        prop_assume!(!project.to_string().contains('#'));
+        let installable = Installable { project, system };
+        prop_assert_eq!(installable.to_string().parse(), Ok(installable));
+    }
+
+    #[test]
+    fn parsed_inputs_roundtrip(
+        input in "(git|git\\+https|git\\+file|github|gitlab|codeberg|forgejo|sourcehut|path|tarball):[a-z/?=&.~]{0,24}"
+    ) {
+        if let Ok(parsed) = parse(&input) {
+            prop_assert_eq!(parse(&parsed.to_string()), Ok(parsed));
+        }
+    }
+}
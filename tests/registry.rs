@@ -0,0 +1,203 @@
+use nilla_nixos::util::{
+    config::{Config, save_registry, with_registry},
+    project::parse_project,
+    project_ref::{Installable, ProjectRef},
+    registry::{add, expand, is_valid_name, remove},
+};
+
+fn config() -> Config {
+    Config::parse(
+        r#"
+        [registry]
+        infra = "github:org/infra?ref=main&dir=hosts"
+        web = "git:/srv/web.git#web01"
+        "#,
+    )
+    .unwrap()
+}
+
+#[test]
+fn names() {
+    for name in ["infra", "infra-2", "web_01", "9"] {
+        assert!(is_valid_name(name), "{name}");
+    }
+    for name in [
+        "",
+        "-infra",
+        "a/b",
+        "a:b",
+        "./infra",
+        "~infra",
+        "infra?ref=x",
+    ] {
+        assert!(!is_valid_name(name), "{name}");
+    }
+}
+
+#[test]
+fn adding_normalises_and_validates() {
+    let mut config = Config::default();
+    assert_eq!(
+        add(&mut config, "infra", "github:org/infra/main?dir=hosts").unwrap(),
+        "github:org/infra?ref=main&dir=hosts"
+    );
+    assert_eq!(
+        config.registry["infra"],
+        "github:org/infra?ref=main&dir=hosts"
+    );
+
+    // Replacing an entry is allowed
+    add(&mut config, "infra", "./infra#web01").unwrap();
+    assert_eq!(config.registry["infra"], "./infra#web01");
+
+    assert_eq!(
+        add(&mut config, "a/b", "./infra").unwrap_err().to_string(),
+        "Invalid registry name `a/b` (use letters, digits, `-` and `_`)"
+    );
+    assert_eq!(
+        add(&mut config, "x", "gihub:org/infra")
+            .unwrap_err()
+            .to_string(),
+        "Invalid project gihub:org/infra: Unknown project scheme `gihub:`, did you mean `github:`?"
+    );
+    assert_eq!(config.registry.len(), 1);
+}
+
+#[test]
+fn removing() {
+    let mut config = config();
+    assert_eq!(
+        remove(&mut config, "web").unwrap(),
+        "git:/srv/web.git#web01"
+    );
+    assert_eq!(
+        remove(&mut config, "web").unwrap_err().to_string(),
+        "No registry entry named `web`"
+    );
+    assert_eq!(config.registry.len(), 1);
+}
+
+#[test]
+fn expansion() {
+    let config = config();
+    assert_eq!(
+        expand("infra", &config).as_deref(),
+        Some("github:org/infra?ref=main&dir=hosts")
+    );
+    assert_eq!(
+        expand("infra?ref=staging", &config).as_deref(),
+        Some("github:org/infra?dir=hosts&ref=staging")
+    );
+    assert_eq!(
+        expand("infra?submodules=true#web01", &config).as_deref(),
+        Some("github:org/infra?ref=main&dir=hosts&submodules=true#web01")
+    );
+    assert_eq!(
+        expand("web", &config).as_deref(),
+        Some("git:/srv/web.git#web01")
+    );
+    assert_eq!(
+        expand("web#web02", &config).as_deref(),
+        Some("git:/srv/web.git#web02")
+    );
+
+    for input in ["unknown", "./infra", "github:org/infra", "infra2"] {
+        assert_eq!(expand(input, &config), None, "{input}");
+    }
+}
+
+#[test]
+fn registry_is_checked_before_schemes() {
+    let config = config();
+    assert_eq!(
+        parse_project("infra?rev=abc", &config).unwrap(),
+        Installable {
+            project: "github:org/infra?rev=abc&ref=main&dir=hosts"
+                .parse::<ProjectRef>()
+                .unwrap(),
+            system: None,
+        }
+    );
+    assert_eq!(
+        parse_project("./infra", &config).unwrap().project,
+        "./infra".parse::<ProjectRef>().unwrap()
+    );
+    assert_eq!(
+        parse_project("infra?reff=x", &config)
+            .unwrap_err()
+            .to_string(),
+        "Invalid registry entry infra?reff=x (github:org/infra?ref=main&dir=hosts&reff=x): \
+         Unknown parameter `reff` for `github:` reference, did you mean `ref`?"
+    );
+    assert!(parse_project("unknown", &config).is_err());
+}
+
+#[test]
+fn config_roundtrips() {
+    let mut config = config();
+    add(&mut config, "with-quotes", "path:/srv/a \"b\"").unwrap();
+    assert_eq!(Config::parse(&config.to_toml().unwrap()).unwrap(), config);
+    assert_eq!(Config::default().to_toml().unwrap(), "");
+}
+
+#[test]
+fn saving_keeps_the_rest_of_the_file() {
+    let text = r#"# Machines we deploy
+[hosts."github.com"]
+token-env = "GITHUB_TOKEN" # from the CI secrets
+
+[registry]
+# Production
+infra = "github:org/infra?ref=main&dir=hosts"
+web = "git:/srv/web.git#web01"
+"#;
+    let mut config = Config::parse(text).unwrap();
+    remove(&mut config, "web").unwrap();
+    add(&mut config, "lab", "./lab").unwrap();
+    assert_eq!(
+        with_registry(text, &config.registry).unwrap(),
+        r#"# Machines we deploy
+[hosts."github.com"]
+token-env = "GITHUB_TOKEN" # from the CI secrets
+
+[registry]
+# Production
+infra = "github:org/infra?ref=main&dir=hosts"
+lab = "./lab"
+"#
+    );
+
+    let mut config = Config::parse(text).unwrap();
+    config.registry.clear();
+    assert_eq!(
+        with_registry(text, &config.registry).unwrap(),
+        "# Machines we deploy\n[hosts.\"github.com\"]\ntoken-env = \"GITHUB_TOKEN\" # from the CI secrets\n"
+    );
+
+    let registry = [("infra".to_string(), "./infra".to_string())].into();
+    let text = with_registry("", &registry).unwrap();
+    assert_eq!(text, "[registry]\ninfra = \"./infra\"\n");
+    assert_eq!(Config::parse(&text).unwrap().registry, registry);
+}
+
+#[test]
+fn saving_keeps_the_file_mode() {
+    use std::{collections::BTreeMap, os::unix::fs::PermissionsExt};
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.toml");
+    std::fs::write(&path, "[registry]\n").unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+    // No other test here reads the config file
+    unsafe { std::env::set_var("NILLA_NIXOS_CONFIG", &path) };
+
+    let registry = BTreeMap::from([("infra".to_string(), "github:org/infra".to_string())]);
+    save_registry(&registry).unwrap();
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o644);
+    assert!(
+        std::fs::read_to_string(&path)
+            .unwrap()
+            .contains("infra = \"github:org/infra\"")
+    );
+}
@@ -0,0 +1,262 @@
+use std::path::Path;
+
+use nilla_nixos::util::{
+    config::{Config, HostConfig},
+    context::Context,
+    nar::Filter,
+    nix_mock::{Call, MockNix},
+    project::{self, Source, resolve_with},
+};
+use serde_json::json;
+
+mod common;
+
+use common::{run, setup};
+
+async fn resolve(nix: &MockNix, uri: &str) -> anyhow::Result<(Source, Option<String>)> {
+    resolve_with(nix, &Config::default(), uri, "nilla.nix").await
+}
+
+#[tokio::test]
+async fn local_paths_are_added_to_the_store() {
+    let (_dir, infra, nix) = setup();
+
+    let (source, system) = resolve(&nix, infra.to_str().unwrap()).await.unwrap();
+    let Source::Path { entry } = source else {
+        panic!("expected a path source, got {source:?}");
+    };
+    assert_eq!(system, None);
+    assert_eq!(entry.path.store_dir(), nix.store());
+    assert_eq!(entry.path.name(), "infra");
+    assert_eq!(entry.path.subpath(), None);
+    assert!(entry.path.as_path().join("nilla.nix").is_file());
+    assert_eq!(
+        nix.calls()[0],
+        Call::AddToStore(infra.clone(), Filter::GitIgnore)
+    );
+    // Nothing needs evaluating for a plain directory
+    assert!(nix.evaluations().is_empty());
+
+    let uri = format!("path:{}?dir=hosts/prod#web01", infra.display());
+    let (source, system) = resolve(&nix, &uri).await.unwrap();
+    let path = source.get_path();
+    assert_eq!(system.as_deref(), Some("web01"));
+    assert!(path.starts_with(nix.store()));
+    assert!(path.ends_with("hosts/prod"));
+}
+
+#[tokio::test]
+async fn local_git_checkouts_use_fetch_git() {
+    let (_dir, infra, nix) = setup();
+    run(&infra, "git", &["init", "--quiet"]);
+    run(&infra, "git", &["add", "."]);
+    run(&infra, "git", &["commit", "--quiet", "-m", "init"]);
+
+    let fetched = nix.add_fixture(&infra, "source").unwrap();
+    nix.on_eval("builtins.fetchGit", json!(fetched));
+
+    let (source, _) = resolve(&nix, infra.to_str().unwrap()).await.unwrap();
+    assert_eq!(source.get_path(), fetched);
+    assert_eq!(
+        nix.evaluations(),
+        [format!(
+            "builtins.fetchGit (builtins.toPath \"{}\")",
+            infra.display()
+        )]
+    );
+    assert!(nix.calls().contains(&Call::Realise(fetched)));
+    assert!(
+        !nix.calls()
+            .contains(&Call::AddToStore(infra, Filter::GitIgnore))
+    );
+}
+
+#[tokio::test]
+async fn forge_references_use_fetch_git() {
+    let (_dir, infra, nix) = setup();
+    let fetched = nix.add_fixture(&infra, "source").unwrap();
+    nix.on_eval("builtins.fetchGit", json!(fetched));
+
+    let uri = "github:org/infra/main?dir=hosts/prod#web01";
+    let (source, system) = resolve(&nix, uri).await.unwrap();
+    let Source::Git { info, entry } = source else {
+        panic!("expected a git source, got {source:?}");
+    };
+    assert_eq!(system.as_deref(), Some("web01"));
+    assert_eq!(info.url, "git@github.com:org/infra.git");
+    assert_eq!(entry.path.as_path(), fetched.join("hosts/prod"));
+    assert_eq!(entry.path.subpath(), Some(Path::new("hosts/prod")));
+    assert_eq!(entry.path.root().as_path(), fetched);
+
+    let calls = nix.calls();
+    let Call::Evaluate {
+        expr, json, impure, ..
+    } = &calls[0]
+    else {
+        panic!("expected an evaluation, got {:?}", calls[0]);
+    };
+    assert_eq!(
+        expr,
+        "builtins.fetchGit { url = \"git@github.com:org/infra.git\"; ref = \"main\"; submodules = false; }"
+    );
+    assert!(*json && *impure);
+    assert_eq!(calls[1], Call::Realise(fetched));
+}
+
+#[tokio::test]
+async fn fetchers_get_host_credentials() {
+    let (_dir, infra, nix) = setup();
+    let fetched = nix.add_fixture(&infra, "source").unwrap();
+    nix.on_eval("builtins.fetchGit", json!(fetched));
+
+    let mut config = Config::default();
+    config.hosts.insert(
+        "github.com".to_string(),
+        HostConfig {
+            ssh_identity: Some("/keys/infra".to_string()),
+            ..Default::default()
+        },
+    );
+    resolve_with(&nix, &config, "github:org/infra", "nilla.nix")
+        .await
+        .unwrap();
+
+    let Call::Evaluate { env, .. } = &nix.calls()[0] else {
+        panic!("expected an evaluation");
+    };
+    assert!(
+        env.iter().any(|(_, value)| value.contains("/keys/infra")),
+        "{env:?}"
+    );
+}
+
+#[tokio::test]
+async fn tarballs_use_fetch_tarball() {
+    let (_dir, infra, nix) = setup();
+    let fetched = nix.add_fixture(&infra, "source").unwrap();
+    nix.on_eval("builtins.fetchTarball", json!(fetched));
+
+    let (source, _) = resolve(&nix, "https://example.com/infra.tar.gz")
+        .await
+        .unwrap();
+    assert_eq!(
+        nix.evaluations(),
+        ["builtins.fetchTarball { url = \"https://example.com/infra.tar.gz\"; }"]
+    );
+    let Source::Tarball { url, entry } = source else {
+        panic!("expected a tarball source, got {source:?}");
+    };
+    assert_eq!(url, "https://example.com/infra.tar.gz");
+    assert_eq!(entry.path.as_path(), fetched);
+}
+
+#[tokio::test]
+async fn archives_are_unpacked_into_the_store() {
+    let (dir, _infra, nix) = setup();
+    let root = dir.path().canonicalize().unwrap();
+    run(&root, "tar", &["-czf", "infra.tar.gz", "infra"]);
+    let archive = root.join("infra.tar.gz");
+
+    let (source, _) = resolve(&nix, archive.to_str().unwrap()).await.unwrap();
+    let Source::Tarball { url, entry } = source else {
+        panic!("expected a tarball source, got {source:?}");
+    };
+    assert_eq!(url, format!("file://{}", archive.display()));
+    assert_eq!(entry.path.store_dir(), nix.store());
+    assert!(entry.path.as_path().join("hosts/prod/nilla.nix").is_file());
+    assert!(nix.evaluations().is_empty());
+
+    // A mismatched hash stops before anything reaches the store
+    let nix = MockNix::new(nix.store());
+    let uri = format!("file:{}?sha256={}", archive.display(), "0".repeat(64));
+    let err = resolve(&nix, &uri).await.unwrap_err();
+    assert!(format!("{err:#}").contains("Hash mismatch"), "{err:#}");
+    assert!(nix.calls().is_empty());
+}
+
+#[tokio::test]
+async fn registry_aliases() {
+    let (_dir, infra, nix) = setup();
+    let fetched = nix.add_fixture(&infra, "source").unwrap();
+    nix.on_eval("builtins.fetchGit", json!(fetched));
+
+    let mut config = Config::default();
+    config
+        .registry
+        .insert("infra".to_string(), "github:org/infra/main".to_string());
+
+    // Resolving through a context uses its config rather than the user's
+    let ctx = Context::new(&nix, config);
+    let (_, system) = project::resolve(&ctx, "infra#web01", "nilla.nix")
+        .await
+        .unwrap();
+    assert_eq!(system.as_deref(), Some("web01"));
+    assert!(nix.evaluations()[0].contains("ref = \"main\";"));
+}
+
+#[tokio::test]
+async fn fetch_failures() {
+    let (_dir, infra, nix) = setup();
+
+    nix.fail_eval("builtins.fetchGit", "error: repository not found");
+    let err = resolve(&nix, "github:org/missing").await.unwrap_err();
+    assert!(
+        format!("{err:#}").contains("error: repository not found"),
+        "{err:#}"
+    );
+
+    nix.on_eval("builtins.fetchTarball", json!(42));
+    let err = resolve(&nix, "https://example.com/infra.tar.gz")
+        .await
+        .unwrap_err();
+    assert_eq!(err.to_string(), "Expected a store path, got 42");
+
+    nix.on_eval_raw("builtins.fetchTarball", "/nix/store/x-source");
+    let err = resolve(&nix, "https://example.com/infra.tar.gz")
+        .await
+        .unwrap_err();
+    assert_eq!(err.to_string(), "Got raw, expected JSON");
+
+    let fetched = nix.add_fixture(&infra, "source").unwrap();
+    nix.on_eval("builtins.fetchTarball", json!(fetched));
+    nix.fail_realise(&fetched, "error: path is not valid");
+    let err = resolve(&nix, "https://example.com/infra.tar.gz")
+        .await
+        .unwrap_err();
+    assert!(
+        format!("{err:#}").contains("error: path is not valid"),
+        "{err:#}"
+    );
+}
+
+#[tokio::test]
+async fn local_failures() {
+    let (_dir, infra, nix) = setup();
+
+    let missing = infra.join("missing");
+    let err = resolve(&nix, missing.to_str().unwrap()).await.unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!("Could not find path {}", missing.display())
+    );
+
+    nix.fail_add_to_store("error: cannot add path");
+    let err = resolve(&nix, infra.to_str().unwrap()).await.unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!("Could not add {} to store", infra.display())
+    );
+}
+
+#[tokio::test]
+async fn local_paths_leave_out_ignored_files() {
+    let (_dir, infra, nix) = setup();
+    std::fs::write(infra.join(".gitignore"), "result\n").unwrap();
+    std::os::unix::fs::symlink("/nix/store/x-system", infra.join("result")).unwrap();
+
+    let (source, _) = resolve(&nix, infra.to_str().unwrap()).await.unwrap();
+    let path = source.get_path();
+    assert!(path.join("nilla.nix").is_file());
+    assert!(path.join(".gitignore").is_file());
+    assert!(path.symlink_metadata().is_ok() && path.join("result").symlink_metadata().is_err());
+}
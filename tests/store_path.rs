@@ -0,0 +1,152 @@
+use std::path::Path;
+
+use nilla_nixos::util::{
+    hash::Sha256,
+    nix::{FixedOutputStoreEntry, project_expr},
+    store_path::{StorePath, StorePathError},
+};
+
+const HASH: &str = "lplzlyk8ldz821dl6pmlhk3md1ms69md";
+
+fn parse(path: &str) -> Result<StorePath, StorePathError> {
+    StorePath::parse_in(Path::new("/nix/store"), path)
+}
+
+#[test]
+fn parts() {
+    let path = parse(&format!("/nix/store/{HASH}-source")).unwrap();
+    assert_eq!(path.store_dir(), Path::new("/nix/store"));
+    assert_eq!(path.hash(), HASH);
+    assert_eq!(path.name(), "source");
+    assert_eq!(path.subpath(), None);
+    assert_eq!(path.root(), path);
+    assert_eq!(path.to_string(), format!("/nix/store/{HASH}-source"));
+
+    // Names may contain dashes of their own
+    let path = parse(&format!("/nix/store/{HASH}-nixos-system-web01-25.05")).unwrap();
+    assert_eq!(path.name(), "nixos-system-web01-25.05");
+}
+
+#[test]
+fn subpaths() {
+    let path = parse(&format!("/nix/store/{HASH}-source/hosts/prod/")).unwrap();
+    assert_eq!(path.name(), "source");
+    assert_eq!(path.subpath(), Some(Path::new("hosts/prod")));
+    assert_eq!(
+        path.root().as_path(),
+        Path::new(&format!("/nix/store/{HASH}-source"))
+    );
+    assert_eq!(
+        path.as_path(),
+        Path::new(&format!("/nix/store/{HASH}-source/hosts/prod"))
+    );
+
+    let root = path.root();
+    assert_eq!(root.join("hosts/prod").unwrap(), path);
+    assert_eq!(root.join("hosts").unwrap().join("./prod").unwrap(), path);
+    assert_eq!(root.join("").unwrap(), root);
+    assert_eq!(path.join(".").unwrap(), path);
+    for escape in ["..", "hosts/../..", "/etc"] {
+        assert_eq!(
+            root.join(escape).unwrap_err(),
+            StorePathError::InvalidSubpath {
+                subpath: escape.into()
+            }
+        );
+    }
+    assert!(parse(&format!("/nix/store/{HASH}-source/../x")).is_err());
+}
+
+#[test]
+fn custom_store_directories() {
+    let store = Path::new("/gnu/store");
+    let path = StorePath::parse_in(store, format!("/gnu/store/{HASH}-source/hosts")).unwrap();
+    assert_eq!(path.store_dir(), store);
+    assert_eq!(path.name(), "source");
+    assert_eq!(path.subpath(), Some(Path::new("hosts")));
+
+    assert_eq!(
+        parse(&format!("/gnu/store/{HASH}-source")).unwrap_err(),
+        StorePathError::NotInStore {
+            path: format!("/gnu/store/{HASH}-source").into(),
+            store_dir: "/nix/store".into(),
+        }
+    );
+}
+
+#[test]
+fn invalid_paths() {
+    for path in ["/nix/store", "/nix/storefoo/x", "/tmp/x", "nix/store/x"] {
+        assert!(
+            matches!(parse(path), Err(StorePathError::NotInStore { .. })),
+            "{path}"
+        );
+    }
+    for entry in [
+        "source".to_string(),
+        format!("{}-source", &HASH[1..]),
+        format!("{HASH}x-source"),
+        // `e`, `o`, `t` and `u` are not part of Nix's base-32
+        format!("{}e-source", &HASH[1..]),
+        format!("{HASH}source"),
+    ] {
+        let err = parse(&format!("/nix/store/{entry}")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("/nix/store/{entry} does not start with a valid store path hash")
+        );
+    }
+    for name in ["", ".", "..", "with space", "quote\"d", &"x".repeat(212)] {
+        let err = parse(&format!("/nix/store/{HASH}-{name}")).unwrap_err();
+        assert!(
+            matches!(err, StorePathError::InvalidName { .. }),
+            "{name}: {err}"
+        );
+    }
+    assert!(parse(&format!("/nix/store/{HASH}-{}", "x".repeat(211))).is_ok());
+}
+
+#[test]
+fn sources() {
+    let hash: Sha256 = "sha256-pQpattmS9VmO3ZIQUFn66az8GSmB4IvYhTTCFn6SUmo="
+        .parse()
+        .unwrap();
+    let path = StorePath::for_source(Path::new("/nix/store"), "empty", &hash).unwrap();
+    assert_eq!(
+        path,
+        parse("/nix/store/9ljssglw74jabzzsqsl3lim4d5jgh4ya-empty").unwrap()
+    );
+
+    assert_eq!(
+        StorePath::for_source(Path::new("/nix/store"), "my project", &hash).unwrap_err(),
+        StorePathError::InvalidName {
+            name: "my project".to_string()
+        }
+    );
+}
+
+#[test]
+fn project_imports_pin_the_whole_entry() {
+    let entry = FixedOutputStoreEntry {
+        path: parse(&format!("/nix/store/{HASH}-source/hosts/prod")).unwrap(),
+        hash: "0sjjj9z1dhilhpc8pq4154czrb79z9cm044jvn75kxcjv6v5l2m5".to_string(),
+    };
+    assert_eq!(
+        project_expr("nilla.nix", &entry).to_string(),
+        format!(
+            "import ((builtins.path {{ path = \"/nix/store/{HASH}-source\"; \
+             sha256 = \"0sjjj9z1dhilhpc8pq4154czrb79z9cm044jvn75kxcjv6v5l2m5\"; \
+             name = \"source\"; }}) + \"/hosts/prod/nilla.nix\")"
+        )
+    );
+
+    let entry = FixedOutputStoreEntry {
+        path: entry.path.root(),
+        ..entry
+    };
+    assert!(
+        project_expr("nilla.nix", &entry)
+            .to_string()
+            .ends_with("+ \"/nilla.nix\")")
+    );
+}
@@ -0,0 +1,121 @@
+use std::path::Path;
+
+use nilla_nixos::{
+    commands::info::describe,
+    util::{
+        nix_expr::Expr,
+        nix_mock::{Call, MockNix},
+        system_info::{links_to, query_info},
+    },
+};
+use serde_json::{Value, json};
+
+const TOPLEVEL: &str = "/nix/store/0c7jbpvqazdbq2wlcbg9cmf9j6c7f3nz-nixos-system-web01-24.11";
+
+fn evaluated() -> Value {
+    json!({
+        "nixpkgs": "/nix/store/8rhx5qpzx3bsvhjfbxk2h4ppq7q5k0vy-source",
+        "revision": "5e4fbfb6b3de1aa2872b76d49fafc942626e2add",
+        "version": "24.11.20250101.5e4fbfb",
+        "release": "24.11",
+        "codeName": "Vicuna",
+        "stateVersion": "23.05",
+        "kernel": "6.6.68",
+        "bootloaders": [
+            ["systemd-boot", false],
+            ["grub", true],
+            ["generic-extlinux-compatible", false],
+            ["limine", false],
+        ],
+        "isContainer": false,
+        "hostPlatform": "x86_64-linux",
+        "baseModules": 1200,
+        "modules": 14,
+        "services": {
+            "nginx": true,
+            "openssh": true,
+            "postgresql": false,
+            "udev": false,
+        },
+        "drvPath": "/nix/store/y3fd4mwv8a5a6vq3lfa7kl8hzj9ysrz8-nixos-system-web01-24.11.drv",
+        "outPath": TOPLEVEL,
+    })
+}
+
+#[tokio::test]
+async fn summary() {
+    let dir = tempfile::tempdir().unwrap();
+    let nix = MockNix::new(dir.path());
+    nix.on_eval("drvPath", evaluated());
+
+    let system = Expr::var("system");
+    let info = query_info(&nix, &system).await.unwrap();
+    assert_eq!(info.bootloader.as_deref(), Some("GRUB"));
+    assert_eq!(info.enabled_services, ["nginx", "openssh"]);
+    assert_eq!(info.kernel.as_deref(), Some("6.6.68"));
+    assert_eq!((info.base_modules, info.modules), (1200, 14));
+    assert_eq!(info.out_path, TOPLEVEL);
+
+    let calls = nix.calls();
+    let Call::Evaluate {
+        expr, json, impure, ..
+    } = &calls[0]
+    else {
+        panic!("{calls:?}");
+    };
+    assert!(*json && !impure);
+    assert!(expr.starts_with("let system = system; config = system.config; in {"));
+    assert!(expr.contains("nixpkgs = builtins.toString system.pkgs.path;"));
+    assert!(expr.contains(
+        "services = builtins.mapAttrs (name: service: service.enable or false) config.services;"
+    ));
+    assert!(expr.contains("config.boot.loader.systemd-boot.enable or false"));
+
+    let table = describe("web01", &info, Some(true), Some(false)).to_string();
+    let lines: Vec<&str> = table.lines().map(str::trim_end).collect();
+    for expected in [
+        " System            web01",
+        " NixOS             24.11.20250101.5e4fbfb (Vicuna)",
+        " State version     23.05",
+        " Modules           1214 (1200 from NixOS)",
+        " Enabled services  2",
+        " Running           yes",
+        " Booted            no",
+    ] {
+        assert!(lines.contains(&expected), "{expected:?} in\n{table}");
+    }
+}
+
+#[tokio::test]
+async fn containers() {
+    let dir = tempfile::tempdir().unwrap();
+    let nix = MockNix::new(dir.path());
+    let mut value = evaluated();
+    value["bootloaders"][1][1] = json!(false);
+    value["isContainer"] = json!(true);
+    value["revision"] = Value::Null;
+    nix.on_eval("drvPath", value);
+
+    let info = query_info(&nix, &Expr::var("system")).await.unwrap();
+    assert_eq!(info.bootloader.as_deref(), Some("none (container)"));
+    assert_eq!(info.revision, None);
+
+    let table = describe("ct", &info, None, None).to_string();
+    assert!(table.contains("unknown, this machine is not running NixOS"));
+}
+
+#[test]
+fn running_and_booted() {
+    let dir = tempfile::tempdir().unwrap();
+    let current = dir.path().join("current-system");
+    let booted = dir.path().join("booted-system");
+    std::os::unix::fs::symlink(TOPLEVEL, &current).unwrap();
+    std::os::unix::fs::symlink("/nix/store/xxx-nixos-system-web01-24.05", &booted).unwrap();
+
+    assert_eq!(links_to(&current, TOPLEVEL), Some(true));
+    assert_eq!(links_to(&booted, TOPLEVEL), Some(false));
+    assert_eq!(
+        links_to(Path::new("/nonexistent/current-system"), TOPLEVEL),
+        None
+    );
+}